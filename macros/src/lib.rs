@@ -1,13 +1,13 @@
 extern crate proc_macro;
 
 use quote::{format_ident, quote};
-use syn::{parse_macro_input, ItemFn, Attribute, Token, FnArg};
-use syn::parse::{Parse, ParseStream};
-use regex::Regex;
+use proc_macro2::TokenStream as TokenStream2;
+use syn::{parse_quote, Attribute, Expr, ExprLit, GenericArgument, GenericParam, Ident, ImplItem, Item, Lit, LitStr, Meta, Pat, PathArguments, ReturnType, Signature, Token, FnArg, Type};
+use syn::parse::{Parse, ParseStream, Parser};
 
 #[derive(Debug)]
 struct FunctionToolAttribute {
-    name: Option<String>,
+    name: Option<Ident>,
     description: Option<String>,
 }
 
@@ -16,8 +16,6 @@ impl Parse for FunctionToolAttribute {
         let mut name = None;
         let mut description = None;
 
-        let check_name_pattern = Regex::new(r"^[_a-zA-Z][_a-zA-Z0-9]*").unwrap();
-
         while !input.is_empty() {
             let key = input.parse::<syn::Ident>()?;
             let _eq = input.parse::<Token![=]>()?;
@@ -25,10 +23,9 @@ impl Parse for FunctionToolAttribute {
 
             match key.to_string().as_str() {
                 "name" => {
-                    if !check_name_pattern.is_match(&value.value()) {
-                        return Err(syn::Error::new(key.span(), format!("Value {} isn't proper ident", &value.value())));
-                    }
-                    name = Some(value.value());
+                    let ident = syn::parse_str::<Ident>(&value.value())
+                        .map_err(|_| syn::Error::new(value.span(), format!("`{}` isn't a valid identifier", value.value())))?;
+                    name = Some(Ident::new(&ident.to_string(), value.span()));
                 }
                 "description" => description = Some(value.value()),
                 _ => return Err(syn::Error::new(key.span(), "expected `name`, `description`")),
@@ -43,49 +40,265 @@ impl Parse for FunctionToolAttribute {
     }
 }
 
-#[proc_macro_attribute]
-pub fn function_tool(args: proc_macro::TokenStream, item: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let attr_args = parse_macro_input!(args as FunctionToolAttribute);
-    let input_fn = parse_macro_input!(item as ItemFn);
-    
-    let origin_ident = input_fn.sig.ident.clone();
+/// Options of a single argument, given as `#[param(description = "...", default = <expr>)]`.
+#[derive(Default)]
+struct ParamAttribute {
+    description: Option<LitStr>,
+    default: Option<Expr>,
+}
 
-    let mut function_description = attr_args
-        .description.as_ref().cloned()
-        .unwrap_or(String::new());
-    
-    let mut function_ident = attr_args
-        .name.as_ref().cloned()
-        .map(|e| syn::parse_str::<syn::Ident>(&e).unwrap())
-        .unwrap_or(input_fn.sig.ident.clone());
+impl ParamAttribute {
+    fn from_attrs(attrs: &[Attribute]) -> syn::Result<Self> {
+        let mut param = ParamAttribute::default();
 
-    let parameters_struct_ident = format_ident!("{}Parameters", function_ident);
-    let params = input_fn.sig.inputs
-        .iter()
-        .filter_map(|arg| {
-            match arg {
-                FnArg::Receiver(_) => None,
-                FnArg::Typed(arg) => Some((arg.pat.clone(), arg.ty.clone())),
+        for attr in attrs.iter().filter(|e| e.path().is_ident("param")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("description") {
+                    param.description = Some(meta.value()?.parse()?);
+                } else if meta.path.is_ident("default") {
+                    param.default = Some(meta.value()?.parse()?);
+                } else {
+                    return Err(meta.error("expected `description`, `default`"));
+                }
+                Ok(())
+            })?;
+        }
+
+        Ok(param)
+    }
+}
+
+fn is_named(ty: &Type, name: &str) -> bool {
+    match ty {
+        Type::Path(path) => path.path.segments.last().is_some_and(|e| e.ident == name),
+        _ => false,
+    }
+}
+
+fn is_option(ty: &Type) -> bool {
+    is_named(ty, "Option")
+}
+
+/// Rejects the types a parameter struct can't hold, references are only handled at the top level by `owned_type`.
+fn check_type(ty: &Type) -> syn::Result<()> {
+    let message = match ty {
+        Type::Reference(_) => "borrowed types are only supported at the top level, use e.g. `Option<String>` instead of `Option<&str>`",
+        Type::ImplTrait(_) => "tool arguments need a concrete type",
+        Type::TraitObject(_) => "trait objects can't be deserialized, use a concrete type",
+        Type::BareFn(_) => "functions can't be passed as tool arguments",
+        Type::Never(_) | Type::Infer(_) | Type::Macro(_) | Type::Verbatim(_) => "unsupported tool argument type",
+        Type::Paren(paren) => return check_type(&paren.elem),
+        Type::Group(group) => return check_type(&group.elem),
+        Type::Array(array) => return check_type(&array.elem),
+        Type::Slice(slice) => return check_type(&slice.elem),
+        Type::Tuple(tuple) => return tuple.elems.iter().try_for_each(check_type),
+        Type::Path(path) => {
+            for segment in &path.path.segments {
+                let PathArguments::AngleBracketed(ref args) = segment.arguments else { continue };
+                for arg in &args.args {
+                    match arg {
+                        GenericArgument::Type(ty) => check_type(ty)?,
+                        GenericArgument::Lifetime(lifetime) => {
+                            return Err(syn::Error::new_spanned(lifetime, "types with lifetimes can't be deserialized from JSON, use the owned type"));
+                        }
+                        _ => {}
+                    }
+                }
             }
+            return Ok(());
+        }
+        _ => return Ok(()),
+    };
+    Err(syn::Error::new_spanned(ty, message))
+}
+
+/// The type the argument is deserialized as, and whether the function borrows it: `&str` becomes `String`,
+/// `&[T]` `Vec<T>`, `&Path` `PathBuf` and any other `&T` just `T`.
+fn owned_type(ty: &Type) -> syn::Result<(Type, bool)> {
+    let Type::Reference(reference) = ty else {
+        check_type(ty)?;
+        return Ok((ty.clone(), false));
+    };
+    if reference.mutability.is_some() {
+        return Err(syn::Error::new_spanned(reference, "tool arguments can't be `&mut`, the tool owns the deserialized value"));
+    }
+
+    let owned = match *reference.elem {
+        Type::Path(ref path) if path.path.is_ident("str") => parse_quote!(String),
+        Type::Path(ref path) if path.path.is_ident("Path") => parse_quote!(std::path::PathBuf),
+        Type::Slice(ref slice) => {
+            let elem = &slice.elem;
+            parse_quote!(Vec<#elem>)
+        }
+        ref elem => elem.clone(),
+    };
+    check_type(&owned)?;
+    Ok((owned, true))
+}
+
+/// One argument of a tool function, as a field of the parameter struct.
+struct Param {
+    ident: Ident,
+    ty: Type,
+    borrowed: bool,
+    attr: ParamAttribute,
+}
+
+/// Joins the `///` lines of a function into one description.
+fn doc_comment(attrs: &[Attribute]) -> Option<String> {
+    let lines = attrs
+        .iter()
+        .filter_map(|attr| match attr.meta {
+            Meta::NameValue(ref meta) if meta.path.is_ident("doc") => match meta.value {
+                Expr::Lit(ExprLit { lit: Lit::Str(ref doc), .. }) => Some(doc.value().trim().to_string()),
+                _ => None,
+            },
+            _ => None,
         })
         .collect::<Vec<_>>();
 
+    (!lines.is_empty()).then(|| lines.join(" ").trim().to_string())
+}
+
+/// `count_from` becomes `CountFrom`, the casing Rust expects of type names.
+fn upper_camel_case(name: &str) -> String {
+    name.split('_')
+        .filter(|e| !e.is_empty())
+        .map(|e| {
+            let mut chars = e.chars();
+            chars.next().map(|first| first.to_uppercase().chain(chars).collect::<String>()).unwrap_or_default()
+        })
+        .collect()
+}
+
+/// Generates the parameter struct and the `Tool` impl for one function. `owner` is the type of the impl block
+/// a method belongs to, its tool then keeps the receiver in an `Arc`. `#[param]` attributes are stripped from `sig`.
+fn expand_tool(attr_args: &FunctionToolAttribute, attrs: &[Attribute], sig: &mut Signature, owner: Option<&Type>) -> syn::Result<TokenStream2> {
+    let origin_ident = sig.ident.clone();
+
+    let function_description = attr_args
+        .description.as_ref().cloned()
+        .or(doc_comment(attrs))
+        .unwrap_or(String::new());
+
+    let function_ident = attr_args
+        .name.as_ref().cloned()
+        .unwrap_or(sig.ident.clone());
+
+    if let Some(asyncness) = sig.asyncness {
+        return Err(syn::Error::new_spanned(asyncness, "tool functions can't be `async`, tools are executed synchronously"));
+    }
+    // Lifetimes only ever belong to borrowed arguments, which are passed from the owned parameter struct.
+    if sig.generics.params.iter().any(|e| !matches!(e, GenericParam::Lifetime(_))) {
+        return Err(syn::Error::new_spanned(&sig.generics, "tool functions can't be generic, the parameters need a concrete JSON schema"));
+    }
+
+    let receiver = match sig.receiver() {
+        Some(receiver) if receiver.mutability.is_some() || receiver.reference.is_none() => {
+            return Err(syn::Error::new_spanned(receiver, "tool methods must take `&self`"));
+        }
+        Some(_) if owner.is_none() => {
+            return Err(syn::Error::new_spanned(&sig.ident, "put `#[function_tool]` on the impl block to turn methods into tools"));
+        }
+        receiver => receiver.is_some(),
+    };
+
+    // `parse` names its structs `ParseTool` and `ParseParameters`, names like `CountFrom` stay as they are.
+    let struct_prefix = upper_camel_case(&function_ident.to_string());
+    let parameters_struct_ident = format_ident!("{}Parameters", struct_prefix, span = function_ident.span());
+    let mut params = vec![];
+    for arg in sig.inputs.iter_mut() {
+        if let FnArg::Typed(arg) = arg {
+            let Pat::Ident(ref pat) = *arg.pat else {
+                return Err(syn::Error::new_spanned(&arg.pat, "tool arguments must be plain identifiers, they become the JSON parameter names"));
+            };
+            let (ty, borrowed) = owned_type(&arg.ty)?;
+            let attr = ParamAttribute::from_attrs(&arg.attrs)?;
+            // `#[param]` only exists for the macro, the compiler must never see it.
+            arg.attrs.retain(|e| !e.path().is_ident("param"));
+            params.push(Param { ident: pat.ident.clone(), ty, borrowed, attr });
+        }
+    }
+
+    let mut default_fns = vec![];
     let parameter_fields = params
         .iter()
-        .map(|(pat, ty)| quote! {
-            #pat: #ty
+        .map(|Param { ident, ty, attr, .. }| {
+            let doc = attr.description.as_ref().map(|e| quote! { #[doc = #e] });
+            let serde = match attr.default {
+                Some(ref default) => {
+                    let default_ident = format_ident!("__{}_default_{}", parameters_struct_ident, ident);
+                    let default_path = LitStr::new(&default_ident.to_string(), proc_macro2::Span::call_site());
+                    let value = if is_option(ty) { quote! { Some(#default) } } else { quote! { #default } };
+
+                    default_fns.push(quote! {
+                        #[allow(non_snake_case)]
+                        fn #default_ident() -> #ty {
+                            #value
+                        }
+                    });
+                    quote! { #[serde(default = #default_path)] }
+                }
+                // Optional arguments may be left out by the model.
+                None if is_option(ty) => quote! { #[serde(default, skip_serializing_if = "Option::is_none")] },
+                None => quote! {},
+            };
+
+            quote! {
+                #doc
+                #serde
+                #ident: #ty
+            }
         })
         .collect::<Vec<_>>();
 
-    let arg_list = params.iter().map(|(pat, _)| {
-        quote! { params.#pat }
+    let arg_list = params.iter().map(|Param { ident, borrowed, .. }| {
+        if *borrowed { quote! { &params.#ident } } else { quote! { params.#ident } }
     });
-    
-    let tool_struct_ident = format_ident!("{}Tool", function_ident);
-    
+    let function = match owner {
+        Some(_) if receiver => quote! { self.receiver.#origin_ident },
+        Some(owner) => quote! { <#owner>::#origin_ident },
+        None => quote! { #origin_ident },
+    };
+
+    // Errors of fallible functions are handed to the model instead of aborting the tool call.
+    let returns_result = matches!(sig.output, ReturnType::Type(_, ref ty) if is_named(ty, "Result"));
+    let call = if returns_result {
+        quote! {
+            match #function(#(#arg_list),*) {
+                Ok(result) => Ok(serde_json::json!({ "result": result })),
+                Err(e) => Ok(serde_json::json!({ "error": e.to_string() })),
+            }
+        }
+    } else {
+        quote! {
+            let result = #function(#(#arg_list),*);
+            Ok(serde_json::json! ({
+                "result": result,
+            }))
+        }
+    };
+
+    let tool_struct_ident = format_ident!("{}Tool", struct_prefix, span = function_ident.span());
+    let tool_struct = match owner {
+        Some(owner) if receiver => quote! {
+            struct #tool_struct_ident {
+                receiver: std::sync::Arc<#owner>,
+            }
+
+            impl #tool_struct_ident {
+                #[allow(dead_code)]
+                fn new(receiver: impl Into<std::sync::Arc<#owner>>) -> Self {
+                    Self { receiver: receiver.into() }
+                }
+            }
+        },
+        _ => quote! { struct #tool_struct_ident {} },
+    };
+
     let parameter_struct = quote! {
-        struct #tool_struct_ident {}
-        
+        #tool_struct
+
         #[derive(Debug, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
         struct #parameters_struct_ident {
             #(#parameter_fields),*
@@ -93,7 +306,7 @@ pub fn function_tool(args: proc_macro::TokenStream, item: proc_macro::TokenStrea
 
         impl_tool_params!(#parameters_struct_ident);
 
-        #input_fn
+        #(#default_fns)*
     };
 
     let struct_impl = quote! {
@@ -101,24 +314,150 @@ pub fn function_tool(args: proc_macro::TokenStream, item: proc_macro::TokenStrea
             fn metadata(&self) -> ToolMetaData {
                 ToolMetaData {
                     name: stringify!(#function_ident).to_string(),
-                    description: stringify!(#function_description).to_string(),
+                    description: #function_description.to_string(),
                     parameters: #parameters_struct_ident :: schema(),
                 }
             }
 
             fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
                 let params = serde_json::from_value::<#parameters_struct_ident>(parameters)?;
-                let result = #origin_ident(#(#arg_list),*);
-                Ok(serde_json::json! ({
-                    "result": result,
-                }))
+                #call
             }
         }
     };
 
-
-    quote! {
+    Ok(quote! {
         #parameter_struct
         #struct_impl
-    }.into()
+    })
+}
+
+/// Expands `#[function_tool(args)]` on `item`, split from the entry point so it can be tested.
+fn expand(args: TokenStream2, item: TokenStream2) -> syn::Result<TokenStream2> {
+    let attr_args = FunctionToolAttribute::parse.parse2(args)?;
+
+    match syn::parse2::<Item>(item)? {
+        Item::Fn(mut input_fn) => expand_tool(&attr_args, &input_fn.attrs, &mut input_fn.sig, None)
+            .map(|tool| quote! {
+                #tool
+                #input_fn
+            }),
+        Item::Impl(mut input_impl) => {
+            let owner = input_impl.self_ty.clone();
+            let mut tools = vec![];
+
+            for item in input_impl.items.iter_mut() {
+                let ImplItem::Fn(method) = item else { continue };
+                let Some(index) = method.attrs.iter().position(|e| e.path().is_ident("function_tool")) else { continue };
+
+                let attr = method.attrs.remove(index);
+                let method_args = match attr.meta {
+                    Meta::Path(_) => Ok(FunctionToolAttribute { name: None, description: None }),
+                    _ => attr.parse_args::<FunctionToolAttribute>(),
+                };
+                tools.push(method_args.and_then(|e| expand_tool(&e, &method.attrs, &mut method.sig, Some(&owner))));
+            }
+
+            tools.into_iter().collect::<syn::Result<Vec<_>>>().map(|tools| quote! {
+                #input_impl
+                #(#tools)*
+            })
+        }
+        other => Err(syn::Error::new_spanned(other, "`#[function_tool]` expects a function or an impl block")),
+    }
+}
+
+/// Turns a function into a tool. On an impl block, every method marked with `#[function_tool(...)]`
+/// becomes a tool whose struct owns the receiver, so tools can carry clients, keys or caches.
+#[proc_macro_attribute]
+pub fn function_tool(args: proc_macro::TokenStream, item: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    expand(args.into(), item.into())
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand_pretty(args: TokenStream2, item: TokenStream2) -> String {
+        let expanded = expand(args, item).unwrap();
+        prettyplease::unparse(&syn::parse2(expanded).unwrap())
+    }
+
+    #[test]
+    fn test_expand_function() {
+        insta::assert_snapshot!(expand_pretty(
+            quote! { name = "Add" },
+            quote! {
+                /// Add two numbers.
+                fn add(#[param(description = "first")] a: i32, #[param(default = 1)] b: i32) -> i32 {
+                    a + b
+                }
+            },
+        ));
+    }
+
+    #[test]
+    fn test_expand_option_and_result() {
+        insta::assert_snapshot!(expand_pretty(
+            quote! { description = "parse a number" },
+            quote! {
+                fn parse(text: String, radix: Option<u32>) -> anyhow::Result<i64> {
+                    Ok(i64::from_str_radix(&text, radix.unwrap_or(10))?)
+                }
+            },
+        ));
+    }
+
+    #[test]
+    fn test_expand_borrowed() {
+        insta::assert_snapshot!(expand_pretty(
+            quote! {},
+            quote! {
+                fn search<'a>(query: &'a str, paths: &[String], root: &Path, filter: &Filter, mut limit: usize) -> usize {
+                    limit
+                }
+            },
+        ));
+    }
+
+    #[test]
+    fn test_expand_impl_block() {
+        insta::assert_snapshot!(expand_pretty(
+            quote! {},
+            quote! {
+                impl Counter {
+                    /// Count from a number.
+                    #[function_tool(name = "CountFrom")]
+                    fn count_from(&self, start: u64) -> u64 {
+                        start + self.step
+                    }
+
+                    #[function_tool]
+                    fn reset() -> bool {
+                        true
+                    }
+
+                    fn helper(&self) {}
+                }
+            },
+        ));
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |args: TokenStream2, item: TokenStream2| expand(args, item).unwrap_err().to_string();
+
+        assert_eq!(error(quote! { name = "my-tool" }, quote! { fn f() {} }), "`my-tool` isn't a valid identifier");
+        assert_eq!(error(quote! { title = "x" }, quote! { fn f() {} }), "expected `name`, `description`");
+        assert!(error(quote! {}, quote! { fn f(text: &mut String) {} }).contains("can't be `&mut`"));
+        assert!(error(quote! {}, quote! { fn f(text: Option<&str>) {} }).contains("only supported at the top level"));
+        assert!(error(quote! {}, quote! { fn f(text: Cow<'static, str>) {} }).contains("lifetimes"));
+        assert!(error(quote! {}, quote! { fn f(callback: fn() -> i32) {} }).contains("functions can't be passed"));
+        assert!(error(quote! {}, quote! { fn f<T>(value: T) {} }).contains("can't be generic"));
+        assert!(error(quote! {}, quote! { async fn f() {} }).contains("can't be `async`"));
+        assert!(error(quote! {}, quote! { fn f((a, b): (i32, i32)) {} }).contains("plain identifiers"));
+        assert!(error(quote! {}, quote! { struct S; }).contains("expects a function or an impl block"));
+    }
 }
@@ -0,0 +1,10 @@
+include!("../prelude.rs");
+
+use macros::function_tool;
+
+#[function_tool(name = "read-file")]
+fn read_file(path: String) -> String {
+    path
+}
+
+fn main() {}
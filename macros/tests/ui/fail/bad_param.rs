@@ -0,0 +1,10 @@
+include!("../prelude.rs");
+
+use macros::function_tool;
+
+#[function_tool]
+fn repeat(#[param(times = 2)] text: String) -> String {
+    text
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+include!("../prelude.rs");
+
+use macros::function_tool;
+
+#[function_tool]
+fn echo<T: ToString>(value: T) -> String {
+    value.to_string()
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+include!("../prelude.rs");
+
+use macros::function_tool;
+
+#[function_tool]
+fn append(text: &mut String, suffix: &str) {
+    text.push_str(suffix);
+}
+
+fn main() {}
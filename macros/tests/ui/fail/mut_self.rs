@@ -0,0 +1,18 @@
+include!("../prelude.rs");
+
+use macros::function_tool;
+
+struct Counter {
+    count: u64,
+}
+
+#[function_tool]
+impl Counter {
+    #[function_tool]
+    fn increment(&mut self) -> u64 {
+        self.count += 1;
+        self.count
+    }
+}
+
+fn main() {}
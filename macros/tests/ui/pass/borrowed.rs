@@ -0,0 +1,38 @@
+include!("../prelude.rs");
+
+use std::path::Path;
+use macros::function_tool;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+enum Case {
+    Upper,
+    Lower,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+struct Style {
+    case: Case,
+    separator: Option<String>,
+}
+
+#[function_tool]
+fn join<'a>(words: &'a [String], root: &Path, style: &Style, prefix: &str) -> String {
+    let joined = words.join(style.separator.as_deref().unwrap_or(" "));
+    let joined = match style.case {
+        Case::Upper => joined.to_uppercase(),
+        Case::Lower => joined.to_lowercase(),
+    };
+    format!("{}{}/{}", prefix, root.display(), joined)
+}
+
+fn main() {
+    let arguments = json!({
+        "words": ["a", "B"],
+        "root": "/tmp",
+        "style": { "case": "Upper", "separator": "-" },
+        "prefix": "> ",
+    });
+    assert_eq!(JoinTool {}.execute(arguments).unwrap(), json!({ "result": "> /tmp/A-B" }));
+    assert!(JoinTool {}.metadata().parameters["$defs"]["Style"].is_object());
+}
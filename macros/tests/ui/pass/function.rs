@@ -0,0 +1,28 @@
+include!("../prelude.rs");
+
+use macros::function_tool;
+
+/// Add two numbers.
+/// Negative numbers work too.
+#[function_tool(name = "Add")]
+fn add(#[param(description = "the first number")] a: i64, #[param(default = 1)] b: i64) -> i64 {
+    a + b
+}
+
+#[function_tool(description = "parse a number")]
+fn parse(text: String, radix: Option<u32>) -> anyhow::Result<i64> {
+    Ok(i64::from_str_radix(text.trim(), radix.unwrap_or(10))?)
+}
+
+fn main() {
+    let metadata = AddTool {}.metadata();
+    assert_eq!(metadata.name, "Add");
+    assert_eq!(metadata.description, "Add two numbers. Negative numbers work too.");
+    assert_eq!(metadata.parameters["properties"]["a"]["description"], "the first number");
+    assert_eq!(metadata.parameters["required"], json!(["a"]));
+    assert_eq!(AddTool {}.execute(json!({ "a": 2 })).unwrap(), json!({ "result": 3 }));
+
+    assert_eq!(ParseTool {}.metadata().description, "parse a number");
+    assert_eq!(ParseTool {}.execute(json!({ "text": "ff", "radix": 16 })).unwrap(), json!({ "result": 255 }));
+    assert!(ParseTool {}.execute(json!({ "text": "x" })).unwrap()["error"].is_string());
+}
@@ -0,0 +1,31 @@
+include!("../prelude.rs");
+
+use macros::function_tool;
+
+struct Counter {
+    step: u64,
+}
+
+#[function_tool]
+impl Counter {
+    /// Count up from a number.
+    #[function_tool(name = "CountFrom")]
+    fn count_from(&self, start: u64) -> u64 {
+        start + self.step
+    }
+
+    #[function_tool(name = "Zero")]
+    fn zero() -> u64 {
+        0
+    }
+
+    #[allow(dead_code)]
+    fn helper(&self) {}
+}
+
+fn main() {
+    let tool = CountFromTool::new(Counter { step: 2 });
+    assert_eq!(tool.metadata().description, "Count up from a number.");
+    assert_eq!(tool.execute(json!({ "start": 1 })).unwrap(), json!({ "result": 3 }));
+    assert_eq!(ZeroTool {}.execute(json!({})).unwrap(), json!({ "result": 0 }));
+}
@@ -0,0 +1,32 @@
+// The parts of the `rag` crate the expansion refers to, included by every ui test.
+
+#[allow(unused_imports)]
+use serde_json::{json, Value};
+
+pub trait Tool {
+    fn metadata(&self) -> ToolMetaData;
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value>;
+}
+
+#[derive(Debug)]
+pub struct ToolMetaData {
+    pub name: String,
+    pub description: String,
+    pub parameters: Value,
+}
+
+pub trait ToolParameters {
+    fn schema() -> Value;
+}
+
+#[macro_export]
+macro_rules! impl_tool_params {
+    ($t:ty) => {
+        impl $crate::ToolParameters for $t {
+            fn schema() -> Value {
+                serde_json::to_value(schemars::schema_for!($t)).unwrap()
+            }
+        }
+    };
+}
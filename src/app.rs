@@ -1,11 +1,35 @@
+use std::io::{stdin, IsTerminal, Read};
+use std::path::PathBuf;
+use std::sync::Arc;
 use async_openai::Client;
-use async_openai::config::OpenAIConfig;
-use clap::Parser;
-use crate::config::Config;
+use async_openai::types::FinishReason;
+use clap::{Parser, Subcommand};
+use colored::Colorize;
+use serde_json::Value;
+use crate::batch::{self, BatchOptions};
+use crate::bus::EventBus;
+use crate::cache::ResponseCache;
+use crate::client::{ChatClient, Provider, WithQuery};
+use crate::config::{Config, ProviderKind};
+use crate::doctor;
+use crate::events::{EventSender, OutputFormat, TokenUsage, TurnPerf};
+use crate::filters::FilterChain;
+use crate::includes::{fence, Image};
+use crate::interrupts::Interrupts;
+use crate::keychain;
+use crate::logging::Verbosity;
 use crate::manager::ContextManager;
-use crate::processor::Processor;
-use crate::rq::{RqBody, RqBodyBuilder};
+use crate::processor::{Processor, TurnOutcome};
+use crate::recorder::{self, Recorder};
+use crate::retry::CONTINUE_PROMPT;
+use crate::rq::{RqBodyBuilder, StreamOptions};
+use crate::runner;
+use crate::serve;
+use crate::templates::{self, Templates};
 use crate::tools::ToolRegistry;
+use crate::transcript::{Session, Transcript, LAST_SESSION_FILE};
+use crate::tui;
+use crate::usage::{parse_since, render_report, UsageDb, UsageGrouping, USAGE_DB};
 
 #[derive(Parser)]
 #[command(author = "obsidrielle", version = "1.0.0", about = "rust LLM ag(ent) for everything.", long_about = None)]
@@ -19,18 +43,257 @@ pub struct App {
     /// Set base url and exit
     #[arg(long = "sb")]
     set_base_url: Option<String>,
+    /// Store a secret `name=value` in the system keychain and exit
+    #[arg(long = "ss")]
+    set_secret: Option<String>,
+    /// Answer a single prompt on stdout and exit, with 1 if the request failed and 130 if it was cancelled
+    #[arg(short = 'p', long = "prompt")]
+    prompt: Option<String>,
+    /// How answers are written, colors are left out with any of them
+    #[arg(long = "output", value_enum)]
+    output: Option<OutputFormat>,
+    /// Start with a persona from the config
+    #[arg(long = "persona")]
+    persona: Option<String>,
+    /// Print every request with the commands and hooks applied instead of sending it
+    #[arg(long = "dry-run")]
+    dry_run: bool,
+    /// Log the request and response metadata to stderr as well as to the log file
+    #[arg(short = 'v', long = "verbose")]
+    verbose: bool,
+    /// Log the full requests too, with secrets masked
+    #[arg(long = "debug")]
+    debug: bool,
+    /// Chat in a full-screen interface instead of the line-based prompt
+    #[arg(long = "tui", conflicts_with_all = ["prompt", "question", "output"])]
+    tui: bool,
+    /// The same as `-p`, input piped to stdin is appended to it
+    #[arg(conflicts_with = "prompt")]
+    question: Option<String>,
+    #[command(subcommand)]
+    command: Option<AppCommand>,
 }
 
-impl App {
-    pub fn new() -> Self {
-        Self {
-            set_api_key: None,
-            set_base_url: None,
-            set_model: None,
+#[derive(Subcommand)]
+enum AppCommand {
+    /// List the models the provider offers, with their context size where it is reported
+    Models,
+    /// Check the config and the connection to the provider, with a fix for each problem found
+    Doctor,
+    /// Export the last session, as JSON to a `.json` path and as markdown otherwise
+    Export { path: PathBuf },
+    /// Add up the tokens and cost of past requests
+    Usage {
+        /// How far back, like `12h`, `7d`, `2w` or `2025-01-31`, everything by default
+        #[arg(long)]
+        since: Option<String>,
+        #[arg(long, value_enum, default_value = "model")]
+        by: UsageGrouping,
+    },
+    /// Print a session recorded with `transcript.enabled`, or send its requests again with `--run`
+    Replay {
+        file: PathBuf,
+        /// Send every recorded request again and print the new answers
+        #[arg(long)]
+        run: bool,
+    },
+    /// Ask several models the same question at once and print their answers with how long each took
+    Compare {
+        /// Comma separated, like `gpt-4o,deepseek-chat`
+        models: String,
+        /// Input piped to stdin is appended to it
+        prompt: Option<String>,
+    },
+    /// Serve an OpenAI-compatible `/v1/chat/completions` that answers through rag, with its commands, hooks and tools
+    Serve {
+        #[arg(long, default_value_t = 8080)]
+        port: u16,
+        /// Where to listen, `0.0.0.0` makes it reachable from other machines
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+    },
+    /// Answer every prompt of a JSONL file on its own and write the results as JSONL, a line per prompt. A line is a
+    /// prompt as a JSON string or an object with `prompt` and optionally `id`, `system` and `vars`. Running it again
+    /// asks only the prompts without a result
+    Batch {
+        input: PathBuf,
+        /// `<input>.results.jsonl` by default
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// How many prompts are asked at once
+        #[arg(long, default_value_t = 4)]
+        concurrency: usize,
+        /// The system prompt of every prompt, instead of the configured one
+        #[arg(long)]
+        system: Option<String>,
+        /// Fill the saved template with the `vars` of each line, the line's prompt is `{{prompt}}`
+        #[arg(long)]
+        template: Option<String>,
+    },
+    /// Run a `.rag` script without asking for input: prompts, `@` commands, `#` comments and `assert contains`,
+    /// `assert not contains` or `assert matches` lines that check the last answer. Fails if an assertion does
+    Run { script: PathBuf },
+    /// Manage and run the prompt templates kept in the config directory
+    Prompt {
+        #[command(subcommand)]
+        action: PromptAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum PromptAction {
+    /// Save a template, `{{name}}` marks a variable. The template is read from stdin unless given
+    Add { name: String, template: Option<String> },
+    /// List the saved templates with their variables
+    List,
+    /// Fill in a template with `key=value` pairs and answer it like `-p` does
+    Run { name: String, vars: Vec<String> },
+}
+
+/// Providers that report a context size use one of these names for it.
+const CONTEXT_SIZE_KEYS: [&str; 4] = ["context_length", "context_window", "max_context_length", "max_model_len"];
+
+/// `(id, context size)` of every model in a `/models` response, sorted by id.
+pub(crate) fn parse_models(response: &Value) -> Vec<(String, Option<u64>)> {
+    let mut models = response["data"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|model| {
+            let id = model["id"].as_str()?.to_string();
+            let context = CONTEXT_SIZE_KEYS.iter().find_map(|key| model[key].as_u64());
+            Some((id, context))
+        })
+        .collect::<Vec<_>>();
+    models.sort();
+    models
+}
+
+/// Appends what was piped to stdin to the question, fenced so the model can tell them apart.
+fn with_piped_input(question: Option<&str>, piped: &str) -> Option<String> {
+    let piped = piped.trim_end();
+    match question {
+        _ if piped.trim().is_empty() => question.map(str::to_string),
+        None => Some(piped.to_string()),
+        Some(question) => {
+            let fence = fence(piped);
+            Some(format!("{}\n\n{}\n{}\n{}", question, fence, piped, fence))
         }
     }
+}
+
+/// Lists the models with their context size, and their price per million tokens where it is known.
+async fn list_models(context: &Context) -> anyhow::Result<()> {
+    match context.config.provider {
+        Some(ProviderKind::Azure) => anyhow::bail!("Azure OpenAI doesn't list the deployments, set `model` to the name of one"),
+        Some(ProviderKind::Bedrock) => anyhow::bail!("`aws bedrock list-foundation-models` lists the Bedrock models, set `model` to the id of one"),
+        Some(ProviderKind::OpenAi) | None => {}
+    }
+    let response = context.client.models().list_byot::<Value>().await?;
+    let models = parse_models(&response);
+    let width = models.iter().map(|(id, _)| id.len()).max().unwrap_or_default();
+    for (id, size) in models {
+        let price = context.config.price(&id).map(|e| format!("  ${:.2}/${:.2} per 1M tokens", e.input, e.output)).unwrap_or_default();
+        match size {
+            Some(size) => println!("{:width$}  {:>7} tokens{}", id, size, price, width = width),
+            None if price.is_empty() => println!("{}", id),
+            None => println!("{:width$}  {:>14}{}", id, "", price, width = width),
+        }
+    }
+    Ok(())
+}
 
+impl App {
     pub async fn run(&mut self, mut context: Context, mut processor: Processor) -> anyhow::Result<()> {
+        context.dry_run = self.dry_run;
+        match self.command {
+            Some(AppCommand::Models) => return list_models(&context).await,
+            Some(AppCommand::Doctor) => return doctor::run(&context).await,
+            Some(AppCommand::Export { ref path }) => {
+                let last = context.config.config_dir().join(LAST_SESSION_FILE);
+                if !last.exists() {
+                    anyhow::bail!("No session to export yet, it is kept once rag answered something");
+                }
+                Session::load(&last)?.export(path)?;
+                return Ok(());
+            }
+            Some(AppCommand::Usage { ref since, by }) => {
+                let since = match since {
+                    Some(since) => parse_since(since, chrono::Local::now().timestamp())?,
+                    None => 0,
+                };
+                let path = context.config.config_dir().join(USAGE_DB);
+                let rows = UsageDb::open(&path)?.report(since, by)?;
+                match rows.is_empty() {
+                    true => println!("{}", "No requests recorded in that time".truecolor(128, 138, 135)),
+                    false => render_report(&rows, by).iter().for_each(|e| println!("{}", e)),
+                }
+                return Ok(());
+            }
+            Some(AppCommand::Replay { ref file, run }) => {
+                let entries = recorder::load(file)?;
+                match run {
+                    true => recorder::rerun(context.chat.as_ref(), &entries, &context.events).await?,
+                    false => recorder::print(&entries),
+                }
+                return Ok(());
+            }
+            Some(AppCommand::Compare { ref models, ref prompt }) => {
+                let prompt = Self::with_stdin(prompt.as_deref())?;
+                Self::answer_once(&mut context, &mut processor, format!("@compare {} {}", models, prompt)).await;
+            }
+            Some(AppCommand::Serve { port, ref host }) => return serve::run(&mut context, &mut processor, &format!("{}:{}", host, port)).await,
+            Some(AppCommand::Batch { ref input, ref output, concurrency, ref system, ref template }) => {
+                let options = BatchOptions {
+                    input: input.clone(),
+                    output: output.clone(),
+                    concurrency,
+                    system: system.clone(),
+                    template: template.clone(),
+                };
+                return batch::run(&context, &options).await;
+            }
+            Some(AppCommand::Run { ref script }) => return runner::run(&mut context, &mut processor, script).await,
+            Some(AppCommand::Prompt { ref action }) => {
+                let templates = Templates::new(&context.config.config_dir());
+                match action {
+                    PromptAction::Add { name, template } => {
+                        let template = match template {
+                            Some(template) => template.clone(),
+                            None => {
+                                let mut template = String::new();
+                                stdin().read_to_string(&mut template)?;
+                                template
+                            }
+                        };
+                        let path = templates.add(name, &template)?;
+                        println!("{}", format!("Saved {}", path.display()).truecolor(128, 138, 135));
+                    }
+                    PromptAction::List => {
+                        for name in templates.list()? {
+                            let variables = templates::variables(&templates.load(&name)?);
+                            if variables.is_empty() {
+                                println!("{}", name);
+                            } else {
+                                println!("{}  {}", name, variables.join(", ").truecolor(128, 138, 135));
+                            }
+                        }
+                    }
+                    PromptAction::Run { name, vars } => {
+                        let prompt = templates.expand(name, &templates::parse_vars(vars.iter().map(String::as_str))?)?;
+                        Self::answer_once(&mut context, &mut processor, prompt).await;
+                    }
+                }
+                return Ok(());
+            }
+            None => {}
+        }
+        if let Some(ref e) = self.set_secret {
+            let (name, value) = e.split_once('=').ok_or(anyhow::anyhow!("Expected `name=value`"))?;
+            keychain::set_secret(name, value)?;
+            std::process::exit(0);
+        }
         if let Some(ref e) = self.set_model {
             context.config.model = e.to_string();
         }
@@ -41,36 +304,241 @@ impl App {
             context.config.api_key = e.to_string();
         }
         if self.set_api_key.is_some() || self.set_base_url.is_some() || self.set_model.is_some() {
-            context.config.save_config();
+            context.config.save_config()?;
             std::process::exit(0);
         }
 
+        if let Some(ref name) = self.persona {
+            context.switch_persona(name)?;
+        }
+
+        if self.is_one_shot() {
+            let prompt = self.one_shot_prompt()?;
+            Self::answer_once(&mut context, &mut processor, prompt).await;
+        }
+
+        if self.tui {
+            return tui::run(&mut context, &mut processor).await;
+        }
         processor.run(&mut context).await
     }
+
+    /// Answers `prompt` and exits, with 1 if the request failed and 130 if it was cancelled.
+    async fn answer_once(context: &mut Context, processor: &mut Processor, prompt: String) -> ! {
+        let code = match processor.run_once(context, prompt).await {
+            Ok(TurnOutcome::Answered | TurnOutcome::Skipped) => 0,
+            Ok(TurnOutcome::Cancelled) => 130,
+            // The error is already shown with the partial answer.
+            Ok(TurnOutcome::Failed(_)) => 1,
+            Err(e) => {
+                eprintln!("{}", format!("Error: {:#}", e).red());
+                1
+            }
+        };
+        std::process::exit(code);
+    }
+
+    /// Whether this is a one-shot run that keeps stdout to the answer, piping to rag makes one too.
+    pub fn is_one_shot(&self) -> bool {
+        let runs_template = matches!(self.command, Some(AppCommand::Prompt { action: PromptAction::Run { .. } }));
+        let compares = matches!(self.command, Some(AppCommand::Compare { .. }));
+        self.prompt.is_some() || self.question.is_some() || runs_template || compares || !stdin().is_terminal()
+    }
+
+    pub fn verbosity(&self) -> Verbosity {
+        match (self.debug, self.verbose) {
+            (true, _) => Verbosity::Debug,
+            (false, true) => Verbosity::Verbose,
+            (false, false) => Verbosity::Quiet,
+        }
+    }
+
+    pub fn output(&self) -> Option<OutputFormat> {
+        self.output
+    }
+
+    fn one_shot_prompt(&self) -> anyhow::Result<String> {
+        Self::with_stdin(self.prompt.as_deref().or(self.question.as_deref()))
+    }
+
+    /// `question` with what was piped to stdin appended.
+    fn with_stdin(question: Option<&str>) -> anyhow::Result<String> {
+        let mut piped = vec![];
+        if !stdin().is_terminal() {
+            stdin().read_to_end(&mut piped)?;
+        }
+        with_piped_input(question, &String::from_utf8_lossy(&piped)).ok_or(anyhow::anyhow!("Nothing to ask, pass a prompt or pipe some input"))
+    }
 }
 
 pub(crate) struct Context {
     pub config: Config,
     pub manager: ContextManager,
-    pub client: Client<OpenAIConfig>,
+    /// Lists the models and serves the other endpoints.
+    pub client: Client<WithQuery>,
+    /// Where the chat requests go, the same provider as `client` unless a test replaced it.
+    pub chat: Arc<dyn ChatClient>,
     pub rq_body: RqBodyBuilder,
     pub tools: ToolRegistry,
+    pub transcript: Transcript,
+    pub filters: FilterChain,
+    /// What the hooks show goes through here to the renderer.
+    pub events: EventSender,
+    /// The processor's bus, for subscribers that dispatch events of their own.
+    pub bus: Arc<EventBus>,
+    /// Cancels the response stream on Ctrl+C.
+    pub interrupts: Interrupts,
+    /// Images the next question carries, attached with `@image`.
+    pub images: Vec<Image>,
+    /// The persona switched to last, if any.
+    pub persona: Option<String>,
+    /// Tokens the provider reported for the session so far, kept by `TokenTracer`.
+    pub usage: TokenUsage,
+    /// How fast each answer of the session streamed, kept by `PerfTracer` for `@stats`.
+    pub perf: Vec<TurnPerf>,
+    /// Records the raw requests and responses when `transcript.enabled` is set.
+    pub recorder: Option<Arc<Recorder>>,
+    /// The answers kept when `cache.enabled` is set, for `@cache clear`.
+    pub cache: Option<Arc<ResponseCache>>,
+    /// Print the requests instead of sending them, `--dry-run`.
+    pub dry_run: bool,
+    /// Print the request of the current question instead of sending it, set by `@dry`.
+    pub dry_run_once: bool,
+    /// Take the input as it is, without running the `@` commands, for input that doesn't come from the user at the terminal.
+    pub plain_input: bool,
+    /// The models `@compare` asks the current question instead of the current one.
+    pub compare: Option<Vec<String>>,
+    /// Set by a hook to stop reading the current response stream.
+    pub stop_stream: bool,
+    /// The last error a response stream of the current turn failed with.
+    pub turn_error: Option<String>,
+    /// Why the provider ended the last response, if it said.
+    pub finish_reason: Option<FinishReason>,
+    /// The model the last response came from, one of `model_fallbacks` when the configured one failed.
+    pub answered_by: Option<String>,
 }
 
 impl Context {
-    pub fn new(config: Config, context_manager: ContextManager, client: Client<OpenAIConfig>) -> Self {
-        let tools = ToolRegistry::new();
+    pub fn new(config: Config, context_manager: ContextManager, client: Client<WithQuery>, tools: ToolRegistry, events: EventSender) -> Self {
+        let filters = FilterChain::new(&config.content_filters);
         
         let mut base_body = RqBodyBuilder::default();
         base_body.tools(Some(tools.to_tools_call_body()));
         base_body.model(config.model.clone());
+        base_body.sampling(&config.sampling);
         
         Self {
             config,
             manager: context_manager,
+            chat: Arc::new(Provider::new(client.clone())),
             client,
             rq_body: base_body,
-            tools: ToolRegistry::new(),
+            tools,
+            transcript: Transcript::new(),
+            filters,
+            events,
+            bus: Arc::default(),
+            interrupts: Interrupts::listen(),
+            images: vec![],
+            persona: None,
+            usage: TokenUsage::default(),
+            perf: vec![],
+            recorder: None,
+            cache: None,
+            dry_run: false,
+            dry_run_once: false,
+            plain_input: false,
+            compare: None,
+            stop_stream: false,
+            turn_error: None,
+            finish_reason: None,
+            answered_by: None,
         }
     }
-}
\ No newline at end of file
+
+    /// The request for the conversation so far, not streamed for the models in `non_streaming_models`.
+    pub fn request_body(&mut self) -> anyhow::Result<Value> {
+        let stream = !self.config.non_streaming_models.contains(&self.config.model);
+        let body = self.rq_body
+            .messages(self.manager.as_messages())
+            .stream(stream)
+            .stream_options(stream.then(StreamOptions::default))
+            .build()?;
+        Ok(body.into_rq_body())
+    }
+
+    /// The model the last response came from, the configured one before anything was sent.
+    pub fn answered_model(&self) -> &str {
+        self.answered_by.as_deref().unwrap_or(&self.config.model)
+    }
+
+    /// The models the current one falls back to, in order.
+    pub fn fallbacks(&self) -> Vec<String> {
+        let fallbacks = &self.config.model_fallbacks;
+        let next = fallbacks.iter().position(|e| *e == self.config.model).map_or(0, |e| e + 1);
+        fallbacks[next..].iter().filter(|e| **e != self.config.model).cloned().collect()
+    }
+
+    /// The request that has the model go on with `partial`, an answer that broke off, where it stopped.
+    pub fn continuation_body(&mut self, partial: &str) -> anyhow::Result<Value> {
+        let mut body = self.request_body()?;
+        if let Some(messages) = body["messages"].as_array_mut()
+            && !partial.is_empty()
+        {
+            messages.push(serde_json::json!({ "role": "assistant", "content": partial }));
+            messages.push(serde_json::json!({ "role": "user", "content": CONTINUE_PROMPT }));
+        }
+        Ok(body)
+    }
+
+    /// Switches to the persona `name` from the config: its system prompt, sampling parameters and tools replace the current ones.
+    pub fn switch_persona(&mut self, name: &str) -> anyhow::Result<()> {
+        let Some(persona) = self.config.personas.get(name).cloned() else {
+            let names = self.config.personas.keys().cloned().collect::<Vec<_>>();
+            anyhow::bail!("There is no persona named {}, the configured ones are: {}", name, names.join(", "));
+        };
+
+        match persona.system_prompt.as_ref().or(self.config.system_prompt.as_ref()) {
+            Some(prompt) => self.manager.set_system(prompt),
+            None => self.manager.remove_system(),
+        }
+        self.config.sampling = persona.sampling;
+        self.rq_body.sampling(&self.config.sampling);
+        for unknown in self.tools.restrict(persona.tools.as_deref()) {
+            eprintln!("{}", format!("Warning: Persona {} lists {}, which isn't a tool", name, unknown).yellow());
+        }
+        self.rq_body.tools(Some(self.tools.to_tools_call_body()));
+        self.persona = Some(name.to_string());
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_piped_input() {
+        assert_eq!(with_piped_input(Some("hi"), " \n"), Some("hi".to_string()));
+        assert_eq!(with_piped_input(None, "diff\n"), Some("diff".to_string()));
+        assert_eq!(with_piped_input(None, ""), None);
+        assert_eq!(with_piped_input(Some("review this"), "+ a\n- b\n"), Some("review this\n\n```\n+ a\n- b\n```".to_string()));
+        assert_eq!(with_piped_input(Some("explain"), "````rust\n````"), Some("explain\n\n`````\n````rust\n````\n`````".to_string()));
+    }
+
+    #[test]
+    fn test_parse_models() {
+        let response = serde_json::json!({
+            "object": "list",
+            "data": [
+                { "id": "deepseek-reasoner", "object": "model" },
+                { "id": "anthropic/claude", "context_length": 200000 },
+                { "id": "local", "max_model_len": 32768 },
+            ],
+        });
+        assert_eq!(parse_models(&response), [
+            ("anthropic/claude".to_string(), Some(200000)),
+            ("deepseek-reasoner".to_string(), None),
+            ("local".to_string(), Some(32768)),
+        ]);
+    }
+}
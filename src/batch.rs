@@ -0,0 +1,224 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::{stderr, IsTerminal, Write};
+use std::path::PathBuf;
+use async_openai::types::{ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs};
+use chrono::Local;
+use colored::Colorize;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::app::Context;
+use crate::budget::cost;
+use crate::compare;
+use crate::events::{Discard, EventSender, TokenUsage};
+use crate::rq::StreamOptions;
+use crate::templates::Templates;
+use crate::usage::{UsageDb, UsageRecord, USAGE_DB};
+
+/// A line of the input, either a prompt as a JSON string or an object with one.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+struct BatchItem {
+    /// Matches the result to the prompt, the line number if not given.
+    id: Option<String>,
+    prompt: Option<String>,
+    /// Replaces the configured system prompt for this prompt, `--system` replaces both.
+    system: Option<String>,
+    /// Fill in `--template`, the prompt is `{{prompt}}` there.
+    vars: HashMap<String, String>,
+}
+
+/// A line of the output.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct BatchResult {
+    id: String,
+    model: String,
+    content: String,
+    error: Option<String>,
+    usage: Option<TokenUsage>,
+    /// USD, `None` without a price for the model.
+    cost: Option<f64>,
+    duration_ms: u64,
+}
+
+/// What `rag batch` was asked to do.
+#[derive(Debug)]
+pub struct BatchOptions {
+    pub input: PathBuf,
+    pub output: Option<PathBuf>,
+    pub concurrency: usize,
+    pub system: Option<String>,
+    pub template: Option<String>,
+}
+
+/// The items of a JSONL input, with their ids filled in. Empty lines are skipped.
+fn parse_items(input: &str) -> anyhow::Result<Vec<(String, BatchItem)>> {
+    let mut items = vec![];
+    let mut ids = HashSet::new();
+    for (number, line) in input.lines().enumerate().map(|(i, e)| (i + 1, e)) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let item = match serde_json::from_str::<Value>(line) {
+            Ok(Value::String(prompt)) => BatchItem { prompt: Some(prompt), ..Default::default() },
+            Ok(value) => serde_json::from_value(value).map_err(|e| anyhow::anyhow!("Line {} isn't a prompt: {}", number, e))?,
+            Err(e) => anyhow::bail!("Line {} isn't valid JSON: {}", number, e),
+        };
+        let id = item.id.clone().unwrap_or_else(|| number.to_string());
+        if !ids.insert(id.clone()) {
+            anyhow::bail!("Line {} has the id {} of an earlier line, the results couldn't be told apart", number, id);
+        }
+        items.push((id, item));
+    }
+    Ok(items)
+}
+
+/// The results of an earlier run that don't need asking again. The failed ones are left out, and so is a last line
+/// that was cut off.
+fn answered(output: &str) -> Vec<BatchResult> {
+    output
+        .lines()
+        .filter_map(|e| serde_json::from_str::<BatchResult>(e).ok())
+        .filter(|e| e.error.is_none())
+        .collect()
+}
+
+/// The question `item` asks, filled into `template` if there is one.
+fn prompt(item: &BatchItem, template: Option<&str>, templates: &Templates) -> anyhow::Result<String> {
+    let Some(template) = template else { return item.prompt.clone().ok_or(anyhow::anyhow!("There is no prompt")) };
+    let mut vars = item.vars.clone();
+    if let Some(ref prompt) = item.prompt {
+        vars.entry("prompt".to_string()).or_insert(prompt.clone());
+    }
+    templates.expand(template, &vars)
+}
+
+/// Answers every prompt of the input on its own, `concurrency` at a time, and appends the results to the output as they
+/// come. Prompts with a result from an earlier run are skipped, so running it again after a failure only asks the rest.
+pub async fn run(context: &Context, options: &BatchOptions) -> anyhow::Result<()> {
+    let items = parse_items(&std::fs::read_to_string(&options.input)?)?;
+    let output = options.output.clone().unwrap_or_else(|| options.input.with_extension("results.jsonl"));
+
+    // Failed results are dropped from the output, they are asked again.
+    let done = answered(&std::fs::read_to_string(&output).unwrap_or_default());
+    let mut lines = done.iter().map(serde_json::to_string).collect::<Result<Vec<_>, _>>()?;
+    lines.iter_mut().for_each(|e| e.push('\n'));
+    std::fs::write(&output, lines.concat())?;
+    let done = done.into_iter().map(|e| e.id).collect::<HashSet<_>>();
+
+    let pending = items.into_iter().filter(|(id, _)| !done.contains(id)).collect::<Vec<_>>();
+    let total = done.len() + pending.len();
+    if pending.is_empty() {
+        println!("{}", format!("All {} prompts are answered in {} already", total, output.display()).truecolor(128, 138, 135));
+        return Ok(());
+    }
+    if !done.is_empty() {
+        eprintln!("{}", format!("Resuming, {} of {} prompts are answered already", done.len(), total).truecolor(128, 138, 135));
+    }
+
+    // Every question is built before the first is sent, a mistake in the input shouldn't show halfway through.
+    let templates = Templates::new(&context.config.config_dir());
+    let model = context.config.model.clone();
+    let stream = !context.config.non_streaming_models.contains(&model);
+    let configured_system = context.manager.system();
+    let mut requests = vec![];
+    for (id, item) in pending {
+        let prompt = prompt(&item, options.template.as_deref(), &templates).map_err(|e| anyhow::anyhow!("{}: {:#}", id, e))?;
+        let mut messages: Vec<ChatCompletionRequestMessage> = vec![];
+        if let Some(system) = options.system.as_ref().or(item.system.as_ref()).or(configured_system.as_ref()) {
+            messages.push(ChatCompletionRequestSystemMessageArgs::default().content(system.as_str()).build()?.into());
+        }
+        messages.push(ChatCompletionRequestUserMessageArgs::default().content(prompt).build()?.into());
+        // Nothing runs the tools here, the model is left without them.
+        let body = context.rq_body
+            .clone()
+            .messages(messages)
+            .tools(None)
+            .stream(stream)
+            .stream_options(stream.then(StreamOptions::default))
+            .build()?;
+        requests.push((id, body.into_rq_body()));
+    }
+
+    let retry = context.config.retry.clone().unwrap_or_default();
+    let events = EventSender::spawn(Box::new(Discard));
+    let chat = context.chat.clone();
+    let answers = futures::stream::iter(requests)
+        .map(|(id, body)| {
+            let (chat, retry, events, model) = (chat.clone(), retry.clone(), events.clone(), model.clone());
+            async move { (id, compare::answer(chat.as_ref(), body, &model, &retry, &events).await) }
+        })
+        .buffer_unordered(options.concurrency.max(1));
+    tokio::pin!(answers);
+
+    let mut file = OpenOptions::new().append(true).create(true).open(&output)?;
+    let db = UsageDb::open(&context.config.config_dir().join(USAGE_DB));
+    let progress = stderr().is_terminal();
+    let (mut finished, mut failed) = (done.len(), 0);
+    while let Some((id, answer)) = answers.next().await {
+        let result = BatchResult {
+            id,
+            cost: answer.usage.and_then(|e| cost(&context.config, &answer.model, &e)),
+            model: answer.model,
+            content: answer.content,
+            error: answer.error,
+            usage: answer.usage,
+            duration_ms: answer.elapsed.as_millis() as u64,
+        };
+        writeln!(file, "{}", serde_json::to_string(&result)?)?;
+        file.flush()?;
+        if let (Ok(db), Some(usage)) = (&db, result.usage) {
+            let record = UsageRecord { timestamp: Local::now().timestamp(), model: result.model.clone(), usage, cost: result.cost, duration_ms: result.duration_ms };
+            if let Err(e) = db.record(&record) {
+                eprintln!("{}", format!("Warning: Failed to record the usage: {}", e).yellow());
+            }
+        }
+
+        finished += 1;
+        failed += usize::from(result.error.is_some());
+        if progress {
+            eprint!("\r\x1b[2K{}", format!("[{}/{}] {} failed", finished, total, failed).truecolor(128, 138, 135));
+        }
+    }
+    if progress {
+        eprintln!();
+    }
+
+    match failed {
+        0 => {
+            println!("{}", format!("Answered {} prompts into {}", total, output.display()).truecolor(128, 138, 135));
+            Ok(())
+        }
+        failed => anyhow::bail!("{} of {} prompts failed, see `error` in {}, the same command asks only those again", failed, total, output.display()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch() {
+        let items = parse_items("\"plain\"\n\n{\"id\": \"q\", \"prompt\": \"hi\", \"vars\": {\"lang\": \"rust\"}}\n{\"system\": \"be brief\"}\n").unwrap();
+        assert_eq!(items.iter().map(|(id, _)| id.as_str()).collect::<Vec<_>>(), ["1", "q", "4"]);
+        assert_eq!(items[0].1.prompt.as_deref(), Some("plain"));
+        assert_eq!(items[1].1.vars["lang"], "rust");
+        assert!(parse_items("{\"id\": \"a\"}\n{\"id\": \"a\"}").unwrap_err().to_string().contains("id a"));
+        assert!(parse_items("{oops").unwrap_err().to_string().starts_with("Line 1"));
+
+        let dir = std::env::temp_dir().join(format!("rag-batch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let templates = Templates::new(&dir);
+        templates.add("review", "Review this {{lang}}: {{prompt}}").unwrap();
+        assert_eq!(prompt(&items[1].1, Some("review"), &templates).unwrap(), "Review this rust: hi");
+        assert_eq!(prompt(&items[1].1, None, &templates).unwrap(), "hi");
+        assert!(prompt(&items[2].1, None, &templates).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let ok = BatchResult { id: "1".to_string(), model: "m".to_string(), content: "a".to_string(), error: None, usage: None, cost: None, duration_ms: 1 };
+        let failed = BatchResult { id: "2".to_string(), error: Some("down".to_string()), ..serde_json::from_str(&serde_json::to_string(&ok).unwrap()).unwrap() };
+        let output = format!("{}\n{}\n{{\"id\": \"3\", \"mod", serde_json::to_string(&ok).unwrap(), serde_json::to_string(&failed).unwrap());
+        assert_eq!(answered(&output), [ok]);
+    }
+}
@@ -0,0 +1,564 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use async_openai::error::{ApiError, OpenAIError};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use crate::client::{ChatClient, ChunkStream};
+use crate::config::{Config, RetryConfig};
+use crate::events::EventSender;
+use crate::retry::retrying;
+use crate::rq::RsChunkBody;
+
+const SERVICE: &str = "bedrock";
+
+/// What requests to AWS are signed with, from `AWS_ACCESS_KEY_ID` and the like or else the profile in `~/.aws/credentials`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+impl Credentials {
+    pub fn load() -> anyhow::Result<Self> {
+        if let (Ok(access_key_id), Ok(secret_access_key)) = (std::env::var("AWS_ACCESS_KEY_ID"), std::env::var("AWS_SECRET_ACCESS_KEY")) {
+            return Ok(Self { access_key_id, secret_access_key, session_token: std::env::var("AWS_SESSION_TOKEN").ok() });
+        }
+        let path = std::env::var("AWS_SHARED_CREDENTIALS_FILE")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| dirs::home_dir().unwrap_or_default().join(".aws").join("credentials"));
+        let profile = std::env::var("AWS_PROFILE").unwrap_or("default".to_string());
+        let content = std::fs::read_to_string(&path)
+            .map_err(|e| anyhow::anyhow!("No AWS credentials, set AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY or write {}: {}", path.display(), e))?;
+        Self::from_profile(&content, &profile).ok_or(anyhow::anyhow!("No AWS credentials for the profile {} in {}", profile, path.display()))
+    }
+
+    /// The keys of `[profile]` in a credentials file.
+    fn from_profile(content: &str, profile: &str) -> Option<Self> {
+        let mut values = HashMap::new();
+        let mut current = false;
+        for line in content.lines().map(str::trim).filter(|e| !e.is_empty() && !e.starts_with(['#', ';'])) {
+            if let Some(section) = line.strip_prefix('[').and_then(|e| e.strip_suffix(']')) {
+                current = section.trim() == profile;
+            } else if current && let Some((key, value)) = line.split_once('=') {
+                values.insert(key.trim().to_string(), value.trim().to_string());
+            }
+        }
+        Some(Self {
+            access_key_id: values.remove("aws_access_key_id")?,
+            secret_access_key: values.remove("aws_secret_access_key")?,
+            session_token: values.remove("aws_session_token"),
+        })
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|e| format!("{:02x}", e)).collect()
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encodes everything but the characters SigV4 leaves as they are.
+fn uri_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|e| match e {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (e as char).to_string(),
+            e => format!("%{:02X}", e),
+        })
+        .collect()
+}
+
+/// Who signs the requests to `service` in `region`, with SigV4.
+struct Signer<'a> {
+    credentials: &'a Credentials,
+    region: &'a str,
+    service: &'a str,
+}
+
+impl Signer<'_> {
+    /// The `Authorization` header of a request, `path` and `query` as they are sent before they are encoded.
+    /// `headers` are the ones signed, lowercase and `host` and `x-amz-date` among them.
+    fn authorization(&self, method: &str, path: &str, query: &[(String, String)], headers: &[(&str, &str)], payload: &[u8], now: DateTime<Utc>) -> String {
+        let Self { credentials, region, service } = self;
+        let mut query = query.iter().map(|(name, value)| format!("{}={}", uri_encode(name), uri_encode(value))).collect::<Vec<_>>();
+        query.sort();
+        let mut headers = headers.to_vec();
+        headers.sort();
+        let canonical_headers = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value.trim())).collect::<String>();
+        let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+        // Every service but S3 has the path encoded a second time.
+        let canonical_path = path.split('/').map(|e| uri_encode(&uri_encode(e))).collect::<Vec<_>>().join("/");
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method,
+            canonical_path,
+            query.join("&"),
+            canonical_headers,
+            signed_headers,
+            hex(&Sha256::digest(payload))
+        );
+
+        let date = now.format("%Y%m%d").to_string();
+        let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", now.format("%Y%m%dT%H%M%SZ"), scope, hex(&Sha256::digest(canonical_request)));
+        let key = [*region, *service, "aws4_request"].iter().fold(hmac(format!("AWS4{}", credentials.secret_access_key).as_bytes(), &date), |key, e| hmac(&key, e));
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            credentials.access_key_id,
+            scope,
+            signed_headers,
+            hex(&hmac(&key, &string_to_sign))
+        )
+    }
+}
+
+/// The Converse request for an OpenAI style chat request.
+pub fn converse_request(body: &Value) -> Value {
+    let mut system = vec![];
+    let mut messages: Vec<Value> = vec![];
+    for message in body["messages"].as_array().map(Vec::as_slice).unwrap_or_default() {
+        let (role, content) = match message["role"].as_str().unwrap_or_default() {
+            "system" | "developer" => {
+                system.push(serde_json::json!({ "text": text_of(&message["content"]) }));
+                continue;
+            }
+            "assistant" => ("assistant", assistant_content(message)),
+            "tool" => {
+                let id = message["tool_call_id"].as_str().unwrap_or_default();
+                let text = text_of(&message["content"]);
+                // A result only goes with a call of the last answer.
+                let called = messages.iter().rev().find(|e| e["role"] == "assistant").is_some_and(|e| {
+                    e["content"].as_array().into_iter().flatten().any(|e| e["toolUse"]["toolUseId"] == id)
+                });
+                let block = match called {
+                    true => serde_json::json!({ "toolResult": { "toolUseId": id, "content": [{ "text": text }] } }),
+                    false => serde_json::json!({ "text": format!("The result of the tool call {}: {}", id, text) }),
+                };
+                ("user", vec![block])
+            }
+            _ => ("user", user_content(&message["content"])),
+        };
+        if content.is_empty() {
+            continue;
+        }
+        // Bedrock wants the roles to take turns.
+        match messages.last_mut() {
+            Some(last) if last["role"] == role => last["content"].as_array_mut().unwrap().extend(content),
+            _ => messages.push(serde_json::json!({ "role": role, "content": content })),
+        }
+    }
+
+    let mut request = serde_json::json!({ "messages": messages });
+    if !system.is_empty() {
+        request["system"] = system.into();
+    }
+    let mut inference = serde_json::Map::new();
+    for (from, to) in [("max_tokens", "maxTokens"), ("temperature", "temperature"), ("top_p", "topP"), ("stop", "stopSequences")] {
+        if !body[from].is_null() {
+            inference.insert(to.to_string(), body[from].clone());
+        }
+    }
+    if !inference.is_empty() {
+        request["inferenceConfig"] = inference.into();
+    }
+    let tools = body["tools"].as_array().map(Vec::as_slice).unwrap_or_default();
+    if !tools.is_empty() {
+        let specs = tools
+            .iter()
+            .map(|e| {
+                let function = &e["function"];
+                serde_json::json!({ "toolSpec": {
+                    "name": function["name"],
+                    "description": function["description"].as_str().unwrap_or_default(),
+                    "inputSchema": { "json": function["parameters"] },
+                } })
+            })
+            .collect::<Vec<_>>();
+        request["toolConfig"] = serde_json::json!({ "tools": specs });
+    }
+    if !body["thinking"].is_null() {
+        request["additionalModelRequestFields"] = serde_json::json!({ "thinking": body["thinking"] });
+    }
+    request
+}
+
+/// The text of a message content, which is a string or a list of parts.
+fn text_of(content: &Value) -> String {
+    match content.as_array() {
+        Some(parts) => parts.iter().filter_map(|e| e["text"].as_str()).collect::<Vec<_>>().join("\n"),
+        None => content.as_str().unwrap_or_default().to_string(),
+    }
+}
+
+fn user_content(content: &Value) -> Vec<Value> {
+    let Some(parts) = content.as_array() else {
+        return vec![serde_json::json!({ "text": content.as_str().unwrap_or_default() })];
+    };
+    parts
+        .iter()
+        .filter_map(|part| match part["type"].as_str()? {
+            "text" => Some(serde_json::json!({ "text": part["text"] })),
+            // Only images sent inline, `data:image/png;base64,...`, can be passed on.
+            "image_url" => {
+                let (media_type, data) = part["image_url"]["url"].as_str()?.strip_prefix("data:image/")?.split_once(";base64,")?;
+                Some(serde_json::json!({ "image": { "format": media_type, "source": { "bytes": data } } }))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+fn assistant_content(message: &Value) -> Vec<Value> {
+    let mut content = vec![];
+    let text = text_of(&message["content"]);
+    if !text.is_empty() {
+        content.push(serde_json::json!({ "text": text }));
+    }
+    for call in message["tool_calls"].as_array().into_iter().flatten() {
+        let arguments = call["function"]["arguments"].as_str().and_then(|e| serde_json::from_str::<Value>(e).ok());
+        content.push(serde_json::json!({ "toolUse": {
+            "toolUseId": call["id"],
+            "name": call["function"]["name"],
+            "input": arguments.filter(Value::is_object).unwrap_or(serde_json::json!({})),
+        } }));
+    }
+    content
+}
+
+/// A message of an `application/vnd.amazon.eventstream` response.
+#[derive(Debug, Clone, PartialEq)]
+struct Message {
+    headers: HashMap<String, String>,
+    payload: Vec<u8>,
+}
+
+/// Splits the bytes of an event stream into its messages, which may arrive in any number of pieces.
+#[derive(Debug, Default)]
+struct EventStreamDecoder {
+    buffer: Vec<u8>,
+}
+
+impl EventStreamDecoder {
+    /// Takes in `bytes`, returns the messages they complete.
+    fn push(&mut self, bytes: &[u8]) -> Result<Vec<Message>, String> {
+        self.buffer.extend_from_slice(bytes);
+        let mut messages = vec![];
+        while self.buffer.len() >= 12 {
+            let total = u32::from_be_bytes(self.buffer[0..4].try_into().unwrap()) as usize;
+            let headers = u32::from_be_bytes(self.buffer[4..8].try_into().unwrap()) as usize;
+            if crc32fast::hash(&self.buffer[0..8]).to_be_bytes() != self.buffer[8..12] || total < 16 + headers {
+                return Err("The event stream is corrupt".to_string());
+            }
+            if self.buffer.len() < total {
+                break;
+            }
+            let message = self.buffer.drain(..total).collect::<Vec<_>>();
+            if crc32fast::hash(&message[..total - 4]).to_be_bytes() != message[total - 4..] {
+                return Err("An event of the stream is corrupt".to_string());
+            }
+            messages.push(Message {
+                headers: parse_headers(&message[12..12 + headers]).ok_or("The headers of an event can't be read")?,
+                payload: message[12 + headers..total - 4].to_vec(),
+            });
+        }
+        Ok(messages)
+    }
+}
+
+/// The string headers of a message, the others are skipped.
+fn parse_headers(mut bytes: &[u8]) -> Option<HashMap<String, String>> {
+    let mut headers = HashMap::new();
+    let take = |bytes: &mut &[u8], n: usize| {
+        let (taken, rest) = bytes.split_at_checked(n)?;
+        *bytes = rest;
+        Some(taken.to_vec())
+    };
+    while !bytes.is_empty() {
+        let name_length = take(&mut bytes, 1)?[0] as usize;
+        let name = String::from_utf8_lossy(&take(&mut bytes, name_length)?).to_string();
+        let length = match take(&mut bytes, 1)?[0] {
+            0 | 1 => 0,
+            2 => 1,
+            3 => 2,
+            4 => 4,
+            5 | 8 => 8,
+            9 => 16,
+            6 | 7 => u16::from_be_bytes(take(&mut bytes, 2)?.try_into().ok()?) as usize,
+            _ => return None,
+        };
+        let value = take(&mut bytes, length)?;
+        headers.insert(name, String::from_utf8_lossy(&value).to_string());
+    }
+    Some(headers)
+}
+
+/// Turns the Converse stream events into the chunks an OpenAI compatible stream would have sent.
+#[derive(Debug, Default)]
+struct Translator {
+    /// The tool call index of each content block that is a tool use.
+    tool_calls: HashMap<u64, u64>,
+}
+
+impl Translator {
+    fn chunk(&mut self, event_type: &str, event: &Value) -> Option<Value> {
+        let delta = |delta: Value| serde_json::json!({ "choices": [{ "index": 0, "delta": delta }] });
+        let block = event["contentBlockIndex"].as_u64().unwrap_or_default();
+        match event_type {
+            "contentBlockStart" => {
+                let tool_use = &event["start"]["toolUse"];
+                if tool_use.is_null() {
+                    return None;
+                }
+                let index = self.tool_calls.len() as u64;
+                self.tool_calls.insert(block, index);
+                Some(delta(serde_json::json!({ "tool_calls": [{
+                    "index": index,
+                    "id": tool_use["toolUseId"],
+                    "type": "function",
+                    "function": { "name": tool_use["name"], "arguments": "" },
+                }] })))
+            }
+            "contentBlockDelta" => {
+                let change = &event["delta"];
+                if let Some(text) = change["text"].as_str() {
+                    Some(delta(serde_json::json!({ "content": text })))
+                } else if let Some(text) = change["reasoningContent"]["text"].as_str() {
+                    Some(delta(serde_json::json!({ "reasoning_content": text })))
+                } else {
+                    let input = change["toolUse"]["input"].as_str()?;
+                    let index = self.tool_calls.get(&block)?;
+                    Some(delta(serde_json::json!({ "tool_calls": [{ "index": index, "function": { "arguments": input } }] })))
+                }
+            }
+            "messageStop" => {
+                let reason = match event["stopReason"].as_str().unwrap_or_default() {
+                    "tool_use" => "tool_calls",
+                    "max_tokens" => "length",
+                    "guardrail_intervened" | "content_filtered" => "content_filter",
+                    _ => "stop",
+                };
+                Some(serde_json::json!({ "choices": [{ "index": 0, "delta": {}, "finish_reason": reason }] }))
+            }
+            "metadata" => {
+                let usage = &event["usage"];
+                Some(serde_json::json!({ "choices": [], "usage": {
+                    "prompt_tokens": usage["inputTokens"],
+                    "completion_tokens": usage["outputTokens"],
+                    "total_tokens": usage["totalTokens"],
+                } }))
+            }
+            _ => None,
+        }
+    }
+
+    /// The chunk of a message of the stream, an exception fails it.
+    fn translate(&mut self, message: Message) -> Result<Option<RsChunkBody>, OpenAIError> {
+        let payload = serde_json::from_slice::<Value>(&message.payload).unwrap_or_default();
+        if message.headers.get(":message-type").is_some_and(|e| e != "event") {
+            let kind = message.headers.get(":exception-type").or(message.headers.get(":error-code")).cloned().unwrap_or_default();
+            let text = payload["message"].as_str().or(message.headers.get(":error-message").map(String::as_str)).unwrap_or_default();
+            return Err(OpenAIError::StreamError(format!("{}: {}", kind, text)));
+        }
+        let event_type = message.headers.get(":event-type").map(String::as_str).unwrap_or_default();
+        match self.chunk(event_type, &payload) {
+            Some(chunk) => RsChunkBody::parse(chunk),
+            None => Ok(None),
+        }
+    }
+}
+
+/// What a failed request amounts to: rate limits and server errors as the status the retries look for,
+/// everything else as an API error, which is how a prompt that is too long is told apart.
+fn request_error(status: reqwest::StatusCode, body: &str) -> OpenAIError {
+    let body = serde_json::from_str::<Value>(body).unwrap_or_default();
+    let message = body["message"].as_str().or(body["Message"].as_str()).unwrap_or(status.canonical_reason().unwrap_or_default()).to_string();
+    if status.as_u16() == 429 || status.is_server_error() {
+        return OpenAIError::StreamError(format!("Invalid status code: {}: {}", status, message));
+    }
+    OpenAIError::ApiError(ApiError { message, r#type: Some(status.to_string()), param: None, code: None })
+}
+
+/// AWS Bedrock's Converse API, `model` is the id of a model like `anthropic.claude-3-5-sonnet-20240620-v1:0`.
+pub struct Bedrock {
+    region: String,
+    query: Vec<(String, String)>,
+    http_client: reqwest::Client,
+}
+
+impl Bedrock {
+    pub fn new(config: &Config, http_client: reqwest::Client) -> Self {
+        let query = config.extra_query.clone().into_iter().collect();
+        Self { region: config.aws_region().unwrap_or_default(), query, http_client }
+    }
+
+    async fn send(&self, body: &Value) -> Result<reqwest::Response, OpenAIError> {
+        let credentials = Credentials::load().map_err(|e| OpenAIError::InvalidArgument(e.to_string()))?;
+        let host = format!("bedrock-runtime.{}.amazonaws.com", self.region);
+        let path = format!("/model/{}/converse-stream", body["model"].as_str().unwrap_or_default());
+        let payload = serde_json::to_vec(&converse_request(body)).map_err(OpenAIError::JSONDeserialize)?;
+        let now = Utc::now();
+        let date = now.format("%Y%m%dT%H%M%SZ").to_string();
+
+        let mut headers = vec![("content-type", "application/json"), ("host", host.as_str()), ("x-amz-date", date.as_str())];
+        if let Some(ref token) = credentials.session_token {
+            headers.push(("x-amz-security-token", token));
+        }
+        let signer = Signer { credentials: &credentials, region: &self.region, service: SERVICE };
+        let authorization = signer.authorization("POST", &path, &self.query, &headers, &payload, now);
+        let url = format!("https://{}{}", host, path.split('/').map(uri_encode).collect::<Vec<_>>().join("/"));
+        let mut request = self.http_client.post(url).query(&self.query).header("authorization", authorization);
+        for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+            request = request.header(name, value);
+        }
+        let response = request.body(payload).send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            return Err(request_error(status, &response.text().await.unwrap_or_default()));
+        }
+        Ok(response)
+    }
+}
+
+#[async_trait]
+impl ChatClient for Bedrock {
+    async fn stream(&self, body: Value, retry: &RetryConfig, events: &EventSender) -> Result<ChunkStream, OpenAIError> {
+        let response = retrying(retry, events, || self.send(&body)).await?;
+        let mut decoder = EventStreamDecoder::default();
+        let mut translator = Translator::default();
+        let chunks = response.bytes_stream().flat_map(move |bytes| {
+            let chunks = match bytes.map_err(OpenAIError::Reqwest).and_then(|e| decoder.push(&e).map_err(OpenAIError::StreamError)) {
+                Ok(messages) => messages.into_iter().filter_map(|e| translator.translate(e).transpose()).collect(),
+                Err(e) => vec![Err(e)],
+            };
+            futures::stream::iter(chunks)
+        });
+        Ok(Box::pin(chunks))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_signature() {
+        // The `get-vanilla` case of AWS's SigV4 test suite.
+        let credentials = Credentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+        };
+        let now = Utc.with_ymd_and_hms(2015, 8, 30, 12, 36, 0).unwrap();
+        let headers = [("host", "example.amazonaws.com"), ("x-amz-date", "20150830T123600Z")];
+        let signer = Signer { credentials: &credentials, region: "us-east-1", service: "service" };
+        assert_eq!(
+            signer.authorization("GET", "/", &[], &headers, b"", now),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, SignedHeaders=host;x-amz-date, \
+             Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+        assert_eq!(uri_encode("anthropic.claude-v2:1"), "anthropic.claude-v2%3A1");
+
+        let file = "[default]\naws_access_key_id = A\naws_secret_access_key = S\n\n[work]\naws_access_key_id=B\naws_secret_access_key=T\naws_session_token=X\n";
+        assert_eq!(Credentials::from_profile(file, "work").unwrap().session_token.as_deref(), Some("X"));
+        assert_eq!(Credentials::from_profile(file, "default").unwrap().access_key_id, "A");
+        assert_eq!(Credentials::from_profile(file, "other"), None);
+    }
+
+    #[test]
+    fn test_converse_request() {
+        let body = serde_json::json!({
+            "model": "m",
+            "max_tokens": 100,
+            "messages": [
+                { "role": "system", "content": "Be brief." },
+                { "role": "user", "content": [
+                    { "type": "text", "text": "What is this?" },
+                    { "type": "image_url", "image_url": { "url": "data:image/png;base64,AAAA" } },
+                ] },
+                { "role": "assistant", "content": "", "tool_calls": [{ "id": "t1", "type": "function", "function": { "name": "ls", "arguments": "{\"path\":\".\"}" } }] },
+                { "role": "tool", "tool_call_id": "t1", "content": "a.png" },
+                { "role": "tool", "tool_call_id": "0", "content": "b.png" },
+                { "role": "user", "content": "And now?" },
+            ],
+            "tools": [{ "type": "function", "function": { "name": "ls", "description": "lists", "parameters": { "type": "object" } } }],
+        });
+        assert_eq!(converse_request(&body), serde_json::json!({
+            "system": [{ "text": "Be brief." }],
+            "messages": [
+                { "role": "user", "content": [{ "text": "What is this?" }, { "image": { "format": "png", "source": { "bytes": "AAAA" } } }] },
+                { "role": "assistant", "content": [{ "toolUse": { "toolUseId": "t1", "name": "ls", "input": { "path": "." } } }] },
+                { "role": "user", "content": [
+                    { "toolResult": { "toolUseId": "t1", "content": [{ "text": "a.png" }] } },
+                    { "text": "The result of the tool call 0: b.png" },
+                    { "text": "And now?" },
+                ] },
+            ],
+            "inferenceConfig": { "maxTokens": 100 },
+            "toolConfig": { "tools": [{ "toolSpec": { "name": "ls", "description": "lists", "inputSchema": { "json": { "type": "object" } } } }] },
+        }));
+    }
+
+    fn frame(headers: &[(&str, &str)], payload: &Value) -> Vec<u8> {
+        let mut encoded = vec![];
+        for (name, value) in headers {
+            encoded.push(name.len() as u8);
+            encoded.extend_from_slice(name.as_bytes());
+            encoded.push(7);
+            encoded.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            encoded.extend_from_slice(value.as_bytes());
+        }
+        let payload = payload.to_string().into_bytes();
+        let total = 16 + encoded.len() + payload.len();
+        let mut message = (total as u32).to_be_bytes().to_vec();
+        message.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+        message.extend_from_slice(&crc32fast::hash(&message).to_be_bytes());
+        message.extend(encoded);
+        message.extend(payload);
+        message.extend_from_slice(&crc32fast::hash(&message).to_be_bytes());
+        message
+    }
+
+    #[test]
+    fn test_event_stream() {
+        let event = |kind: &str, payload: Value| frame(&[(":message-type", "event"), (":event-type", kind)], &payload);
+        let bytes = [
+            event("messageStart", serde_json::json!({ "role": "assistant" })),
+            event("contentBlockDelta", serde_json::json!({ "contentBlockIndex": 0, "delta": { "text": "Hi" } })),
+            event("contentBlockStart", serde_json::json!({ "contentBlockIndex": 1, "start": { "toolUse": { "toolUseId": "t", "name": "ls" } } })),
+            event("contentBlockDelta", serde_json::json!({ "contentBlockIndex": 1, "delta": { "toolUse": { "input": "{}" } } })),
+            event("messageStop", serde_json::json!({ "stopReason": "tool_use" })),
+            event("metadata", serde_json::json!({ "usage": { "inputTokens": 3, "outputTokens": 2, "totalTokens": 5 } })),
+        ]
+        .concat();
+
+        let mut decoder = EventStreamDecoder::default();
+        let (first, rest) = bytes.split_at(20);
+        assert_eq!(decoder.push(first).unwrap(), []);
+        let messages = decoder.push(rest).unwrap();
+        assert_eq!(messages.len(), 6);
+
+        let mut translator = Translator::default();
+        let chunks = messages.into_iter().filter_map(|e| translator.translate(e).unwrap()).collect::<Vec<_>>();
+        assert_eq!(chunks[0].choices[0].delta.content, "Hi");
+        let call = &chunks[1].choices[0].delta.tool_calls.as_ref().unwrap()[0];
+        assert_eq!((call.index, call.function.as_ref().unwrap().name.as_deref()), (0, Some("ls")));
+        assert_eq!(chunks[2].choices[0].delta.tool_calls.as_ref().unwrap()[0].function.as_ref().unwrap().arguments.as_deref(), Some("{}"));
+        assert_eq!(chunks[3].choices[0].finish_reason, Some(async_openai::types::FinishReason::ToolCalls));
+        assert_eq!(chunks[4].usage.as_ref().unwrap().total_tokens, 5);
+
+        let exception = frame(&[(":message-type", "exception"), (":exception-type", "throttlingException")], &serde_json::json!({ "message": "slow down" }));
+        let message = decoder.push(&exception).unwrap().remove(0);
+        assert_eq!(translator.translate(message).unwrap_err().to_string(), "stream failed: throttlingException: slow down");
+        assert!(EventStreamDecoder::default().push(&[0; 12]).is_err());
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::HashSet;
+use std::io::{stdin, IsTerminal};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use async_trait::async_trait;
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use crate::app::Context;
+use crate::bus::{Event, Subscriber};
+use crate::config::{BudgetConfig, Config};
+use crate::events::{format_tokens, TokenUsage, UiEvent};
+use crate::tools::guard;
+use crate::usage::unrecorded;
+
+/// Where the spending of the day is kept, relative to the config directory.
+pub const SPEND_FILE: &str = "spend.json";
+/// The share of a budget that is warned about.
+const WARN_AT: f64 = 0.8;
+
+/// What the requests of a day cost together, across sessions.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DailySpend {
+    pub date: String,
+    pub usd: f64,
+}
+
+impl DailySpend {
+    /// Today's spending, nothing if the file is missing or from another day.
+    pub fn load(path: &Path) -> Self {
+        let today = Local::now().format("%Y-%m-%d").to_string();
+        let spend = std::fs::read_to_string(path).ok().and_then(|e| serde_json::from_str::<Self>(&e).ok());
+        match spend {
+            Some(spend) if spend.date == today => spend,
+            _ => Self { date: today, usd: 0.0 },
+        }
+    }
+
+    /// Adds `usd` to today's spending, returns the new total.
+    pub fn add(path: &Path, usd: f64) -> anyhow::Result<f64> {
+        let mut spend = Self::load(path);
+        spend.usd += usd;
+        std::fs::write(path, serde_json::to_string(&spend)?)?;
+        Ok(spend.usd)
+    }
+}
+
+/// What `usage` costs in USD with `model`, `None` if there's no price for it.
+pub fn cost(config: &Config, model: &str, usage: &TokenUsage) -> Option<f64> {
+    let price = config.price(model)?;
+    Some((usage.prompt_tokens as f64 * price.input + usage.completion_tokens as f64 * price.output) / 1_000_000.0)
+}
+
+/// The share used of every configured limit, with what it is in words.
+fn shares(budget: &BudgetConfig, session_tokens: u64, daily_usd: f64) -> Vec<(&'static str, f64, String)> {
+    let mut shares = vec![];
+    if let Some(limit) = budget.session_tokens {
+        let description = format!("{} of {} session tokens", format_tokens(session_tokens), format_tokens(limit));
+        shares.push(("session_tokens", session_tokens as f64 / limit.max(1) as f64, description));
+    }
+    if let Some(limit) = budget.daily_usd {
+        let description = format!("${:.2} of ${:.2} for today", daily_usd, limit);
+        shares.push(("daily_usd", daily_usd / limit.max(0.01), description));
+    }
+    shares
+}
+
+/// Warns once a budget is 80% used and stops the questions once one is used up, unless the user lets them through.
+#[derive(Debug, Default)]
+pub struct BudgetGuard {
+    /// The session usage already added to the day's spending.
+    priced: Mutex<TokenUsage>,
+    warned: Mutex<HashSet<&'static str>>,
+    unpriced: AtomicBool,
+    /// Set once the user chose to go on past the budget, for the rest of the session.
+    overridden: AtomicBool,
+}
+
+impl BudgetGuard {
+    /// Whether the question may be sent.
+    fn check(&self, ctx: &Context, budget: &BudgetConfig) -> anyhow::Result<bool> {
+        let daily_usd = match budget.daily_usd {
+            Some(_) => DailySpend::load(&ctx.config.config_dir().join(SPEND_FILE)).usd,
+            None => 0.0,
+        };
+        let shares = shares(budget, ctx.usage.total_tokens, daily_usd);
+
+        let exceeded = shares.iter().filter(|(_, share, _)| *share >= 1.0).map(|(_, _, e)| e.as_str()).collect::<Vec<_>>();
+        if !exceeded.is_empty() && !self.overridden.load(Ordering::Relaxed) {
+            // Nobody to ask, a script should fail rather than spend.
+            if !stdin().is_terminal() {
+                anyhow::bail!("The budget is used up, {}, raise `budget` in the config to go on", exceeded.join(", "));
+            }
+            let question = format!("The budget is used up, {}. Send it anyway, ignoring the budget for the rest of the session", exceeded.join(", "));
+            if !guard::ask(&question)? {
+                ctx.events.emit(UiEvent::Warning("Not sent, raise `budget` in the config to go on".to_string()));
+                return Ok(false);
+            }
+            self.overridden.store(true, Ordering::Relaxed);
+        }
+
+        let mut warned = self.warned.lock().unwrap();
+        for (limit, share, description) in shares {
+            if (WARN_AT..1.0).contains(&share) && warned.insert(limit) {
+                ctx.events.emit(UiEvent::Warning(format!("{:.0}% of the budget used, {}", share * 100.0, description)));
+            }
+        }
+        Ok(true)
+    }
+
+    /// Adds what the session used since the last call to the day's spending.
+    fn record(&self, ctx: &Context) -> anyhow::Result<()> {
+        let usage = unrecorded(&self.priced, ctx.usage);
+        if usage.total_tokens == 0 {
+            return Ok(());
+        }
+
+        match cost(&ctx.config, ctx.answered_model(), &usage) {
+            Some(usd) => {
+                DailySpend::add(&ctx.config.config_dir().join(SPEND_FILE), usd)?;
+            }
+            None if !self.unpriced.swap(true, Ordering::Relaxed) => ctx.events.emit(UiEvent::Warning(format!(
+                "No price for {} in `prices`, its tokens don't count towards `budget.daily_usd`",
+                ctx.config.model
+            ))),
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Subscriber for BudgetGuard {
+    async fn handle(&self, ctx: &mut Context, event: &mut Event<'_>) -> anyhow::Result<()> {
+        let Some(budget) = ctx.config.budget.clone() else { return Ok(()) };
+        match event {
+            Event::UserInput(input) if !input.trim().is_empty() && !self.check(ctx, &budget)? => input.clear(),
+            Event::TurnEnd if budget.daily_usd.is_some() => {
+                if let Err(e) = self.record(ctx) {
+                    ctx.events.emit(UiEvent::Warning(format!("Failed to keep the day's spending: {}", e)));
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use super::*;
+    use crate::config::{ModelMetadata, ModelPrice};
+
+    #[test]
+    fn test_budget() {
+        let mut config = Config::default();
+        config.prices = BTreeMap::from([("gpt-4o".to_string(), ModelPrice { input: 2.5, output: 10.0 })]);
+        let listed = ModelMetadata { context_length: None, price: Some(ModelPrice { input: 0.1, output: 0.1 }) };
+        config.catalog = BTreeMap::from([("gpt-4o".to_string(), listed), ("llama3".to_string(), listed)]);
+        let usage = TokenUsage { prompt_tokens: 200_000, completion_tokens: 50_000, total_tokens: 250_000 };
+        assert_eq!(cost(&config, "gpt-4o", &usage), Some(1.0));
+        assert_eq!(cost(&config, "llama3", &usage), Some(0.025));
+        assert_eq!(cost(&config, "mistral", &usage), None);
+
+        let budget = BudgetConfig { daily_usd: Some(2.0), session_tokens: Some(100_000) };
+        assert_eq!(shares(&budget, 90_000, 2.5), [
+            ("session_tokens", 0.9, "90k of 100k session tokens".to_string()),
+            ("daily_usd", 1.25, "$2.50 of $2.00 for today".to_string()),
+        ]);
+
+        let path = std::env::temp_dir().join(format!("rag-spend-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"date": "2000-01-01", "usd": 9.0}"#).unwrap();
+        assert_eq!(DailySpend::load(&path).usd, 0.0);
+        DailySpend::add(&path, 0.25).unwrap();
+        assert_eq!(DailySpend::add(&path, 0.5).unwrap(), 0.75);
+        let _ = std::fs::remove_file(&path);
+    }
+}
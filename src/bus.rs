@@ -0,0 +1,188 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+use async_trait::async_trait;
+use crate::app::Context;
+use crate::rq::RsChunkBody;
+
+/// The lifecycle points of a session, in the order a turn goes through them.
+#[derive(Debug)]
+pub enum Event<'a> {
+    /// Before the prompt for the next input is shown.
+    BeforeInput,
+    /// What the user entered, subscribers may rewrite it. The turn is skipped if it ends up empty.
+    UserInput(&'a mut String),
+    /// A chunk of the response stream, after the content filters.
+    Chunk(&'a RsChunkBody),
+    /// The response stream failed, the turn goes on with what arrived.
+    StreamError(&'a str),
+    /// A subscriber set `stop_stream`, the rest of the response is dropped.
+    Cancelled,
+    /// A tool call the model asked for, right before it runs.
+    ToolCall { name: &'a str, arguments: &'a str },
+    /// The answer is complete.
+    TurnEnd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    BeforeInput,
+    UserInput,
+    Chunk,
+    StreamError,
+    Cancelled,
+    ToolCall,
+    TurnEnd,
+}
+
+impl Event<'_> {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::BeforeInput => EventKind::BeforeInput,
+            Event::UserInput(_) => EventKind::UserInput,
+            Event::Chunk(_) => EventKind::Chunk,
+            Event::StreamError(_) => EventKind::StreamError,
+            Event::Cancelled => EventKind::Cancelled,
+            Event::ToolCall { .. } => EventKind::ToolCall,
+            Event::TurnEnd => EventKind::TurnEnd,
+        }
+    }
+}
+
+/// Shared between threads, so state lives in atomics or behind a `Mutex` that isn't held across an await.
+#[async_trait]
+pub trait Subscriber: Debug + Send + Sync {
+    async fn handle(&self, ctx: &mut Context, event: &mut Event<'_>) -> anyhow::Result<()>;
+}
+
+/// Runs before the built-in subscribers.
+#[cfg(test)]
+pub const PRIORITY_EARLY: i32 = -100;
+pub const PRIORITY_DEFAULT: i32 = 0;
+/// Runs after the built-in subscribers.
+pub const PRIORITY_LATE: i32 = 100;
+
+#[derive(Debug)]
+struct Subscription {
+    kinds: Vec<EventKind>,
+    priority: i32,
+    subscriber: Arc<dyn Subscriber>,
+}
+
+/// Hands every event to the subscribers registered for its kind, lower priorities first and
+/// subscribers of the same priority in the order they subscribed.
+#[derive(Debug, Default)]
+pub struct EventBus {
+    subscriptions: Vec<Subscription>,
+}
+
+impl EventBus {
+    pub fn subscribe(&mut self, kinds: &[EventKind], priority: i32, subscriber: Arc<dyn Subscriber>) {
+        let position = self.subscriptions.partition_point(|e| e.priority <= priority);
+        self.subscriptions.insert(position, Subscription {
+            kinds: kinds.to_vec(),
+            priority,
+            subscriber,
+        });
+    }
+
+    /// Stops at the first subscriber that fails.
+    pub async fn dispatch(&self, ctx: &mut Context, event: &mut Event<'_>) -> anyhow::Result<()> {
+        let kind = event.kind();
+        for subscription in self.subscriptions.iter().filter(|e| e.kinds.contains(&kind)) {
+            subscription.subscriber.handle(ctx, event).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use async_openai::Client;
+    use async_openai::config::OpenAIConfig;
+    use crate::config::Config;
+    use crate::events::Discard;
+    use crate::processor::Processor;
+
+    #[derive(Debug)]
+    struct Noop;
+
+    #[async_trait]
+    impl Subscriber for Noop {
+        async fn handle(&self, _ctx: &mut Context, _event: &mut Event<'_>) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_subscribe_orders_by_priority() {
+        let mut bus = EventBus::default();
+        bus.subscribe(&[EventKind::Chunk], PRIORITY_LATE, Arc::new(Noop));
+        bus.subscribe(&[EventKind::Chunk], PRIORITY_DEFAULT, Arc::new(Noop));
+        bus.subscribe(&[EventKind::TurnEnd], PRIORITY_EARLY, Arc::new(Noop));
+        bus.subscribe(&[EventKind::UserInput], PRIORITY_DEFAULT, Arc::new(Noop));
+
+        let order = bus.subscriptions.iter().map(|e| (e.priority, e.kinds[0])).collect::<Vec<_>>();
+        assert_eq!(order, [
+            (PRIORITY_EARLY, EventKind::TurnEnd),
+            (PRIORITY_DEFAULT, EventKind::Chunk),
+            (PRIORITY_DEFAULT, EventKind::UserInput),
+            (PRIORITY_LATE, EventKind::Chunk),
+        ]);
+        assert_eq!(Event::UserInput(&mut String::new()).kind(), EventKind::UserInput);
+    }
+
+    /// Appends its text to the input, after giving up its turn once like a subscriber waiting on I/O.
+    #[derive(Debug)]
+    struct Append(&'static str, AtomicUsize);
+
+    #[async_trait]
+    impl Subscriber for Append {
+        async fn handle(&self, _ctx: &mut Context, event: &mut Event<'_>) -> anyhow::Result<()> {
+            tokio::task::yield_now().await;
+            self.1.fetch_add(1, Ordering::Relaxed);
+            if let Event::UserInput(input) = event {
+                input.push_str(self.0);
+            }
+            Ok(())
+        }
+    }
+
+    #[derive(Debug)]
+    struct Fail;
+
+    #[async_trait]
+    impl Subscriber for Fail {
+        async fn handle(&self, _ctx: &mut Context, _event: &mut Event<'_>) -> anyhow::Result<()> {
+            anyhow::bail!("hook failed")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatch() {
+        let (_, mut ctx) = Processor::builder()
+            .with_config(Config::default())
+            .with_backend(Client::with_config(OpenAIConfig::new().into()))
+            .with_renderer(Box::new(Discard))
+            .build()
+            .unwrap();
+        let late = Arc::new(Append(" late", AtomicUsize::new(0)));
+        let mut bus = EventBus::default();
+        bus.subscribe(&[EventKind::UserInput], PRIORITY_LATE, late.clone());
+        bus.subscribe(&[EventKind::UserInput, EventKind::TurnEnd], PRIORITY_EARLY, Arc::new(Append(" early", AtomicUsize::new(0))));
+
+        let mut input = "hi".to_string();
+        bus.dispatch(&mut ctx, &mut Event::UserInput(&mut input)).await.unwrap();
+        assert_eq!(input, "hi early late");
+        bus.dispatch(&mut ctx, &mut Event::TurnEnd).await.unwrap();
+        assert_eq!(late.1.load(Ordering::Relaxed), 1);
+
+        // The subscribers after a failing one don't see the event.
+        bus.subscribe(&[EventKind::UserInput], PRIORITY_DEFAULT, Arc::new(Fail));
+        let e = bus.dispatch(&mut ctx, &mut Event::UserInput(&mut input)).await.unwrap_err();
+        assert_eq!(e.to_string(), "hook failed");
+        assert_eq!(input, "hi early late early");
+        assert_eq!(late.1.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,164 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use async_openai::error::OpenAIError;
+use async_trait::async_trait;
+use chrono::Local;
+use futures::StreamExt;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use crate::client::{ChatClient, ChunkStream};
+use crate::config::RetryConfig;
+use crate::events::{EventSender, UiEvent};
+use crate::rq::RsChunkBody;
+
+/// Where the cached responses are kept, relative to the config directory.
+pub const CACHE_DB: &str = "cache.db";
+/// How long a response is served from the cache unless `cache.ttl_secs` says otherwise, a day.
+pub const DEFAULT_TTL_SECS: u64 = 24 * 60 * 60;
+
+/// Responses by a hash of the request that got them.
+pub struct ResponseCache {
+    connection: Mutex<Connection>,
+    ttl_secs: u64,
+}
+
+impl ResponseCache {
+    pub fn open(path: &Path, ttl_secs: u64) -> anyhow::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS responses (
+                key TEXT PRIMARY KEY,
+                created INTEGER NOT NULL,
+                chunks TEXT NOT NULL
+            );",
+        )?;
+        Ok(Self { connection: Mutex::new(connection), ttl_secs })
+    }
+
+    /// The model, the messages and the parameters of `body`, but not whether it is streamed, hashed.
+    pub fn key(body: &Value) -> String {
+        let mut body = body.clone();
+        if let Some(body) = body.as_object_mut() {
+            body.remove("stream");
+            body.remove("stream_options");
+        }
+        // The keys of a JSON object are kept sorted, the same request always hashes the same.
+        let hash = Sha256::digest(body.to_string());
+        hash.iter().map(|e| format!("{:02x}", e)).collect()
+    }
+
+    /// The chunks cached for `key`, unless they expired.
+    pub fn get(&self, key: &str) -> anyhow::Result<Option<Vec<Value>>> {
+        let oldest = Local::now().timestamp() - self.ttl_secs as i64;
+        let connection = self.connection.lock().unwrap();
+        connection.execute("DELETE FROM responses WHERE created < ?1", params![oldest])?;
+        let chunks = connection
+            .query_row("SELECT chunks FROM responses WHERE key = ?1", params![key], |row| row.get::<_, String>(0))
+            .optional()?;
+        Ok(chunks.map(|e| serde_json::from_str(&e)).transpose()?)
+    }
+
+    pub fn put(&self, key: &str, chunks: &[Value]) -> anyhow::Result<()> {
+        self.connection.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO responses (key, created, chunks) VALUES (?1, ?2, ?3)",
+            params![key, Local::now().timestamp(), serde_json::to_string(chunks)?],
+        )?;
+        Ok(())
+    }
+
+    /// Removes every cached response and returns how many there were.
+    pub fn clear(&self) -> anyhow::Result<usize> {
+        Ok(self.connection.lock().unwrap().execute("DELETE FROM responses", [])?)
+    }
+}
+
+/// Answers a request that was answered before from the cache, and caches every other answer that streams to its end.
+pub struct CachingClient {
+    inner: Arc<dyn ChatClient>,
+    cache: Arc<ResponseCache>,
+}
+
+impl CachingClient {
+    pub fn new(inner: Arc<dyn ChatClient>, cache: Arc<ResponseCache>) -> Self {
+        Self { inner, cache }
+    }
+}
+
+#[async_trait]
+impl ChatClient for CachingClient {
+    async fn stream(&self, body: Value, retry: &RetryConfig, events: &EventSender) -> Result<ChunkStream, OpenAIError> {
+        let key = ResponseCache::key(&body);
+        match self.cache.get(&key) {
+            Ok(Some(chunks)) => {
+                tracing::info!(key, "answered from the cache");
+                events.emit(UiEvent::Cached);
+                let chunks = chunks.into_iter().filter_map(|e| RsChunkBody::parse(e).transpose());
+                return Ok(Box::pin(futures::stream::iter(chunks.collect::<Vec<_>>())));
+            }
+            Ok(None) => {}
+            Err(e) => tracing::warn!(error = %e, "failed to read the cache"),
+        }
+
+        let stream = self.inner.stream(body, retry, events).await?;
+        let received = Arc::new(Mutex::new(Some(vec![])));
+        let chunks = received.clone();
+        let cache = self.cache.clone();
+        // An answer that failed or was dropped halfway isn't cached.
+        let save = futures::stream::once(async move {
+            if let Some(chunks) = received.lock().unwrap().take()
+                && let Err(e) = cache.put(&key, &chunks)
+            {
+                tracing::warn!(error = %e, "failed to cache the response");
+            }
+            None
+        });
+        let stream = stream.map(move |e| {
+            let mut chunks = chunks.lock().unwrap();
+            match e {
+                Ok(ref chunk) => chunks.iter_mut().for_each(|chunks| chunks.push(serde_json::to_value(chunk).unwrap_or_default())),
+                Err(_) => *chunks = None,
+            }
+            Some(e)
+        });
+        Ok(Box::pin(stream.chain(save).filter_map(futures::future::ready)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::mock::{Discard, MockClient};
+
+    async fn answer(client: &dyn ChatClient, body: &Value) -> String {
+        let events = EventSender::spawn(Box::new(Discard));
+        let stream = client.stream(body.clone(), &RetryConfig::default(), &events).await.unwrap();
+        stream.map(|e| e.map(|e| e.choices[0].delta.content.clone()).unwrap_or_default()).collect::<Vec<_>>().await.concat()
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let path = std::env::temp_dir().join(format!("rag-cache-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let cache = Arc::new(ResponseCache::open(&path, DEFAULT_TTL_SECS).unwrap());
+        let failed = vec![MockClient::chunk(serde_json::json!({ "content": "Par" })), serde_json::json!({ "error": "reset" })];
+        let mock = Arc::new(MockClient::new(vec![failed, MockClient::answer("Paris."), MockClient::answer("Rome.")]));
+        let client = CachingClient::new(mock.clone(), cache.clone());
+
+        let question = |model: &str| serde_json::json!({ "model": model, "stream": true, "messages": [{ "role": "user", "content": "capital?" }] });
+        assert_eq!(answer(&client, &question("a")).await, "Par");
+        assert_eq!(answer(&client, &question("a")).await, "Paris.");
+        let mut unstreamed = question("a");
+        unstreamed["stream"] = false.into();
+        assert_eq!(answer(&client, &unstreamed).await, "Paris.");
+        assert_eq!(answer(&client, &question("b")).await, "Rome.");
+        assert_eq!(mock.requests.lock().unwrap().len(), 3);
+
+        assert_eq!(cache.clear().unwrap(), 2);
+        let expired = ResponseCache::open(&path, 0).unwrap();
+        expired.put("k", &[]).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        assert_eq!(expired.get("k").unwrap(), None);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,397 @@
+use std::collections::{HashMap, HashSet};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use async_openai::Client;
+use async_openai::config::{AzureConfig, Config as ClientConfig, OpenAIConfig};
+use reqwest::header::HeaderMap;
+use secrecy::SecretString;
+use async_openai::error::OpenAIError;
+use async_trait::async_trait;
+use colored::Colorize;
+use futures::StreamExt;
+use futures_core::Stream;
+use serde_json::Value;
+use crate::config::{Config, RetryConfig, DEFAULT_AZURE_API_VERSION};
+use crate::events::{EventSender, UiEvent};
+use crate::retry::{complete, falls_back, open_stream};
+use crate::rq::RsChunkBody;
+
+pub type ChunkStream = Pin<Box<dyn Stream<Item = Result<RsChunkBody, OpenAIError>> + Send>>;
+
+/// Where the chat requests go, the provider or a stand-in for it.
+#[async_trait]
+pub trait ChatClient: Send + Sync {
+    /// Sends the request `body` and streams the answer, transient failures before the first chunk are retried as `retry` allows.
+    async fn stream(&self, body: Value, retry: &RetryConfig, events: &EventSender) -> Result<ChunkStream, OpenAIError>;
+}
+
+/// `C` with `extra_query` added to the query of every request.
+#[derive(Debug, Clone, Default)]
+pub struct WithQuery<C = OpenAIConfig> {
+    inner: C,
+    query: Vec<(String, String)>,
+}
+
+impl<C> WithQuery<C> {
+    pub fn new(inner: C, query: Vec<(String, String)>) -> Self {
+        Self { inner, query }
+    }
+}
+
+impl From<OpenAIConfig> for WithQuery {
+    fn from(inner: OpenAIConfig) -> Self {
+        Self::new(inner, vec![])
+    }
+}
+
+impl<C: ClientConfig> ClientConfig for WithQuery<C> {
+    fn headers(&self) -> HeaderMap {
+        self.inner.headers()
+    }
+
+    fn url(&self, path: &str) -> String {
+        self.inner.url(path)
+    }
+
+    fn query(&self) -> Vec<(&str, &str)> {
+        let extra = self.query.iter().map(|(name, value)| (name.as_str(), value.as_str()));
+        self.inner.query().into_iter().chain(extra).collect()
+    }
+
+    fn api_base(&self) -> &str {
+        self.inner.api_base()
+    }
+
+    fn api_key(&self) -> &SecretString {
+        self.inner.api_key()
+    }
+}
+
+/// The provider behind `client`. A model that refuses a streamed request gets it again without streaming,
+/// and its requests aren't streamed for the rest of the session.
+pub struct Provider<C: ClientConfig = WithQuery> {
+    client: Client<C>,
+    unstreamed: Mutex<HashSet<String>>,
+}
+
+impl<C: ClientConfig> Provider<C> {
+    pub fn new(client: Client<C>) -> Self {
+        Self { client, unstreamed: Mutex::default() }
+    }
+
+    /// Sends `body` without streaming, the whole answer comes as a single chunk.
+    async fn complete(&self, mut body: Value, retry: &RetryConfig, events: &EventSender) -> Result<ChunkStream, OpenAIError> {
+        body["stream"] = false.into();
+        if let Some(body) = body.as_object_mut() {
+            body.remove("stream_options");
+        }
+        let chunk = as_chunk(complete(&self.client, body, retry, events).await?);
+        Ok(Box::pin(futures::stream::once(async { chunk })))
+    }
+}
+
+#[async_trait]
+impl<C: ClientConfig + Send + Sync> ChatClient for Provider<C> {
+    async fn stream(&self, body: Value, retry: &RetryConfig, events: &EventSender) -> Result<ChunkStream, OpenAIError> {
+        let model = body["model"].as_str().unwrap_or_default().to_string();
+        if body["stream"] == false || self.unstreamed.lock().unwrap().contains(&model) {
+            return self.complete(body, retry, events).await;
+        }
+
+        let e = match open_stream(&self.client, body.clone(), retry, events).await {
+            Ok(stream) => return Ok(Box::pin(stream.filter_map(|e| futures::future::ready(e.and_then(RsChunkBody::parse).transpose())))),
+            Err(e) if rejects_streaming(&e) => e,
+            Err(e) => return Err(e),
+        };
+        tracing::warn!(model, error = %e, "streamed request refused, sending it without streaming");
+        // A request that is wrong in some other way fails again, with the reason the event source didn't keep.
+        let stream = self.complete(body, retry, events).await?;
+        events.flush();
+        eprintln!(
+            "{}",
+            format!("Warning: {} refused to stream, its answers come whole for the rest of the session, add it to `non_streaming_models` to skip the first try", model).yellow()
+        );
+        self.unstreamed.lock().unwrap().insert(model);
+        Ok(stream)
+    }
+}
+
+/// Where `provider = "azure"` sends the requests: the deployment in the path, `api-version` in the query and the key as `api-key`.
+/// The deployment is left to each request.
+pub fn azure_config(config: &Config) -> AzureConfig {
+    AzureConfig::new()
+        .with_api_base(config.base_url.trim_end_matches('/'))
+        .with_api_key(&config.api_key)
+        .with_api_version(config.api_version.as_deref().unwrap_or(DEFAULT_AZURE_API_VERSION))
+}
+
+/// Azure OpenAI, where a model is the name of a deployment, each has a url of its own.
+pub struct Azure {
+    config: AzureConfig,
+    query: Vec<(String, String)>,
+    http_client: reqwest::Client,
+    deployments: Mutex<HashMap<String, Arc<Provider<WithQuery<AzureConfig>>>>>,
+}
+
+impl Azure {
+    pub fn new(config: AzureConfig, query: Vec<(String, String)>, http_client: reqwest::Client) -> Self {
+        Self { config, query, http_client, deployments: Mutex::default() }
+    }
+
+    fn deployment(&self, name: &str) -> Arc<Provider<WithQuery<AzureConfig>>> {
+        let mut deployments = self.deployments.lock().unwrap();
+        let config = || WithQuery::new(self.config.clone().with_deployment_id(name), self.query.clone());
+        let client = || Client::with_config(config()).with_http_client(self.http_client.clone());
+        deployments.entry(name.to_string()).or_insert_with(|| Arc::new(Provider::new(client()))).clone()
+    }
+}
+
+#[async_trait]
+impl ChatClient for Azure {
+    async fn stream(&self, body: Value, retry: &RetryConfig, events: &EventSender) -> Result<ChunkStream, OpenAIError> {
+        let provider = self.deployment(body["model"].as_str().unwrap_or_default());
+        provider.stream(body, retry, events).await
+    }
+}
+
+/// Sends `body` to its model and, while that fails in a way another model may not, to the next of `fallbacks`.
+/// Returns the stream with the model it comes from.
+pub async fn stream_with_fallbacks(
+    client: &dyn ChatClient,
+    mut body: Value,
+    fallbacks: &[String],
+    retry: &RetryConfig,
+    events: &EventSender,
+) -> Result<(ChunkStream, String), OpenAIError> {
+    let mut fallbacks = fallbacks.iter();
+    loop {
+        let e = match client.stream(body.clone(), retry, events).await {
+            Ok(stream) => return Ok((stream, body["model"].as_str().unwrap_or_default().to_string())),
+            Err(e) => e,
+        };
+        let Some(next) = fallbacks.next().filter(|_| falls_back(&e)) else { return Err(e) };
+        let from = body["model"].as_str().unwrap_or_default().to_string();
+        tracing::warn!(from, to = next, error = %e, "request failed, falling back to the next model");
+        events.emit(UiEvent::FellBack { from, to: next.clone(), error: e.to_string() });
+        body["model"] = next.as_str().into();
+    }
+}
+
+/// Whether a streamed request failed in a way a request that isn't streamed may not.
+fn rejects_streaming(error: &OpenAIError) -> bool {
+    match error {
+        // The event source drops the body of the response, a 400 is all there is to go by.
+        OpenAIError::StreamError(e) => e.contains("Invalid status code: 400"),
+        OpenAIError::ApiError(e) => e.message.to_lowercase().contains("stream"),
+        _ => false,
+    }
+}
+
+/// The chunk a whole response amounts to, so an answer that wasn't streamed takes the same way as one that was.
+fn as_chunk(mut response: Value) -> Result<RsChunkBody, OpenAIError> {
+    for choice in response["choices"].as_array_mut().into_iter().flatten() {
+        let mut message = choice["message"].take();
+        // A stream numbers the tool calls, a response only lists them.
+        for (index, call) in message["tool_calls"].as_array_mut().into_iter().flatten().enumerate() {
+            call["index"] = index.into();
+        }
+        if message["content"].is_null() {
+            message["content"] = "".into();
+        }
+        choice["delta"] = message;
+    }
+    response["object"] = "chat.completion.chunk".into();
+    serde_json::from_value(response).map_err(OpenAIError::JSONDeserialize)
+}
+
+#[cfg(test)]
+pub mod mock {
+    use std::collections::VecDeque;
+    use super::*;
+    pub use crate::events::Discard;
+
+    /// Answers each request with the next scripted response and keeps the requests it got.
+    #[derive(Debug, Default)]
+    pub struct MockClient {
+        responses: Mutex<VecDeque<Vec<Value>>>,
+        pub requests: Mutex<Vec<Value>>,
+    }
+
+    impl MockClient {
+        /// Each response is the chunks of one stream, in the order they are sent. An `{"error": ...}` fails the stream there.
+        pub fn new(responses: Vec<Vec<Value>>) -> Self {
+            Self { responses: Mutex::new(responses.into()), requests: Mutex::default() }
+        }
+
+        /// A chunk carrying `delta`, the rest of it filled in.
+        pub fn chunk(delta: Value) -> Value {
+            serde_json::json!({
+                "id": "mock",
+                "created": 0,
+                "model": "mock",
+                "object": "chat.completion.chunk",
+                "choices": [{ "index": 0, "delta": delta }],
+            })
+        }
+
+        /// The chunks of a plain answer streamed word by word.
+        pub fn answer(text: &str) -> Vec<Value> {
+            text.split_inclusive(' ').map(|e| Self::chunk(serde_json::json!({ "role": "assistant", "content": e }))).collect()
+        }
+    }
+
+    #[async_trait]
+    impl ChatClient for MockClient {
+        async fn stream(&self, body: Value, _retry: &RetryConfig, _events: &EventSender) -> Result<ChunkStream, OpenAIError> {
+            self.requests.lock().unwrap().push(body);
+            let chunks = self.responses.lock().unwrap().pop_front().ok_or(OpenAIError::StreamError("Nothing scripted".to_string()))?;
+            let chunks = chunks.into_iter().map(|e| match e["error"].as_str() {
+                Some(error) => Err(OpenAIError::StreamError(error.to_string())),
+                None => serde_json::from_value(e).map_err(OpenAIError::JSONDeserialize),
+            });
+            let chunks = chunks.collect::<Vec<_>>();
+            Ok(Box::pin(futures::stream::iter(chunks)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_response_as_chunk() {
+        let response = serde_json::json!({
+            "id": "r",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "o1",
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{ "id": "c", "type": "function", "function": { "name": "Add", "arguments": "{\"a\": 1, \"b\": 2}" } }],
+                },
+                "finish_reason": "tool_calls",
+            }],
+            "usage": { "prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15 },
+        });
+        let chunk = as_chunk(response).unwrap();
+        let delta = &chunk.choices[0].delta;
+        assert_eq!(delta.content, "");
+        let calls = delta.tool_calls.as_ref().unwrap();
+        assert_eq!((calls[0].index, calls[0].function.as_ref().unwrap().name.as_deref()), (0, Some("Add")));
+        assert_eq!(chunk.usage.unwrap().total_tokens, 15);
+
+        assert!(rejects_streaming(&OpenAIError::StreamError("Invalid status code: 400 Bad Request".to_string())));
+        assert!(!rejects_streaming(&OpenAIError::StreamError("Invalid status code: 401 Unauthorized".to_string())));
+    }
+
+    #[test]
+    fn test_azure_config() {
+        let mut config = Config::default();
+        config.base_url = "https://res.openai.azure.com/".to_string();
+        config.api_key = "key".to_string();
+        let azure = azure_config(&config).with_deployment_id("gpt-4o");
+        assert_eq!(azure.url("/chat/completions"), "https://res.openai.azure.com/openai/deployments/gpt-4o/chat/completions");
+        assert_eq!(azure.query(), [("api-version", DEFAULT_AZURE_API_VERSION)]);
+        assert_eq!(azure.headers()["api-key"], "key");
+
+        let extra = vec![("tenant".to_string(), "a".to_string())];
+        assert_eq!(WithQuery::new(azure, extra.clone()).query(), [("api-version", DEFAULT_AZURE_API_VERSION), ("tenant", "a")]);
+        assert_eq!(WithQuery::new(OpenAIConfig::new(), extra).query(), [("tenant", "a")]);
+    }
+
+    #[tokio::test]
+    async fn test_extra_headers_and_query_are_sent() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 8192];
+            let n = socket.read(&mut request).await.unwrap();
+            let body = r#"{"choices": []}"#;
+            let response = format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}", body.len(), body);
+            socket.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&request[..n]).to_lowercase()
+        });
+
+        let mut config = Config::default();
+        config.extra_headers.insert("X-Team".to_string(), "rag".to_string());
+        config.extra_query.insert("tenant".to_string(), "a".to_string());
+        let query = config.extra_query.clone().into_iter().collect();
+        let openai = OpenAIConfig::new().with_api_base(format!("http://{}/v1", address));
+        let client = Client::with_config(WithQuery::new(openai, query)).with_http_client(crate::http_client(&config).unwrap());
+        client.chat().create_byot::<_, Value>(serde_json::json!({ "model": "m", "messages": [] })).await.unwrap();
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("post /v1/chat/completions?tenant=a "), "{}", request);
+        assert!(request.contains("\r\nx-team: rag\r\n"), "{}", request);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_and_request_timeout() {
+        use std::time::{Duration, Instant};
+        use tokio::io::AsyncReadExt;
+
+        // A proxy that takes the request and never answers.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, received) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 8192];
+            let n = socket.read(&mut request).await.unwrap();
+            let _ = sender.send(String::from_utf8_lossy(&request[..n]).into_owned());
+            tokio::time::sleep(Duration::from_secs(30)).await;
+        });
+
+        let mut config = Config::default();
+        config.http_proxy = Some(format!("http://{}", address));
+        config.request_timeout_secs = Some(1);
+        let openai = OpenAIConfig::new().with_api_base("http://provider.invalid/v1");
+        let client = Client::with_config(WithQuery::from(openai)).with_http_client(crate::http_client(&config).unwrap());
+        let started = Instant::now();
+        let e = client.chat().create_byot::<_, Value>(serde_json::json!({ "model": "m", "messages": [] })).await.unwrap_err();
+
+        assert!(received.await.unwrap().starts_with("POST http://provider.invalid/v1/chat/completions "));
+        assert!(matches!(e, OpenAIError::Reqwest(ref e) if e.is_timeout()), "{}", e);
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    /// Rate limited on every model but `answers`.
+    struct RateLimited {
+        answers: &'static str,
+        asked: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl ChatClient for RateLimited {
+        async fn stream(&self, body: Value, _retry: &RetryConfig, _events: &EventSender) -> Result<ChunkStream, OpenAIError> {
+            let model = body["model"].as_str().unwrap().to_string();
+            self.asked.lock().unwrap().push(model.clone());
+            if model != self.answers {
+                return Err(OpenAIError::StreamError("Invalid status code: 429 Too Many Requests".to_string()));
+            }
+            Ok(Box::pin(futures::stream::empty()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fallbacks() {
+        let events = EventSender::spawn(Box::new(mock::Discard));
+        let fallbacks = ["b".to_string(), "c".to_string()];
+        let client = RateLimited { answers: "c", asked: Mutex::default() };
+        let body = serde_json::json!({ "model": "a" });
+        let (_, model) = stream_with_fallbacks(&client, body.clone(), &fallbacks, &RetryConfig::default(), &events).await.unwrap();
+        assert_eq!(model, "c");
+        assert_eq!(*client.asked.lock().unwrap(), ["a", "b", "c"]);
+
+        let client = RateLimited { answers: "d", asked: Mutex::default() };
+        assert!(stream_with_fallbacks(&client, body, &fallbacks, &RetryConfig::default(), &events).await.is_err());
+        assert_eq!(client.asked.lock().unwrap().len(), 3);
+    }
+}
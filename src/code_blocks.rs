@@ -0,0 +1,142 @@
+use regex::Regex;
+
+/// A fenced code block of an answer, `lang` is inferred from the code when the fence has no tag.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodeBlock {
+    pub lang: String,
+    pub code: String,
+}
+
+impl CodeBlock {
+    pub fn extension(&self) -> &str {
+        match self.lang.as_str() {
+            "" | "text" | "plaintext" => "txt",
+            "rust" => "rs",
+            "python" => "py",
+            "javascript" => "js",
+            "typescript" => "ts",
+            "shell" | "bash" | "sh" | "zsh" => "sh",
+            "markdown" => "md",
+            "yaml" => "yml",
+            "cpp" | "c++" => "cpp",
+            lang => lang,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct CodeBlocks {
+    pattern: Regex,
+}
+
+impl CodeBlocks {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"(?s)```(?<lang>[\w+-]*)[^\n]*\n(?<code>.*?)```").unwrap(),
+        }
+    }
+
+    pub fn extract(&self, answer: &str) -> Vec<CodeBlock> {
+        self.pattern
+            .captures_iter(answer)
+            .map(|caps| {
+                let code = caps["code"].to_string();
+                let lang = match &caps["lang"] {
+                    "" => infer_language(&code).unwrap_or_default().to_string(),
+                    lang => lang.to_lowercase(),
+                };
+                CodeBlock { lang, code }
+            })
+            .collect()
+    }
+}
+
+/// Guesses the language of an untagged block from telltale keywords, checked from the most to the least distinctive.
+pub fn infer_language(code: &str) -> Option<&'static str> {
+    let trimmed = code.trim_start();
+    let lines = code.lines().map(str::trim).filter(|e| !e.is_empty()).collect::<Vec<_>>();
+    let first = *lines.first()?;
+    let has = |needles: &[&str]| needles.iter().any(|e| code.contains(e));
+
+    if let Some(shebang) = first.strip_prefix("#!") {
+        return Some(match shebang {
+            e if e.contains("python") => "python",
+            e if e.contains("node") => "javascript",
+            _ => "bash",
+        });
+    }
+    if trimmed.starts_with("<?php") {
+        return Some("php");
+    }
+    if trimmed.starts_with('<') && has(&["<html", "<!DOCTYPE", "<div", "<body"]) {
+        return Some("html");
+    }
+    if trimmed.starts_with('<') && trimmed.ends_with('>') {
+        return Some("xml");
+    }
+    if (trimmed.starts_with('{') || trimmed.starts_with('[')) && serde_json::from_str::<serde_json::Value>(code).is_ok() {
+        return Some("json");
+    }
+    if has(&["fn ", "let mut ", "impl ", "pub struct ", "use std::", "println!("]) && has(&["fn ", "::", "->", "let "]) {
+        return Some("rust");
+    }
+    if has(&["#include"]) {
+        return Some(if has(&["std::", "<iostream>", "class ", "template<"]) { "cpp" } else { "c" });
+    }
+    if first.starts_with("package ") && has(&["func "]) {
+        return Some("go");
+    }
+    if has(&["public class ", "public static void ", "import java."]) {
+        return Some("java");
+    }
+    if lines.iter().any(|e| e.starts_with("def ") || e.starts_with("from ") && e.contains(" import ") || e.starts_with("elif "))
+        || has(&["print(", "self."]) && lines.iter().any(|e| e.ends_with(':')) {
+        return Some("python");
+    }
+    if has(&["interface ", ": string", ": number", "<T>"]) && has(&["const ", "function ", "export ", "=>"]) {
+        return Some("typescript");
+    }
+    if has(&["function ", "const ", "console.log", "=> {", "require("]) {
+        return Some("javascript");
+    }
+    let upper = code.to_uppercase();
+    if (upper.contains("SELECT ") && upper.contains(" FROM ")) || upper.starts_with("CREATE TABLE") || upper.starts_with("INSERT INTO") {
+        return Some("sql");
+    }
+    if lines.iter().any(|e| e.starts_with('[') && e.ends_with(']')) && lines.iter().any(|e| e.contains(" = ")) {
+        return Some("toml");
+    }
+    if lines.iter().all(|e| e.starts_with('#') || e.starts_with("- ") || e.starts_with("---") || e.split_once(':').is_some_and(|(k, _)| !k.contains(' ')) || e.starts_with(' ')) {
+        return Some("yaml");
+    }
+    if lines.iter().any(|e| {
+        e.starts_with("$ ") || ["cd ", "echo ", "sudo ", "export ", "apt ", "brew ", "cargo ", "npm ", "pip ", "git ", "mkdir ", "curl "]
+            .iter()
+            .any(|cmd| e.starts_with(cmd))
+    }) {
+        return Some("bash");
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_infer_language() {
+        assert_eq!(infer_language("fn main() {\n    println!(\"hi\");\n}\n"), Some("rust"));
+        assert_eq!(infer_language("def add(a, b):\n    return a + b\n"), Some("python"));
+        assert_eq!(infer_language("#!/bin/sh\nls\n"), Some("bash"));
+        assert_eq!(infer_language("{\"a\": [1, 2]}"), Some("json"));
+        assert_eq!(infer_language("SELECT name FROM users WHERE id = 1;"), Some("sql"));
+        assert_eq!(infer_language("const add = (a, b) => a + b;\nconsole.log(add(1, 2));"), Some("javascript"));
+        assert_eq!(infer_language("name: rag\nversion: 1\n"), Some("yaml"));
+        assert_eq!(infer_language("[package]\nname = \"rag\"\n"), Some("toml"));
+        assert_eq!(infer_language("$ cargo build --release"), Some("bash"));
+        assert_eq!(infer_language("Just some words here."), None);
+
+        let blocks = CodeBlocks::new().extract("a\n```\nimport os\ndef f():\n    pass\n```\nb\n```Rust\nfn f() {}\n```");
+        assert_eq!(blocks.iter().map(|e| e.extension()).collect::<Vec<_>>(), ["py", "rs"]);
+    }
+}
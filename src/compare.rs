@@ -0,0 +1,165 @@
+use std::time::{Duration, Instant};
+use chrono::Local;
+use futures::StreamExt;
+use serde_json::Value;
+use crate::budget::cost;
+use crate::client::ChatClient;
+use crate::config::{Config, RetryConfig};
+use crate::events::{format_tokens, EventSender, TokenUsage, UiEvent};
+use crate::usage::{UsageDb, UsageRecord, USAGE_DB};
+
+/// One model's answer to a compared question.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Compared {
+    pub model: String,
+    pub content: String,
+    pub error: Option<String>,
+    pub usage: Option<TokenUsage>,
+    /// From sending the question to the first token of reasoning or content.
+    pub first_token: Option<Duration>,
+    /// From sending the question to the end of the answer.
+    pub elapsed: Duration,
+    /// Chunks that carried tokens, an answer that wasn't streamed comes as one.
+    pub chunks: u64,
+}
+
+/// Sends `body` to each of `models` at once and collects the answers, in the order of `models`.
+pub async fn ask(client: &dyn ChatClient, body: &Value, models: &[String], config: &Config, events: &EventSender) -> Vec<Compared> {
+    let retry = config.retry.clone().unwrap_or_default();
+    let answers = models.iter().map(|model| {
+        let mut body = body.clone();
+        let stream = !config.non_streaming_models.contains(model);
+        body["model"] = model.as_str().into();
+        body["stream"] = stream.into();
+        if let Some(body) = body.as_object_mut().filter(|_| !stream) {
+            body.remove("stream_options");
+        }
+        answer(client, body, model, &retry, events)
+    });
+    futures::future::join_all(answers).await
+}
+
+/// Sends `body` and collects the answer, a failed request is an answer with an error.
+pub async fn answer(client: &dyn ChatClient, body: Value, model: &str, retry: &RetryConfig, events: &EventSender) -> Compared {
+    let started = Instant::now();
+    let mut compared = Compared { model: model.to_string(), ..Default::default() };
+    match client.stream(body, retry, events).await {
+        Ok(mut stream) => {
+            while let Some(chunk) = stream.next().await {
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        compared.error = Some(e.to_string());
+                        continue;
+                    }
+                };
+                if let Some(ref usage) = chunk.usage {
+                    compared.usage = Some(TokenUsage {
+                        prompt_tokens: usage.prompt_tokens,
+                        completion_tokens: usage.completion_tokens,
+                        total_tokens: usage.total_tokens,
+                    });
+                }
+                let Some(choice) = chunk.choices.first() else { continue };
+                let reasoning = choice.delta.reasoning_content.as_ref().is_some_and(|e| !e.is_empty());
+                if reasoning || !choice.delta.content.is_empty() {
+                    compared.first_token.get_or_insert(started.elapsed());
+                    compared.chunks += 1;
+                }
+                compared.content.push_str(&choice.delta.content);
+            }
+        }
+        Err(e) => compared.error = Some(e.to_string()),
+    }
+    compared.elapsed = started.elapsed();
+    compared
+}
+
+/// Shows the answers one after another under the name of their model, then how each did.
+pub fn show(answers: &[Compared], events: &EventSender) {
+    for answer in answers {
+        events.emit(UiEvent::AnswerStarted { model: answer.model.clone() });
+        events.emit(UiEvent::ContentDelta(answer.content.trim_end().to_string()));
+        if let Some(ref e) = answer.error {
+            events.emit(UiEvent::Error(e.clone()));
+        }
+        events.emit(UiEvent::AnswerFinished);
+    }
+    events.emit(UiEvent::Notice(table(answers).join("\n")));
+}
+
+/// The latency and tokens of each answer, a row per model.
+fn table(answers: &[Compared]) -> Vec<String> {
+    let width = answers.iter().map(|e| e.model.chars().count()).max().unwrap_or_default().max(5);
+    let line = |model: &str, first_token: &str, total: &str, tokens: &str, speed: &str| {
+        format!("{:<width$}  {:>11}  {:>7}  {:>6}  {:>8}", model, first_token, total, tokens, speed, width = width)
+    };
+    let mut lines = vec![line("model", "first token", "total", "tokens", "tokens/s")];
+    for answer in answers {
+        let completion = answer.usage.map(|e| e.completion_tokens);
+        let streaming = answer.elapsed.saturating_sub(answer.first_token.unwrap_or_default()).as_secs_f64().max(0.001);
+        lines.push(line(
+            &answer.model,
+            &answer.first_token.map(|e| format!("{:.2}s", e.as_secs_f64())).unwrap_or("-".to_string()),
+            &format!("{:.1}s", answer.elapsed.as_secs_f64()),
+            &completion.map(format_tokens).unwrap_or("-".to_string()),
+            // An answer that came whole says nothing about the speed.
+            &completion.filter(|_| answer.error.is_none() && answer.chunks > 1).map(|e| format!("{:.1}", e as f64 / streaming)).unwrap_or("-".to_string()),
+        ));
+    }
+    lines
+}
+
+/// Keeps the usage of the answers in [`USAGE_DB`], as if each had been asked on its own.
+pub fn record_usage(config: &Config, answers: &[Compared]) -> anyhow::Result<()> {
+    let db = UsageDb::open(&config.config_dir().join(USAGE_DB))?;
+    for answer in answers {
+        let Some(usage) = answer.usage else { continue };
+        db.record(&UsageRecord {
+            timestamp: Local::now().timestamp(),
+            model: answer.model.clone(),
+            usage,
+            cost: cost(config, &answer.model, &usage),
+            duration_ms: answer.elapsed.as_millis() as u64,
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use super::*;
+    use crate::client::mock::MockClient;
+    use crate::events::{Renderer, UiEvent};
+
+    struct Discard;
+
+    impl Renderer for Discard {
+        fn render(&mut self, _event: UiEvent) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compare() {
+        let mut answer = MockClient::answer("Paris.");
+        answer.push(serde_json::json!({ "choices": [], "usage": { "prompt_tokens": 10, "completion_tokens": 2, "total_tokens": 12 } }));
+        let mock = Arc::new(MockClient::new(vec![answer, vec![serde_json::json!({ "error": "overloaded" })]]));
+        let mut config = Config::default();
+        config.non_streaming_models = vec!["o1".to_string()];
+        let body = serde_json::json!({ "model": "m", "stream": true, "stream_options": { "include_usage": true }, "messages": [] });
+        let models = ["gpt-4o".to_string(), "o1".to_string()];
+        let answers = ask(mock.as_ref(), &body, &models, &config, &EventSender::spawn(Box::new(Discard))).await;
+
+        let requests = mock.requests.lock().unwrap();
+        assert_eq!(requests.iter().map(|e| (e["model"].as_str().unwrap(), e["stream"] == true)).collect::<Vec<_>>(), [("gpt-4o", true), ("o1", false)]);
+        assert!(requests[1].get("stream_options").is_none());
+        assert_eq!((answers[0].content.as_str(), answers[0].usage.map(|e| e.total_tokens)), ("Paris.", Some(12)));
+        assert_eq!(answers[1].error.as_deref(), Some("stream failed: overloaded"));
+
+        let lines = table(&answers);
+        assert_eq!(lines[0], "model   first token    total  tokens  tokens/s");
+        assert!(lines[2].starts_with("o1                -     0.0s       -         -"), "{}", lines[2]);
+    }
+}
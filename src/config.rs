@@ -1,34 +1,686 @@
+use std::collections::{BTreeMap, HashMap};
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use colored::Colorize;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub(crate) struct Config {
+    /// Format version of the file, older files are migrated when they are loaded.
+    #[serde(default)]
+    pub version: u32,
     pub base_url: String,
     pub api_key: String,
     pub model: String,
+    /// Sent first in every request and never shifted out of the context, `@system` replaces it for the session.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+    /// Root directory the filesystem tools are confined to, defaults to the working directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sandbox_root: Option<PathBuf>,
+    /// Ask before a tool modifies a file in the sandbox.
+    #[serde(default = "default_true")]
+    pub confirm_writes: bool,
+    /// Where overwritten files are copied to before a tool modifies them, defaults to `backups` in the config dir.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backup_dir: Option<PathBuf>,
+    /// Host patterns like `api.github.com` or `*.example.org` the `http_request` tool may call, empty disables it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub http_allowed_hosts: Vec<String>,
+    /// Response bodies longer than this are truncated before they are handed to the model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_max_response_bytes: Option<usize>,
+    /// Enables the `web_search` tool.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub web_search: Option<WebSearchConfig>,
+    /// Per-tool override of whether a call runs directly, needs confirmation or is never offered to the model.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub tool_permissions: HashMap<String, ToolPermission>,
+    /// Per-tool `timeout_secs` and `max_output_bytes`, the `default` entry applies to every tool without its own.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub tool_limits: HashMap<String, ToolLimits>,
+    /// Enables the read-only `mail_search` tool.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mail: Option<MailConfig>,
+    /// Enables the read-only `calendar_events` tool.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub calendar: Option<CalendarConfig>,
+    /// Enables the GitHub issue and pull request tools.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub github: Option<GitHubConfig>,
+    /// Enables the Jira issue tools.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jira: Option<JiraConfig>,
+    /// Enables the `execute_command` tool, which only ever runs inside this sandbox.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command_sandbox: Option<CommandSandboxConfig>,
+    /// Tools implemented by other programs, see `ExternalToolConfig`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub external_tools: Vec<ExternalToolConfig>,
+    /// Interpreter `run_python` starts, defaults to `python3`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub python_interpreter: Option<String>,
+    /// Command `@open` edits answers with, e.g. `code --wait`, defaults to `$VISUAL`, `$EDITOR` and then `vi`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub editor: Option<String>,
+    /// Transformations applied in order to the streamed answer before it is shown or kept.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub content_filters: Vec<ContentFilter>,
+    /// Tokens the model accepts, what `@tokens` compares the context against.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_window: Option<u64>,
+    /// Tokens a single `@file` may add, larger files are cut down to the parts relevant to the question. 16000 by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_token_budget: Option<u64>,
+    /// How the reasoning of thinking models is shown, `@reasoning` changes it for the session.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning: Option<ReasoningDisplay>,
+    /// Reasoning tokens a single answer may spend, the stream is closed once they are used up.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thinking_budget: Option<u64>,
+    /// Model parameters sent with every request, `@set` changes them for the session.
+    #[serde(default, skip_serializing_if = "SamplingConfig::is_default")]
+    pub sampling: SamplingConfig,
+    /// Seconds the provider may send nothing before a request is given up, unlimited by default so long answers can stream.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_timeout_secs: Option<u64>,
+    /// Seconds connecting to the provider may take, defaults to 30.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_timeout_secs: Option<u64>,
+    /// Proxy for plain http requests to the provider, e.g. `http://proxy.corp:3128`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_proxy: Option<String>,
+    /// Proxy for https requests to the provider, `HTTPS_PROXY` from the environment applies if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub https_proxy: Option<String>,
+    /// Models whose requests aren't streamed, for endpoints that refuse `stream = true`.
+    /// Others that refuse it are found out with a first try, which is saved by listing them here.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub non_streaming_models: Vec<String>,
+    /// Models asked in turn when the one before them fails with a rate limit, an overload or a context that is too long.
+    /// The current model falls back to those after it, or to all of them if it isn't listed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub model_fallbacks: Vec<String>,
+    /// The API `base_url` speaks, an OpenAI compatible one when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<ProviderKind>,
+    /// The `api-version` Azure is asked with, defaults to [`DEFAULT_AZURE_API_VERSION`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_version: Option<String>,
+    /// The AWS region Bedrock is called in, `AWS_REGION` when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aws_region: Option<String>,
+    /// How often in a row an answer cut off at the token limit is asked to go on by itself, never when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_continuations: Option<u32>,
+    /// How often a request failing with a rate limit, server or connection error is sent again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryConfig>,
+    /// Limits on what a session and a day may spend, warned about at 80% and enforced at 100%.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub budget: Option<BudgetConfig>,
+    /// What the models cost in USD per million tokens, by model name, needed for `budget.daily_usd`.
+    /// Those OpenRouter lists are priced from its catalog unless they are set here.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub prices: BTreeMap<String, ModelPrice>,
+    /// How much of the input history is kept in `history` next to the config.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history: Option<HistoryConfig>,
+    /// Recording of the raw requests and responses to `transcripts` next to the config, for `rag replay`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transcript: Option<TranscriptConfig>,
+    /// Answers to requests asked before served from `cache.db` next to the config, off by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache: Option<CacheConfig>,
+    /// Masking of credentials, private keys and email addresses in the questions before they are sent, on by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redaction: Option<RedactionConfig>,
+    /// Presets `@persona` and `--persona` switch to, by name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub personas: BTreeMap<String, PersonaConfig>,
+    /// Commands of your own, `@name` by default, run after the built-in ones that rewrite the input.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub commands: BTreeMap<String, CustomCommandConfig>,
+    /// Headers sent with every request to the provider, e.g. a tenant header an internal gateway wants.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub extra_headers: BTreeMap<String, String>,
+    /// Query parameters added to every request to the provider.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub extra_query: BTreeMap<String, String>,
+    /// What OpenRouter is told about the app with every request, when `base_url` points there.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub openrouter: Option<OpenRouterConfig>,
+    /// The context sizes and prices the provider's catalog lists, by model name, never written to the file.
+    #[serde(skip)]
+    pub catalog: BTreeMap<String, ModelMetadata>,
     #[serde(skip)]
     config_file_path: PathBuf,
 }
 
-const DEFAULT_BASE_URL: &str = "https://ark.cn-beijing.volces.com/api/v3";
-const DEFAULT_MODEL: &str = "deepseek-r1-250120";
-const DEFAULT_API_KEY: &str = "6f1797f8-b0d5-4a1e-9450-17ed67c0ad2f";
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebSearchBackend {
+    Searxng,
+    Brave,
+    #[default]
+    DuckDuckGo,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WebSearchConfig {
+    #[serde(default)]
+    pub backend: WebSearchBackend,
+    /// Base url of the SearxNG instance.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Brave Search API subscription token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_results: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolPermission {
+    Allow,
+    Confirm,
+    Deny,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ToolLimits {
+    /// Seconds after which a call is abandoned and reported as timed out. It keeps running, only the exec tools stop
+    /// their commands, and calls waiting for the user to confirm aren't timed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+    /// Results longer than this, serialized as JSON, are cut before they are handed to the model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_output_bytes: Option<usize>,
+}
+
+impl ToolLimits {
+    /// Fills the limits `self` leaves unset from `fallback`.
+    pub fn or(self, fallback: ToolLimits) -> ToolLimits {
+        ToolLimits {
+            timeout_secs: self.timeout_secs.or(fallback.timeout_secs),
+            max_output_bytes: self.max_output_bytes.or(fallback.max_output_bytes),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MailConfig {
+    pub imap_host: String,
+    #[serde(default = "default_imap_port")]
+    pub imap_port: u16,
+    pub username: String,
+    /// Keychain entry holding the password, defaults to `imap`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_secret: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CalendarConfig {
+    /// CalDAV collection url of the calendar.
+    pub url: String,
+    pub username: String,
+    /// Keychain entry holding the password, defaults to `caldav`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_secret: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GitHubConfig {
+    /// `owner/name` used when the model doesn't name a repository.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repo: Option<String>,
+    /// REST endpoint, only needed for GitHub Enterprise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_url: Option<String>,
+    /// Keychain entry holding the access token, defaults to `github_token`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_secret: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JiraConfig {
+    /// Base url of the Jira site, e.g. `https://example.atlassian.net`.
+    pub url: String,
+    /// Account email for Jira Cloud, leave empty to send the token as a bearer token (Jira Data Center).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// Project key new issues are filed in when the model doesn't name one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub project: Option<String>,
+    /// Keychain entry holding the API token, defaults to `jira_token`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_secret: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SandboxRuntime {
+    #[default]
+    Docker,
+    Podman,
+    /// `bwrap`, Linux only, runs the host's own binaries.
+    Bubblewrap,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CommandSandboxConfig {
+    #[serde(default)]
+    pub runtime: SandboxRuntime,
+    /// Container image for docker and podman, defaults to `debian:stable-slim`, and to `python:3-slim` for `run_python`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    /// CPU share for containers, defaults to 1.
+    #[serde(default, skip_serializing_if = "Option::is_none", serialize_with = "serialize_f32")]
+    pub cpus: Option<f32>,
+    /// Memory limit, defaults to 512 MiB.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_mb: Option<u64>,
+    /// Wall clock limit per command, defaults to 30 seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+    /// Allow network access from inside the sandbox.
+    #[serde(default)]
+    pub network: bool,
+}
+
+/// A program called with the JSON arguments on stdin, which prints the JSON result to stdout.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExternalToolConfig {
+    pub name: String,
+    pub description: String,
+    /// The program and its arguments, e.g. `[python3, /home/me/tools/weather.py]`, run in the sandbox root.
+    pub command: Vec<String>,
+    /// JSON schema of the arguments object, defaults to no arguments.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<serde_json::Value>,
+    /// Wall clock limit per call, defaults to 30 seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+}
+
+/// A command declared in the config, replaced in the input by the output of `shell` or by `template`.
+/// The capture groups of the pattern fill in `{{1}}` or `{{group}}` in either.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CustomCommandConfig {
+    /// Regular expression the command is found with, `@<name>` by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shell: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    /// Shown by `@help`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+/// A system prompt with the sampling parameters and tools that suit it. Switching to one replaces all three.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PersonaConfig {
+    /// Defaults to `system_prompt` of the config.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+    #[serde(default, skip_serializing_if = "SamplingConfig::is_default")]
+    pub sampling: SamplingConfig,
+    /// Names of the tools offered to the model, all of them if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<String>>,
+}
+
+/// Unset parameters are left to the provider's defaults.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SamplingConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none", serialize_with = "serialize_f32")]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none", serialize_with = "serialize_f32")]
+    pub top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none", serialize_with = "serialize_f32")]
+    pub presence_penalty: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none", serialize_with = "serialize_f32")]
+    pub frequency_penalty: Option<f32>,
+    /// Sequences that end the answer when generated.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    /// How hard a reasoning model thinks, `low`, `medium` or `high` for OpenAI's models.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning_effort: Option<String>,
+    /// Tokens a model with extended thinking may think for, sent as `thinking` with `budget_tokens`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thinking: Option<u32>,
+}
+
+impl SamplingConfig {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Sets a parameter from its `key=value` form, `none` unsets it and `stop` takes a comma separated list.
+    pub fn set(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        fn parse<T: std::str::FromStr>(key: &str, value: &str) -> anyhow::Result<Option<T>> {
+            if value == "none" {
+                return Ok(None);
+            }
+            value.parse().map(Some).map_err(|_| anyhow::anyhow!("{} isn't a valid value for {}", value, key))
+        }
+
+        match key {
+            "temperature" | "temp" => self.temperature = parse(key, value)?,
+            "top_p" => self.top_p = parse(key, value)?,
+            "max_tokens" | "max" => self.max_tokens = parse(key, value)?,
+            "presence_penalty" => self.presence_penalty = parse(key, value)?,
+            "frequency_penalty" => self.frequency_penalty = parse(key, value)?,
+            "stop" if value == "none" => self.stop.clear(),
+            "stop" => self.stop = value.split(',').map(str::to_string).collect(),
+            "reasoning_effort" | "effort" => self.reasoning_effort = parse(key, value)?,
+            "thinking" => self.thinking = parse(key, value)?,
+            _ => anyhow::bail!(
+                "Unknown parameter {}, expected temperature, top_p, max_tokens, presence_penalty, frequency_penalty, stop, reasoning_effort or thinking",
+                key
+            ),
+        }
+        Ok(())
+    }
+
+    /// `key=value` for every parameter that is set.
+    pub fn describe(&self) -> Vec<String> {
+        let parameters = [
+            ("temperature", self.temperature.map(|e| e.to_string())),
+            ("top_p", self.top_p.map(|e| e.to_string())),
+            ("max_tokens", self.max_tokens.map(|e| e.to_string())),
+            ("presence_penalty", self.presence_penalty.map(|e| e.to_string())),
+            ("frequency_penalty", self.frequency_penalty.map(|e| e.to_string())),
+            ("stop", (!self.stop.is_empty()).then(|| self.stop.join(","))),
+            ("reasoning_effort", self.reasoning_effort.clone()),
+            ("thinking", self.thinking.map(|e| e.to_string())),
+        ];
+        parameters
+            .into_iter()
+            .filter_map(|(key, value)| Some(format!("{}={}", key, value?)))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RetryConfig {
+    /// Attempts in total including the first, defaults to 3, 1 disables retrying.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_attempts: Option<u32>,
+    /// Delay before the first retry, doubled for every further one, defaults to 500 ms.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub initial_delay_ms: Option<u64>,
+    /// Upper bound of the delay before jitter, defaults to 30 seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_delay_secs: Option<u64>,
+    /// Whether an answer whose stream broke off is asked to go on where it stopped, within `max_attempts`. Off by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub continue_dropped: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReasoningDisplay {
+    /// Streamed in gray before the answer.
+    #[default]
+    On,
+    /// Not shown at all.
+    Off,
+    /// A single line counting the reasoning tokens, replaced by the answer.
+    Collapse,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    OpenAi,
+    /// Azure OpenAI, `base_url` is the resource's endpoint and `model` the name of a deployment.
+    Azure,
+    /// AWS Bedrock, signed with the AWS credentials instead of `api_key`, `base_url` is unused.
+    Bedrock,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BudgetConfig {
+    /// USD all sessions of a day may spend together, priced with `prices`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub daily_usd: Option<f64>,
+    /// Tokens a single session may use.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_tokens: Option<u64>,
+}
+
+/// USD per million tokens.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ModelPrice {
+    pub input: f64,
+    pub output: f64,
+}
+
+/// What a provider's catalog says about a model.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ModelMetadata {
+    pub context_length: Option<u64>,
+    pub price: Option<ModelPrice>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OpenRouterConfig {
+    /// The app's url, sent as `HTTP-Referer` for OpenRouter's rankings.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub referer: Option<String>,
+    /// The app's name, sent as `X-Title`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HistoryConfig {
+    /// Entries kept, the oldest are dropped first, defaults to 1000.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_entries: Option<usize>,
+    /// Whether an input repeating the one before it is left out, defaults to true.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ignore_duplicates: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TranscriptConfig {
+    /// Whether every session is recorded, a file each, off by default.
+    #[serde(default)]
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CacheConfig {
+    /// Whether a request the same as one answered before is answered from the cache.
+    #[serde(default)]
+    pub enabled: bool,
+    /// Seconds a cached answer is served for, defaults to a day.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RedactionConfig {
+    /// Whether anything is masked, defaults to true.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+    /// Values sent as they are, by pattern: `api_key`, `aws_access_key`, `aws_secret_key`, `private_key` or `email`.
+    /// `"*"` lets everything a pattern finds through.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub allow: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentFilter {
+    StripEmoji,
+    /// Replaces full-width and CJK punctuation with its ASCII counterpart.
+    NormalizePunctuation,
+    CollapseBlankLines,
+}
+
+/// TOML only has 64 bit floats, widened as is 0.7 would be written as 0.699999988079071.
+fn serialize_f32<S: Serializer>(value: &Option<f32>, serializer: S) -> Result<S::Ok, S::Error> {
+    value.map(|e| e.to_string().parse::<f64>().unwrap_or(e as f64)).serialize(serializer)
+}
+
+fn default_imap_port() -> u16 {
+    993
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Bumped whenever a change to the fields needs [`Config::migrate`] to rewrite older files.
+pub const CONFIG_VERSION: u32 = 1;
+
+const CONFIG_FILE: &str = "rag.toml";
+/// Names the config had before it moved to TOML, both were written as YAML.
+const LEGACY_CONFIG_FILES: [&str; 2] = ["rag.yaml", "rag.json"];
+
+/// Suggested by the first-run setup.
+pub const DEFAULT_BASE_URL: &str = "https://ark.cn-beijing.volces.com/api/v3";
+pub const DEFAULT_MODEL: &str = "deepseek-r1-250120";
+/// The `api-version` Azure OpenAI is asked with unless `api_version` is set.
+pub const DEFAULT_AZURE_API_VERSION: &str = "2024-10-21";
+
+/// Something wrong with the config and how to fix it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigProblem {
+    pub problem: String,
+    pub fix: String,
+}
+
+impl ConfigProblem {
+    pub fn new(problem: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self { problem: problem.into(), fix: fix.into() }
+    }
+}
 
 impl Config {
-    pub fn new() -> Self {
+    /// Reads the config file, a missing file gives an empty config for the first-run setup to fill.
+    pub fn new() -> anyhow::Result<Self> {
         let mut config = Self {
+            version: CONFIG_VERSION,
             base_url: String::new(),
             api_key: String::new(),
             model: String::new(),
+            system_prompt: None,
+            sandbox_root: None,
+            confirm_writes: true,
+            backup_dir: None,
+            http_allowed_hosts: vec![],
+            http_max_response_bytes: None,
+            web_search: None,
+            tool_permissions: HashMap::new(),
+            tool_limits: HashMap::new(),
+            mail: None,
+            calendar: None,
+            github: None,
+            jira: None,
+            command_sandbox: None,
+            external_tools: vec![],
+            python_interpreter: None,
+            editor: None,
+            content_filters: vec![],
+            context_window: None,
+            file_token_budget: None,
+            reasoning: None,
+            thinking_budget: None,
+            sampling: SamplingConfig::default(),
+            request_timeout_secs: None,
+            connect_timeout_secs: None,
+            http_proxy: None,
+            https_proxy: None,
+            non_streaming_models: vec![],
+            model_fallbacks: vec![],
+            provider: None,
+            api_version: None,
+            aws_region: None,
+            max_continuations: None,
+            retry: None,
+            budget: None,
+            prices: BTreeMap::new(),
+            history: None,
+            transcript: None,
+            cache: None,
+            redaction: None,
+            personas: BTreeMap::new(),
+            commands: BTreeMap::new(),
+            extra_headers: BTreeMap::new(),
+            extra_query: BTreeMap::new(),
+            openrouter: None,
+            catalog: BTreeMap::new(),
             config_file_path: PathBuf::new(),
         };
 
         config.get_default_config_file();
-        config.load_config();
-        config
+        config.load_config()?;
+        Ok(config)
+    }
+
+    /// Checks what can be checked without a request, `rag doctor` also tries the provider.
+    pub fn validate(&self) -> Vec<ConfigProblem> {
+        let mut problems = vec![];
+        match reqwest::Url::parse(&self.base_url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            Ok(url) => problems.push(ConfigProblem::new(
+                format!("base_url uses the unsupported scheme {}", url.scheme()),
+                "use an http or https url, e.g. `rag --sb https://api.openai.com/v1`",
+            )),
+            Err(e) => problems.push(ConfigProblem::new(
+                format!("base_url `{}` isn't a valid url: {}", self.base_url, e),
+                "set the provider's API url, e.g. `rag --sb https://api.openai.com/v1`",
+            )),
+        }
+        if self.provider == Some(ProviderKind::Azure) && self.base_url.contains("/openai/deployments") {
+            problems.push(ConfigProblem::new(
+                "base_url has the deployment in it, rag adds that from `model`",
+                "set base_url to the resource's endpoint, e.g. `https://<resource>.openai.azure.com`, and model to the deployment's name",
+            ));
+        }
+        if self.provider == Some(ProviderKind::Bedrock) {
+            if self.aws_region().is_none() {
+                problems.push(ConfigProblem::new("No AWS region for Bedrock", "set `aws_region` in the config or AWS_REGION"));
+            }
+        } else if self.api_key.trim().is_empty() {
+            problems.push(ConfigProblem::new("api_key is empty", "set it with `rag --sa <key>`"));
+        }
+        if self.model.trim().is_empty() {
+            problems.push(ConfigProblem::new("model is empty", "set it with `rag --sm <model>`, `rag models` lists the available ones"));
+        }
+        for (name, proxy) in [("http_proxy", &self.http_proxy), ("https_proxy", &self.https_proxy)] {
+            if let Some(Err(e)) = proxy.as_deref().map(reqwest::Url::parse) {
+                problems.push(ConfigProblem::new(
+                    format!("{} isn't a valid url: {}", name, e),
+                    format!("write it as `http://host:port` in {}", self.config_file_path.display()),
+                ));
+            }
+        }
+        for (name, value) in &self.extra_headers {
+            if let Err(e) = reqwest::header::HeaderName::from_bytes(name.as_bytes()) {
+                problems.push(ConfigProblem::new(
+                    format!("extra_headers has `{}`, which isn't a valid header name: {}", name, e),
+                    format!("use only letters, digits and dashes in the names of extra_headers in {}", self.config_file_path.display()),
+                ));
+            } else if let Err(e) = reqwest::header::HeaderValue::from_str(value) {
+                problems.push(ConfigProblem::new(
+                    format!("the extra header {} has a value that isn't valid in a header: {}", name, e),
+                    format!("leave out line breaks and other control characters in {}", self.config_file_path.display()),
+                ));
+            }
+        }
+        problems
     }
 
     fn get_default_config_file(&mut self) {
@@ -43,50 +695,109 @@ impl Config {
             }
         };
 
-        config_dir.push("rag.yaml");
+        config_dir.push(CONFIG_FILE);
         self.config_file_path = config_dir;
     }
 
-    fn ensure_config_file_exists(&mut self) -> bool {
-        std::fs::create_dir_all(self.config_file_path.parent().unwrap()).expect("Failed to create config dir");
-        if !self.config_file_path.exists() {
-            let config_file_path = self.config_file_path.as_path();
-            File::create(config_file_path).expect("Failed to create config file");
+    /// `aws_region`, or else the region the AWS environment variables name.
+    pub fn aws_region(&self) -> Option<String> {
+        self.aws_region.clone().or_else(|| std::env::var("AWS_REGION").ok()).or_else(|| std::env::var("AWS_DEFAULT_REGION").ok())
+    }
 
-            println!("{}", format!("Cannot to find config file, Using default config and creating: {:?}", config_file_path).red());
-            println!("{}", format!("    base_url: {}", &DEFAULT_BASE_URL).yellow());
-            println!("{}", format!("    model: {}", &DEFAULT_MODEL).yellow());
-            println!("{}", format!("    api_key: {}", &DEFAULT_API_KEY).yellow());
+    /// The price of `model` from `prices`, or else from the catalog.
+    pub fn price(&self, model: &str) -> Option<ModelPrice> {
+        self.prices.get(model).copied().or_else(|| self.catalog.get(model)?.price)
+    }
 
-            self.api_key = DEFAULT_API_KEY.to_string();
-            self.model = DEFAULT_MODEL.to_string();
-            self.base_url = DEFAULT_BASE_URL.to_string();
-            self.save_config();
+    /// `context_window`, or else the context size the catalog lists for the current model.
+    pub fn model_context_window(&self) -> Option<u64> {
+        self.context_window.or_else(|| self.catalog.get(&self.model)?.context_length)
+    }
 
-            return false;
-        }
-        true
+    pub fn config_dir(&self) -> PathBuf {
+        self.config_file_path.parent().map(|e| e.to_path_buf()).unwrap_or_default()
+    }
+
+    pub fn config_file_path(&self) -> &Path {
+        &self.config_file_path
+    }
+
+    /// Whether the config was read from a file, a first run has none until the setup saves it.
+    pub fn exists(&self) -> bool {
+        self.config_file_path.exists() || self.legacy_config_file().is_some()
     }
 
-    pub fn save_config(&mut self) {
-        let mut file = OpenOptions::new()
+    fn legacy_config_file(&self) -> Option<PathBuf> {
+        LEGACY_CONFIG_FILES.iter().map(|e| self.config_dir().join(e)).find(|e| e.exists())
+    }
+
+    pub fn save_config(&mut self) -> anyhow::Result<()> {
+        let path = self.config_file_path.as_path();
+        std::fs::create_dir_all(self.config_dir())
+            .map_err(|e| anyhow::anyhow!("Failed to create {}: {}", self.config_dir().display(), e))?;
+        let config_toml = toml::to_string(self).map_err(|e| anyhow::anyhow!("Failed to serialize the config: {}", e))?;
+        OpenOptions::new()
             .write(true)
+            .create(true)
             .truncate(true)
-            .open(self.config_file_path.as_path())
-            .expect("Failed to open config file");
-        let config_json = serde_yaml::to_string(self).expect("Failed to serialize config file");
-        file.write_all(&config_json.into_bytes()).expect("Failed to write config file");
+            .open(path)
+            .and_then(|mut e| e.write_all(config_toml.as_bytes()))
+            .map_err(|e| anyhow::anyhow!("Failed to write {}: {}", path.display(), e))
     }
 
-    fn load_config(&mut self) {
-        if self.ensure_config_file_exists() {
-            let mut file = File::open(self.config_file_path.as_path()).expect("Failed to open config file");
-            let mut config_string = String::new();
+    /// Brings a config written by an older version up to [`CONFIG_VERSION`], returns whether anything changed.
+    fn migrate(&mut self) -> anyhow::Result<bool> {
+        if self.version > CONFIG_VERSION {
+            anyhow::bail!(
+                "The config has version {}, this rag only knows up to {}, update rag to use it",
+                self.version,
+                CONFIG_VERSION
+            );
+        }
+        // Version 0 is the YAML file, which only differs in its format. Steps for later versions go here, oldest first.
+        let migrated = self.version < CONFIG_VERSION;
+        self.version = CONFIG_VERSION;
+        Ok(migrated)
+    }
 
-            file.read_to_string(&mut config_string).expect("Failed to read from config file");
-            *self = serde_yaml::from_str(config_string.as_str()).expect("Failed to deserialize config");
-            self.get_default_config_file();
+    /// Reads the TOML config, or else the legacy YAML one, which is converted and moved aside to `<name>.bak`.
+    fn load_config(&mut self) -> anyhow::Result<()> {
+        let path = self.config_file_path.clone();
+        let legacy = match path.exists() {
+            true => None,
+            false => match self.legacy_config_file() {
+                Some(legacy) => Some(legacy),
+                None => return Ok(()),
+            },
+        };
+        let source = legacy.as_deref().unwrap_or(&path);
+
+        let mut config_string = String::new();
+        File::open(source)
+            .and_then(|mut e| e.read_to_string(&mut config_string))
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", source.display(), e))?;
+        let mut config: Self = match legacy {
+            Some(_) => serde_yaml::from_str(&config_string).map_err(|e| anyhow::anyhow!("Failed to parse {}: {}", source.display(), e))?,
+            None => toml::from_str(&config_string).map_err(|e| anyhow::anyhow!("Failed to parse {}: {}", source.display(), e))?,
+        };
+        config.config_file_path = path;
+
+        if config.migrate()? || legacy.is_some() {
+            let moved = config.save_config().and_then(|_| match legacy {
+                Some(ref legacy) => {
+                    let mut backup = legacy.clone().into_os_string();
+                    backup.push(".bak");
+                    Ok(std::fs::rename(legacy, backup)?)
+                }
+                None => Ok(()),
+            });
+            match moved {
+                Ok(()) => println!("{}", format!("Migrated the config to {}", config.config_file_path.display()).truecolor(128, 138, 135)),
+                Err(e) => eprintln!("{}", format!("Warning: Failed to migrate the config, using it as is: {:#}", e).yellow()),
+            }
         }
+        *self = config;
+        Ok(())
     }
 }
 //
@@ -97,4 +808,127 @@ impl Config {
 //         config.load_config();
 //         RefCell::new(config)
 //     };
-// }
\ No newline at end of file
+// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let mut config = Config {
+            base_url: "https://api.example.com/v1".to_string(),
+            api_key: "key".to_string(),
+            model: "model".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(config.validate(), []);
+
+        config.base_url = "api.example.com".to_string();
+        config.api_key = " ".to_string();
+        config.http_proxy = Some("//proxy:3128".to_string());
+        config.extra_headers = BTreeMap::from([("X Title".to_string(), "rag".to_string()), ("X-Title".to_string(), "a\nb".to_string())]);
+        let problems = config.validate().into_iter().map(|e| e.problem).collect::<Vec<_>>();
+        assert_eq!(problems.len(), 5, "{:?}", problems);
+        assert!(problems[0].starts_with("base_url `api.example.com` isn't a valid url"));
+        assert_eq!(problems[1], "api_key is empty");
+        assert!(problems[2].starts_with("http_proxy"));
+        assert!(problems[3].starts_with("extra_headers has `X Title`"));
+        assert!(problems[4].starts_with("the extra header X-Title"));
+    }
+
+    fn temp_config_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rag-config-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn sample_config(dir: &Path) -> Config {
+        Config {
+            version: CONFIG_VERSION,
+            base_url: "https://api.example.com/v1".to_string(),
+            api_key: "key".to_string(),
+            model: "model".to_string(),
+            confirm_writes: true,
+            http_allowed_hosts: vec!["*.example.org".to_string()],
+            tool_permissions: HashMap::from([("write_file".to_string(), ToolPermission::Confirm)]),
+            tool_limits: HashMap::from([("default".to_string(), ToolLimits { timeout_secs: Some(5), max_output_bytes: None })]),
+            web_search: Some(WebSearchConfig { backend: WebSearchBackend::Brave, ..Default::default() }),
+            external_tools: vec![ExternalToolConfig {
+                name: "weather".to_string(),
+                description: "The weather".to_string(),
+                command: vec!["python3".to_string(), "weather.py".to_string()],
+                parameters: Some(serde_json::json!({ "type": "object", "properties": { "city": { "type": "string" } } })),
+                timeout_secs: None,
+            }],
+            content_filters: vec![ContentFilter::StripEmoji],
+            sampling: SamplingConfig { temperature: Some(0.7), stop: vec!["END".to_string()], ..Default::default() },
+            retry: Some(RetryConfig { max_attempts: Some(5), initial_delay_ms: None, max_delay_secs: None, continue_dropped: None }),
+            config_file_path: dir.join(CONFIG_FILE),
+            ..Default::default()
+        }
+    }
+
+    fn load(dir: &Path) -> anyhow::Result<Config> {
+        let mut config = Config { config_file_path: dir.join(CONFIG_FILE), ..Default::default() };
+        config.load_config()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = temp_config_dir("round-trip");
+        let mut config = sample_config(&dir);
+        config.save_config().unwrap();
+
+        let loaded = load(&dir).unwrap();
+        assert_eq!(toml::to_string(&loaded).unwrap(), toml::to_string(&config).unwrap());
+        assert_eq!(loaded.sampling, config.sampling);
+        assert!(std::fs::read_to_string(dir.join(CONFIG_FILE)).unwrap().contains("temperature = 0.7\n"));
+        assert_eq!(loaded.config_file_path, dir.join(CONFIG_FILE));
+    }
+
+    #[test]
+    fn test_migrate_legacy_yaml() {
+        let dir = temp_config_dir("legacy");
+        let config = sample_config(&dir);
+        let mut yaml = serde_yaml::to_string(&config).unwrap();
+        yaml = yaml.replace("version: 1\n", "");
+        std::fs::write(dir.join("rag.yaml"), &yaml).unwrap();
+
+        let loaded = load(&dir).unwrap();
+        assert_eq!(loaded.version, CONFIG_VERSION);
+        assert_eq!(toml::to_string(&loaded).unwrap(), toml::to_string(&config).unwrap());
+        assert!(dir.join(CONFIG_FILE).exists());
+        assert!(!dir.join("rag.yaml").exists());
+        assert_eq!(std::fs::read_to_string(dir.join("rag.yaml.bak")).unwrap(), yaml);
+
+        // The migrated file is read on its own from now on.
+        assert_eq!(load(&dir).unwrap().model, "model");
+    }
+
+    #[test]
+    fn test_newer_version_is_rejected() {
+        let dir = temp_config_dir("newer");
+        let mut config = sample_config(&dir);
+        config.version = CONFIG_VERSION + 1;
+        config.save_config().unwrap();
+        assert!(load(&dir).unwrap_err().to_string().contains("update rag"));
+    }
+
+    #[test]
+    fn test_sampling_set() {
+        let mut sampling = SamplingConfig::default();
+        sampling.set("temp", "0.2").unwrap();
+        sampling.set("max_tokens", "400").unwrap();
+        sampling.set("stop", "END,STOP").unwrap();
+        sampling.set("effort", "high").unwrap();
+        assert_eq!(sampling.describe(), ["temperature=0.2", "max_tokens=400", "stop=END,STOP", "reasoning_effort=high"]);
+        assert!(sampling.set("thinking", "lots").is_err());
+
+        sampling.set("max_tokens", "none").unwrap();
+        assert!(sampling.set("top_p", "high").is_err());
+        assert!(sampling.set("seed", "1").is_err());
+        assert_eq!(sampling.max_tokens, None);
+    }
+}
@@ -0,0 +1,167 @@
+use std::future::Future;
+use std::time::Duration;
+use async_openai::Client;
+use async_openai::error::OpenAIError;
+use colored::Colorize;
+use futures::StreamExt;
+use serde_json::Value;
+use crate::app::Context;
+use crate::bedrock::Bedrock;
+use crate::client::{self, ChatClient};
+use crate::config::{ConfigProblem, ProviderKind, RetryConfig};
+use crate::setup;
+
+/// How long a single check may take, the client retries server errors far longer on its own.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Runs `check` with [`CHECK_TIMEOUT`], `None` if it ran out.
+async fn timed<T>(check: impl Future<Output = Result<T, OpenAIError>>) -> Option<Result<T, OpenAIError>> {
+    tokio::time::timeout(CHECK_TIMEOUT, check).await.ok()
+}
+
+/// What to do about a failed request, judged by the error the provider sent back.
+fn request_fix(error: &OpenAIError) -> String {
+    let (message, code) = match error {
+        OpenAIError::ApiError(e) => (
+            e.message.to_lowercase(),
+            format!("{} {}", e.code.as_deref().unwrap_or_default(), e.r#type.as_deref().unwrap_or_default()).to_lowercase(),
+        ),
+        e => (e.to_string().to_lowercase(), String::new()),
+    };
+    let mentions = |needles: &[&str]| needles.iter().any(|e| message.contains(e) || code.contains(e));
+
+    if mentions(&["api key", "api_key", "unauthorized", "authentication", "401"]) {
+        "check the key, set it with `rag --sa <key>`".to_string()
+    } else if mentions(&["model", "404"]) {
+        "`rag models` lists the available models, set one with `rag --sm <model>`".to_string()
+    } else if mentions(&["quota", "billing", "credit"]) {
+        "check the plan and billing of the account".to_string()
+    } else {
+        "see the error above, `@model` or `--sm` picks another model".to_string()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Report {
+    failed: usize,
+}
+
+impl Report {
+    fn pass(&self, message: &str) {
+        println!("{} {}", "✓".green(), message);
+    }
+
+    fn fail(&mut self, problem: &ConfigProblem) {
+        self.failed += 1;
+        println!("{} {}", "✗".red(), problem.problem);
+        println!("    {}", format!("Fix: {}", problem.fix).truecolor(128, 138, 135));
+    }
+}
+
+/// Sends the one token request of `setup::probe` through `chat`, for the providers that don't speak OpenAI's API.
+async fn probe_chat(chat: &dyn ChatClient, context: &Context) -> Result<(), OpenAIError> {
+    let body = serde_json::json!({
+        "model": context.config.model,
+        "messages": [{ "role": "user", "content": "ping" }],
+        "max_tokens": 1,
+    });
+    let mut stream = chat.stream(body, &RetryConfig::default(), &context.events).await?;
+    while let Some(chunk) = stream.next().await {
+        chunk?;
+    }
+    Ok(())
+}
+
+/// Checks the config and then the provider step by step, so the first failing step points at the cause.
+/// Exits with 1 if any check failed.
+pub async fn run(context: &Context) -> anyhow::Result<()> {
+    let config = &context.config;
+    let mut report = Report::default();
+
+    let path = config.config_file_path();
+    if config.exists() {
+        report.pass(&format!("Config file {}", path.display()));
+    } else {
+        report.fail(&ConfigProblem::new(format!("No config file at {}", path.display()), "run `rag` in a terminal to set it up"));
+    }
+    let problems = config.validate();
+    if problems.is_empty() {
+        report.pass("Config values");
+    }
+    problems.iter().for_each(|e| report.fail(e));
+
+    // Nothing further can work without a valid url, which Bedrock makes up itself.
+    let bedrock = config.provider == Some(ProviderKind::Bedrock);
+    if reqwest::Url::parse(&config.base_url).is_err() && !bedrock {
+        std::process::exit(1);
+    }
+
+    // Any answer, even an error, means the server is there.
+    let listed = match bedrock {
+        true => Some(Ok(Value::Null)),
+        false => timed(context.client.models().list_byot::<Value>()).await,
+    };
+    let unreachable = match listed {
+        None => Some(format!("no answer within {}s", CHECK_TIMEOUT.as_secs())),
+        Some(Err(OpenAIError::Reqwest(e))) => Some(e.to_string()),
+        Some(_) => None,
+    };
+    match unreachable {
+        Some(e) => {
+            report.fail(&ConfigProblem::new(
+                format!("Can't reach {}: {}", config.base_url, e),
+                "check the url, the network and `http_proxy`/`https_proxy` in the config",
+            ));
+            std::process::exit(1);
+        }
+        None if bedrock => {}
+        None => report.pass(&format!("Endpoint {} is reachable", config.base_url)),
+    }
+
+    // Through the proxy, timeouts and headers real requests use.
+    let http_client = crate::http_client(config)?;
+    let probed = match config.provider {
+        Some(ProviderKind::Azure) => {
+            let azure = Client::with_config(client::azure_config(config).with_deployment_id(&config.model)).with_http_client(http_client);
+            timed(setup::probe(&azure, &config.model)).await
+        }
+        Some(ProviderKind::Bedrock) => timed(probe_chat(&Bedrock::new(config, http_client), context)).await,
+        Some(ProviderKind::OpenAi) | None => timed(setup::probe(&context.client, &config.model)).await,
+    };
+    match probed {
+        Some(Ok(())) => report.pass(&format!("Model {} answers", config.model)),
+        Some(Err(e)) => report.fail(&ConfigProblem::new(format!("Model {} failed: {}", config.model, e), request_fix(&e))),
+        None => report.fail(&ConfigProblem::new(
+            format!("Model {} gave no answer within {}s", config.model, CHECK_TIMEOUT.as_secs()),
+            "try again later, or pick another model with `rag --sm <model>`",
+        )),
+    }
+
+    if report.failed > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::error::ApiError;
+
+    fn api_error(message: &str, code: Option<&str>) -> OpenAIError {
+        OpenAIError::ApiError(ApiError {
+            message: message.to_string(),
+            r#type: None,
+            param: None,
+            code: code.map(str::to_string),
+        })
+    }
+
+    #[test]
+    fn test_request_fix() {
+        assert!(request_fix(&api_error("Incorrect API key provided: sk-abc", Some("invalid_api_key"))).contains("--sa"));
+        assert!(request_fix(&api_error("The model `gpt-9` does not exist", Some("model_not_found"))).contains("rag models"));
+        assert!(request_fix(&api_error("You exceeded your current quota", None)).contains("billing"));
+        assert!(request_fix(&OpenAIError::StreamError("boom".to_string())).contains("see the error"));
+    }
+}
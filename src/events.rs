@@ -0,0 +1,617 @@
+use std::io::{stderr, stdout, Write};
+use std::sync::mpsc::{self, Sender};
+use std::time::Duration;
+use async_openai::types::FinishReason;
+use colored::Colorize;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc::UnboundedSender;
+use crate::style;
+
+/// How answers are written, chosen with `--output`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// The answer as it streams, without colors or emoji.
+    Plain,
+    /// A JSON record per answer, on a line of its own.
+    Json,
+    /// The answer with the reasoning quoted and tool calls in code blocks.
+    Markdown,
+}
+
+/// The tokens a single answer used.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+/// How fast a single answer streamed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TurnPerf {
+    pub model: String,
+    /// From sending the question to the first token of reasoning or content.
+    pub first_token: Duration,
+    /// From the first token to the last chunk.
+    pub streaming: Duration,
+    /// Completion tokens, as reported by the provider or counted as deltas.
+    pub tokens: u64,
+}
+
+impl TurnPerf {
+    pub fn tokens_per_sec(&self) -> f64 {
+        self.tokens as f64 / self.streaming.as_secs_f64().max(0.001)
+    }
+
+    /// `first token 0.42s, 3.1s at 48.2 tokens/s`
+    pub fn summary(&self) -> String {
+        format!(
+            "first token {:.2}s, {:.1}s at {:.1} tokens/s",
+            self.first_token.as_secs_f64(),
+            self.streaming.as_secs_f64(),
+            self.tokens_per_sec()
+        )
+    }
+}
+
+/// Everything the hooks have to show, independent of how it is shown.
+#[derive(Debug, Clone, PartialEq)]
+pub enum UiEvent {
+    AnswerStarted { model: String },
+    Reasoning(String),
+    /// Reasoning to show as no more than a progress line, renderers that can't do that treat it like `Reasoning`.
+    CollapsedReasoning(String),
+    ContentDelta(String),
+    /// The arguments streamed so far for the tool call at `index`.
+    ToolCallDelta { index: u32, name: String, arguments: String },
+    ToolStarted { name: String, arguments: String },
+    ThinkingBudget { used: u64, budget: u64, exceeded: bool },
+    /// The reasoning tokens so far against `thinking_budget`, while the reasoning streams without being shown.
+    ThinkingProgress { used: u64, budget: u64 },
+    /// Tokens used by the answer and in the session so far.
+    Usage { turn: TokenUsage, total_tokens: u64 },
+    Perf(TurnPerf),
+    AnswerFinished,
+    /// The answer is served from the response cache instead of the provider.
+    Cached,
+    /// The request failed for `from` and went to `to` instead.
+    FellBack { from: String, to: String, error: String },
+    /// A request failed transiently and is sent again after `delay`.
+    Retrying { attempt: u32, max_attempts: u32, delay: Duration, error: String },
+    /// The provider ended the answer before it was done, at the token limit or by its content filter.
+    Stopped(FinishReason),
+    /// The answer was cut short with Ctrl+C.
+    Cancelled,
+    Error(String),
+    /// What a command has to tell, a line or more.
+    Notice(String),
+    /// Something that went wrong without ending the turn, shown after `Warning: `.
+    Warning(String),
+}
+
+/// Consumes the events of a session, on a thread of its own.
+pub trait Renderer: Send {
+    fn render(&mut self, event: UiEvent) -> anyhow::Result<()>;
+}
+
+enum Message {
+    Event(UiEvent),
+    Flush(Sender<()>),
+}
+
+/// Sends events to the renderer thread, which lives as long as any clone of the sender.
+#[derive(Debug, Clone)]
+pub struct EventSender {
+    sender: Sender<Message>,
+}
+
+impl EventSender {
+    pub fn spawn(mut renderer: Box<dyn Renderer>) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            for message in receiver {
+                match message {
+                    Message::Event(event) => {
+                        if let Err(e) = renderer.render(event) {
+                            eprintln!("{}", format!("Warning: Failed to render: {}", e).yellow());
+                        }
+                    }
+                    Message::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+        Self { sender }
+    }
+
+    pub fn emit(&self, event: UiEvent) {
+        let _ = self.sender.send(Message::Event(event));
+    }
+
+    /// Waits until every event emitted so far is rendered, call it before writing to the terminal directly.
+    pub fn flush(&self) {
+        let (done, wait) = mpsc::channel();
+        if self.sender.send(Message::Flush(done)).is_ok() {
+            let _ = wait.recv();
+        }
+    }
+}
+
+/// Formats a token count like `950` or `1.2k`.
+pub fn format_tokens(tokens: u64) -> String {
+    if tokens < 1000 {
+        return tokens.to_string();
+    }
+    let thousands = format!("{:.1}", tokens as f64 / 1000.0);
+    format!("{}k", thousands.trim_end_matches(".0"))
+}
+
+const PREVIEW_WIDTH: usize = 72;
+const SPINNER: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+/// Streams the events to stdout, the default renderer.
+#[derive(Debug)]
+pub struct TerminalRenderer {
+    /// Index of the tool call whose arguments are currently previewed on the last line.
+    previewing: Option<u32>,
+    preview_key_pattern: Regex,
+    /// What the current answer's line starts with, redrawn along with the collapsed reasoning.
+    answer_prefix: String,
+    /// Reasoning deltas counted on the last line while it is collapsed, roughly one token each.
+    collapsed: Option<u64>,
+    /// The thinking budget the collapsed count is shown against, once the budget reported progress.
+    thinking_budget: Option<u64>,
+}
+
+impl TerminalRenderer {
+    pub fn new() -> Self {
+        Self {
+            previewing: None,
+            preview_key_pattern: Regex::new(r#""[^"]*"\s*:\s*"#).unwrap(),
+            answer_prefix: String::new(),
+            collapsed: None,
+            thinking_budget: None,
+        }
+    }
+
+    /// Redraws the answer's line as a spinner with the `tokens` of reasoning so far, against the budget if there is one.
+    fn render_collapsed(&mut self, out: &mut impl Write, tokens: u64) -> anyhow::Result<()> {
+        self.collapsed = Some(tokens);
+        let used = match self.thinking_budget {
+            Some(budget) => format!("{}/{}", format_tokens(tokens), format_tokens(budget)),
+            None => format_tokens(tokens),
+        };
+        let status = format!("{} thinking… {} tokens", SPINNER[tokens as usize % SPINNER.len()], used);
+        write!(out, "\r\x1b[2K{}{}", self.answer_prefix, status.truecolor(128, 138, 135))?;
+        Ok(())
+    }
+
+    /// Redraws the gray `name: arguments…` line for a tool call whose arguments are still streaming.
+    fn render_preview(&mut self, out: &mut impl Write, index: u32, name: &str, arguments: &str) -> anyhow::Result<()> {
+        if self.previewing.is_some_and(|e| e != index) {
+            writeln!(out)?;
+        }
+        self.previewing = Some(index);
+
+        // Strip the JSON keys and punctuation so only the argument values are shown.
+        let values = self.preview_key_pattern.replace_all(arguments, "");
+        let values = values
+            .replace("\\n", " ")
+            .replace(['{', '}', '"', '\n'], "");
+        let mut preview = values.trim().chars().take(PREVIEW_WIDTH).collect::<String>();
+        preview.push('…');
+
+        write!(out, "\r\x1b[2K{}", format!("{}: {}", name, preview).truecolor(128, 138, 135))?;
+        Ok(())
+    }
+}
+
+impl Renderer for TerminalRenderer {
+    fn render(&mut self, event: UiEvent) -> anyhow::Result<()> {
+        let mut out = stdout().lock();
+
+        // Both count the same deltas, with a collapsed reasoning the progress redraws the line with the same count.
+        let thinking = match event {
+            UiEvent::CollapsedReasoning(_) => Some(self.collapsed.unwrap_or_default() + 1),
+            UiEvent::ThinkingProgress { used, budget } => {
+                self.thinking_budget = Some(budget);
+                Some(used)
+            }
+            _ => None,
+        };
+        if let Some(tokens) = thinking {
+            self.render_collapsed(&mut out, tokens)?;
+            out.flush()?;
+            return Ok(());
+        }
+        // Whatever comes after the reasoning takes its line.
+        self.thinking_budget = None;
+        if self.collapsed.take().is_some() {
+            write!(out, "\r\x1b[2K{}", self.answer_prefix)?;
+        }
+
+        if let UiEvent::ToolCallDelta { index, ref name, ref arguments } = event {
+            self.render_preview(&mut out, index, name, arguments)?;
+            out.flush()?;
+            return Ok(());
+        }
+        if self.previewing.take().is_some() {
+            writeln!(out)?;
+        }
+
+        match event {
+            UiEvent::AnswerStarted { model } => {
+                self.answer_prefix = format!("{}{}: ", style::emoji("🤖"), model);
+                write!(out, "{}", self.answer_prefix)?
+            }
+            UiEvent::Reasoning(content) => write!(out, "{}", content.truecolor(128, 138, 135))?,
+            UiEvent::CollapsedReasoning(_) | UiEvent::ThinkingProgress { .. } => {}
+            UiEvent::ContentDelta(content) => write!(out, "{}", content)?,
+            UiEvent::ToolCallDelta { .. } => {}
+            UiEvent::ToolStarted { name, arguments } => {
+                writeln!(out, "{}", format!("Info: call tools {}, with arguments {}", name, arguments).truecolor(128, 138, 135))?
+            }
+            UiEvent::ThinkingBudget { used, budget, exceeded } => {
+                let status = format!("thinking {}/{} tokens", format_tokens(used), format_tokens(budget));
+                if exceeded {
+                    writeln!(out, "\n{}", format!("{}, budget exceeded, stopping", status).yellow())?
+                } else {
+                    writeln!(out, "\n{}", status.truecolor(128, 138, 135))?
+                }
+            }
+            UiEvent::Usage { total_tokens, .. } => write!(out, "{}", format!("\ntoken usage: {}", total_tokens).truecolor(128, 138, 135))?,
+            UiEvent::Perf(perf) => write!(out, "{}", format!("\n{}", perf.summary()).truecolor(128, 138, 135))?,
+            UiEvent::AnswerFinished => writeln!(out)?,
+            UiEvent::Cached => write!(out, "{}", "(cached) ".truecolor(128, 138, 135))?,
+            // The answer's line is started over for the model that answers it.
+            UiEvent::FellBack { from, to, error } => {
+                writeln!(out, "\r\x1b[2K{}", format!("Warning: {} failed, {}, asking {}", from, error, to).yellow())?;
+                self.answer_prefix = format!("{}{} (for {}): ", style::emoji("🤖"), to, from);
+                write!(out, "{}", self.answer_prefix)?
+            }
+            UiEvent::Retrying { attempt, max_attempts, delay, error } => writeln!(
+                out,
+                "{}",
+                format!("Warning: {}, retrying in {:.1}s ({}/{})", error, delay.as_secs_f32(), attempt + 1, max_attempts).yellow()
+            )?,
+            UiEvent::Stopped(reason) => writeln!(out, "\n{}", stopped(reason).yellow())?,
+            UiEvent::Cancelled => writeln!(out, "\n{}", "Cancelled, the answer is incomplete".yellow())?,
+            UiEvent::Error(e) => writeln!(out, "\n{}", format!("Error: {}", e).red())?,
+            UiEvent::Notice(text) => writeln!(out, "{}", text.truecolor(128, 138, 135))?,
+            UiEvent::Warning(text) => writeln!(out, "{}", format!("Warning: {}", text).yellow())?,
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// A renderer for the events nobody looks at.
+#[derive(Debug)]
+pub struct Discard;
+
+impl Renderer for Discard {
+    fn render(&mut self, _event: UiEvent) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Hands the events to an async task, for the interfaces that draw or send them themselves.
+#[derive(Debug)]
+pub struct ChannelRenderer {
+    sender: UnboundedSender<UiEvent>,
+}
+
+impl ChannelRenderer {
+    pub fn new(sender: UnboundedSender<UiEvent>) -> Self {
+        Self { sender }
+    }
+}
+
+impl Renderer for ChannelRenderer {
+    fn render(&mut self, event: UiEvent) -> anyhow::Result<()> {
+        self.sender.send(event).map_err(|_| anyhow::anyhow!("Nothing takes the events anymore"))
+    }
+}
+
+/// Writes only the answer to stdout and everything else to stderr, so the output of `rag -p` can be piped on.
+#[derive(Debug, Default)]
+pub struct OneShotRenderer {
+    /// Whether the last thing written to stderr was reasoning, which needs a line break before anything else.
+    reasoning: bool,
+}
+
+impl Renderer for OneShotRenderer {
+    fn render(&mut self, event: UiEvent) -> anyhow::Result<()> {
+        let (mut out, mut err) = (stdout().lock(), stderr().lock());
+        let reasoning = matches!(event, UiEvent::Reasoning(_));
+        if std::mem::replace(&mut self.reasoning, reasoning) && !reasoning {
+            writeln!(err)?;
+        }
+        match event {
+            UiEvent::ContentDelta(content) => write!(out, "{}", content)?,
+            UiEvent::AnswerFinished => writeln!(out)?,
+            UiEvent::Reasoning(content) => write!(err, "{}", content.truecolor(128, 138, 135))?,
+            UiEvent::ToolStarted { name, arguments } => {
+                writeln!(err, "{}", format!("Info: call tools {}, with arguments {}", name, arguments).truecolor(128, 138, 135))?
+            }
+            event => render_status(&mut err, event)?,
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes the notices, warnings and errors among the events to `err`, for the renderers that keep stdout to the answer.
+fn render_status(err: &mut impl Write, event: UiEvent) -> anyhow::Result<()> {
+    match event {
+        UiEvent::ThinkingBudget { used, budget, exceeded: true } => writeln!(
+            err,
+            "\n{}",
+            format!("thinking {}/{} tokens, budget exceeded, stopping", format_tokens(used), format_tokens(budget)).yellow()
+        )?,
+        UiEvent::FellBack { from, to, error } => writeln!(err, "{}", format!("Warning: {} failed, {}, asking {}", from, error, to).yellow())?,
+        UiEvent::Retrying { attempt, max_attempts, delay, error } => writeln!(
+            err,
+            "{}",
+            format!("Warning: {}, retrying in {:.1}s ({}/{})", error, delay.as_secs_f32(), attempt + 1, max_attempts).yellow()
+        )?,
+        UiEvent::Stopped(reason) => writeln!(err, "\n{}", stopped(reason).yellow())?,
+        UiEvent::Cancelled => writeln!(err, "\n{}", "Cancelled, the answer is incomplete".yellow())?,
+        UiEvent::Error(e) => writeln!(err, "\n{}", format!("Error: {}", e).red())?,
+        UiEvent::Notice(text) => writeln!(err, "{}", text.truecolor(128, 138, 135))?,
+        UiEvent::Warning(text) => writeln!(err, "{}", format!("Warning: {}", text).yellow())?,
+        _ => {}
+    }
+    Ok(())
+}
+
+/// What the user is told about an answer the provider ended for `reason`.
+pub fn stopped(reason: FinishReason) -> String {
+    match reason {
+        FinishReason::Length => "Warning: The answer hit the token limit and is cut off, `@continue` picks it up where it stopped".to_string(),
+        FinishReason::ContentFilter => "Warning: The provider's content filter stopped the answer, the rest of it was withheld".to_string(),
+        reason => format!("Warning: The answer stopped early ({:?})", reason),
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+struct ToolCallRecord {
+    name: String,
+    /// The arguments as JSON, or as the string the model sent if that isn't valid JSON.
+    arguments: Value,
+}
+
+/// Everything about one answer, what `--output json` writes.
+#[derive(Debug, Default, PartialEq, Serialize)]
+struct AnswerRecord {
+    model: String,
+    content: String,
+    reasoning: String,
+    tool_calls: Vec<ToolCallRecord>,
+    usage: Option<TokenUsage>,
+    error: Option<String>,
+    cancelled: bool,
+    cached: bool,
+}
+
+/// Collects each answer and writes it as a single line of JSON once it is finished.
+#[derive(Debug, Default)]
+pub struct JsonRenderer {
+    answer: AnswerRecord,
+}
+
+impl JsonRenderer {
+    /// Takes in `event`, returns the finished answer.
+    fn collect(&mut self, event: UiEvent) -> Option<AnswerRecord> {
+        match event {
+            UiEvent::AnswerStarted { model } => self.answer = AnswerRecord { model, ..Default::default() },
+            UiEvent::FellBack { to, .. } => self.answer.model = to,
+            UiEvent::Reasoning(content) | UiEvent::CollapsedReasoning(content) => self.answer.reasoning.push_str(&content),
+            UiEvent::ContentDelta(content) => self.answer.content.push_str(&content),
+            UiEvent::ToolStarted { name, arguments } => self.answer.tool_calls.push(ToolCallRecord {
+                name,
+                arguments: serde_json::from_str(&arguments).unwrap_or(Value::String(arguments)),
+            }),
+            UiEvent::Usage { turn, .. } => self.answer.usage = Some(turn),
+            UiEvent::Cancelled => self.answer.cancelled = true,
+            UiEvent::Cached => self.answer.cached = true,
+            UiEvent::Error(e) => self.answer.error = Some(e),
+            UiEvent::AnswerFinished => return Some(std::mem::take(&mut self.answer)),
+            _ => {}
+        }
+        None
+    }
+}
+
+impl Renderer for JsonRenderer {
+    fn render(&mut self, event: UiEvent) -> anyhow::Result<()> {
+        render_status(&mut stderr().lock(), event.clone())?;
+        if let Some(answer) = self.collect(event) {
+            let mut out = stdout().lock();
+            writeln!(out, "{}", serde_json::to_string(&answer)?)?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the answer as markdown: the reasoning as a quote and every tool call as a JSON code block.
+#[derive(Debug)]
+pub struct MarkdownRenderer {
+    /// Whether the reasoning quote is open and where in its line the last delta ended.
+    quoting: bool,
+    line_start: bool,
+}
+
+impl MarkdownRenderer {
+    pub fn new() -> Self {
+        Self { quoting: false, line_start: true }
+    }
+
+    fn markdown(&mut self, event: UiEvent) -> String {
+        let mut text = String::new();
+        if self.quoting && !matches!(event, UiEvent::Reasoning(_) | UiEvent::CollapsedReasoning(_)) {
+            self.quoting = false;
+            text.push_str("\n\n");
+        }
+        match event {
+            UiEvent::Reasoning(content) | UiEvent::CollapsedReasoning(content) => {
+                self.quoting = true;
+                for (i, line) in content.split('\n').enumerate() {
+                    if i > 0 {
+                        text.push('\n');
+                        self.line_start = true;
+                    }
+                    if self.line_start && !line.is_empty() {
+                        text.push_str("> ");
+                        self.line_start = false;
+                    }
+                    text.push_str(line);
+                }
+            }
+            UiEvent::ContentDelta(content) => text.push_str(&content),
+            UiEvent::ToolStarted { name, arguments } => {
+                let arguments = serde_json::from_str::<Value>(&arguments)
+                    .and_then(|e| serde_json::to_string_pretty(&e))
+                    .unwrap_or(arguments);
+                text.push_str(&format!("\n\n**Tool call** `{}`\n\n```json\n{}\n```\n\n", name, arguments));
+            }
+            UiEvent::AnswerFinished => {
+                self.line_start = true;
+                text.push('\n');
+            }
+            _ => {}
+        }
+        text
+    }
+}
+
+impl Renderer for MarkdownRenderer {
+    fn render(&mut self, event: UiEvent) -> anyhow::Result<()> {
+        render_status(&mut stderr().lock(), event.clone())?;
+        let mut out = stdout().lock();
+        write!(out, "{}", self.markdown(event))?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// The renderer for `format`, the terminal one if none is given, or the one-shot one when answering a single prompt.
+pub fn renderer(format: Option<OutputFormat>, one_shot: bool) -> Box<dyn Renderer> {
+    match format {
+        None | Some(OutputFormat::Plain) if one_shot => Box::new(OneShotRenderer::default()),
+        None | Some(OutputFormat::Plain) => Box::new(TerminalRenderer::new()),
+        Some(OutputFormat::Json) => Box::new(JsonRenderer::default()),
+        Some(OutputFormat::Markdown) => Box::new(MarkdownRenderer::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    struct Recorder(Arc<Mutex<Vec<UiEvent>>>);
+
+    impl Renderer for Recorder {
+        fn render(&mut self, event: UiEvent) -> anyhow::Result<()> {
+            self.0.lock().unwrap().push(event);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_events_arrive_in_order() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let sender = EventSender::spawn(Box::new(Recorder(events.clone())));
+
+        sender.emit(UiEvent::Reasoning("hmm".to_string()));
+        sender.clone().emit(UiEvent::ContentDelta("hi".to_string()));
+        sender.emit(UiEvent::Usage { turn: TokenUsage::default(), total_tokens: 3 });
+        sender.flush();
+
+        assert_eq!(*events.lock().unwrap(), [
+            UiEvent::Reasoning("hmm".to_string()),
+            UiEvent::ContentDelta("hi".to_string()),
+            UiEvent::Usage { turn: TokenUsage::default(), total_tokens: 3 },
+        ]);
+        assert_eq!(format_tokens(1200), "1.2k");
+    }
+
+    #[test]
+    fn test_collapsed_thinking() {
+        let mut renderer = TerminalRenderer::new();
+        let mut out = Vec::new();
+        renderer.render_collapsed(&mut out, 950).unwrap();
+        renderer.thinking_budget = Some(2000);
+        renderer.render_collapsed(&mut out, 1200).unwrap();
+
+        let out = Regex::new(r"\x1b\[[0-9;]*m").unwrap().replace_all(std::str::from_utf8(&out).unwrap(), "").into_owned();
+        assert_eq!(out, "\r\x1b[2K⠋ thinking… 950 tokens\r\x1b[2K⠋ thinking… 1.2k/2k tokens");
+    }
+
+    #[test]
+    fn test_tool_call_preview() {
+        let mut renderer = TerminalRenderer::new();
+        let mut out = Vec::new();
+        renderer.render_preview(&mut out, 0, "write_file", r#"{"path": "a.rs", "con"#).unwrap();
+        renderer.render_preview(&mut out, 0, "write_file", r#"{"path": "a.rs", "content": "fn main"#).unwrap();
+        renderer.render_preview(&mut out, 1, "ls", &format!(r#"{{"path": "{}"#, "x".repeat(100))).unwrap();
+
+        // The colors are left out, whether there are any depends on the terminal.
+        let out = Regex::new(r"\x1b\[[0-9;]*m").unwrap().replace_all(std::str::from_utf8(&out).unwrap(), "").into_owned();
+        let line = |text: &str| format!("\r\x1b[2K{}", text);
+        assert_eq!(out, [
+            line("write_file: a.rs, con…"),
+            line("write_file: a.rs, fn main…"),
+            "\n".to_string(),
+            line(&format!("ls: {}…", "x".repeat(PREVIEW_WIDTH))),
+        ].concat());
+    }
+
+    #[test]
+    fn test_json_record() {
+        let mut renderer = JsonRenderer::default();
+        let usage = TokenUsage { prompt_tokens: 5, completion_tokens: 2, total_tokens: 7 };
+        for event in [
+            UiEvent::AnswerStarted { model: "m".to_string() },
+            UiEvent::Cached,
+            UiEvent::Reasoning("hm".to_string()),
+            UiEvent::CollapsedReasoning("m".to_string()),
+            UiEvent::ToolStarted { name: "ls".to_string(), arguments: r#"{"path": "."}"#.to_string() },
+            UiEvent::ContentDelta("hi".to_string()),
+            UiEvent::Usage { turn: usage, total_tokens: 70 },
+        ] {
+            assert_eq!(renderer.collect(event), None);
+        }
+        let record = serde_json::to_value(renderer.collect(UiEvent::AnswerFinished).unwrap()).unwrap();
+        assert_eq!(record, serde_json::json!({
+            "model": "m",
+            "content": "hi",
+            "reasoning": "hmm",
+            "tool_calls": [{ "name": "ls", "arguments": { "path": "." } }],
+            "usage": { "prompt_tokens": 5, "completion_tokens": 2, "total_tokens": 7 },
+            "error": null,
+            "cancelled": false,
+            "cached": true,
+        }));
+    }
+
+    #[test]
+    fn test_markdown_quotes_reasoning() {
+        let mut renderer = MarkdownRenderer::new();
+        let text = [
+            UiEvent::Reasoning("first ".to_string()),
+            UiEvent::Reasoning("line\nsecond".to_string()),
+            UiEvent::ContentDelta("answer".to_string()),
+            UiEvent::AnswerFinished,
+        ]
+        .into_iter()
+        .map(|e| renderer.markdown(e))
+        .collect::<String>();
+        assert_eq!(text, "> first line\n> second\n\nanswer\n");
+    }
+}
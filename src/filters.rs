@@ -0,0 +1,137 @@
+use std::fmt::Debug;
+use crate::config::ContentFilter;
+
+/// Transforms streamed content deltas, filters may keep state across the deltas of one answer.
+pub trait ChunkFilter: Debug + Send {
+    fn apply(&mut self, delta: &str) -> String;
+
+    /// Called before a new answer starts streaming.
+    fn reset(&mut self) {}
+}
+
+#[derive(Debug)]
+struct StripEmoji;
+
+impl ChunkFilter for StripEmoji {
+    fn apply(&mut self, delta: &str) -> String {
+        delta
+            .chars()
+            .filter(|e| !matches!(*e as u32,
+                0x1F000..=0x1FAFF | 0x2600..=0x27BF | 0x2B00..=0x2BFF | 0xFE00..=0xFE0F | 0x200D | 0xE0020..=0xE007F))
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct NormalizePunctuation;
+
+impl ChunkFilter for NormalizePunctuation {
+    fn apply(&mut self, delta: &str) -> String {
+        delta
+            .chars()
+            .map(|e| match e {
+                // Full-width forms of the printable ASCII range.
+                '\u{FF01}'..='\u{FF5E}' => char::from_u32(e as u32 - 0xFEE0).unwrap_or(e),
+                '\u{3000}' => ' ',
+                '。' => '.',
+                '、' => ',',
+                '「' | '」' | '『' | '』' | '“' | '”' => '"',
+                '‘' | '’' => '\'',
+                '【' => '[',
+                '】' => ']',
+                other => other,
+            })
+            .collect()
+    }
+}
+
+/// Allows at most one blank line in a row, even when the newlines arrive in separate deltas.
+#[derive(Debug, Default)]
+struct CollapseBlankLines {
+    newlines: usize,
+}
+
+impl ChunkFilter for CollapseBlankLines {
+    fn apply(&mut self, delta: &str) -> String {
+        let mut output = String::with_capacity(delta.len());
+        for e in delta.chars() {
+            match e {
+                '\n' => {
+                    self.newlines += 1;
+                    if self.newlines <= 2 {
+                        output.push(e);
+                    }
+                }
+                '\r' => {}
+                e if e.is_whitespace() && self.newlines > 0 => {}
+                e => {
+                    self.newlines = 0;
+                    output.push(e);
+                }
+            }
+        }
+        output
+    }
+
+    fn reset(&mut self) {
+        self.newlines = 0;
+    }
+}
+
+/// The configured filters, applied in order to every content delta before it is rendered or stored.
+#[derive(Debug, Default)]
+pub(crate) struct FilterChain {
+    filters: Vec<Box<dyn ChunkFilter>>,
+}
+
+impl FilterChain {
+    pub fn new(filters: &[ContentFilter]) -> Self {
+        Self {
+            filters: filters
+                .iter()
+                .map(|e| -> Box<dyn ChunkFilter> {
+                    match e {
+                        ContentFilter::StripEmoji => Box::new(StripEmoji),
+                        ContentFilter::NormalizePunctuation => Box::new(NormalizePunctuation),
+                        ContentFilter::CollapseBlankLines => Box::new(CollapseBlankLines::default()),
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    /// Appends a filter that runs after the configured ones.
+    pub fn push(&mut self, filter: Box<dyn ChunkFilter>) {
+        self.filters.push(filter);
+    }
+
+    pub fn apply(&mut self, delta: &str) -> String {
+        self.filters
+            .iter_mut()
+            .fold(delta.to_string(), |delta, filter| filter.apply(&delta))
+    }
+
+    pub fn reset(&mut self) {
+        self.filters.iter_mut().for_each(|e| e.reset());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_chain() {
+        let mut chain = FilterChain::new(&[
+            ContentFilter::StripEmoji,
+            ContentFilter::NormalizePunctuation,
+            ContentFilter::CollapseBlankLines,
+        ]);
+
+        let output = ["好的！🚀\n", "\n", "  \n\n", "（完成）"]
+            .iter()
+            .map(|e| chain.apply(e))
+            .collect::<String>();
+        assert_eq!(output, "好的!\n\n(完成)");
+    }
+}
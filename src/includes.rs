@@ -0,0 +1,472 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use glob::{MatchOptions, Pattern};
+use crate::manager::estimate_tokens;
+
+/// Ignore file read next to `.gitignore` files, for what the model shouldn't see but git should.
+pub const IGNORE_FILE: &str = ".ragignore";
+/// Tokens a single `@file` may add to the prompt unless the config sets `file_token_budget`.
+pub const DEFAULT_TOKEN_BUDGET: u64 = 16_000;
+/// Files listed for a directory or glob at most.
+const MAX_FILES: usize = 500;
+/// Lines per chunk when a file is cut down to the parts relevant to the question.
+const CHUNK_LINES: usize = 40;
+/// Share of the budget the head gets when a file is cut down to its head and tail, the rest goes to the tail.
+const HEAD_SHARE: f64 = 2.0 / 3.0;
+
+/// Images larger than this are refused, providers reject them anyway.
+const MAX_IMAGE_BYTES: usize = 20 * 1024 * 1024;
+
+/// An image the next question carries, for models that can see.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    /// Where it came from, shown instead of the image in the transcript.
+    pub source: String,
+    /// An http(s) URL, or the image inlined as a `data:` URL.
+    pub url: String,
+}
+
+impl Image {
+    /// Loads `source`, a URL is passed on for the provider to fetch and a file is inlined.
+    pub fn load(source: &str) -> anyhow::Result<Self> {
+        if source.starts_with("http://") || source.starts_with("https://") {
+            return Ok(Self { source: source.to_string(), url: source.to_string() });
+        }
+        Self::from_bytes(source, &fs::read(source)?)
+    }
+
+    /// Encodes raw RGBA pixels, what the clipboard holds, as a PNG.
+    pub fn from_rgba(source: &str, width: u32, height: u32, pixels: &[u8]) -> anyhow::Result<Self> {
+        let mut bytes = vec![];
+        let mut encoder = png::Encoder::new(&mut bytes, width, height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()?.write_image_data(pixels)?;
+        Self::from_bytes(source, &bytes)
+    }
+
+    pub fn from_bytes(source: &str, bytes: &[u8]) -> anyhow::Result<Self> {
+        let mime = image_mime(bytes).ok_or(anyhow::anyhow!("{} isn't a PNG, JPEG, GIF or WebP image", source))?;
+        if bytes.len() > MAX_IMAGE_BYTES {
+            anyhow::bail!("{} has {} bytes, images may have {} at most", source, bytes.len(), MAX_IMAGE_BYTES);
+        }
+        Ok(Self {
+            source: source.to_string(),
+            url: format!("data:{};base64,{}", mime, STANDARD.encode(bytes)),
+        })
+    }
+}
+
+/// The MIME type of the image formats vision models take, judged by the first bytes.
+fn image_mime(bytes: &[u8]) -> Option<&'static str> {
+    match bytes {
+        [0x89, b'P', b'N', b'G', ..] => Some("image/png"),
+        [0xff, 0xd8, 0xff, ..] => Some("image/jpeg"),
+        [b'G', b'I', b'F', b'8', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        _ => None,
+    }
+}
+
+/// A backtick fence one longer than any run in `text`, so `text` can't close it.
+pub fn fence(text: &str) -> String {
+    let longest = text.split(|e| e != '`').map(str::len).max().unwrap_or_default();
+    "`".repeat(longest.max(2) + 1)
+}
+
+/// What a file is, judged by its first bytes, `None` for text.
+fn binary_kind(bytes: &[u8]) -> Option<&'static str> {
+    const MAGIC: [(&[u8], &str); 8] = [
+        (b"\x89PNG", "a PNG image"),
+        (b"\xff\xd8\xff", "a JPEG image"),
+        (b"GIF8", "a GIF image"),
+        (b"%PDF", "a PDF document"),
+        (b"PK\x03\x04", "a zip archive"),
+        (b"\x7fELF", "an executable"),
+        (b"MZ", "an executable"),
+        (b"\x1f\x8b", "a gzip archive"),
+    ];
+    if let Some((_, kind)) = MAGIC.iter().find(|(magic, _)| bytes.starts_with(magic)) {
+        return Some(kind);
+    }
+    let head = &bytes[..bytes.len().min(8192)];
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        // The head may end in the middle of a character.
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&head[..e.valid_up_to()]).unwrap_or_default(),
+        Err(_) => return Some("a binary file"),
+    };
+    text.contains('\0').then_some("a binary file")
+}
+
+/// The contents of a text file, a binary one is refused with what it seems to be.
+fn read_text(path: &Path) -> anyhow::Result<String> {
+    let bytes = fs::read(path)?;
+    if let Some(kind) = binary_kind(&bytes) {
+        anyhow::bail!("{} is {}, only text files can be included", path.display(), kind);
+    }
+    Ok(String::from_utf8(bytes)?)
+}
+
+/// Splits `path:10-80`, `path:10-` or `path:10` into the path and the 1-based, inclusive line range.
+fn line_range(target: &str) -> Option<(&str, usize, Option<usize>)> {
+    let (path, range) = target.rsplit_once(':')?;
+    let (from, to) = match range.split_once('-') {
+        Some((from, "")) => (from.parse().ok()?, None),
+        Some((from, to)) => (from.parse().ok()?, Some(to.parse().ok()?)),
+        None => (range.parse().ok()?, range.parse().ok()),
+    };
+    (from > 0 && to.is_none_or(|to| to >= from)).then_some((path, from, to))
+}
+
+/// The lines `from..=to` of `path`, with a note of the lines shown when the range runs past the end.
+fn read_lines(path: &Path, from: usize, to: Option<usize>) -> anyhow::Result<String> {
+    let content = read_text(path)?;
+    let count = content.lines().count();
+    if from > count {
+        anyhow::bail!("{} has only {} lines", path.display(), count);
+    }
+    let to = to.unwrap_or(count).min(count);
+    Ok(content.lines().skip(from - 1).take(to + 1 - from).collect::<Vec<_>>().join("\n"))
+}
+
+fn is_glob(target: &str) -> bool {
+    target.contains(['*', '?', '['])
+}
+
+/// The files under `dir` that neither `.gitignore` nor [`IGNORE_FILE`] exclude, hidden ones left out.
+fn walk(dir: &Path) -> impl Iterator<Item = PathBuf> {
+    ignore::WalkBuilder::new(dir)
+        .require_git(false)
+        .add_custom_ignore_filename(IGNORE_FILE)
+        .build()
+        .filter_map(Result::ok)
+        .filter(|e| e.file_type().is_some_and(|e| e.is_file()))
+        .map(|e| e.path().strip_prefix("./").map(Path::to_path_buf).unwrap_or_else(|_| e.path().to_path_buf()))
+}
+
+/// The files matching `pattern`, searched from the directory its literal part names.
+fn glob_files(pattern: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let matcher = Pattern::new(pattern)?;
+    let options = MatchOptions { require_literal_separator: true, ..MatchOptions::new() };
+    let base = Path::new(pattern)
+        .components()
+        .take_while(|e| !is_glob(&e.as_os_str().to_string_lossy()))
+        .collect::<PathBuf>();
+    let base = if base.as_os_str().is_empty() { PathBuf::from(".") } else { base };
+    Ok(walk(&base).filter(|e| matcher.matches_path_with(e, options)).collect())
+}
+
+/// What `@file` inserts for `target`: a file, every file under a directory or every file matching a glob.
+/// A file over `budget` tokens is cut down to the parts that matter most for `question`, the rest of the prompt.
+pub fn include(target: &str, budget: u64, question: &str) -> anyhow::Result<String> {
+    let path = Path::new(target);
+    let mut files = if is_glob(target) {
+        glob_files(target)?
+    } else if path.is_dir() {
+        walk(path).collect()
+    } else {
+        // A file that really is named `name:10` wins over the line range.
+        let content = match line_range(target) {
+            Some((path, from, to)) if !Path::new(target).exists() => read_lines(Path::new(path), from, to)?,
+            _ => read_text(path)?,
+        };
+        let tokens = estimate_tokens(&content);
+        if tokens <= budget {
+            return Ok(format!("{}: {}", target, content));
+        }
+        return Ok(format!(
+            "{}: {}\n[{} has ~{} tokens and was cut down to ~{}, the lines left out are marked]",
+            target,
+            truncate(&content, budget, question),
+            target,
+            tokens,
+            budget
+        ));
+    };
+    if files.is_empty() {
+        anyhow::bail!("no files match");
+    }
+    files.sort();
+    Ok(render_files(target, &files, budget))
+}
+
+/// Lowercase words of `text` worth searching for.
+fn terms(text: &str) -> Vec<String> {
+    const STOP_WORDS: [&str; 16] = [
+        "the", "and", "for", "with", "this", "that", "what", "how", "why", "does", "are", "from", "file", "code", "can", "you",
+    ];
+    let mut terms = text
+        .split(|e: char| !e.is_alphanumeric() && e != '_')
+        .map(str::to_lowercase)
+        .filter(|e| e.chars().count() >= 3 && !STOP_WORDS.contains(&e.as_str()))
+        .collect::<Vec<_>>();
+    terms.sort();
+    terms.dedup();
+    terms
+}
+
+/// Marks the lines `from..to` (0-based) as left out.
+fn gap(from: usize, to: usize) -> String {
+    format!("[... lines {}-{} left out ...]", from + 1, to)
+}
+
+/// Cuts `content` down to about `budget` tokens: the chunks that mention the most terms of `question`,
+/// or its head and tail when nothing in it matches.
+fn truncate(content: &str, budget: u64, question: &str) -> String {
+    let lines = content.lines().collect::<Vec<_>>();
+    let terms = terms(question);
+    let chunks = lines.chunks(CHUNK_LINES).map(|e| e.join("\n")).collect::<Vec<_>>();
+    let mut scored = chunks
+        .iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let chunk = chunk.to_lowercase();
+            (terms.iter().map(|e| chunk.matches(e.as_str()).count()).sum::<usize>(), index)
+        })
+        .filter(|(score, _)| *score > 0)
+        .collect::<Vec<_>>();
+    if scored.is_empty() {
+        return head_tail(&lines, budget);
+    }
+
+    // The best chunks that fit, shown in file order.
+    scored.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    let mut tokens = 0;
+    let mut picked = vec![];
+    for (_, index) in scored {
+        let cost = estimate_tokens(&chunks[index]);
+        if tokens + cost <= budget {
+            tokens += cost;
+            picked.push(index);
+        }
+    }
+    if picked.is_empty() {
+        return head_tail(&lines, budget);
+    }
+    picked.sort();
+
+    let mut parts = vec![];
+    let mut next = 0;
+    for index in picked {
+        if index > next {
+            parts.push(gap(next * CHUNK_LINES, index * CHUNK_LINES));
+        }
+        parts.push(chunks[index].clone());
+        next = index + 1;
+    }
+    if next < chunks.len() {
+        parts.push(gap(next * CHUNK_LINES, lines.len()));
+    }
+    parts.join("\n")
+}
+
+/// The first lines of `lines` within two thirds of `budget` and the last ones within the rest.
+fn head_tail(lines: &[&str], budget: u64) -> String {
+    let take = |lines: &mut dyn Iterator<Item = &&str>, budget: u64| {
+        let mut tokens = 0;
+        lines
+            .take_while(|e| {
+                tokens += estimate_tokens(e) + 1;
+                tokens <= budget
+            })
+            .count()
+    };
+    let head_budget = (budget as f64 * HEAD_SHARE) as u64;
+    let head = take(&mut lines.iter(), head_budget);
+    let tail = take(&mut lines[head..].iter().rev(), budget - head_budget).min(lines.len() - head);
+
+    if head == 0 && tail == 0 {
+        // A single line longer than the budget, minified code most likely.
+        let text = lines.join("\n");
+        let end = text.char_indices().nth(budget as usize * 4).map_or(text.len(), |(end, _)| end);
+        return format!("{}\n[... the rest is left out ...]", &text[..end]);
+    }
+    let mut parts = lines[..head].to_vec();
+    let gap = gap(head, lines.len() - tail);
+    parts.push(&gap);
+    parts.extend_from_slice(&lines[lines.len() - tail..]);
+    parts.join("\n")
+}
+
+fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..1024 => format!("{} B", bytes),
+        1024..1_048_576 => format!("{:.1} KB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MB", bytes as f64 / 1_048_576.0),
+    }
+}
+
+/// An indented tree of the files under `dir` with their sizes, what `.gitignore` and [`IGNORE_FILE`] exclude left out.
+pub fn tree(dir: &str) -> anyhow::Result<String> {
+    let root = Path::new(dir);
+    if !root.is_dir() {
+        anyhow::bail!("{} isn't a directory", dir);
+    }
+    let mut files = walk(root)
+        .map(|e| {
+            let size = e.metadata().map(|e| e.len()).unwrap_or_default();
+            (e.strip_prefix(root).map(Path::to_path_buf).unwrap_or(e), size)
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+
+    let total = files.iter().map(|(_, size)| size).sum();
+    let mut text = format!("{} ({} files, {})\n", dir, files.len(), format_size(total));
+    let mut open = Vec::<&std::ffi::OsStr>::new();
+    for (path, size) in files.iter().take(MAX_FILES) {
+        let dirs = path.parent().map(|e| e.iter().collect::<Vec<_>>()).unwrap_or_default();
+        let shared = open.iter().zip(&dirs).take_while(|(a, b)| a == b).count();
+        open.truncate(shared);
+        for dir in &dirs[shared..] {
+            text.push_str(&format!("{}{}/\n", "  ".repeat(open.len() + 1), dir.to_string_lossy()));
+            open.push(dir);
+        }
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        text.push_str(&format!("{}{}  {}\n", "  ".repeat(open.len() + 1), name, format_size(*size)));
+    }
+    if files.len() > MAX_FILES {
+        text.push_str(&format!("  ... {} more files\n", files.len() - MAX_FILES));
+    }
+    Ok(text)
+}
+
+/// A listing of `files` followed by as many of them as fit into `budget`, each in a fence.
+fn render_files(target: &str, files: &[PathBuf], budget: u64) -> String {
+    let listed = &files[..files.len().min(MAX_FILES)];
+    let mut text = format!("{} ({} files):\n", target, files.len());
+    listed.iter().for_each(|e| text.push_str(&format!("- {}\n", e.display())));
+    if files.len() > listed.len() {
+        text.push_str(&format!("- ... {} more\n", files.len() - listed.len()));
+    }
+
+    let (mut tokens, mut binary, mut over_cap) = (0, 0, 0);
+    for path in listed {
+        let Ok(content) = read_text(path) else {
+            binary += 1;
+            continue;
+        };
+        let cost = estimate_tokens(&content);
+        if tokens + cost > budget {
+            over_cap += 1;
+            continue;
+        }
+        tokens += cost;
+
+        let fence = fence(&content);
+        let lang = path.extension().unwrap_or_default().to_string_lossy();
+        text.push_str(&format!("\n{}:\n{}{}\n{}\n{}\n", path.display(), fence, lang, content.trim_end(), fence));
+    }
+
+    if binary > 0 {
+        text.push_str(&format!("\n[{} binary files left out]\n", binary));
+    }
+    if over_cap > 0 {
+        text.push_str(&format!("\n[{} files left out to stay under {} tokens, ask for them by name]\n", over_cap, budget));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_include_directory_and_glob() {
+        let dir = std::env::temp_dir().join(format!("rag-includes-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("src/nested")).unwrap();
+        fs::create_dir_all(dir.join("target")).unwrap();
+        fs::write(dir.join(".gitignore"), "target/\n").unwrap();
+        fs::write(dir.join(IGNORE_FILE), "*.secret\n").unwrap();
+        fs::write(dir.join("src/main.rs"), "fn main() {}").unwrap();
+        fs::write(dir.join("src/nested/lib.rs"), "```\npub fn f() {}").unwrap();
+        fs::write(dir.join("src/key.secret"), "hunter2").unwrap();
+        fs::write(dir.join("src/image.png"), [0x89, 0x50, 0x00, 0xff]).unwrap();
+        fs::write(dir.join("target/out.rs"), "build output").unwrap();
+
+        let root = dir.display().to_string();
+        let include = |target: &str| include(target, DEFAULT_TOKEN_BUDGET, "");
+        let text = include(&root).unwrap();
+        assert!(text.starts_with(&format!("{} (3 files):", root)));
+        assert!(text.contains("fn main() {}") && text.contains("````rs\n```\npub fn f() {}\n````"));
+        assert!(!text.contains("hunter2") && !text.contains("build output"));
+        assert!(text.contains("[1 binary files left out]"));
+
+        let text = include(&format!("{}/src/**/*.rs", root)).unwrap();
+        assert!(text.contains("(2 files):") && text.contains("nested/lib.rs"));
+        let text = include(&format!("{}/src/*.rs", root)).unwrap();
+        assert!(text.contains("(1 files):") && !text.contains("nested"));
+        assert!(include(&format!("{}/src/*.py", root)).is_err());
+    }
+
+    #[test]
+    fn test_tree() {
+        let dir = std::env::temp_dir().join(format!("rag-tree-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("src/tools")).unwrap();
+        fs::create_dir_all(dir.join("target")).unwrap();
+        fs::write(dir.join(".gitignore"), "target/\n").unwrap();
+        fs::write(dir.join("Cargo.toml"), "x".repeat(2048)).unwrap();
+        fs::write(dir.join("src/main.rs"), "fn main() {}").unwrap();
+        fs::write(dir.join("src/tools/fs.rs"), "").unwrap();
+        fs::write(dir.join("target/out"), "").unwrap();
+
+        let root = dir.display().to_string();
+        let expected = format!("{} (3 files, 2.0 KB)\n  Cargo.toml  2.0 KB\n  src/\n    main.rs  12 B\n    tools/\n      fs.rs  0 B\n", root);
+        assert_eq!(tree(&root).unwrap(), expected);
+        assert!(tree(&format!("{}/Cargo.toml", root)).is_err());
+    }
+
+    #[test]
+    fn test_line_ranges_and_binary_files() {
+        assert_eq!(line_range("src/main.rs:10-80"), Some(("src/main.rs", 10, Some(80))));
+        assert_eq!(line_range("src/main.rs:10-"), Some(("src/main.rs", 10, None)));
+        assert_eq!(line_range("src/main.rs:7"), Some(("src/main.rs", 7, Some(7))));
+        assert_eq!(line_range("src/main.rs:80-10"), None);
+        assert_eq!(line_range(r"C:\notes.txt"), None);
+
+        let path = std::env::temp_dir().join(format!("rag-lines-{}.txt", std::process::id()));
+        fs::write(&path, "a\nb\nc\nd\n").unwrap();
+        let target = |range: &str| format!("{}:{}", path.display(), range);
+        assert_eq!(include(&target("2-3"), DEFAULT_TOKEN_BUDGET, "").unwrap(), format!("{}: b\nc", target("2-3")));
+        assert_eq!(include(&target("3-99"), DEFAULT_TOKEN_BUDGET, "").unwrap(), format!("{}: c\nd", target("3-99")));
+        assert!(include(&target("9"), DEFAULT_TOKEN_BUDGET, "").is_err());
+
+        fs::write(&path, b"\x89PNG\r\n\x1a\n\0\0").unwrap();
+        let error = include(&path.display().to_string(), DEFAULT_TOKEN_BUDGET, "").unwrap_err();
+        assert!(error.to_string().ends_with("is a PNG image, only text files can be included"));
+        assert_eq!(binary_kind("日本".as_bytes()), None);
+        assert_eq!(binary_kind(&"日本".as_bytes()[..4]), None);
+        assert_eq!(binary_kind(b"a\0b"), Some("a binary file"));
+    }
+
+    #[test]
+    fn test_images() {
+        let image = Image::from_bytes("dot.png", b"\x89PNG\r\n\x1a\n").unwrap();
+        assert_eq!(image.url, "data:image/png;base64,iVBORw0KGgo=");
+        assert!(Image::from_bytes("notes.txt", b"hello").is_err());
+        assert_eq!(Image::load("https://example.com/a.png").unwrap().url, "https://example.com/a.png");
+        assert!(Image::from_rgba("clipboard", 1, 1, &[255, 0, 0, 255]).unwrap().url.starts_with("data:image/png;base64,iVBORw0KGgo"));
+        assert!(Image::from_rgba("clipboard", 2, 2, &[0; 4]).is_err());
+    }
+
+    #[test]
+    fn test_truncate() {
+        let content = (1..=400).map(|e| format!("line {}", e)).collect::<Vec<_>>().join("\n");
+        let cut = truncate(&content, 100, "why is the parser failing?");
+        assert!(cut.starts_with("line 1\n") && cut.ends_with("line 400"));
+        assert!(cut.contains("left out ...]\nline 3"));
+        assert!(estimate_tokens(&cut) <= 120);
+
+        let content = content.replace("line 205", "fn parse() { fail }");
+        let cut = truncate(&content, 100, "why does parse fail?");
+        assert_eq!(cut.lines().next(), Some("[... lines 1-200 left out ...]"));
+        assert!(cut.contains("fn parse() { fail }"));
+        assert!(cut.ends_with("[... lines 241-400 left out ...]"));
+
+        assert!(truncate(&"x".repeat(1000), 10, "").starts_with(&"x".repeat(40)));
+    }
+}
@@ -0,0 +1,90 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::Poll;
+use tokio::sync::Notify;
+use tokio::sync::futures::Notified;
+
+/// Appended to an answer cut short with Ctrl+C, so neither the model nor the transcript take it as complete.
+pub const CANCELLED_NOTE: &str = "\n\n[The user cancelled this answer, it is incomplete.]";
+
+#[derive(Debug, Default)]
+struct Inner {
+    streaming: AtomicBool,
+    notify: Notify,
+}
+
+/// Turns Ctrl+C into the cancellation of the running stream. A Ctrl+C while nothing streams exits,
+/// so a second one still gets out of a session stuck elsewhere.
+#[derive(Debug, Clone, Default)]
+pub struct Interrupts {
+    inner: Arc<Inner>,
+}
+
+impl Interrupts {
+    /// Takes over Ctrl+C for the rest of the process, needs a tokio runtime.
+    pub fn listen() -> Self {
+        let interrupts = Self::default();
+        let inner = interrupts.inner.clone();
+        tokio::spawn(async move {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                if inner.streaming.load(Ordering::SeqCst) {
+                    inner.notify.notify_waiters();
+                } else {
+                    std::process::exit(130);
+                }
+            }
+        });
+        interrupts
+    }
+
+    /// Resolves on the next Ctrl+C, which only cancels while the returned future is alive.
+    pub fn cancellation(&self) -> Cancellation<'_> {
+        // Registered before streaming is set, so no Ctrl+C in between goes unnoticed.
+        let notified = Box::pin(self.inner.notify.notified());
+        self.inner.streaming.store(true, Ordering::SeqCst);
+        Cancellation {
+            notified,
+            streaming: &self.inner.streaming,
+        }
+    }
+}
+
+pub struct Cancellation<'a> {
+    notified: Pin<Box<Notified<'a>>>,
+    streaming: &'a AtomicBool,
+}
+
+impl Future for Cancellation<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<()> {
+        self.notified.as_mut().poll(cx)
+    }
+}
+
+impl Drop for Cancellation<'_> {
+    fn drop(&mut self) {
+        self.streaming.store(false, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_cancellation() {
+        let interrupts = Interrupts::default();
+        let mut cancellation = interrupts.cancellation();
+        assert!(interrupts.inner.streaming.load(Ordering::SeqCst));
+
+        interrupts.inner.notify.notify_waiters();
+        tokio::time::timeout(Duration::from_secs(1), &mut cancellation).await.unwrap();
+
+        drop(cancellation);
+        assert!(!interrupts.inner.streaming.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,28 @@
+use anyhow::anyhow;
+
+const SERVICE: &str = "rag";
+
+/// Environment variable that overrides the keychain entry `name`, e.g. `RAG_SECRET_GITHUB_TOKEN`.
+fn env_name(name: &str) -> String {
+    let name = name
+        .chars()
+        .map(|e| if e.is_ascii_alphanumeric() { e.to_ascii_uppercase() } else { '_' })
+        .collect::<String>();
+    format!("RAG_SECRET_{}", name)
+}
+
+/// Looks a secret up in the environment first and then in the system keychain.
+pub fn get_secret(name: &str) -> anyhow::Result<String> {
+    if let Ok(value) = std::env::var(env_name(name)) {
+        return Ok(value);
+    }
+
+    keyring::Entry::new(SERVICE, name)?
+        .get_password()
+        .map_err(|e| anyhow!("Secret {} not found ({}), store it with `rag --ss {}=<value>` or set {}", name, e, name, env_name(name)))
+}
+
+pub fn set_secret(name: &str, value: &str) -> anyhow::Result<()> {
+    keyring::Entry::new(SERVICE, name)?.set_password(value)?;
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use std::io::IsTerminal;
+use std::path::Path;
+use serde_json::Value;
+use tracing::level_filters::LevelFilter;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{Builder, Rotation};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+/// Where the logs are kept, relative to the config directory.
+pub const LOG_DIR: &str = "logs";
+/// Days of logs kept, a file each.
+const KEPT_LOG_FILES: usize = 7;
+/// Keys whose values never end up in a log.
+const SECRET_KEYS: [&str; 7] = ["api_key", "api-key", "apikey", "authorization", "password", "secret", "token"];
+
+/// How much is logged, `--verbose` and `--debug`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verbosity {
+    /// Request and response metadata to the log file only.
+    Quiet,
+    /// The metadata to stderr as well.
+    Verbose,
+    /// The full requests too, redacted, to both.
+    Debug,
+}
+
+/// Logs to a daily file under `config_dir` and, when asked for, to stderr. The guard has to live as long as logging should.
+pub fn init(config_dir: &Path, verbosity: Verbosity) -> anyhow::Result<WorkerGuard> {
+    let level = match verbosity {
+        Verbosity::Debug => LevelFilter::DEBUG,
+        _ => LevelFilter::INFO,
+    };
+    // Only rag's own events, the http stack is far too chatty.
+    let targets = Targets::new().with_target(env!("CARGO_PKG_NAME"), level);
+
+    let dir = config_dir.join(LOG_DIR);
+    std::fs::create_dir_all(&dir)?;
+    let appender = Builder::new()
+        .rotation(Rotation::DAILY)
+        .filename_prefix("rag")
+        .filename_suffix("log")
+        .max_log_files(KEPT_LOG_FILES)
+        .build(dir)?;
+    let (writer, guard) = tracing_appender::non_blocking(appender);
+    let file = tracing_subscriber::fmt::layer().with_writer(writer).with_ansi(false).with_filter(targets.clone());
+
+    let stderr = (verbosity != Verbosity::Quiet).then(|| {
+        tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
+            .with_ansi(std::io::stderr().is_terminal())
+            .without_time()
+            .with_filter(targets)
+    });
+    tracing_subscriber::registry().with(file).with(stderr).try_init()?;
+    Ok(guard)
+}
+
+/// `body` with the values of secret looking keys masked and inline images cut down to their size, for the debug log.
+pub fn redact(body: &Value) -> Value {
+    match body {
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(key, value)| {
+                    let secret = SECRET_KEYS.iter().any(|e| key.to_lowercase().contains(e)) && !key.ends_with("_tokens");
+                    (key.clone(), if secret { Value::String("***".to_string()) } else { redact(value) })
+                })
+                .collect(),
+        ),
+        Value::Array(values) => Value::Array(values.iter().map(redact).collect()),
+        Value::String(text) if text.starts_with("data:") => {
+            let kind = text.split(';').next().unwrap_or_default();
+            Value::String(format!("{};… {} bytes", kind, text.len()))
+        }
+        value => value.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        let body = serde_json::json!({
+            "model": "m",
+            "max_tokens": 400,
+            "headers": { "Authorization": "Bearer sk-1", "x-api-key": "sk-2" },
+            "messages": [{ "role": "user", "content": [{ "image_url": { "url": "data:image/png;base64,iVBORw0KGgo=" } }] }],
+        });
+        assert_eq!(redact(&body), serde_json::json!({
+            "model": "m",
+            "max_tokens": 400,
+            "headers": { "Authorization": "***", "x-api-key": "***" },
+            "messages": [{ "role": "user", "content": [{ "image_url": { "url": "data:image/png;… 34 bytes" } }] }],
+        }));
+    }
+}
@@ -1,35 +1,158 @@
+use std::sync::Arc;
+use std::time::Duration;
 use async_openai::Client;
 use async_openai::config::OpenAIConfig;
-use crate::app::{App, Context};
-use crate::config::Config;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use crate::app::App;
+use crate::bedrock::Bedrock;
+use crate::client::{Azure, ChatClient, WithQuery};
+use crate::config::{Config, ProviderKind};
 use crate::manager::ContextManager;
 use crate::processor::Processor;
 
 use crate::tools::ToolParameters;
 use clap::Parser;
+use colored::Colorize;
 
+mod batch;
+mod bedrock;
+mod budget;
+mod bus;
+mod cache;
+mod client;
+mod code_blocks;
+mod compare;
 mod config;
+mod doctor;
+mod events;
+mod filters;
+mod includes;
+mod interrupts;
 mod manager;
+mod openrouter;
 mod processor;
 mod app;
 mod tools;
+mod recorder;
+mod redaction;
+mod retry;
 mod rq;
+mod runner;
+mod setup;
+mod style;
+mod templates;
+mod scripts;
+mod serve;
 mod rl_helper;
+mod keychain;
+mod logging;
+mod transcript;
+mod tui;
+mod usage;
+#[cfg(feature = "vcr")]
+mod vcr;
+
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 30;
+
+/// The http client requests to the provider go through, with the configured timeouts, proxies and `extra_headers`.
+fn http_client(config: &Config) -> anyhow::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(config.connect_timeout_secs.unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS)));
+    // A total timeout would cut off long streamed answers, this one only fires while nothing arrives.
+    if let Some(timeout) = config.request_timeout_secs {
+        builder = builder.read_timeout(Duration::from_secs(timeout));
+    }
+    if let Some(ref proxy) = config.http_proxy {
+        builder = builder.proxy(reqwest::Proxy::http(proxy)?);
+    }
+    if let Some(ref proxy) = config.https_proxy {
+        builder = builder.proxy(reqwest::Proxy::https(proxy)?);
+    }
+    let mut headers = match openrouter::is_openrouter(&config.base_url) {
+        true => openrouter::headers(config)?,
+        false => HeaderMap::new(),
+    };
+    for (name, value) in &config.extra_headers {
+        headers.insert(HeaderName::from_bytes(name.as_bytes())?, HeaderValue::from_str(value)?);
+    }
+    Ok(builder.default_headers(headers).build()?)
+}
 
 #[tokio::main]
 async fn main() {
-    let config = Config::new();
-    let manager = ContextManager::new(10);
+    let mut app: App = app::App::parse();
+    style::init(app.output().is_some());
+
+    let mut config = match Config::new() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", format!("Error: {:#}", e).red());
+            eprintln!("{}", "Fix: correct the file, or move it away to run the setup again".yellow());
+            std::process::exit(1);
+        }
+    };
+    if !config.exists() && let Err(e) = setup::first_run(&mut config).await {
+        eprintln!("{}", format!("Error: {:#}", e).red());
+        std::process::exit(1);
+    }
+    let _log_guard = match logging::init(&config.config_dir(), app.verbosity()) {
+        Ok(guard) => Some(guard),
+        Err(e) => {
+            eprintln!("{}", format!("Warning: Failed to set up logging: {}", e).yellow());
+            None
+        }
+    };
+    for problem in config.validate() {
+        eprintln!("{}", format!("Warning: {}, {}", problem.problem, problem.fix).yellow());
+    }
 
     let rq_config = OpenAIConfig::new()
         .with_api_base(config.base_url.clone())
         .with_api_key(config.api_key.clone());
 
-    let client = Client::with_config(rq_config);
+    let extra_query = config.extra_query.clone().into_iter().collect::<Vec<_>>();
 
-    let context = Context::new(config, manager, client);
-    let processor = Processor::new(true);
+    let http_client = match http_client(&config) {
+        Ok(http_client) => http_client,
+        Err(e) => {
+            eprintln!("{}", format!("Error: Failed to build the http client: {:#}", e).red());
+            std::process::exit(1);
+        }
+    };
+    let chat: Option<Arc<dyn ChatClient>> = match config.provider {
+        Some(ProviderKind::Azure) => Some(Arc::new(Azure::new(client::azure_config(&config), extra_query.clone(), http_client.clone()))),
+        Some(ProviderKind::Bedrock) => Some(Arc::new(Bedrock::new(&config, http_client.clone()))),
+        Some(ProviderKind::OpenAi) | None => None,
+    };
+    let client = Client::with_config(WithQuery::new(rq_config, extra_query)).with_http_client(http_client);
+    if openrouter::is_openrouter(&config.base_url) {
+        match openrouter::catalog(&client, &config.config_dir()).await {
+            Ok(catalog) => config.catalog = catalog,
+            Err(e) => eprintln!("{}", format!("Warning: Failed to fetch the OpenRouter catalog, models without `prices` aren't priced: {}", e).yellow()),
+        }
+    }
 
-    let mut app: App = app::App::parse();
-    app.run(context, processor).await.expect("Internal Error: "); 
+    #[cfg(feature = "vcr")]
+    let cassette = vcr::from_env(&client).expect("Failed to open the cassette");
+    let builder = Processor::builder()
+        .with_config(config)
+        .with_backend(client)
+        .with_context_policy(ContextManager::new(10))
+        .with_default_hooks()
+        .with_renderer(events::renderer(app.output(), app.is_one_shot()));
+    let builder = match chat {
+        Some(chat) => builder.with_chat_client(chat),
+        None => builder,
+    };
+    #[cfg(feature = "vcr")]
+    let builder = match cassette {
+        Some(chat) => builder.with_chat_client(chat),
+        None => builder,
+    };
+    let (processor, context) = builder.build().expect("Failed to initialize context");
+
+    if let Err(e) = app.run(context, processor).await {
+        eprintln!("{}", format!("Error: {:#}", e).red());
+        std::process::exit(1);
+    }
 }
@@ -1,4 +1,32 @@
-use async_openai::types::{ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs};
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestAssistantMessageContent,
+    ChatCompletionRequestAssistantMessageContentPart, ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs,
+    ChatCompletionRequestUserMessageContent, ChatCompletionRequestUserMessageContentPart,
+};
+
+/// Roughly what a tokenizer makes of `text`: a token per CJK character and per four other characters.
+pub fn estimate_tokens(text: &str) -> u64 {
+    let (cjk, other) = text.chars().fold((0u64, 0u64), |(cjk, other), e| match e {
+        '\u{3000}'..='\u{9fff}' | '\u{ac00}'..='\u{d7af}' | '\u{f900}'..='\u{faff}' | '\u{ff00}'..='\u{ffef}' => (cjk + 1, other),
+        _ => (cjk, other + 1),
+    });
+    cjk + other.div_ceil(4)
+}
+
+/// The role of `message` and the text the model reads of it, tool call arguments included.
+pub fn message_text(message: &ChatCompletionRequestMessage) -> (String, String) {
+    let value = serde_json::to_value(message).unwrap_or_default();
+    let role = value["role"].as_str().unwrap_or_default().to_string();
+    let mut text = match value["content"] {
+        serde_json::Value::String(ref text) => text.clone(),
+        serde_json::Value::Array(ref parts) => parts.iter().filter_map(|e| e["text"].as_str()).collect::<Vec<_>>().join("\n"),
+        _ => String::new(),
+    };
+    for call in value["tool_calls"].as_array().map(Vec::as_slice).unwrap_or_default() {
+        text.push_str(&format!("\n{}({})", call["function"]["name"].as_str().unwrap_or_default(), call["function"]["arguments"].as_str().unwrap_or_default()));
+    }
+    (role, text)
+}
 
 #[derive(Debug, Default)]
 pub(crate) struct ContextManager {
@@ -15,16 +43,153 @@ impl ContextManager {
     }
 
     fn shift(&mut self) {
-        self.contexts.remove(1);
-        self.contexts.remove(1);
+        let start = usize::from(self.system().is_some());
+        let end = (start + 2).min(self.contexts.len());
+        self.contexts.drain(start..end);
     }
 
     pub fn add(&mut self, message: ChatCompletionRequestMessage) {
-        if self.contexts.len() == self.max_size { self.shift(); }
+        if self.contexts.len() >= self.max_size { self.shift(); }
         self.contexts.push(message); 
     }
 
-    pub fn as_messages<'a>(&mut self) -> Vec<ChatCompletionRequestMessage> {
+    /// Puts `calls` on the answer that made them, the last message, so the tool results added next can refer to them.
+    pub fn add_tool_calls(&mut self, calls: Vec<ChatCompletionMessageToolCall>) {
+        match self.contexts.last_mut() {
+            Some(ChatCompletionRequestMessage::Assistant(message)) => message.tool_calls = Some(calls),
+            _ => self.add(ChatCompletionRequestAssistantMessageArgs::default().tool_calls(calls).build().unwrap().into()),
+        }
+    }
+
+    /// Text of the pinned system message, the first message if it is a system one.
+    pub fn system(&self) -> Option<String> {
+        match self.contexts.first() {
+            Some(message @ ChatCompletionRequestMessage::System(_)) => Some(message_text(message).1),
+            _ => None,
+        }
+    }
+
+    pub fn remove_system(&mut self) {
+        if self.system().is_some() {
+            self.contexts.remove(0);
+        }
+    }
+
+    /// Replaces the pinned system message, or pins one in front of the conversation.
+    pub fn set_system(&mut self, prompt: &str) {
+        let message = ChatCompletionRequestSystemMessageArgs::default().content(prompt).build().unwrap().into();
+        match self.system() {
+            Some(_) => self.contexts[0] = message,
+            None => self.contexts.insert(0, message),
+        }
+    }
+
+    /// Text of the most recent assistant message that has any.
+    pub fn last_answer(&self) -> Option<String> {
+        self.contexts.iter().rev().find_map(|message| {
+            let ChatCompletionRequestMessage::Assistant(message) = message else { return None };
+            let text = match message.content.as_ref()? {
+                ChatCompletionRequestAssistantMessageContent::Text(text) => text.clone(),
+                ChatCompletionRequestAssistantMessageContent::Array(parts) => parts
+                    .iter()
+                    .filter_map(|e| match e {
+                        ChatCompletionRequestAssistantMessageContentPart::Text(part) => Some(part.text.as_str()),
+                        _ => None,
+                    })
+                    .collect(),
+            };
+            (!text.is_empty()).then_some(text)
+        })
+    }
+
+    /// Forgets the conversation, system messages stay.
+    pub fn clear(&mut self) {
+        self.contexts.retain(|e| matches!(e, ChatCompletionRequestMessage::System(_)));
+    }
+
+    /// Removes the last user message together with everything that answered it, tool results included,
+    /// and returns its text.
+    pub fn pop_exchange(&mut self) -> Option<String> {
+        let index = self.contexts.iter().rposition(|e| matches!(e, ChatCompletionRequestMessage::User(_)))?;
+        let ChatCompletionRequestMessage::User(message) = self.contexts.drain(index..).next()? else { return None };
+        Some(match message.content {
+            ChatCompletionRequestUserMessageContent::Text(text) => text,
+            ChatCompletionRequestUserMessageContent::Array(parts) => parts
+                .iter()
+                .filter_map(|e| match e {
+                    ChatCompletionRequestUserMessageContentPart::Text(part) => Some(part.text.as_str()),
+                    _ => None,
+                })
+                .collect(),
+        })
+    }
+
+    pub fn messages(&self) -> &[ChatCompletionRequestMessage] {
+        &self.contexts
+    }
+
+    /// How many messages are kept before the oldest exchange is dropped.
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    pub fn as_messages(&mut self) -> Vec<ChatCompletionRequestMessage> {
         self.contexts.clone()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::types::{ChatCompletionRequestToolMessageArgs, ChatCompletionRequestUserMessageArgs};
+
+    fn user(text: &str) -> ChatCompletionRequestMessage {
+        ChatCompletionRequestUserMessageArgs::default().content(text).build().unwrap().into()
+    }
+
+    fn assistant(text: &str) -> ChatCompletionRequestMessage {
+        ChatCompletionRequestAssistantMessageArgs::default().content(text).build().unwrap().into()
+    }
+
+    #[test]
+    fn test_pop_exchange_and_clear() {
+        let mut manager = ContextManager::new(10);
+        manager.add(ChatCompletionRequestSystemMessageArgs::default().content("be brief").build().unwrap().into());
+        manager.add(user("hi"));
+        manager.add(assistant("hello"));
+        manager.add(user("list files"));
+        manager.add(assistant(""));
+        manager.add(ChatCompletionRequestToolMessageArgs::default().content("[]").tool_call_id("0").build().unwrap().into());
+        manager.add(assistant("there are none"));
+
+        assert_eq!(manager.pop_exchange().as_deref(), Some("list files"));
+        assert_eq!(manager.as_messages().len(), 3);
+        assert_eq!(manager.last_answer().as_deref(), Some("hello"));
+
+        manager.clear();
+        assert_eq!(manager.as_messages().len(), 1);
+        assert_eq!(manager.pop_exchange(), None);
+    }
+
+    #[test]
+    fn test_pinned_system() {
+        let mut manager = ContextManager::new(4);
+        manager.add(user("q1"));
+        manager.add(assistant("a1"));
+        manager.set_system("be brief");
+        manager.add(user("q2"));
+        manager.add(assistant("a2"));
+        manager.set_system("be verbose");
+
+        assert_eq!(manager.system().as_deref(), Some("be verbose"));
+        assert_eq!(manager.messages().len(), 3);
+        assert_eq!(manager.last_answer().as_deref(), Some("a2"));
+    }
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("hello world"), 3);
+        assert_eq!(estimate_tokens("你好，世界"), 5);
+        assert_eq!(message_text(&user("hi")), ("user".to_string(), "hi".to_string()));
+    }
+}
@@ -0,0 +1,91 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+use async_openai::Client;
+use reqwest::header::{HeaderMap, HeaderValue};
+use serde_json::Value;
+use crate::client::WithQuery;
+use crate::config::{Config, ModelMetadata, ModelPrice};
+
+/// Where the catalog is kept between sessions, relative to the config directory.
+pub const CATALOG_FILE: &str = "openrouter_models.json";
+/// How long the kept catalog is used before it is fetched again, a day.
+const CATALOG_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether `base_url` is OpenRouter's API.
+pub fn is_openrouter(base_url: &str) -> bool {
+    reqwest::Url::parse(base_url).ok().and_then(|e| e.host_str().map(|e| e == "openrouter.ai" || e.ends_with(".openrouter.ai"))).unwrap_or_default()
+}
+
+/// `HTTP-Referer` and `X-Title` as `openrouter` sets them.
+pub fn headers(config: &Config) -> anyhow::Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    let Some(ref openrouter) = config.openrouter else { return Ok(headers) };
+    if let Some(ref referer) = openrouter.referer {
+        headers.insert("HTTP-Referer", HeaderValue::from_str(referer)?);
+    }
+    if let Some(ref title) = openrouter.title {
+        headers.insert("X-Title", HeaderValue::from_str(title)?);
+    }
+    Ok(headers)
+}
+
+/// The context size and price of every model in a `/models` response, the prices there are USD per token as strings.
+pub fn parse_catalog(response: &Value) -> BTreeMap<String, ModelMetadata> {
+    let per_million = |e: &Value| e.as_str().and_then(|e| e.parse::<f64>().ok()).map(|e| e * 1_000_000.0);
+    response["data"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|model| {
+            let pricing = &model["pricing"];
+            let price = per_million(&pricing["prompt"]).zip(per_million(&pricing["completion"]));
+            Some((model["id"].as_str()?.to_string(), ModelMetadata {
+                context_length: model["context_length"].as_u64(),
+                price: price.map(|(input, output)| ModelPrice { input, output }),
+            }))
+        })
+        .collect()
+}
+
+/// The catalog kept in `dir`, fetched again once it is a day old.
+pub async fn catalog(client: &Client<WithQuery>, dir: &Path) -> anyhow::Result<BTreeMap<String, ModelMetadata>> {
+    let path = dir.join(CATALOG_FILE);
+    let fresh = std::fs::metadata(&path).and_then(|e| e.modified()).ok().and_then(|e| e.elapsed().ok()).is_some_and(|e| e < CATALOG_MAX_AGE);
+    if fresh && let Ok(response) = std::fs::read_to_string(&path).map_err(anyhow::Error::from).and_then(|e| Ok(serde_json::from_str(&e)?)) {
+        return Ok(parse_catalog(&response));
+    }
+
+    let response = tokio::time::timeout(FETCH_TIMEOUT, client.models().list_byot::<Value>())
+        .await
+        .map_err(|_| anyhow::anyhow!("no answer within {}s", FETCH_TIMEOUT.as_secs()))??;
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(&path, response.to_string())?;
+    Ok(parse_catalog(&response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_catalog() {
+        assert!(is_openrouter("https://openrouter.ai/api/v1"));
+        assert!(!is_openrouter("https://api.openai.com/v1"));
+
+        let response = serde_json::json!({ "data": [
+            { "id": "openai/gpt-4o", "context_length": 128000, "pricing": { "prompt": "0.0000025", "completion": "0.00001" } },
+            { "id": "meta-llama/llama-3-8b:free", "context_length": 8192, "pricing": { "prompt": "0", "completion": "0" } },
+            { "id": "unlisted" },
+        ] });
+        let catalog = parse_catalog(&response);
+        let gpt = catalog["openai/gpt-4o"];
+        assert_eq!(gpt.context_length, Some(128000));
+        let price = gpt.price.unwrap();
+        assert!((price.input - 2.5).abs() < 1e-9 && (price.output - 10.0).abs() < 1e-9);
+        assert_eq!(catalog["meta-llama/llama-3-8b:free"].price, Some(ModelPrice { input: 0.0, output: 0.0 }));
+        assert_eq!(catalog["unlisted"], ModelMetadata::default());
+    }
+}
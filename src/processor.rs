@@ -1,169 +1,664 @@
 use std::fmt::Debug;
 use std::fs;
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::io::{stdout, Write};
+use std::collections::{BTreeMap, HashMap};
 use std::path::Path;
-use std::pin::Pin;
-use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use async_openai::Client;
-use async_openai::config::OpenAIConfig;
-use async_openai::error::OpenAIError;
-use async_openai::types::{ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestFunctionMessageArgs, ChatCompletionRequestToolMessageArgs, ChatCompletionRequestUserMessageArgs, ChatCompletionResponseMessage};
+use async_trait::async_trait;
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessageContentPartImage,
+    ChatCompletionRequestToolMessageArgs, ChatCompletionRequestUserMessageArgs, ChatCompletionRequestUserMessageContent,
+    ChatCompletionRequestUserMessageContentPart, ChatCompletionToolType, FinishReason, FunctionCall, ImageDetail, ImageUrl, ResponseFormat,
+    ResponseFormatJsonSchema,
+};
 use colored::Colorize;
 use encoding_rs::GBK;
 use futures::StreamExt;
-use futures_core::Stream;
 use regex::Regex;
-use serde_json::{json, Value};
-use crate::app::Context;
-use rustyline::{CompletionType, Config, DefaultEditor, EditMode, Editor};
-use rustyline::hint::HistoryHinter;
-use rustyline::validate::MatchingBracketValidator;
-use crate::rl_helper::RlHelper;
-use crate::rq::{RqBodyBuilder, RsChunkBody};
+use rustyline::error::ReadlineError;
+use serde_json::Value;
+use crate::app::{parse_models, Context};
+use crate::budget::BudgetGuard;
+use crate::compare;
+use crate::cache::{self, CachingClient, ResponseCache};
+use crate::bus::{Event, EventBus, EventKind, Subscriber, PRIORITY_DEFAULT, PRIORITY_LATE};
+use crate::config::{Config, CustomCommandConfig, ReasoningDisplay, RetryConfig};
+use crate::manager::{estimate_tokens, message_text, ContextManager};
+use crate::client::{self, ChatClient, WithQuery};
+use crate::code_blocks::CodeBlocks;
+use crate::events::{format_tokens, EventSender, Renderer, TerminalRenderer, TokenUsage, TurnPerf, UiEvent};
+use crate::includes::{self, Image, DEFAULT_TOKEN_BUDGET};
+use crate::interrupts::CANCELLED_NOTE;
+use crate::logging;
+use crate::rl_helper::{command_prefix, Completions, Prompted, RlHelper, HISTORY_FILE, MULTILINE_END, MULTILINE_START};
+use crate::redaction::RedactionHook;
+use crate::recorder::{Recorder, ResponseLog};
+use crate::retry::{self, CONTINUE_PROMPT, DROPPED_NOTE};
+use crate::rq::RsChunkBody;
+use crate::scripts::{ScriptFilter, ScriptHook, Scripts};
+use crate::style;
+use crate::templates::{self, Templates};
+use crate::tools::git::{git, truncate_diff};
+use crate::tools::guard;
+use crate::tools::web::{html_to_markdown, BROWSER_USER_AGENT};
+use crate::tools::ToolRegistry;
+use crate::transcript::{Role, LAST_SESSION_FILE};
+use crate::usage::UsageRecorder;
 
 #[derive(Debug, Default)]
 pub(crate) struct Processor {
-    pre_input_hooks: Vec<Rc<dyn PreInputHook>>,
-    pre_call_hooks: Vec<Rc<dyn PreCallHook>>,
-    post_call_hooks: Vec<Rc<dyn PostCallHook>>,
-    pre_next_input_hooks: Vec<Rc<dyn PreNextInputHook>>,
+    bus: Arc<EventBus>,
+    /// What the commands start with, for the completer.
+    commands: Vec<String>,
 }
 
 impl Processor {
-    pub fn new(default_hooks: bool) -> Self {
-        let mut process = Processor {
-            pre_input_hooks: vec![],
-            pre_call_hooks: vec![],
-            post_call_hooks: vec![],
-            pre_next_input_hooks: vec![],
+    pub fn builder() -> ProcessorBuilder<Missing, Missing> {
+        ProcessorBuilder {
+            config: Missing,
+            backend: Missing,
+            manager: None,
+            tools: None,
+            renderer: None,
+            chat: None,
+            default_hooks: false,
+            bus: EventBus::default(),
+        }
+    }
+
+    /// Returns what the commands start with, for the completer.
+    fn add_default_hooks(bus: &mut EventBus, config: &Config) -> Vec<String> {
+        let parser = CommandParser::new(&config.commands);
+        let commands = parser.commands.iter().map(|e| command_prefix(e.help().0)).chain([MULTILINE_START.to_string()]).collect();
+        let token_tracer = Arc::new(TokenTracer::new());
+        let perf_tracer = Arc::new(PerfTracer::default());
+        let budget_guard = Arc::new(BudgetGuard::default());
+        let usage_recorder = Arc::new(UsageRecorder::default());
+        let tools_executor = Arc::new(ToolsExecutor::new());
+        let thinking_budget = Arc::new(ThinkingBudget::new());
+
+        bus.subscribe(&[EventKind::UserInput], PRIORITY_DEFAULT, Arc::new(InlineOverrides::new()));
+        bus.subscribe(&[EventKind::UserInput], PRIORITY_DEFAULT, Arc::new(parser));
+        // After the commands, so what they inline is masked too.
+        bus.subscribe(&[EventKind::UserInput], PRIORITY_DEFAULT, Arc::new(RedactionHook::new()));
+        bus.subscribe(&[EventKind::UserInput], PRIORITY_DEFAULT, budget_guard.clone());
+        bus.subscribe(&[EventKind::UserInput], PRIORITY_DEFAULT, Arc::new(AnswerPrompt));
+        // After the commands, so the time they take isn't counted as waiting for the answer.
+        bus.subscribe(&[EventKind::UserInput], PRIORITY_LATE, perf_tracer.clone());
+        bus.subscribe(&[EventKind::UserInput], PRIORITY_LATE, usage_recorder.clone());
+        bus.subscribe(&[EventKind::Chunk], PRIORITY_DEFAULT, Arc::new(ReasoningCollector));
+        bus.subscribe(&[EventKind::Chunk], PRIORITY_DEFAULT, thinking_budget.clone());
+        bus.subscribe(&[EventKind::Chunk], PRIORITY_DEFAULT, Arc::new(ContentCollector));
+        bus.subscribe(&[EventKind::StreamError], PRIORITY_DEFAULT, Arc::new(ErrorReporter));
+        bus.subscribe(&[EventKind::Chunk, EventKind::Cancelled, EventKind::TurnEnd], PRIORITY_DEFAULT, tools_executor);
+        bus.subscribe(&[EventKind::Chunk, EventKind::TurnEnd], PRIORITY_DEFAULT, token_tracer);
+        bus.subscribe(&[EventKind::Chunk, EventKind::TurnEnd], PRIORITY_DEFAULT, perf_tracer);
+        bus.subscribe(&[EventKind::TurnEnd], PRIORITY_DEFAULT, budget_guard);
+        bus.subscribe(&[EventKind::TurnEnd], PRIORITY_DEFAULT, usage_recorder);
+        bus.subscribe(&[EventKind::TurnEnd], PRIORITY_DEFAULT, thinking_budget);
+        bus.subscribe(&[EventKind::TurnEnd], PRIORITY_DEFAULT, Arc::new(NewLine));
+        bus.subscribe(&[EventKind::TurnEnd], PRIORITY_LATE, Arc::new(SessionSaver::default()));
+        commands
+    }
+
+    /// Subscribes the rhai scripts in `dir` after the built-in subscribers, and runs their `filter` after the configured filters.
+    fn add_script_hooks(bus: &mut EventBus, context: &mut Context, dir: &Path) -> anyhow::Result<()> {
+        let scripts = Arc::new(Scripts::load(dir)?);
+        if scripts.is_empty() {
+            return Ok(());
+        }
+
+        context.filters.push(Box::new(ScriptFilter::new(scripts.clone())));
+        let kinds = [EventKind::UserInput, EventKind::Chunk, EventKind::ToolCall, EventKind::TurnEnd];
+        bus.subscribe(&kinds, PRIORITY_LATE, Arc::new(ScriptHook::new(scripts)));
+        Ok(())
+    }
+
+    pub async fn run(&mut self, context: &mut Context) -> anyhow::Result<()> {
+        let prompt = format!("{}^D:", style::emoji("🌟"));
+        let history = context.config.history.clone().unwrap_or_default();
+        let history_file = context.config.config_dir().join(HISTORY_FILE);
+        let mut rl = RlHelper::new_rl(&prompt, self.completions(context), &history, &history_file)?;
+
+        loop {
+            // Everything below writes to the terminal directly, the last answer has to be out first.
+            context.events.flush();
+            self.bus.dispatch(context, &mut Event::BeforeInput).await?;
+
+            let mut user_input = match Prompted::from_readline(rl.readline(&prompt))? {
+                Prompted::Input(input) => input,
+                Prompted::Again => continue,
+                Prompted::Quit => {
+                    println!("{}", "bye".yellow());
+                    return Ok(());
+                }
+            };
+            // Saved right away, so neither `@exit`, Ctrl+C during the answer nor a panic loses it.
+            if !user_input.is_empty() && rl.add_history_entry(user_input.as_str())? {
+                let saved = std::fs::create_dir_all(history_file.parent().unwrap_or(Path::new(".")))
+                    .map_err(ReadlineError::from)
+                    .and_then(|_| rl.append_history(&history_file));
+                if let Err(e) = saved {
+                    eprintln!("{}", format!("Warning: Failed to save the input history: {}", e).yellow());
+                }
+            }
+
+            self.turn(context, &mut user_input).await?;
+        }
+    }
+
+    /// The completer's candidates, the models are listed in the background so the prompt isn't held up by the provider.
+    fn completions(&self, context: &Context) -> Completions {
+        let completions = Completions {
+            commands: self.commands.clone(),
+            models: Arc::new(Mutex::new(vec![context.config.model.clone()])),
+            personas: context.config.personas.keys().cloned().collect(),
+            config_dir: context.config.config_dir(),
         };
 
-        if default_hooks { process.add_default_hooks(); }
-        process
+        let (client, models) = (context.client.clone(), completions.models.clone());
+        tokio::spawn(async move {
+            let listed = tokio::time::timeout(Duration::from_secs(30), client.models().list_byot::<Value>()).await;
+            if let Ok(Ok(response)) = listed {
+                let mut models = models.lock().unwrap();
+                for (id, _) in parse_models(&response) {
+                    if !models.contains(&id) {
+                        models.push(id);
+                    }
+                }
+            }
+        });
+        completions
     }
 
+    /// Answers a single prompt without the interactive prompt, for `rag -p`.
+    pub async fn run_once(&mut self, context: &mut Context, mut prompt: String) -> anyhow::Result<TurnOutcome> {
+        let outcome = self.turn(context, &mut prompt).await;
+        context.events.flush();
+        outcome
+    }
 
-    fn add_default_hooks(&mut self) {
-        let token_tracer = Rc::new(TokenTracer::new());
-        let tools_executor = Rc::new(ToolsExecutor::new());
+    /// Runs the input through the subscribers, asks the model and streams the answer, tool calls included.
+    async fn turn(&mut self, context: &mut Context, user_input: &mut String) -> anyhow::Result<TurnOutcome> {
+        self.bus.dispatch(context, &mut Event::UserInput(user_input)).await?;
+        let dry_run = std::mem::take(&mut context.dry_run_once) || context.dry_run;
+        let compared = context.compare.take();
+        // Commands like `@open` may consume the whole input, there's nothing to ask then.
+        if user_input.trim().is_empty() { return Ok(TurnOutcome::Skipped); }
+        let images = std::mem::take(&mut context.images);
+
+        context.manager.add(ChatCompletionRequestUserMessageArgs::default()
+            .content(user_message_content(user_input, &images))
+            .build()?
+            .into());
+
+        let rq_body = context.request_body()?;
+        // The question is taken back, the conversation goes on as if it had never been asked.
+        if dry_run {
+            context.manager.pop_exchange();
+            context.images = images;
+            context.events.emit(UiEvent::Notice(serde_json::to_string_pretty(&rq_body)?));
+            return Ok(TurnOutcome::Skipped);
+        }
+        if let Some(models) = compared {
+            context.manager.pop_exchange();
+            return self.compare(context, rq_body, &models).await;
+        }
+        let mut shown = user_input.clone();
+        images.iter().for_each(|e| shown.push_str(&format!("\n[image: {}]", e.source)));
+        context.transcript.push(Role::User, &shown);
+        let retry = context.config.retry.clone().unwrap_or_default();
+        context.filters.reset();
+        context.stop_stream = false;
+        context.turn_error = None;
+
+        let mut streamed = self.stream_answer(context, rq_body, &retry).await?;
+        let mut answer = std::mem::take(&mut streamed.answer);
+        // An answer that broke off or hit the token limit is picked up where it stopped, the continuations add up to
+        // one answer. One with tool calls can't be put back together.
+        let max_attempts = retry::max_attempts(&retry);
+        let max_continuations = context.config.max_continuations.unwrap_or_default();
+        let (mut attempt, mut continuations) = (1, 0);
+        loop {
+            let dropped = context.turn_error.is_some() && retry.continue_dropped.unwrap_or(false) && attempt < max_attempts;
+            let truncated = context.turn_error.is_none() && streamed.finish_reason == Some(FinishReason::Length) && continuations < max_continuations;
+            if streamed.cancelled || streamed.tool_calls || !(dropped || truncated) {
+                break;
+            }
+            if let Some(error) = context.turn_error.take() {
+                let delay = retry::backoff(&retry, attempt);
+                context.events.emit(UiEvent::Retrying { attempt, max_attempts, delay, error: format!("The answer broke off, {}", error) });
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            } else {
+                continuations += 1;
+                tracing::info!(continuations, max_continuations, "answer hit the token limit, continuing");
+            }
 
-        self.add_hook(Hook::PreCallHook(Rc::new(CommandParser::new())));
-        self.add_hook(Hook::PreCallHook(Rc::new(AnswerPrompt)));
-        self.add_hook(Hook::PostCallHook(Rc::new(ReasoningCollector)));
-        self.add_hook(Hook::PostCallHook(Rc::new(ContentCollector)));
-        self.add_hook(Hook::PostCallHook(tools_executor.clone()));
-        self.add_hook(Hook::PostCallHook(token_tracer.clone()));
-        self.add_hook(Hook::PreNextInputHook(tools_executor.clone()));
-        self.add_hook(Hook::PreNextInputHook(token_tracer.clone()));
-        self.add_hook(Hook::PreNextInputHook(Rc::new(NewLine)));
+            let rq_body = context.continuation_body(&answer)?;
+            streamed = match self.stream_answer(context, rq_body, &retry).await {
+                Ok(streamed) => streamed,
+                Err(e) => {
+                    context.turn_error = Some(e.to_string());
+                    break;
+                }
+            };
+            answer.push_str(&streamed.answer);
+        }
+        let cancelled = streamed.cancelled;
+
+        // The partial answer stays in the context, marked so the model doesn't build on it as if it were complete.
+        if cancelled {
+            answer.push_str(CANCELLED_NOTE);
+            context.transcript.push(Role::Assistant, CANCELLED_NOTE);
+            context.events.emit(UiEvent::Cancelled);
+            self.bus.dispatch(context, &mut Event::Cancelled).await?;
+        } else if context.turn_error.is_some() && !answer.is_empty() {
+            answer.push_str(DROPPED_NOTE);
+            context.transcript.push(Role::Assistant, DROPPED_NOTE);
+        } else {
+            report_stop(&context.events, streamed.finish_reason);
+        }
+        context.finish_reason = streamed.finish_reason;
+
+        context.manager.add(ChatCompletionRequestAssistantMessageArgs::default()
+            .content(answer)
+            .build()?
+            .into());
+        self.bus.dispatch(context, &mut Event::TurnEnd).await?;
+
+        Ok(match context.turn_error.take() {
+            _ if cancelled => TurnOutcome::Cancelled,
+            Some(e) => TurnOutcome::Failed(e),
+            None => TurnOutcome::Answered,
+        })
     }
 
-    fn add_hook(&mut self, hook: Hook) {
-        match hook {
-            Hook::PreInputHook(hook) => self.pre_input_hooks.push(hook),
-            Hook::PreCallHook(hook) => self.pre_call_hooks.push(hook),
-            Hook::PostCallHook(hook) => self.post_call_hooks.push(hook),
-            Hook::PreNextInputHook(hook) => self.pre_next_input_hooks.push(hook),
+    /// Asks each of `models` the question of `rq_body` at once and prints their answers, which stay out of the conversation.
+    async fn compare(&mut self, context: &mut Context, rq_body: Value, models: &[String]) -> anyhow::Result<TurnOutcome> {
+        let interrupts = context.interrupts.clone();
+        let cancellation = interrupts.cancellation();
+        let answers = tokio::select! {
+            answers = compare::ask(context.chat.as_ref(), &rq_body, models, &context.config, &context.events) => answers,
+            _ = cancellation => {
+                context.events.emit(UiEvent::Cancelled);
+                return Ok(TurnOutcome::Cancelled);
+            }
+        };
+        compare::show(&answers, &context.events);
+        if let Err(e) = compare::record_usage(&context.config, &answers) {
+            context.events.emit(UiEvent::Warning(format!("Failed to record the usage: {}", e)));
         }
+        Ok(match answers.iter().find_map(|e| e.error.clone()) {
+            Some(e) if answers.iter().all(|e| e.error.is_some()) => TurnOutcome::Failed(e),
+            _ => TurnOutcome::Answered,
+        })
     }
 
-    pub async fn run(&mut self, context: &mut Context) -> anyhow::Result<()> {
-        let mut rl = RlHelper::new_rl()?;
-        let prompt = "🌟 ^D:".blue().bold().to_string();
+    /// Sends `rq_body` and streams the answer through the subscribers until it ends, breaks off or is cancelled.
+    /// A stream that fails midway leaves its error in `turn_error`.
+    async fn stream_answer(&mut self, context: &mut Context, rq_body: Value, retry: &RetryConfig) -> anyhow::Result<Streamed> {
+        log_request(&rq_body);
+        if let Some(ref recorder) = context.recorder {
+            recorder.request(&rq_body);
+        }
+
+        let started = Instant::now();
+        let mut stream = match client::stream_with_fallbacks(context.chat.as_ref(), rq_body, &context.fallbacks(), retry, &context.events).await {
+            Ok((stream, model)) => {
+                context.answered_by = Some(model);
+                stream
+            }
+            Err(e) => {
+                if let Some(ref recorder) = context.recorder {
+                    recorder.response(&ResponseLog::default(), false, Some(&e.to_string()));
+                }
+                return Err(e.into());
+            }
+        };
+
+        let interrupts = context.interrupts.clone();
+        let mut cancellation = interrupts.cancellation();
+        let mut streamed = Streamed::default();
+        let mut response = ResponseLog::default();
 
         loop {
-            for e in &self.pre_input_hooks { e.pre_input(context)? }
+            let result = tokio::select! {
+                _ = &mut cancellation => {
+                    streamed.cancelled = true;
+                    break;
+                }
+                result = stream.next() => match result {
+                    Some(result) => result,
+                    None => break,
+                },
+            };
+            if let Err(ref e) = result {
+                context.turn_error = Some(e.to_string());
+                self.bus.dispatch(context, &mut Event::StreamError(&e.to_string())).await?;
+            }
+            if let Ok(mut chunk) = result {
+                response.add(&chunk);
 
-            let mut user_input = rl.readline(&prompt)?.trim().to_string();
+                if !chunk.choices.is_empty() {
+                    chunk.choices[0].delta.content = context.filters.apply(&chunk.choices[0].delta.content);
+                    streamed.answer.push_str(chunk.choices[0].delta.content.as_str());
+                }
 
-            for e in &self.pre_call_hooks { e.pre_call(context, &mut user_input)? }
+                self.bus.dispatch(context, &mut Event::Chunk(&chunk)).await?;
+            }
+            // Dropping the stream closes the connection, which makes the provider stop generating.
+            if context.stop_stream {
+                self.bus.dispatch(context, &mut Event::Cancelled).await?;
+                break;
+            }
+        }
+        drop(cancellation);
+        response.log(started, streamed.cancelled, context.turn_error.as_deref());
+        if let Some(ref recorder) = context.recorder {
+            recorder.response(&response, streamed.cancelled, context.turn_error.as_deref());
+        }
+        streamed.tool_calls = response.has_tool_calls();
+        streamed.finish_reason = response.finish_reason();
+        Ok(streamed)
+    }
+}
 
-            context.manager.add(ChatCompletionRequestUserMessageArgs::default()
-                .content(user_input.as_str())
-                .build()?
-                .into());
+/// What a single response stream brought.
+#[derive(Debug, Default)]
+struct Streamed {
+    answer: String,
+    cancelled: bool,
+    tool_calls: bool,
+    finish_reason: Option<FinishReason>,
+}
+
+/// Tells the user about an answer the provider ended before it was done.
+fn report_stop(events: &EventSender, reason: Option<FinishReason>) {
+    if let Some(reason @ (FinishReason::Length | FinishReason::ContentFilter)) = reason {
+        events.emit(UiEvent::Stopped(reason));
+    }
+}
+
+/// Logs what is asked of which model, the whole body only at debug level.
+fn log_request(body: &Value) {
+    let count = |key: &str| body[key].as_array().map(Vec::len).unwrap_or_default();
+    tracing::info!(model = %body["model"].as_str().unwrap_or_default(), messages = count("messages"), tools = count("tools"), "request");
+    tracing::debug!(body = %logging::redact(body), "request body");
+}
 
-            let rq_body = context
-                .rq_body
-                .messages(context.manager.as_messages())
-                .build()?;
+/// The text alone, or the text followed by the images for a model that can see.
+fn user_message_content(text: &str, images: &[Image]) -> ChatCompletionRequestUserMessageContent {
+    if images.is_empty() {
+        return ChatCompletionRequestUserMessageContent::Text(text.to_string());
+    }
+    let mut parts = vec![ChatCompletionRequestUserMessageContentPart::Text(text.into())];
+    parts.extend(images.iter().map(|e| {
+        ChatCompletionRequestUserMessageContentPart::ImageUrl(ChatCompletionRequestMessageContentPartImage {
+            image_url: ImageUrl { url: e.url.clone(), detail: Some(ImageDetail::Auto) },
+        })
+    }));
+    ChatCompletionRequestUserMessageContent::Array(parts)
+}
+
+/// How a turn ended, what `rag -p` exits with.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum TurnOutcome {
+    Answered,
+    /// The input was used up by commands, nothing was asked.
+    Skipped,
+    Cancelled,
+    /// A response stream failed, the answer is likely incomplete.
+    Failed(String),
+}
+
+/// A required builder field that hasn't been set yet.
+pub struct Missing;
+
+/// Builds a `Processor` together with the `Context` it runs on, `build` only exists once the config and backend are set.
+pub(crate) struct ProcessorBuilder<C, B> {
+    config: C,
+    backend: B,
+    manager: Option<ContextManager>,
+    tools: Option<ToolRegistry>,
+    renderer: Option<Box<dyn Renderer>>,
+    chat: Option<Arc<dyn ChatClient>>,
+    default_hooks: bool,
+    bus: EventBus,
+}
+
+impl<C, B> ProcessorBuilder<C, B> {
+    pub fn with_config(self, config: Config) -> ProcessorBuilder<Config, B> {
+        ProcessorBuilder {
+            config,
+            backend: self.backend,
+            manager: self.manager,
+            tools: self.tools,
+            renderer: self.renderer,
+            chat: self.chat,
+            default_hooks: self.default_hooks,
+            bus: self.bus,
+        }
+    }
 
-            // println!("{}", serde_json::to_string_pretty(&rq_body)?);
+    pub fn with_backend(self, backend: Client<WithQuery>) -> ProcessorBuilder<C, Client<WithQuery>> {
+        ProcessorBuilder {
+            config: self.config,
+            backend,
+            manager: self.manager,
+            tools: self.tools,
+            renderer: self.renderer,
+            chat: self.chat,
+            default_hooks: self.default_hooks,
+            bus: self.bus,
+        }
+    }
 
-            let mut stream: Pin<Box<dyn Stream<Item = Result<Value, OpenAIError>>>> = context
-                .client
-                .chat()
-                .create_stream_byot(rq_body.to_rq_body())
-                .await?;
+    /// Subscribes the built-in commands, rendering and tool execution, at `PRIORITY_DEFAULT`.
+    pub fn with_default_hooks(mut self) -> Self {
+        self.default_hooks = true;
+        self
+    }
 
-            let mut answer = String::new();
+    /// Subscribes to the events of `kinds`, see `EventBus` for the order subscribers run in.
+    #[cfg(test)]
+    pub fn with_subscriber(mut self, kinds: &[EventKind], priority: i32, subscriber: Arc<dyn Subscriber>) -> Self {
+        self.bus.subscribe(kinds, priority, subscriber);
+        self
+    }
 
-            while let Some(result) = stream.next().await {
-                // println!("{:?}", result);
-                if let Ok(chunk) = result {
-                    let chunk = serde_json::from_value::<RsChunkBody>(chunk.clone())?;
+    /// Replaces the tools otherwise built from the config.
+    #[cfg(test)]
+    pub fn with_tools(mut self, tools: ToolRegistry) -> Self {
+        self.tools = Some(tools);
+        self
+    }
 
-                    if !chunk.choices.is_empty() {
-                        answer.push_str(chunk.choices[0].delta.content.as_str());
-                    }
+    /// Receives the events of the session instead of the terminal.
+    pub fn with_renderer(mut self, renderer: Box<dyn Renderer>) -> Self {
+        self.renderer = Some(renderer);
+        self
+    }
 
-                    for e in &self.post_call_hooks { e.post_call(context, &chunk)?; }
+    /// Sends the chat requests to `chat` instead of the backend, which still serves everything else.
+    pub fn with_chat_client(mut self, chat: Arc<dyn ChatClient>) -> Self {
+        self.chat = Some(chat);
+        self
+    }
+
+    /// Decides how much history is kept, ten messages by default.
+    pub fn with_context_policy(mut self, manager: ContextManager) -> Self {
+        self.manager = Some(manager);
+        self
+    }
+}
+
+impl ProcessorBuilder<Config, Client<WithQuery>> {
+    pub fn build(self) -> anyhow::Result<(Processor, Context)> {
+        let tools = match self.tools {
+            Some(tools) => tools,
+            None => ToolRegistry::new(&self.config)?,
+        };
+        let mut manager = self.manager.unwrap_or(ContextManager::new(10));
+        if let Some(ref prompt) = self.config.system_prompt {
+            manager.set_system(prompt);
+        }
+        let events = EventSender::spawn(self.renderer.unwrap_or_else(|| Box::new(TerminalRenderer::new())));
+        let hooks_dir = self.config.config_dir().join("hooks");
+        let mut context = Context::new(self.config, manager, self.backend, tools, events);
+        if let Some(chat) = self.chat {
+            context.chat = chat;
+        }
+        if let Some(config) = context.config.cache.as_ref().filter(|e| e.enabled) {
+            let ttl_secs = config.ttl_secs.unwrap_or(cache::DEFAULT_TTL_SECS);
+            match ResponseCache::open(&context.config.config_dir().join(cache::CACHE_DB), ttl_secs) {
+                Ok(cache) => {
+                    let cache = Arc::new(cache);
+                    context.chat = Arc::new(CachingClient::new(context.chat.clone(), cache.clone()));
+                    context.cache = Some(cache);
                 }
+                Err(e) => eprintln!("{}", format!("Warning: Failed to open the response cache: {}", e).yellow()),
             }
+        }
 
-            context.manager.add(ChatCompletionRequestAssistantMessageArgs::default()
-                .content(answer)
-                .build()?
-                .into());
-            for e in &self.pre_next_input_hooks { e.pre_next_input(context)?; }
+        let mut bus = self.bus;
+        let mut commands = vec![];
+        if self.default_hooks {
+            commands = Processor::add_default_hooks(&mut bus, &context.config);
+            Processor::add_script_hooks(&mut bus, &mut context, &hooks_dir)?;
+        }
+        if context.config.transcript.as_ref().is_some_and(|e| e.enabled) {
+            match Recorder::start(&context.config.config_dir()) {
+                Ok(recorder) => context.recorder = Some(Arc::new(recorder)),
+                Err(e) => eprintln!("{}", format!("Warning: Failed to start recording the session: {}", e).yellow()),
+            }
         }
+        let bus = Arc::new(bus);
+        context.bus = bus.clone();
+        Ok((Processor { bus, commands }, context))
     }
 }
 
-pub enum Hook {
-    PreInputHook(Rc<dyn PreInputHook>),
-    PreCallHook(Rc<dyn PreCallHook>),
-    PostCallHook(Rc<dyn PostCallHook>),
-    PreNextInputHook(Rc<dyn PreNextInputHook>),
+/// Model parameters for a single turn, given as `?? temp=1.2 max=400 question`.
+#[derive(Debug, Default, PartialEq)]
+struct TurnOverrides {
+    temperature: Option<f32>,
+    max_tokens: Option<u32>,
 }
 
-pub trait PreInputHook: Debug {
-    fn pre_input(&self, ctx: &mut Context) -> anyhow::Result<()>;
+#[derive(Debug)]
+struct InlineOverrides {
+    pattern: Regex,
 }
 
-#[derive(Debug)]
-struct InitPrompt;
+impl InlineOverrides {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"(?s)^\s*\?\?(?<rest>.*)$").unwrap(),
+        }
+    }
 
-impl PreInputHook for InitPrompt {
-    fn pre_input(&self, ctx: &mut Context) -> anyhow::Result<()> {
-        let init_prompt = "🚀 ^D: ";
-        print!("{}", init_prompt);
-        stdout().flush()?;
-        Ok(())
+    /// Splits the leading `key=value` overrides from the question, `None` if the input doesn't start with `??`.
+    fn parse(&self, input: &str) -> Option<(TurnOverrides, String)> {
+        let caps = self.pattern.captures(input)?;
+        let mut overrides = TurnOverrides::default();
+        let mut rest = caps["rest"].trim_start();
+
+        while let Some((key, value)) = rest.split_whitespace().next().and_then(|e| e.split_once('=')) {
+            match key {
+                "temp" | "temperature" => match value.parse() {
+                    Ok(e) => overrides.temperature = Some(e),
+                    Err(_) => eprintln!("{}", format!("Warning: {} isn't a valid temperature", value).yellow()),
+                },
+                "max" | "max_tokens" => match value.parse() {
+                    Ok(e) => overrides.max_tokens = Some(e),
+                    Err(_) => eprintln!("{}", format!("Warning: {} isn't a valid token limit", value).yellow()),
+                },
+                _ => break,
+            }
+            rest = rest[key.len() + 1 + value.len()..].trim_start();
+        }
+
+        Some((overrides, rest.to_string()))
     }
 }
 
-pub trait PreCallHook: Debug {
-    fn pre_call(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()>;
+#[async_trait]
+impl Subscriber for InlineOverrides {
+    async fn handle(&self, ctx: &mut Context, event: &mut Event<'_>) -> anyhow::Result<()> {
+        let Event::UserInput(input) = event else { return Ok(()) };
+        // Overrides only last for one turn, including the follow-up requests of its tool calls.
+        let sampling = &ctx.config.sampling;
+        ctx.rq_body.temperature(sampling.temperature).max_tokens(sampling.max_tokens).response_format(None);
+
+        let Some((overrides, question)) = self.parse(input) else { return Ok(()) };
+        ctx.rq_body
+            .temperature(overrides.temperature.or(sampling.temperature))
+            .max_tokens(overrides.max_tokens.or(sampling.max_tokens));
+        **input = question;
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
-struct CommandParser {
+pub(crate) struct CommandParser {
     commands: Vec<Box<dyn Command>>,
 }
 
 impl CommandParser {
-    pub fn new() -> Self {
+    /// The built-in commands and the `custom` ones from the config, a custom one that fails to load is reported and skipped.
+    pub fn new(custom: &BTreeMap<String, CustomCommandConfig>) -> Self {
         let mut parser = CommandParser {
             commands: vec![],
         };
 
         parser.register_command(Box::new(ExitCommand));
+        // Before `@file`, which would inline the prompt file into the input.
+        parser.register_command(Box::new(SystemPromptCommand::new()));
+        // Before the commands that insert content, so templates can use them.
+        parser.register_command(Box::new(TemplateCommand::new()));
+        for (name, config) in custom {
+            match CustomCommand::new(name, config) {
+                Ok(command) => parser.register_command(Box::new(command)),
+                Err(e) => eprintln!("{}", format!("Warning: Failed to load the command {}: {}", name, e).yellow()),
+            }
+        }
         parser.register_command(Box::new(FileCommand::new()));
+        parser.register_command(Box::new(ImageCommand::new()));
+        parser.register_command(Box::new(DirCommand::new()));
+        parser.register_command(Box::new(UrlCommand::new()));
         parser.register_command(Box::new(SystemCommand::new()));
-
+        parser.register_command(Box::new(OpenCommand::new()));
+        parser.register_command(Box::new(SaveCodeCommand::new()));
+        parser.register_command(Box::new(CopyCommand::new()));
+        parser.register_command(Box::new(ExportCommand::new()));
+        parser.register_command(Box::new(ScrollbackCommand::new()));
+        parser.register_command(Box::new(CommitCommand::new()));
+        parser.register_command(Box::new(EnvCommand::new()));
+        parser.register_command(Box::new(SetCommand::new()));
+        parser.register_command(Box::new(JsonCommand::new()));
+        parser.register_command(Box::new(DryCommand::new()));
+        parser.register_command(Box::new(ContinueCommand::new()));
+        parser.register_command(Box::new(CompareCommand::new()));
+        parser.register_command(Box::new(ModelCommand::new()));
+        parser.register_command(Box::new(ReasoningCommand::new()));
+        parser.register_command(Box::new(PersonaCommand::new()));
+        parser.register_command(Box::new(PsCommand::new()));
+        parser.register_command(Box::new(LogsCommand::new()));
+        // After the commands that expand input, so nothing pasted is run as one.
+        parser.register_command(Box::new(ClipCommand::new()));
+        parser.register_command(Box::new(TokensCommand::new()));
+        parser.register_command(Box::new(StatsCommand::new()));
+        parser.register_command(Box::new(ClearCommand::new()));
+        parser.register_command(Box::new(CacheCommand::new()));
+        parser.register_command(Box::new(UndoCommand::new()));
+        // Last, the input it resends has already been through the other commands.
+        parser.register_command(Box::new(RetryCommand::new()));
+
+        let help = HelpCommand::new(&parser.commands);
+        parser.register_command(Box::new(help));
         parser
     }
 
@@ -172,129 +667,182 @@ impl CommandParser {
     }
 }
 
-impl PreCallHook for CommandParser {
-    fn pre_call(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+#[async_trait]
+impl Subscriber for CommandParser {
+    async fn handle(&self, ctx: &mut Context, event: &mut Event<'_>) -> anyhow::Result<()> {
+        let Event::UserInput(input) = event else { return Ok(()) };
+        if ctx.plain_input {
+            return Ok(());
+        }
         for command in &self.commands {
             if command.is(input.as_str()) {
-                command.execute(input)?;
+                command.execute(ctx, input).await?;
             }
         }
         Ok(())
     }
 }
 
-trait Command: Debug {
+#[async_trait]
+trait Command: Debug + Send + Sync {
     fn is(&self, input: &str) -> bool;
 
-    fn execute(&self, input: &mut String) -> anyhow::Result<()>;
-}
-
-#[derive(Debug)]
-struct ExitCommand;
-
-impl Command for ExitCommand {
-    fn is(&self, input: &str) -> bool {
-        input.starts_with("@exit")
-    }
+    /// The syntax and a one-line description, listed by `@help`.
+    fn help(&self) -> (&str, &str);
 
-    fn execute(&self, _input: &mut String) -> anyhow::Result<()> {
-        println!("{}", "bye".yellow());
-        stdout().flush()?;
-        std::process::exit(0);
-    }
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()>;
 }
 
 #[derive(Debug)]
-struct FileCommand {
+struct HelpCommand {
     pattern: Regex,
+    entries: Vec<(String, String)>,
 }
 
-impl FileCommand {
-    pub fn new() -> Self {
-        Self {
-            pattern: Regex::new(r"@file\((?<path>[^)]+)\)").unwrap(),
-        }
+impl HelpCommand {
+    /// Lists `commands` and itself.
+    pub fn new(commands: &[Box<dyn Command>]) -> Self {
+        let mut help = Self {
+            pattern: Regex::new(r"^\s*@(help|commands)\s*$").unwrap(),
+            entries: vec![],
+        };
+        help.entries = commands.iter().map(|e| e.help()).chain([help.help()]).map(|(a, b)| (a.to_string(), b.to_string())).collect();
+        help
+    }
+
+    fn lines(&self) -> Vec<String> {
+        let width = self.entries.iter().map(|(syntax, _)| syntax.chars().count()).max().unwrap_or_default();
+        let mut lines = self.entries
+            .iter()
+            .map(|(syntax, description)| format!("{:<width$}  {}", syntax, description, width = width))
+            .collect::<Vec<_>>();
+        lines.push(format!("{:<width$}  {}", "??temp=0.2 max=500 question", "sampling overrides for a single question", width = width));
+        let multiline = format!("{} ... {} or line\\", MULTILINE_START, MULTILINE_END);
+        lines.push(format!("{:<width$}  {}", multiline, "write over several lines, until an empty line or `;;`, or continue a line with `\\`", width = width));
+        lines
     }
 }
 
-impl Command for FileCommand {
+#[async_trait]
+impl Command for HelpCommand {
     fn is(&self, input: &str) -> bool {
         self.pattern.is_match(input)
     }
 
-    fn execute(&self, input: &mut String) -> anyhow::Result<()> {
-        let result = self.pattern.replace_all(input.as_str(), |caps: &regex::Captures| {
-            let file_path = Path::new(&caps["path"]);
-            match fs::read_to_string(file_path) {
-                Ok(content) => format!("{}: {}", &caps["path"], content),
-                Err(e) => {
-                    eprintln!("{}", format!("Warning: Failed to read file {}: {}", &caps["path"], e).yellow());
-                    caps[0].to_string()
-                }
-            }
-        });
+    fn help(&self) -> (&str, &str) {
+        ("@help", "list the commands")
+    }
 
-        *input = result.to_string();
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        input.clear();
+        ctx.events.emit(UiEvent::Notice(self.lines().join("\n")));
         Ok(())
     }
 }
 
 #[derive(Debug)]
-struct SystemCommand {
+enum CustomAction {
+    Shell(String),
+    Template(String),
+}
+
+/// A command from the `commands` section of the config.
+#[derive(Debug)]
+struct CustomCommand {
+    syntax: String,
+    description: String,
     pattern: Regex,
+    action: CustomAction,
 }
 
-impl SystemCommand {
-    pub fn new() -> Self {
-        Self {
-            pattern: Regex::new(r"@`(?P<command>.*)`").unwrap(),
+impl CustomCommand {
+    pub fn new(name: &str, config: &CustomCommandConfig) -> anyhow::Result<Self> {
+        let action = match (&config.shell, &config.template) {
+            (Some(shell), None) => CustomAction::Shell(shell.clone()),
+            (None, Some(template)) => CustomAction::Template(template.clone()),
+            _ => anyhow::bail!("set either `shell` or `template`"),
+        };
+        let pattern = match config.pattern {
+            Some(ref pattern) => Regex::new(pattern)?,
+            None => Regex::new(&format!(r"@{}\b", regex::escape(name)))?,
+        };
+        let description = match (&config.description, &action) {
+            (Some(description), _) => description.clone(),
+            (None, CustomAction::Shell(shell)) => format!("insert the output of `{}`", shell),
+            (None, CustomAction::Template(_)) => "insert a template from the config".to_string(),
+        };
+        Ok(Self { syntax: format!("@{}", name), description, pattern, action })
+    }
+
+    /// The command or template of one match, the capture groups filled in. They are quoted for the shell.
+    fn render(&self, caps: &regex::Captures) -> anyhow::Result<String> {
+        let (text, quote) = match self.action {
+            CustomAction::Shell(ref shell) => (shell, true),
+            CustomAction::Template(ref template) => (template, false),
+        };
+        templates::render(text, |variable| {
+            let value = match variable.parse::<usize>() {
+                Ok(index) => caps.get(index),
+                Err(_) => caps.name(variable),
+            };
+            Ok(value.map(|e| if quote { shell_words::quote(e.as_str()).to_string() } else { e.as_str().to_string() }))
+        })
+    }
+
+    /// The text replacing a match rendered to `text`, the output of the shell command or the template as it is.
+    async fn expand(&self, text: String) -> anyhow::Result<String> {
+        match self.action {
+            CustomAction::Shell(_) => run_shell(&text).await,
+            CustomAction::Template(_) => Ok(text),
         }
     }
 }
-impl Command for SystemCommand {
+
+/// Runs `command` with the platform's shell and returns its output, failing with its stderr if it fails.
+async fn run_shell(command: &str) -> anyhow::Result<String> {
+    let output = if cfg!(target_os = "windows") {
+        tokio::process::Command::new("cmd").args(["/C", command]).output().await?
+    } else {
+        tokio::process::Command::new("sh").args(["-c", command]).output().await?
+    };
+    let decode = |bytes: &[u8]| match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => GBK.decode(bytes).0.to_string(),
+    };
+    if !output.status.success() {
+        anyhow::bail!("`{}` failed with {}: {}", command, output.status, decode(&output.stderr).trim());
+    }
+    Ok(decode(&output.stdout).trim_end().to_string())
+}
+
+#[async_trait]
+impl Command for CustomCommand {
     fn is(&self, input: &str) -> bool {
         self.pattern.is_match(input)
     }
 
-    fn execute(&self, input: &mut String) -> anyhow::Result<()> {
-        let result = self.pattern.replace_all(input.as_str(), |caps: &regex::Captures| {
-            if &caps[0] == "@`(?P<command>.*)`" { return caps[0].to_string(); }
-
-            let parts = shell_words::split(&caps["command"]).unwrap();
-            let (elf, args) = parts.split_first().unwrap();
-
-            let mut command = std::process::Command::new(elf);
-            let mut output = command
-                .args(args)
-                .output()
-                .expect("Failed to get command output");
+    fn help(&self) -> (&str, &str) {
+        (&self.syntax, &self.description)
+    }
 
-            if cfg!(target_os = "windows") {
-                println!("cmd /C {}", format!("\"{}\"", &caps["command"]));
-                command = std::process::Command::new("cmd");
-                output = command.arg("/C")
-                    .arg(format!("\"{}\"", &caps["command"]))
-                    .output()
-                    .expect("Failed to get command output");
-            }
-
-            if output.status.success() {
-                let stdout = match String::from_utf8(output.stdout.clone()) {
-                    Ok(inner) => inner,
-                    Err(_) => {
-                        GBK.decode(&output.stdout).0.to_string()
-                    }
-                };
-                stdout
-            } else {
-                let stderr = match String::from_utf8(output.stderr.clone()) {
-                    Ok(inner) => inner,
-                    Err(_) => GBK.decode(&output.stderr).0.to_string(),
-                };
-                let exit_code = output.status.code().unwrap_or(-1);
-                eprintln!("{}", format!("Warning: Command {}, failed with exit code {}: {}", &caps["command"], exit_code, stderr));
-                caps[0].to_string()
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        // Expanded one after another before anything is replaced, a match that fails stays as it is.
+        let rendered = self.pattern.captures_iter(input).map(|caps| self.render(&caps)).collect::<Vec<_>>();
+        let mut expanded = vec![];
+        for text in rendered {
+            let text = match text {
+                Ok(text) => self.expand(text).await,
+                Err(e) => Err(e),
+            };
+            if let Err(ref e) = text {
+                ctx.events.emit(UiEvent::Warning(format!("{} failed: {}", self.syntax, e)));
             }
+            expanded.push(text.ok());
+        }
+
+        let mut expanded = expanded.into_iter();
+        let result = self.pattern.replace_all(input.as_str(), |caps: &regex::Captures| {
+            expanded.next().flatten().unwrap_or_else(|| caps[0].to_string())
         });
         *input = result.to_string();
         Ok(())
@@ -302,193 +850,2787 @@ impl Command for SystemCommand {
 }
 
 #[derive(Debug)]
-struct AnswerPrompt;
+struct ExitCommand;
 
-impl PreCallHook for AnswerPrompt {
-    fn pre_call(&self, ctx: &mut Context, _input: &mut String) -> anyhow::Result<()> {
-        let prompt = format!("🤖 {}: ", &ctx.config.model);
-        print!("{}", prompt);
-        stdout().flush()?;
-        Ok(())
+#[async_trait]
+impl Command for ExitCommand {
+    fn is(&self, input: &str) -> bool {
+        input.starts_with("@exit")
     }
-}
 
-pub trait PreNextInputHook: Debug {
-    fn pre_next_input(&self, ctx: &mut Context) -> anyhow::Result<()>;
-}
+    fn help(&self) -> (&str, &str) {
+        ("@exit", "quit rag")
+    }
 
-pub trait PostCallHook: Debug {
-    fn post_call(&self, ctx: &mut Context, chunk: &RsChunkBody) -> anyhow::Result<()>;
+    async fn execute(&self, ctx: &mut Context, _input: &mut String) -> anyhow::Result<()> {
+        ctx.events.emit(UiEvent::Notice("bye".to_string()));
+        ctx.events.flush();
+        std::process::exit(0);
+    }
 }
 
 #[derive(Debug)]
-struct ReasoningCollector;
-
-impl PostCallHook for ReasoningCollector {
-    fn post_call(&self, _ctx: &mut Context, chunk: &RsChunkBody) -> anyhow::Result<()> {
-        let mut lock = stdout().lock();
+struct SystemPromptCommand {
+    pattern: Regex,
+    file_pattern: Regex,
+}
 
-        if chunk.choices.is_empty() {
-            return Ok(());
+impl SystemPromptCommand {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"(?s)^\s*@system\b\s*(?<prompt>.*?)\s*$").unwrap(),
+            file_pattern: Regex::new(r"^@file\((?<path>[^)]+)\)$").unwrap(),
         }
+    }
 
-        if let Some(ref content) = chunk.choices[0].delta.reasoning_content {
-            write!(lock, "{}", format!("{}", content).truecolor(128, 138, 135)).expect("Failed to write reasoning message");
+    /// The prompt itself, or the contents of the file given as `@file(path)`.
+    fn prompt(&self, prompt: &str) -> anyhow::Result<String> {
+        match self.file_pattern.captures(prompt) {
+            Some(caps) => Ok(fs::read_to_string(&caps["path"])?.trim().to_string()),
+            None => Ok(prompt.to_string()),
         }
-
-        stdout().flush()?;
-        Ok(())
     }
 }
 
-#[derive(Debug)]
-struct ContentCollector;
-
-impl PostCallHook for ContentCollector {
-    fn post_call(&self, _ctx: &mut Context, chunk: &RsChunkBody) -> anyhow::Result<()> {
-        let mut lock = stdout().lock();
-
-        if chunk.choices.is_empty() {
-            return Ok(());
-        }
-
-        let content = &chunk.choices[0].delta.content;
-        write!(lock, "{}", content).expect("Failed to write content message");
+#[async_trait]
+impl Command for SystemPromptCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
 
-        stdout().flush()?;
-        Ok(())
+    fn help(&self) -> (&str, &str) {
+        ("@system [text | @file(path)]", "replace the system prompt for the session, or show it")
     }
-}
 
-#[derive(Debug)]
-struct NewLine;
+    /// `@system <text>` replaces the pinned system message, `@system @file(path)` reads it from a file
+    /// and a bare `@system` prints it.
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        let prompt = self.pattern.captures(input).map(|caps| caps["prompt"].to_string()).unwrap_or_default();
+        input.clear();
 
-impl PreNextInputHook for NewLine {
-    fn pre_next_input(&self, _ctx: &mut Context) -> anyhow::Result<()> {
-        println!();
-        stdout().flush()?;
+        if prompt.is_empty() {
+            match ctx.manager.system() {
+                Some(system) => ctx.events.emit(UiEvent::Notice(system.to_string())),
+                None => ctx.events.emit(UiEvent::Notice("No system prompt set".to_string())),
+            }
+            return Ok(());
+        }
+        match self.prompt(&prompt) {
+            Ok(prompt) => {
+                ctx.manager.set_system(&prompt);
+                ctx.events.emit(UiEvent::Notice("Replaced the system prompt".to_string()));
+            }
+            Err(e) => ctx.events.emit(UiEvent::Warning(format!("Failed to read the system prompt: {}", e))),
+        }
         Ok(())
     }
 }
 
 #[derive(Debug)]
-struct TokenTracer {
-    token_usage: RefCell<u64>,
+struct TemplateCommand {
+    pattern: Regex,
 }
 
-impl TokenTracer {
+impl TemplateCommand {
     pub fn new() -> Self {
         Self {
-            token_usage: RefCell::new(0),
+            pattern: Regex::new(r"@tpl\((?<name>[^,)]+)(?<vars>(,[^,)]*)*)\)").unwrap(),
         }
     }
-}
 
-impl PostCallHook for TokenTracer {
-    fn post_call(&self, _ctx: &mut Context, chunk: &RsChunkBody) -> anyhow::Result<()> {
-        if let Some(usage) = &chunk.usage {
-            *self.token_usage.borrow_mut() += usage.total_tokens;
-        }
-        Ok(())
+    fn expand(ctx: &Context, name: &str, vars: &str) -> anyhow::Result<String> {
+        let vars = templates::parse_vars(vars.split(',').map(str::trim).filter(|e| !e.is_empty()))?;
+        Templates::new(&ctx.config.config_dir()).expand(name, &vars)
     }
 }
 
-impl PreNextInputHook for TokenTracer {
-    fn pre_next_input(&self, _ctx: &mut Context) -> anyhow::Result<()> {
-        let mut lock = stdout().lock();
-        write!(lock, "{}", format!("\ntoken usage: {}", *self.token_usage.borrow_mut()).truecolor(128, 138, 135))?;
+#[async_trait]
+impl Command for TemplateCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@tpl(name, key=value, ...)", "insert a prompt template with its variables filled in")
+    }
+
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        let result = self.pattern.replace_all(input.as_str(), |caps: &regex::Captures| {
+            match Self::expand(ctx, caps["name"].trim(), &caps["vars"]) {
+                Ok(prompt) => prompt,
+                Err(e) => {
+                    ctx.events.emit(UiEvent::Warning(format!("Failed to expand template {}: {}", caps["name"].trim(), e)));
+                    caps[0].to_string()
+                }
+            }
+        });
+
+        *input = result.to_string();
         Ok(())
     }
 }
 
 #[derive(Debug)]
-struct ToolsExecutor {
-    tools_call: RefCell<HashMap<u32, (String, String)>>
+struct FileCommand {
+    pattern: Regex,
 }
 
-impl ToolsExecutor {
+impl FileCommand {
     pub fn new() -> Self {
         Self {
-            tools_call: RefCell::new(HashMap::new()),
+            pattern: Regex::new(r"@file\((?<path>[^)]+)\)").unwrap(),
         }
     }
 }
 
-impl PostCallHook for ToolsExecutor {
-    fn post_call(&self, _ctx: &mut Context, chunk: &RsChunkBody) -> anyhow::Result<()> {
-        if chunk.choices.is_empty() { return Ok(()); }
-        if let Some(ref tool_calls) = chunk.choices[0].delta.tool_calls {
-            for tool_call in tool_calls {
-                if let Some(ref function) = tool_call.function {
-                    if let Some(ref name) = function.name {
-                        self.tools_call.borrow_mut().insert(tool_call.index, (name.to_owned(), String::new()));
-                    }
-                    if let Some(ref arguments) = function.arguments {
-                        self.tools_call
-                            .borrow_mut()
-                            .entry(tool_call.index)
-                            .and_modify(|(_, tool_arguments)| {
-                                tool_arguments.push_str(arguments.as_str());
-                            });
-                    }
-                }
-            }
-        }
+#[async_trait]
+impl Command for FileCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
 
-        Ok(())
+    fn help(&self) -> (&str, &str) {
+        ("@file(path[:10-80] | dir/ | glob)", "insert a file or some of its lines, the files under a directory or matching a glob")
     }
-}
 
-impl PreNextInputHook for ToolsExecutor {
-    fn pre_next_input(&self, ctx: &mut Context) -> anyhow::Result<()> {
-        if self.tools_call.borrow().is_empty() {
-            return Ok(());
+    /// `@file(path)` inserts a file, `@file(path:10-80)` its lines 10 to 80, `@file(dir/)` every file under the directory
+    /// and `@file(src/**/*.rs)` every matching file, the ones git or `.ragignore` ignore left out. Binary files are refused.
+    /// A file over `file_token_budget` is cut down to the parts the rest of the input asks about.
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        let budget = ctx.config.file_token_budget.unwrap_or(DEFAULT_TOKEN_BUDGET);
+        let question = self.pattern.replace_all(input.as_str(), "").to_string();
+        let result = self.pattern.replace_all(input.as_str(), |caps: &regex::Captures| {
+            match includes::include(&caps["path"], budget, &question) {
+                Ok(content) => content,
+                Err(e) => {
+                    ctx.events.emit(UiEvent::Warning(format!("Failed to read file {}: {}", &caps["path"], e)));
+                    caps[0].to_string()
+                }
+            }
+        });
+
+        *input = result.to_string();
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct ImageCommand {
+    pattern: Regex,
+}
+
+impl ImageCommand {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"@image\((?<source>[^)]+)\)").unwrap(),
+        }
+    }
+}
+
+#[async_trait]
+impl Command for ImageCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@image(path or url)", "attach an image to the question, for models that can see")
+    }
+
+    /// Takes `@image(...)` out of the input and attaches the image to the next question, this one if it has text.
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        let result = self.pattern.replace_all(input.as_str(), |caps: &regex::Captures| {
+            match Image::load(caps["source"].trim()) {
+                Ok(image) => {
+                    ctx.images.push(image);
+                    String::new()
+                }
+                Err(e) => {
+                    ctx.events.emit(UiEvent::Warning(format!("Failed to attach {}: {}", caps["source"].trim(), e)));
+                    caps[0].to_string()
+                }
+            }
+        });
+
+        *input = result.to_string();
+        if input.trim().is_empty() && !ctx.images.is_empty() {
+            ctx.events.emit(UiEvent::Notice(format!("{} images attached to the next question", ctx.images.len())));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct DirCommand {
+    pattern: Regex,
+}
+
+impl DirCommand {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"@dir\((?<path>[^)]*)\)").unwrap(),
+        }
+    }
+}
+
+#[async_trait]
+impl Command for DirCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@dir(path)", "insert the file tree of a directory with the file sizes")
+    }
+
+    /// `@dir(path)` inserts the tree of the directory, the working directory for `@dir()`.
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        let result = self.pattern.replace_all(input.as_str(), |caps: &regex::Captures| {
+            let path = match caps["path"].trim() {
+                "" => ".",
+                path => path,
+            };
+            match includes::tree(path) {
+                Ok(tree) => {
+                    let fence = includes::fence(&tree);
+                    format!("\n{}\n{}{}\n", fence, tree, fence)
+                }
+                Err(e) => {
+                    ctx.events.emit(UiEvent::Warning(format!("Failed to list {}: {}", path, e)));
+                    caps[0].to_string()
+                }
+            }
+        });
+
+        *input = result.to_string();
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct ClipCommand {
+    pattern: Regex,
+}
+
+/// What the clipboard holds.
+enum Clip {
+    Text(String),
+    Image(Image),
+}
+
+impl ClipCommand {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"@clip\b").unwrap(),
+        }
+    }
+
+    /// The text on the clipboard, or the image when there's no text.
+    fn read() -> anyhow::Result<Clip> {
+        let mut clipboard = arboard::Clipboard::new()?;
+        if let Ok(text) = clipboard.get_text() {
+            return Ok(Clip::Text(text));
+        }
+        let image = clipboard.get_image().map_err(|_| anyhow::anyhow!("The clipboard holds neither text nor an image"))?;
+        Ok(Clip::Image(Image::from_rgba("clipboard", image.width as u32, image.height as u32, &image.bytes)?))
+    }
+}
+
+#[async_trait]
+impl Command for ClipCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@clip", "insert the clipboard, an image is attached for models that can see")
+    }
+
+    /// Replaces `@clip` with the clipboard text in a fence, an image on the clipboard is attached like `@image` does.
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        match tokio::task::spawn_blocking(Self::read).await? {
+            Ok(clip) => self.insert(ctx, input, clip),
+            Err(e) => ctx.events.emit(UiEvent::Warning(format!("Failed to read the clipboard: {}", e))),
+        }
+        Ok(())
+    }
+}
+
+impl ClipCommand {
+    fn insert(&self, ctx: &mut Context, input: &mut String, clip: Clip) {
+        let replacement = match clip {
+            Clip::Text(text) => {
+                let fence = includes::fence(&text);
+                format!("\n{}\n{}\n{}\n", fence, text.trim_end(), fence)
+            }
+            Clip::Image(image) => {
+                ctx.images.push(image);
+                String::new()
+            }
+        };
+
+        *input = self.pattern.replace_all(input.as_str(), regex::NoExpand(&replacement)).to_string();
+    }
+}
+
+#[derive(Debug)]
+struct UrlCommand {
+    pattern: Regex,
+    client: reqwest::Client,
+}
+
+impl UrlCommand {
+    /// Pages are cut off at roughly this many tokens, assuming ~4 characters per token.
+    const MAX_TOKENS: usize = 8_000;
+
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"@url\((?<url>https?://[^)\s]+)\)").unwrap(),
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(30))
+                .user_agent(BROWSER_USER_AGENT)
+                .build()
+                .unwrap(),
+        }
+    }
+
+    async fn fetch(&self, url: &str) -> anyhow::Result<String> {
+        let response = self.client.get(url).send().await?.error_for_status()?;
+        let is_html = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|e| e.to_str().ok())
+            .is_some_and(|e| e.contains("html"));
+        let body = response.text().await?;
+
+        let content = if is_html { html_to_markdown(&body) } else { body };
+        let max_chars = Self::MAX_TOKENS * 4;
+        Ok(match content.char_indices().nth(max_chars) {
+            Some((end, _)) => format!("{}\n[truncated]", &content[..end]),
+            None => content,
+        })
+    }
+}
+
+#[async_trait]
+impl Command for UrlCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@url(https://...)", "insert a web page as markdown")
+    }
+
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        // Fetched one after another before anything is replaced, a page that fails keeps its `@url(...)`.
+        let mut pages = HashMap::new();
+        for url in self.pattern.captures_iter(input).map(|e| e["url"].to_string()).collect::<Vec<_>>() {
+            if pages.contains_key(&url) {
+                continue;
+            }
+            let page = self.fetch(&url).await;
+            if let Err(ref e) = page {
+                ctx.events.emit(UiEvent::Warning(format!("Failed to fetch {}: {}", url, e)));
+            }
+            pages.insert(url, page.ok());
+        }
+
+        let result = self.pattern.replace_all(input.as_str(), |caps: &regex::Captures| match pages.get(&caps["url"]) {
+            Some(Some(content)) => format!("{}:\n{}\n", &caps["url"], content),
+            _ => caps[0].to_string(),
+        });
+        *input = result.to_string();
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct SystemCommand {
+    pattern: Regex,
+}
+
+impl SystemCommand {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"@`(?P<command>.*)`").unwrap(),
+        }
+    }
+}
+#[async_trait]
+impl Command for SystemCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@`command`", "insert the output of a shell command")
+    }
+
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        // Run one after another before anything is replaced, a command that fails keeps its match.
+        let commands = self.pattern.captures_iter(input)
+            .map(|caps| (&caps[0] != "@`(?P<command>.*)`").then(|| caps["command"].to_string()))
+            .collect::<Vec<_>>();
+        let mut outputs = vec![];
+        for command in commands {
+            let Some(command) = command else {
+                outputs.push(None);
+                continue;
+            };
+            if cfg!(target_os = "windows") {
+                ctx.events.emit(UiEvent::Notice(format!("cmd /C \"{}\"", command)));
+            }
+            let output = match Self::output(&command).await {
+                Ok(output) => Some(output),
+                Err(e) => {
+                    ctx.events.emit(UiEvent::Warning(format!("Command {} failed: {}", command, e)));
+                    None
+                }
+            };
+            outputs.push(output);
+        }
+
+        let mut outputs = outputs.into_iter();
+        let result = self.pattern.replace_all(input.as_str(), |caps: &regex::Captures| {
+            outputs.next().flatten().unwrap_or_else(|| caps[0].to_string())
+        });
+        *input = result.to_string();
+        Ok(())
+    }
+}
+
+impl SystemCommand {
+    /// The stdout of `command`, run through `cmd /C` on Windows, where most commands are built into the shell.
+    async fn output(command: &str) -> anyhow::Result<String> {
+        let output = if cfg!(target_os = "windows") {
+            tokio::process::Command::new("cmd").arg("/C").arg(format!("\"{}\"", command)).output().await?
+        } else {
+            let parts = shell_words::split(command)?;
+            let Some((program, args)) = parts.split_first() else { anyhow::bail!("there is no program") };
+            tokio::process::Command::new(program).args(args).output().await?
+        };
+
+        let decode = |bytes: &[u8]| match String::from_utf8(bytes.to_vec()) {
+            Ok(inner) => inner,
+            Err(_) => GBK.decode(bytes).0.to_string(),
+        };
+        if !output.status.success() {
+            anyhow::bail!("exit code {}: {}", output.status.code().unwrap_or(-1), decode(&output.stderr));
+        }
+        Ok(decode(&output.stdout))
+    }
+}
+
+#[derive(Debug)]
+struct OpenCommand {
+    pattern: Regex,
+    code_blocks: CodeBlocks,
+}
+
+impl OpenCommand {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"@open\b(\((?<block>\d+)\))?").unwrap(),
+            code_blocks: CodeBlocks::new(),
+        }
+    }
+
+    fn editor(ctx: &Context) -> String {
+        ctx.config.editor
+            .clone()
+            .or(std::env::var("VISUAL").ok())
+            .or(std::env::var("EDITOR").ok())
+            .unwrap_or("vi".to_string())
+    }
+
+    /// The last answer, or its `block`-th fenced code block (1-based) together with a file extension for it.
+    fn select(&self, answer: &str, block: Option<usize>) -> anyhow::Result<(String, String)> {
+        let Some(block) = block else { return Ok((answer.to_string(), "md".to_string())) };
+
+        let block = self.code_blocks
+            .extract(answer)
+            .into_iter()
+            .nth(block.saturating_sub(1))
+            .ok_or(anyhow::anyhow!("The last answer has no code block {}", block))?;
+        Ok((block.code.clone(), block.extension().to_string()))
+    }
+
+    /// Opens `content` in the editor and returns the edited text, or `None` when it came back unchanged.
+    fn edit(&self, ctx: &Context, content: &str, extension: &str) -> anyhow::Result<Option<String>> {
+        let path = std::env::temp_dir().join(format!("rag-answer-{}.{}", std::process::id(), extension));
+        fs::write(&path, content)?;
+
+        let editor = Self::editor(ctx);
+        let parts = shell_words::split(&editor)?;
+        let (program, args) = parts.split_first().ok_or(anyhow::anyhow!("Editor command is empty"))?;
+        // The TUI steps aside for the editor like it does for a confirmation.
+        let status = guard::on_terminal(|| Ok(std::process::Command::new(program).args(args).arg(&path).status()?))?;
+        if !status.success() {
+            anyhow::bail!("{} exited with {}", editor, status);
+        }
+
+        let edited = fs::read_to_string(&path)?;
+        let _ = fs::remove_file(&path);
+        Ok((edited.trim_end() != content.trim_end()).then_some(edited))
+    }
+}
+
+#[async_trait]
+impl Command for OpenCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@open[(n)]", "edit the last answer, or its n-th code block, and send it along")
+    }
+
+    /// Replaces `@open` with the edited answer so it's sent along, an unchanged answer is dropped.
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        let Some(caps) = self.pattern.captures(input) else { return Ok(()) };
+        let range = caps.get(0).unwrap().range();
+        let block = caps.name("block").and_then(|e| e.as_str().parse().ok());
+
+        let replacement = match ctx.manager.last_answer() {
+            None => {
+                ctx.events.emit(UiEvent::Warning("There is no answer to open yet".to_string()));
+                String::new()
+            }
+            Some(answer) => match self.select(&answer, block).and_then(|(content, extension)| self.edit(ctx, &content, &extension)) {
+                Ok(edited) => edited.unwrap_or_default(),
+                Err(e) => {
+                    ctx.events.emit(UiEvent::Warning(format!("Failed to open the answer: {}", e)));
+                    String::new()
+                }
+            },
+        };
+
+        input.replace_range(range, &replacement);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct SaveCodeCommand {
+    pattern: Regex,
+    code_blocks: CodeBlocks,
+}
+
+impl SaveCodeCommand {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"^\s*@savecode(\s+(?<block>\d+))?(\s+(?<path>\S+))?\s*$").unwrap(),
+            code_blocks: CodeBlocks::new(),
+        }
+    }
+
+    fn save(&self, answer: &str, block: Option<usize>, path: Option<&str>) -> anyhow::Result<Vec<String>> {
+        let blocks = self.code_blocks.extract(answer);
+        if blocks.is_empty() {
+            anyhow::bail!("The last answer has no code blocks");
+        }
+
+        let selected = match (block, path) {
+            (None, None) => blocks.iter().enumerate().collect::<Vec<_>>(),
+            (block, _) => {
+                let index = block.unwrap_or(1).saturating_sub(1);
+                let code = blocks.get(index).ok_or(anyhow::anyhow!("The last answer has no code block {}", index + 1))?;
+                vec![(index, code)]
+            }
+        };
+
+        selected
+            .into_iter()
+            .map(|(index, block)| {
+                let path = path
+                    .map(str::to_string)
+                    .unwrap_or_else(|| format!("snippet-{}.{}", index + 1, block.extension()));
+                fs::write(&path, &block.code)?;
+                Ok(path)
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl Command for SaveCodeCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@savecode [n] [path]", "save the code blocks of the last answer to files")
+    }
+
+    /// `@savecode [n] [path]` writes the code blocks of the last answer to files, named after their language by default.
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        let Some(caps) = self.pattern.captures(input) else { return Ok(()) };
+        let block = caps.name("block").and_then(|e| e.as_str().parse().ok());
+        let path = caps.name("path").map(|e| e.as_str().to_string());
+        input.clear();
+
+        let Some(answer) = ctx.manager.last_answer() else {
+            ctx.events.emit(UiEvent::Warning("There is no answer to save code from yet".to_string()));
+            return Ok(());
+        };
+        match self.save(&answer, block, path.as_deref()) {
+            Ok(paths) => ctx.events.emit(UiEvent::Notice(paths.iter().map(|e| format!("Saved {}", e)).collect::<Vec<_>>().join("\n"))),
+            Err(e) => ctx.events.emit(UiEvent::Warning(format!("Failed to save code: {}", e))),
+        }
+        Ok(())
+    }
+}
+
+struct CopyCommand {
+    pattern: Regex,
+    code_blocks: CodeBlocks,
+    /// Kept for the whole session, on X11 the copied text is gone once the clipboard that holds it is dropped.
+    clipboard: Mutex<Option<arboard::Clipboard>>,
+}
+
+impl Debug for CopyCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CopyCommand").field("pattern", &self.pattern).finish()
+    }
+}
+
+impl CopyCommand {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"^\s*@copy(?<code>\s+code(\s+(?<block>\d+))?)?\s*$").unwrap(),
+            code_blocks: CodeBlocks::new(),
+            clipboard: Mutex::new(None),
+        }
+    }
+
+    /// The answer, or its `block`-th code block (1-based), with a description of what was picked.
+    fn select(&self, answer: &str, block: Option<usize>) -> anyhow::Result<(String, String)> {
+        let Some(block) = block else { return Ok((answer.to_string(), "the last answer".to_string())) };
+        let code = self.code_blocks
+            .extract(answer)
+            .into_iter()
+            .nth(block.saturating_sub(1))
+            .ok_or(anyhow::anyhow!("The last answer has no code block {}", block))?;
+        Ok((code.code, format!("code block {}", block)))
+    }
+
+    fn copy(&self, text: String) -> anyhow::Result<()> {
+        let mut clipboard = self.clipboard.lock().unwrap();
+        let clipboard = match *clipboard {
+            Some(ref mut clipboard) => clipboard,
+            None => clipboard.insert(arboard::Clipboard::new()?),
+        };
+        clipboard.set_text(text)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Command for CopyCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@copy [code [n]]", "copy the last answer, or one of its code blocks, to the clipboard")
+    }
+
+    /// `@copy` copies the last answer to the clipboard, `@copy code [n]` its n-th code block, the first by default.
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        let Some(caps) = self.pattern.captures(input) else { return Ok(()) };
+        let block = caps.name("code").map(|_| caps.name("block").and_then(|e| e.as_str().parse().ok()).unwrap_or(1));
+        input.clear();
+
+        let Some(answer) = ctx.manager.last_answer() else {
+            ctx.events.emit(UiEvent::Warning("There is no answer to copy yet".to_string()));
+            return Ok(());
+        };
+        match self.select(&answer, block).and_then(|(text, what)| self.copy(text).map(|_| what)) {
+            Ok(what) => ctx.events.emit(UiEvent::Notice(format!("Copied {}", what))),
+            Err(e) => ctx.events.emit(UiEvent::Warning(format!("Failed to copy: {}", e))),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct ExportCommand {
+    pattern: Regex,
+}
+
+impl ExportCommand {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"^\s*@export\s+(?<path>.+?)\s*$").unwrap(),
+        }
+    }
+}
+
+#[async_trait]
+impl Command for ExportCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@export <path>", "export the conversation as markdown, or JSON for a .json path")
+    }
+
+    /// `@export <path>` writes the conversation so far as JSON for a `.json` path and as markdown otherwise.
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        let Some(caps) = self.pattern.captures(input) else { return Ok(()) };
+        let path = Path::new(&caps["path"]).to_path_buf();
+        input.clear();
+
+        match ctx.transcript.to_session(&ctx.config.model).export(&path) {
+            Ok(()) => ctx.events.emit(UiEvent::Notice(format!("Exported to {}", path.display()))),
+            Err(e) => ctx.events.emit(UiEvent::Warning(format!("Failed to export: {}", e))),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct ScrollbackCommand {
+    pattern: Regex,
+}
+
+impl ScrollbackCommand {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"^\s*@scrollback(\s+(?<n>\d+))?\s*$").unwrap(),
+        }
+    }
+}
+
+#[async_trait]
+impl Command for ScrollbackCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@scrollback [n]", "reprint the last n exchanges, or the whole session")
+    }
+
+    /// Reprints the last `n` exchanges, or the whole session, and consumes the input.
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        let n = self.pattern
+            .captures(input)
+            .and_then(|caps| caps.name("n").and_then(|e| e.as_str().parse().ok()));
+
+        ctx.events.emit(UiEvent::Notice(ctx.transcript.render(n, &ctx.config.model)));
+        input.clear();
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct CommitCommand {
+    pattern: Regex,
+}
+
+impl CommitCommand {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"^\s*@commit\b(?<hint>.*)$").unwrap(),
+        }
+    }
+}
+
+#[async_trait]
+impl Command for CommitCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@commit [hint]", "write a commit message for the staged changes and commit them")
+    }
+
+    /// Turns the input into a request to describe the staged diff and commit it through `git_commit`,
+    /// which asks the user before it runs. Anything after `@commit` is passed along as a hint.
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        let hint = self.pattern
+            .captures(input)
+            .map(|caps| caps["hint"].trim().to_string())
+            .unwrap_or_default();
+        let dir = match ctx.config.sandbox_root {
+            Some(ref root) => root.clone(),
+            None => std::env::current_dir()?,
+        };
+
+        let env = ctx.tools.env.clone();
+        let diff = match tokio::task::spawn_blocking(move || git(&dir, &env, &["diff", "--staged", "--no-color"])).await? {
+            Ok(diff) if diff.trim().is_empty() => {
+                ctx.events.emit(UiEvent::Warning("Nothing is staged, `git add` the changes to commit first".to_string()));
+                input.clear();
+                return Ok(());
+            }
+            Ok(diff) => diff,
+            Err(e) => {
+                ctx.events.emit(UiEvent::Warning(format!("{}", e)));
+                input.clear();
+                return Ok(());
+            }
+        };
+        let (diff, truncated) = truncate_diff(diff);
+
+        *input = format!(
+            "Write a Conventional Commits message (`type(scope): summary`, a blank line, then a short body if needed) for the staged changes below{}, then commit them by calling git_commit with it.{}\n\n```diff\n{}```",
+            if truncated { " (the diff was truncated)" } else { "" },
+            if hint.is_empty() { String::new() } else { format!(" Keep in mind: {}", hint) },
+            diff,
+        );
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct EnvCommand {
+    pattern: Regex,
+}
+
+impl EnvCommand {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"^\s*@env\b(?<assignments>.*)$").unwrap(),
+        }
+    }
+}
+
+#[async_trait]
+impl Command for EnvCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@env [KEY=value ...]", "set, unset (KEY=) or list environment variables for the tools")
+    }
+
+    /// `@env KEY=value ...` sets variables for the tools' subprocesses, `KEY=` unsets one and a bare `@env` lists them.
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        let assignments = self.pattern
+            .captures(input)
+            .map(|caps| caps["assignments"].to_string())
+            .unwrap_or_default();
+        input.clear();
+
+        let assignments = match shell_words::split(&assignments) {
+            Ok(assignments) => assignments,
+            Err(e) => {
+                ctx.events.emit(UiEvent::Warning(format!("Failed to parse @env: {}", e)));
+                return Ok(());
+            }
+        };
+        if assignments.is_empty() {
+            let vars = ctx.tools.env.describe();
+            if vars.is_empty() {
+                ctx.events.emit(UiEvent::Notice("No session environment variables set".to_string()));
+            } else {
+                ctx.events.emit(UiEvent::Notice(vars.join("\n")));
+            }
+            return Ok(());
+        }
+
+        for assignment in assignments {
+            let Some((name, value)) = assignment.split_once('=') else {
+                ctx.events.emit(UiEvent::Warning(format!("Expected KEY=value, got {}", assignment)));
+                continue;
+            };
+            if value.is_empty() {
+                ctx.tools.env.remove(name);
+            } else if let Err(e) = ctx.tools.env.set(name, value) {
+                ctx.events.emit(UiEvent::Warning(format!("{}", e)));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct SetCommand {
+    pattern: Regex,
+}
+
+impl SetCommand {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"^\s*@set\b(?<assignments>.*)$").unwrap(),
+        }
+    }
+}
+
+#[async_trait]
+impl Command for SetCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@set [key=value ...]", "set, unset (key=none) or list sampling parameters")
+    }
+
+    /// `@set temperature=0.2 stop=END ...` changes the sampling parameters for the session, `key=none` unsets one
+    /// and a bare `@set` lists them.
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        let assignments = self.pattern
+            .captures(input)
+            .map(|caps| caps["assignments"].to_string())
+            .unwrap_or_default();
+        input.clear();
+
+        for assignment in assignments.split_whitespace() {
+            let result = match assignment.split_once('=') {
+                Some((key, value)) => ctx.config.sampling.set(key, value),
+                None => Err(anyhow::anyhow!("Expected key=value, got {}", assignment)),
+            };
+            if let Err(e) = result {
+                ctx.events.emit(UiEvent::Warning(format!("{}", e)));
+            }
+        }
+        ctx.rq_body.sampling(&ctx.config.sampling);
+
+        let parameters = ctx.config.sampling.describe();
+        if parameters.is_empty() {
+            ctx.events.emit(UiEvent::Notice("No sampling parameters set, the provider's defaults apply".to_string()));
+        } else {
+            ctx.events.emit(UiEvent::Notice(parameters.join("\n")));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct ModelCommand {
+    pattern: Regex,
+}
+
+impl ModelCommand {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"^\s*@model\b\s*(?<model>\S*)\s*$").unwrap(),
+        }
+    }
+}
+
+#[async_trait]
+impl Command for ModelCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@model [name]", "switch the model or show the current one")
+    }
+
+    /// `@model name` switches the model for the rest of the session, a bare `@model` shows the current one.
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        let model = self.pattern.captures(input).map(|caps| caps["model"].to_string()).unwrap_or_default();
+        input.clear();
+
+        if !model.is_empty() {
+            ctx.rq_body.model(model.clone());
+            ctx.config.model = model;
+        }
+        ctx.events.emit(UiEvent::Notice(format!("model: {}", ctx.config.model)));
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct ReasoningCommand {
+    pattern: Regex,
+}
+
+impl ReasoningCommand {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"^\s*@reasoning\b\s*(?<mode>\S*)\s*$").unwrap(),
+        }
+    }
+}
+
+#[async_trait]
+impl Command for ReasoningCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@reasoning [on|off|collapse]", "show the reasoning of thinking models, hide it or keep it to a single line")
+    }
+
+    /// `@reasoning mode` changes how the reasoning is shown for the rest of the session, a bare `@reasoning` shows the current mode.
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        let mode = self.pattern.captures(input).map(|caps| caps["mode"].to_lowercase()).unwrap_or_default();
+        input.clear();
+
+        match mode.as_str() {
+            "" => {}
+            "on" => ctx.config.reasoning = Some(ReasoningDisplay::On),
+            "off" => ctx.config.reasoning = Some(ReasoningDisplay::Off),
+            "collapse" => ctx.config.reasoning = Some(ReasoningDisplay::Collapse),
+            mode => {
+                ctx.events.emit(UiEvent::Warning(format!("Unknown reasoning mode {}, use on, off or collapse", mode)));
+                return Ok(());
+            }
+        }
+        let mode = match ctx.config.reasoning.unwrap_or_default() {
+            ReasoningDisplay::On => "on",
+            ReasoningDisplay::Off => "off",
+            ReasoningDisplay::Collapse => "collapse",
+        };
+        ctx.events.emit(UiEvent::Notice(format!("reasoning: {}", mode)));
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct PersonaCommand {
+    pattern: Regex,
+}
+
+impl PersonaCommand {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"^\s*@persona\b\s*(?<name>\S*)\s*$").unwrap(),
+        }
+    }
+}
+
+#[async_trait]
+impl Command for PersonaCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@persona [name]", "switch to a persona from the config, or list them")
+    }
+
+    /// `@persona name` switches to the persona, a bare `@persona` lists them with the current one marked.
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        let name = self.pattern.captures(input).map(|caps| caps["name"].to_string()).unwrap_or_default();
+        input.clear();
+
+        if !name.is_empty() {
+            match ctx.switch_persona(&name) {
+                Ok(()) => ctx.events.emit(UiEvent::Notice(format!("persona: {}", name))),
+                Err(e) => ctx.events.emit(UiEvent::Warning(format!("{}", e))),
+            }
+            return Ok(());
+        }
+        if ctx.config.personas.is_empty() {
+            ctx.events.emit(UiEvent::Notice("No personas configured, add them under `personas` in the config".to_string()));
+            return Ok(());
+        }
+        let personas = ctx.config.personas.keys().map(|name| {
+            let marker = if ctx.persona.as_ref() == Some(name) { "*" } else { " " };
+            format!("{} {}", marker, name)
+        });
+        ctx.events.emit(UiEvent::Notice(personas.collect::<Vec<_>>().join("\n")));
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct StatsCommand {
+    pattern: Regex,
+}
+
+impl StatsCommand {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"^\s*@stats\s*$").unwrap(),
+        }
+    }
+
+    /// A line per model with its averages, in the order the models were first used.
+    fn report(perf: &[TurnPerf]) -> Vec<String> {
+        let mut models = Vec::<(&str, Vec<&TurnPerf>)>::new();
+        for turn in perf {
+            match models.iter_mut().find(|(model, _)| *model == turn.model) {
+                Some((_, turns)) => turns.push(turn),
+                None => models.push((&turn.model, vec![turn])),
+            }
+        }
+
+        let width = models.iter().map(|(model, _)| model.chars().count()).max().unwrap_or_default().max(5);
+        let mut lines = vec![format!("{:<width$}  {:>7}  {:>11}  {:>8}", "model", "answers", "first token", "tokens/s", width = width)];
+        for (model, turns) in models {
+            let count = turns.len() as f64;
+            let first_token = turns.iter().map(|e| e.first_token.as_secs_f64()).sum::<f64>() / count;
+            // Weighted by streaming time, so a one-token answer doesn't skew the speed.
+            let tokens = turns.iter().map(|e| e.tokens).sum::<u64>() as f64;
+            let streaming = turns.iter().map(|e| e.streaming.as_secs_f64()).sum::<f64>().max(0.001);
+            lines.push(format!(
+                "{:<width$}  {:>7}  {:>10.2}s  {:>8.1}",
+                model,
+                turns.len(),
+                first_token,
+                tokens / streaming,
+                width = width
+            ));
+        }
+        lines
+    }
+}
+
+#[async_trait]
+impl Command for StatsCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@stats", "show the average time to the first token and tokens per second of each model")
+    }
+
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        input.clear();
+        if ctx.perf.is_empty() {
+            ctx.events.emit(UiEvent::Notice("No answers yet".to_string()));
+            return Ok(());
+        }
+        ctx.events.emit(UiEvent::Notice(Self::report(&ctx.perf).join("\n")));
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct TokensCommand {
+    pattern: Regex,
+}
+
+impl TokensCommand {
+    const PREVIEW_WIDTH: usize = 48;
+
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"^\s*@tokens\s*$").unwrap(),
+        }
+    }
+
+    fn report(ctx: &Context) -> Vec<String> {
+        let messages = ctx.manager.messages();
+        let mut lines = vec![];
+        let mut context = 0;
+        for (index, message) in messages.iter().enumerate() {
+            let (role, text) = message_text(message);
+            let tokens = estimate_tokens(&text);
+            context += tokens;
+            let preview = text.split_whitespace().collect::<Vec<_>>().join(" ").chars().take(Self::PREVIEW_WIDTH).collect::<String>();
+            lines.push(format!("{:>3}  {:<9} {:>6}  {}", index + 1, role, format_tokens(tokens), preview));
+        }
+
+        let window = match ctx.config.model_context_window() {
+            Some(window) => format!(" of {} ({}%)", format_tokens(window), context * 100 / window.max(1)),
+            None => String::new(),
+        };
+        lines.push(format!(
+            "context: ~{} tokens{}, {}/{} messages, the oldest exchange is dropped beyond that",
+            format_tokens(context),
+            window,
+            messages.len(),
+            ctx.manager.max_size()
+        ));
+        lines.push(format!(
+            "session: {} tokens ({} prompt, {} completion)",
+            format_tokens(ctx.usage.total_tokens),
+            format_tokens(ctx.usage.prompt_tokens),
+            format_tokens(ctx.usage.completion_tokens)
+        ));
+        lines
+    }
+}
+
+#[async_trait]
+impl Command for TokensCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@tokens", "show the estimated context size and the session's token usage")
+    }
+
+    /// `@tokens` estimates the tokens of every message in the context and shows what the session used so far.
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        input.clear();
+        ctx.events.emit(UiEvent::Notice(Self::report(ctx).join("\n")));
+        if ctx.config.model_context_window().is_none() {
+            ctx.events.emit(UiEvent::Warning("Set `context_window` in the config to compare the context with the model's window".to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct ClearCommand {
+    pattern: Regex,
+}
+
+impl ClearCommand {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"^\s*@clear\s*$").unwrap(),
+        }
+    }
+}
+
+#[async_trait]
+impl Command for ClearCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@clear", "start the conversation over, keeping the system prompt")
+    }
+
+    /// `@clear` starts the conversation over, the system prompt is kept.
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        input.clear();
+        ctx.manager.clear();
+        ctx.events.emit(UiEvent::Notice("Cleared the conversation".to_string()));
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct CacheCommand {
+    pattern: Regex,
+}
+
+impl CacheCommand {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"^\s*@cache\s+clear\s*$").unwrap(),
+        }
+    }
+}
+
+#[async_trait]
+impl Command for CacheCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@cache clear", "forget the cached answers, so the same questions are asked again")
+    }
+
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        input.clear();
+        let Some(ref cache) = ctx.cache else {
+            ctx.events.emit(UiEvent::Warning("The response cache is off, set `cache.enabled` in the config".to_string()));
+            return Ok(());
+        };
+        let removed = cache.clear()?;
+        ctx.events.emit(UiEvent::Notice(format!("Cleared {} cached answers", removed)));
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct UndoCommand {
+    pattern: Regex,
+}
+
+impl UndoCommand {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"^\s*@undo\s*$").unwrap(),
+        }
+    }
+}
+
+#[async_trait]
+impl Command for UndoCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@undo", "drop the last question and its answer")
+    }
+
+    /// `@undo` drops the last question and its answer, tool calls included.
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        input.clear();
+        match ctx.manager.pop_exchange() {
+            Some(_) => ctx.events.emit(UiEvent::Notice("Removed the last exchange".to_string())),
+            None => ctx.events.emit(UiEvent::Warning("There is nothing to undo".to_string())),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct RetryCommand {
+    pattern: Regex,
+}
+
+impl RetryCommand {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"^\s*@retry\s*$").unwrap(),
+        }
+    }
+}
+
+#[async_trait]
+impl Command for RetryCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@retry", "ask the last question again")
+    }
+
+    /// `@retry` drops the last exchange and asks its question again for a fresh answer.
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        match ctx.manager.pop_exchange() {
+            Some(question) => *input = question,
+            None => {
+                input.clear();
+                ctx.events.emit(UiEvent::Warning("There is no question to retry yet".to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct JsonCommand {
+    pattern: Regex,
+}
+
+impl JsonCommand {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"(?s)^\s*@json\b\s*(?<rest>.*)$").unwrap(),
+        }
+    }
+
+    /// `json_schema` with the schema in `path`, named after the file, or `json_object` without one.
+    fn response_format(path: Option<&Path>) -> anyhow::Result<ResponseFormat> {
+        let Some(path) = path else { return Ok(ResponseFormat::JsonObject) };
+        let schema = serde_json::from_str::<Value>(&fs::read_to_string(path)?)?;
+        let name = path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .chars()
+            .map(|e| if e.is_ascii_alphanumeric() || e == '-' { e } else { '_' })
+            .take(64)
+            .collect();
+        Ok(ResponseFormat::JsonSchema {
+            json_schema: ResponseFormatJsonSchema { description: None, name, schema: Some(schema), strict: None },
+        })
+    }
+}
+
+#[async_trait]
+impl Command for JsonCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@json [schema.json] question", "answer with a JSON object, matching the schema if given")
+    }
+
+    /// `@json [schema.json] question` makes the answer to the question a JSON object, one that matches the schema if given.
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        let rest = self.pattern.captures(input).map(|caps| caps["rest"].to_string()).unwrap_or_default();
+        let (schema, question) = match rest.split_once(char::is_whitespace) {
+            Some((first, question)) if first.ends_with(".json") => (Some(first), question.trim_start()),
+            None if rest.ends_with(".json") => (Some(rest.as_str()), ""),
+            _ => (None, rest.as_str()),
+        };
+        if question.is_empty() {
+            ctx.events.emit(UiEvent::Warning("Expected a question after @json".to_string()));
+            input.clear();
+            return Ok(());
+        }
+
+        match Self::response_format(schema.map(Path::new)) {
+            Ok(format) => {
+                ctx.rq_body.response_format(Some(format));
+                *input = question.to_string();
+            }
+            Err(e) => {
+                ctx.events.emit(UiEvent::Warning(format!("Failed to read the schema {}: {}", schema.unwrap_or_default(), e)));
+                input.clear();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct DryCommand {
+    pattern: Regex,
+}
+
+impl DryCommand {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"(?s)^\s*@dry\b\s*(?<rest>.*)$").unwrap(),
+        }
+    }
+}
+
+#[async_trait]
+impl Command for DryCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@dry question", "print the request the question makes instead of sending it")
+    }
+
+    /// `@dry question` prints the request body once the other commands and the hooks are done with the question.
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        let question = self.pattern.captures(input).map(|caps| caps["rest"].to_string()).unwrap_or_default();
+        if question.trim().is_empty() {
+            ctx.events.emit(UiEvent::Warning("Expected a question after @dry".to_string()));
+        }
+        ctx.dry_run_once = true;
+        *input = question;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct ContinueCommand {
+    pattern: Regex,
+}
+
+impl ContinueCommand {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"^\s*@continue\s*$").unwrap(),
+        }
+    }
+}
+
+#[async_trait]
+impl Command for ContinueCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@continue", "have the model go on with an answer that was cut off")
+    }
+
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        if !matches!(ctx.finish_reason, Some(FinishReason::Length)) {
+            ctx.events.emit(UiEvent::Warning("The last answer wasn't cut off at the token limit, asking to continue anyway".to_string()));
+        }
+        *input = CONTINUE_PROMPT.to_string();
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct CompareCommand {
+    pattern: Regex,
+}
+
+impl CompareCommand {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"(?s)^\s*@compare\s+(?<models>\S+)\s*(?<rest>.*)$").unwrap(),
+        }
+    }
+}
+
+#[async_trait]
+impl Command for CompareCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@compare model1,model2 question", "ask several models at once and compare their answers, which stay out of the conversation")
+    }
+
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        let Some(caps) = self.pattern.captures(input) else { return Ok(()) };
+        let models = caps["models"].split(',').map(str::trim).filter(|e| !e.is_empty()).map(str::to_string).collect::<Vec<_>>();
+        let question = caps["rest"].to_string();
+        if question.trim().is_empty() {
+            ctx.events.emit(UiEvent::Warning("Expected a question after @compare model1,model2".to_string()));
+        }
+        ctx.compare = Some(models);
+        *input = question;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct PsCommand {
+    pattern: Regex,
+}
+
+impl PsCommand {
+    const MAX_PROCESSES: usize = 30;
+
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"@ps\((?<pattern>[^)]*)\)").unwrap(),
+        }
+    }
+
+    /// Processes whose command line contains `pattern`, busiest first.
+    fn processes(pattern: &str) -> anyhow::Result<String> {
+        let output = std::process::Command::new("ps")
+            .args(["-eo", "pid,pcpu,pmem,rss,etime,args"])
+            .output()?;
+        if !output.status.success() {
+            anyhow::bail!("ps failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+        }
+
+        let output = String::from_utf8_lossy(&output.stdout);
+        let mut lines = output.lines();
+        let header = lines.next().unwrap_or_default();
+        let own_pid = std::process::id().to_string();
+        let pattern = pattern.trim().to_lowercase();
+
+        let mut processes = lines
+            .filter(|e| e.to_lowercase().contains(&pattern))
+            .filter(|e| e.split_whitespace().next() != Some(own_pid.as_str()))
+            .collect::<Vec<_>>();
+        let cpu = |line: &str| line.split_whitespace().nth(1).and_then(|e| e.parse::<f64>().ok()).unwrap_or_default();
+        processes.sort_by(|a, b| cpu(b).total_cmp(&cpu(a)));
+        processes.truncate(Self::MAX_PROCESSES);
+
+        if processes.is_empty() {
+            anyhow::bail!("no process matches {:?}", pattern);
+        }
+        Ok(format!("{}\n{}", header, processes.join("\n")))
+    }
+}
+
+#[async_trait]
+impl Command for PsCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@ps(pattern)", "insert the processes whose command line matches")
+    }
+
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        // `ps` runs on the blocking pool, once per match before anything is replaced.
+        let mut tables = vec![];
+        for pattern in self.pattern.captures_iter(input).map(|e| e["pattern"].to_string()).collect::<Vec<_>>() {
+            let table = match tokio::task::spawn_blocking(move || Self::processes(&pattern)).await? {
+                Ok(table) => Some(table),
+                Err(e) => {
+                    ctx.events.emit(UiEvent::Warning(format!("Failed to list processes: {}", e)));
+                    None
+                }
+            };
+            tables.push(table);
+        }
+
+        let mut tables = tables.into_iter();
+        let result = self.pattern.replace_all(input.as_str(), |caps: &regex::Captures| match tables.next().flatten() {
+            Some(table) => format!("processes matching {:?}:\n```\n{}\n```\n", caps["pattern"].trim(), table),
+            None => caps[0].to_string(),
+        });
+        *input = result.to_string();
+        Ok(())
+    }
+}
+
+/// Last `n` lines of a log file, continuing into its rotated predecessor (`<file>.1`) when the current one is short.
+fn tail_lines(path: &Path, n: usize) -> anyhow::Result<Vec<String>> {
+    // Only the end of large logs is read.
+    const MAX_BYTES: u64 = 4 * 1024 * 1024;
+
+    let read_tail = |path: &Path| -> anyhow::Result<Vec<String>> {
+        use std::io::{Read, Seek, SeekFrom};
+        let mut file = fs::File::open(path)?;
+        let length = file.metadata()?.len();
+        file.seek(SeekFrom::Start(length.saturating_sub(MAX_BYTES)))?;
+
+        let mut bytes = vec![];
+        file.read_to_end(&mut bytes)?;
+        let mut lines = String::from_utf8_lossy(&bytes).lines().map(|e| e.to_string()).collect::<Vec<_>>();
+        if length > MAX_BYTES && !lines.is_empty() {
+            // The first line was most likely cut in half.
+            lines.remove(0);
+        }
+        Ok(lines)
+    };
+
+    let mut lines = read_tail(path)?;
+    let rotated = path.with_file_name(format!("{}.1", path.file_name().unwrap_or_default().to_string_lossy()));
+    if lines.len() < n && rotated.is_file() {
+        let mut previous = read_tail(&rotated)?;
+        previous.append(&mut lines);
+        lines = previous;
+    }
+
+    Ok(lines.split_off(lines.len().saturating_sub(n)))
+}
+
+#[derive(Debug)]
+struct LogsCommand {
+    pattern: Regex,
+}
+
+impl LogsCommand {
+    const DEFAULT_LINES: usize = 100;
+    const MAX_LINES: usize = 1000;
+
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(r"@logs\((?<source>[^,)]+)(,\s*(?<n>\d+))?\)").unwrap(),
+        }
+    }
+
+    /// Reads a file when `source` is one, otherwise asks journalctl about the systemd unit of that name.
+    fn logs(source: &str, n: usize) -> anyhow::Result<String> {
+        let path = Path::new(source);
+        if path.is_file() {
+            return Ok(tail_lines(path, n)?.join("\n"));
+        }
+
+        let output = std::process::Command::new("journalctl")
+            .args(["-u", source, "-n", &n.to_string(), "--no-pager", "-o", "short-iso"])
+            .output()?;
+        if !output.status.success() {
+            anyhow::bail!("journalctl failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim_end().to_string())
+    }
+}
+
+#[async_trait]
+impl Command for LogsCommand {
+    fn is(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+
+    fn help(&self) -> (&str, &str) {
+        ("@logs(file or unit[, n])", "insert the last n lines of a log file or systemd unit")
+    }
+
+    async fn execute(&self, ctx: &mut Context, input: &mut String) -> anyhow::Result<()> {
+        // Read on the blocking pool, once per match before anything is replaced.
+        let requests = self.pattern.captures_iter(input).map(|caps| {
+            let n = caps.name("n")
+                .and_then(|e| e.as_str().parse().ok())
+                .unwrap_or(Self::DEFAULT_LINES)
+                .min(Self::MAX_LINES);
+            (caps["source"].trim().to_string(), n)
+        }).collect::<Vec<_>>();
+        let mut logs = vec![];
+        for (source, n) in requests {
+            let read = {
+                let source = source.clone();
+                tokio::task::spawn_blocking(move || Self::logs(&source, n)).await?
+            };
+            logs.push(match read {
+                Ok(lines) => Some(format!("last {} lines of {}:\n```\n{}\n```\n", n, source, lines)),
+                Err(e) => {
+                    ctx.events.emit(UiEvent::Warning(format!("Failed to read logs of {}: {}", source, e)));
+                    None
+                }
+            });
+        }
+
+        let mut logs = logs.into_iter();
+        let result = self.pattern.replace_all(input.as_str(), |caps: &regex::Captures| {
+            logs.next().flatten().unwrap_or_else(|| caps[0].to_string())
+        });
+        *input = result.to_string();
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct AnswerPrompt;
+
+#[async_trait]
+impl Subscriber for AnswerPrompt {
+    async fn handle(&self, ctx: &mut Context, event: &mut Event<'_>) -> anyhow::Result<()> {
+        let Event::UserInput(input) = event else { return Ok(()) };
+        if input.trim().is_empty() {
+            return Ok(());
+        }
+
+        ctx.events.emit(UiEvent::AnswerStarted { model: ctx.config.model.clone() });
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct ReasoningCollector;
+
+#[async_trait]
+impl Subscriber for ReasoningCollector {
+    async fn handle(&self, ctx: &mut Context, event: &mut Event<'_>) -> anyhow::Result<()> {
+        let Event::Chunk(chunk) = event else { return Ok(()) };
+        if chunk.choices.is_empty() {
+            return Ok(());
+        }
+
+        if let Some(ref content) = chunk.choices[0].delta.reasoning_content {
+            if let Some(event) = reasoning_event(ctx.config.reasoning.unwrap_or_default(), content) {
+                ctx.events.emit(event);
+            }
+            ctx.transcript.push(Role::Reasoning, content);
+        }
+        Ok(())
+    }
+}
+
+/// The event that shows `content` the way `display` asks for, `None` if it isn't shown.
+fn reasoning_event(display: ReasoningDisplay, content: &str) -> Option<UiEvent> {
+    match display {
+        ReasoningDisplay::On => Some(UiEvent::Reasoning(content.to_string())),
+        ReasoningDisplay::Collapse => Some(UiEvent::CollapsedReasoning(content.to_string())),
+        ReasoningDisplay::Off => None,
+    }
+}
+
+/// Counts streamed reasoning deltas, roughly one token each, against `thinking_budget`. The count is reported as it
+/// grows unless the reasoning itself is shown as it streams, a redrawn count would take the line it is written on.
+#[derive(Debug)]
+struct ThinkingBudget {
+    tokens: AtomicU64,
+    reported: AtomicBool,
+}
+
+impl ThinkingBudget {
+    pub fn new() -> Self {
+        Self {
+            tokens: AtomicU64::new(0),
+            reported: AtomicBool::new(false),
+        }
+    }
+}
+
+impl ThinkingBudget {
+    fn on_chunk(&self, ctx: &mut Context, chunk: &RsChunkBody) -> anyhow::Result<()> {
+        let Some(budget) = ctx.config.thinking_budget else { return Ok(()) };
+        if chunk.choices.is_empty() || self.reported.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let delta = &chunk.choices[0].delta;
+        let tokens = self.tokens.load(Ordering::Relaxed);
+        if delta.reasoning_content.as_ref().is_some_and(|e| !e.is_empty()) {
+            let tokens = self.tokens.fetch_add(1, Ordering::Relaxed) + 1;
+            if tokens > budget {
+                ctx.events.emit(UiEvent::ThinkingBudget { used: tokens, budget, exceeded: true });
+                self.reported.store(true, Ordering::Relaxed);
+                ctx.stop_stream = true;
+            } else if ctx.config.reasoning.unwrap_or_default() != ReasoningDisplay::On {
+                ctx.events.emit(UiEvent::ThinkingProgress { used: tokens, budget });
+            }
+        } else if tokens > 0 && (!delta.content.is_empty() || delta.tool_calls.is_some()) {
+            ctx.events.emit(UiEvent::ThinkingBudget { used: tokens, budget, exceeded: false });
+            self.reported.store(true, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Subscriber for ThinkingBudget {
+    async fn handle(&self, ctx: &mut Context, event: &mut Event<'_>) -> anyhow::Result<()> {
+        match event {
+            Event::Chunk(chunk) => self.on_chunk(ctx, chunk),
+            Event::TurnEnd => {
+                self.tokens.store(0, Ordering::Relaxed);
+                self.reported.store(false, Ordering::Relaxed);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ContentCollector;
+
+#[async_trait]
+impl Subscriber for ContentCollector {
+    async fn handle(&self, ctx: &mut Context, event: &mut Event<'_>) -> anyhow::Result<()> {
+        let Event::Chunk(chunk) = event else { return Ok(()) };
+        if chunk.choices.is_empty() {
+            return Ok(());
+        }
+
+        let content = &chunk.choices[0].delta.content;
+        ctx.events.emit(UiEvent::ContentDelta(content.clone()));
+        ctx.transcript.push(Role::Assistant, content);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct ErrorReporter;
+
+#[async_trait]
+impl Subscriber for ErrorReporter {
+    async fn handle(&self, ctx: &mut Context, event: &mut Event<'_>) -> anyhow::Result<()> {
+        if let Event::StreamError(e) = event {
+            ctx.events.emit(UiEvent::Error(e.to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct NewLine;
+
+#[async_trait]
+impl Subscriber for NewLine {
+    async fn handle(&self, ctx: &mut Context, _event: &mut Event<'_>) -> anyhow::Result<()> {
+        ctx.events.emit(UiEvent::AnswerFinished);
+        Ok(())
+    }
+}
+
+/// Keeps the transcript of the session in `LAST_SESSION_FILE` for `rag export`, after the other subscribers added to it.
+#[derive(Debug, Default)]
+struct SessionSaver {
+    warned: AtomicBool,
+}
+
+#[async_trait]
+impl Subscriber for SessionSaver {
+    async fn handle(&self, ctx: &mut Context, _event: &mut Event<'_>) -> anyhow::Result<()> {
+        let path = ctx.config.config_dir().join(LAST_SESSION_FILE);
+        if let Err(e) = ctx.transcript.to_session(&ctx.config.model).export(&path)
+            && !self.warned.swap(true, Ordering::Relaxed)
+        {
+            eprintln!("{}", format!("Warning: Failed to keep the session for `rag export`: {}", e).yellow());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct TokenTracer {
+    turn: Mutex<TokenUsage>,
+}
+
+impl TokenTracer {
+    pub fn new() -> Self {
+        Self {
+            turn: Mutex::new(TokenUsage::default()),
+        }
+    }
+}
+
+#[async_trait]
+impl Subscriber for TokenTracer {
+    async fn handle(&self, ctx: &mut Context, event: &mut Event<'_>) -> anyhow::Result<()> {
+        match event {
+            Event::Chunk(RsChunkBody { usage: Some(usage), .. }) => {
+                let mut turn = self.turn.lock().unwrap();
+                turn.prompt_tokens += usage.prompt_tokens;
+                turn.completion_tokens += usage.completion_tokens;
+                turn.total_tokens += usage.total_tokens;
+            }
+            Event::TurnEnd => {
+                let turn = std::mem::take(&mut *self.turn.lock().unwrap());
+                if turn.total_tokens > 0 {
+                    ctx.transcript.push_usage(turn);
+                }
+                ctx.usage.prompt_tokens += turn.prompt_tokens;
+                ctx.usage.completion_tokens += turn.completion_tokens;
+                ctx.usage.total_tokens += turn.total_tokens;
+                ctx.events.emit(UiEvent::Usage { turn, total_tokens: ctx.usage.total_tokens });
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Times the first token and the streaming of every answer, shown after the usage and kept for `@stats`.
+#[derive(Debug, Default)]
+struct PerfTracer {
+    turn: Mutex<PerfTimes>,
+}
+
+#[derive(Debug, Default)]
+struct PerfTimes {
+    asked: Option<Instant>,
+    first_token: Option<Instant>,
+    last_chunk: Option<Instant>,
+    deltas: u64,
+    reported_tokens: Option<u64>,
+}
+
+#[async_trait]
+impl Subscriber for PerfTracer {
+    async fn handle(&self, ctx: &mut Context, event: &mut Event<'_>) -> anyhow::Result<()> {
+        let mut turn = self.turn.lock().unwrap();
+        match event {
+            Event::UserInput(_) => *turn = PerfTimes { asked: Some(Instant::now()), ..Default::default() },
+            Event::Chunk(chunk) => {
+                let now = Instant::now();
+                turn.last_chunk = Some(now);
+                if let Some(ref usage) = chunk.usage {
+                    turn.reported_tokens = Some(turn.reported_tokens.unwrap_or_default() + usage.completion_tokens);
+                }
+                let Some(choice) = chunk.choices.first() else { return Ok(()) };
+                if !choice.delta.content.is_empty() || choice.delta.reasoning_content.as_ref().is_some_and(|e| !e.is_empty()) {
+                    turn.first_token.get_or_insert(now);
+                    turn.deltas += 1;
+                }
+            }
+            Event::TurnEnd => {
+                let turn = std::mem::take(&mut *turn);
+                let (Some(asked), Some(first_token), Some(last_chunk)) = (turn.asked, turn.first_token, turn.last_chunk) else {
+                    return Ok(());
+                };
+                let perf = TurnPerf {
+                    model: ctx.config.model.clone(),
+                    first_token: first_token - asked,
+                    streaming: last_chunk - first_token,
+                    tokens: turn.reported_tokens.unwrap_or(turn.deltas),
+                };
+                ctx.events.emit(UiEvent::Perf(perf.clone()));
+                ctx.perf.push(perf);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct ToolsExecutor {
+    tools_call: Mutex<HashMap<u32, PendingCall>>,
+}
+
+/// A tool call as it streams in, the arguments come in pieces.
+#[derive(Debug)]
+struct PendingCall {
+    id: String,
+    name: String,
+    arguments: String,
+}
+
+impl ToolsExecutor {
+    pub fn new() -> Self {
+        Self {
+            tools_call: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl Subscriber for ToolsExecutor {
+    async fn handle(&self, ctx: &mut Context, event: &mut Event<'_>) -> anyhow::Result<()> {
+        match event {
+            Event::Chunk(chunk) => {
+                collect_tool_calls(&mut self.tools_call.lock().unwrap(), chunk, &ctx.events);
+                Ok(())
+            }
+            Event::TurnEnd => self.execute(ctx).await,
+            // The arguments of a cut off call are incomplete, it must not run.
+            Event::Cancelled => {
+                self.tools_call.lock().unwrap().clear();
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The calls of an answer in the order the model made them.
+fn sorted_calls(calls: HashMap<u32, PendingCall>) -> Vec<PendingCall> {
+    let mut calls = calls.into_iter().collect::<Vec<_>>();
+    calls.sort_by_key(|(index, _)| *index);
+    calls.into_iter().map(|(_, call)| call).collect()
+}
+
+/// Adds the tool call deltas of `chunk` to `calls`, showing each call as it grows.
+fn collect_tool_calls(calls: &mut HashMap<u32, PendingCall>, chunk: &RsChunkBody, events: &EventSender) {
+    let Some(tool_calls) = chunk.choices.first().and_then(|e| e.delta.tool_calls.as_ref()) else { return };
+    for tool_call in tool_calls {
+        let Some(ref function) = tool_call.function else { continue };
+        if let Some(ref name) = function.name {
+            // Providers that leave out the id still get one the results can refer to.
+            let id = tool_call.id.clone().unwrap_or_else(|| format!("call_{}", tool_call.index));
+            calls.insert(tool_call.index, PendingCall { id, name: name.to_owned(), arguments: String::new() });
+        }
+        let Some(call) = calls.get_mut(&tool_call.index) else { continue };
+        if let Some(ref arguments) = function.arguments {
+            call.arguments.push_str(arguments);
         }
+        events.emit(UiEvent::ToolCallDelta {
+            index: tool_call.index,
+            name: call.name.clone(),
+            arguments: call.arguments.clone(),
+        });
+    }
+}
+
+impl ToolsExecutor {
+    /// Answers that call tools again are followed up this many times at most, a model going round in circles stops there.
+    const MAX_ROUNDS: usize = 10;
+
+    /// Runs the calls of the answer and asks again with their results, for as long as the answers call tools.
+    async fn execute(&self, ctx: &mut Context) -> anyhow::Result<()> {
+        // Taken out so the lock isn't held across the awaits below.
+        let mut tools_call = sorted_calls(std::mem::take(&mut *self.tools_call.lock().unwrap()));
+        let mut rounds = 0;
+        loop {
+            if tools_call.is_empty() {
+                return Ok(());
+            }
+            // Calls in an answer the provider cut off may be missing their arguments' end.
+            if let Some(reason @ (FinishReason::Length | FinishReason::ContentFilter)) = ctx.finish_reason {
+                tracing::warn!(?reason, calls = tools_call.len(), "answer stopped early, its tool calls don't run");
+                return Ok(());
+            }
+            if rounds == Self::MAX_ROUNDS {
+                ctx.events.emit(UiEvent::Warning(format!("Stopped after {} rounds of tool calls, the last calls didn't run", rounds)));
+                return Ok(());
+            }
+            rounds += 1;
+
+            self.run(ctx, &tools_call).await?;
+            tools_call = self.follow_up(ctx).await?;
+        }
+    }
 
-        for (index, (tool_name, arguments)) in self.tools_call.borrow().iter() {
-            println!("{}", format!("Info: call tools {}, with arguments {}", tool_name, arguments).truecolor(128, 138, 135));
-            let result = ctx.tools.execute(
-                tool_name,
-                serde_json::from_str(arguments.as_str())?
-            )?;
+    /// Runs `tools_call` and adds their results to the context, after the answer that made the calls.
+    async fn run(&self, ctx: &mut Context, tools_call: &[PendingCall]) -> anyhow::Result<()> {
+        // The results must follow an answer that carries the calls they belong to.
+        ctx.manager.add_tool_calls(tools_call
+            .iter()
+            .map(|e| ChatCompletionMessageToolCall {
+                id: e.id.clone(),
+                r#type: ChatCompletionToolType::Function,
+                function: FunctionCall { name: e.name.clone(), arguments: e.arguments.clone() },
+            })
+            .collect());
+
+        let bus = ctx.bus.clone();
+        let mut parsed = vec![];
+        for call in tools_call {
+            ctx.events.emit(UiEvent::ToolStarted { name: call.name.clone(), arguments: call.arguments.clone() });
+            bus.dispatch(ctx, &mut Event::ToolCall { name: &call.name, arguments: &call.arguments }).await?;
+            parsed.push(serde_json::from_str::<Value>(&call.arguments));
+        }
+
+        // The calls run together, only those with unparsable arguments are answered right away.
+        let calls = tools_call
+            .iter()
+            .zip(&parsed)
+            .filter_map(|(call, arguments)| Some((call.name.clone(), arguments.as_ref().ok()?.clone())))
+            .collect::<Vec<_>>();
+        // Confirmation prompts are written directly, after everything emitted before them.
+        ctx.events.flush();
+        let started = Instant::now();
+        let mut executed = ctx.tools.execute_all(calls).await.into_iter();
+        let names = tools_call.iter().map(|e| e.name.as_str()).collect::<Vec<_>>();
+        tracing::info!(tools = ?names, elapsed_ms = started.elapsed().as_millis() as u64, "tool calls");
+
+        for (call, parsed) in tools_call.iter().zip(parsed) {
+            let result = match parsed.map(|_| executed.next().unwrap()) {
+                Ok(Ok(result)) => result,
+                // A failed call is the model's to deal with, like one it got wrong, the session goes on.
+                Ok(Err(e)) => serde_json::json!({
+                    "executed": false,
+                    "error": format!("{:#}", e),
+                }),
+                Err(e) => serde_json::json!({
+                    "executed": false,
+                    "error": format!("The arguments aren't valid JSON: {}", e),
+                }),
+            };
+            ctx.transcript.push(Role::Tool, &format!("{}({}) -> {}", call.name, call.arguments, result));
 
             ctx.manager.add(ChatCompletionRequestToolMessageArgs::default()
                 .content(serde_json::to_string(&result)?)
-                .tool_call_id(index.to_string())
+                .tool_call_id(call.id.clone())
                 .build()?
                 .into());
         }
+        Ok(())
+    }
+
+    /// Streams the answer to the tool results, returns the calls it makes in turn.
+    async fn follow_up(&self, ctx: &mut Context) -> anyhow::Result<Vec<PendingCall>> {
+        let rq_body = ctx.request_body()?;
+        log_request(&rq_body);
+        let recorder = ctx.recorder.clone();
+        if let Some(ref recorder) = recorder {
+            recorder.request(&rq_body);
+        }
+        let client = ctx.chat.clone();
+        let fallbacks = ctx.fallbacks();
+        let retry = ctx.config.retry.clone().unwrap_or_default();
+        let events = ctx.events.clone();
+        let interrupts = ctx.interrupts.clone();
+        let display = ctx.config.reasoning.unwrap_or_default();
+        let filters = &mut ctx.filters;
+        filters.reset();
+        let answered_by = &mut ctx.answered_by;
+
+        let (reasoning, answer, error, finish_reason, tools_call) = async move {
+            let (mut reasoning, mut answer, mut error, mut tools_call) = (String::new(), String::new(), None, HashMap::new());
+            let started = Instant::now();
+            let mut response = ResponseLog::default();
+            let mut stream = match client::stream_with_fallbacks(client.as_ref(), rq_body, &fallbacks, &retry, &events).await {
+                Ok((stream, model)) => {
+                    *answered_by = Some(model);
+                    stream
+                }
+                Err(e) => {
+                    events.emit(UiEvent::Error(e.to_string()));
+                    if let Some(ref recorder) = recorder {
+                        recorder.response(&response, false, Some(&e.to_string()));
+                    }
+                    return (reasoning, answer, Some(e.to_string()), None, tools_call);
+                }
+            };
+
+            let mut cancellation = interrupts.cancellation();
+            loop {
+                let result = tokio::select! {
+                    _ = &mut cancellation => {
+                        answer.push_str(CANCELLED_NOTE);
+                        events.emit(UiEvent::Cancelled);
+                        break;
+                    }
+                    result = stream.next() => match result {
+                        Some(result) => result,
+                        None => break,
+                    },
+                };
+                let chunk = match result {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        events.emit(UiEvent::Error(e.to_string()));
+                        error = Some(e.to_string());
+                        continue;
+                    }
+                };
+                response.add(&chunk);
+                collect_tool_calls(&mut tools_call, &chunk, &events);
+
+                if chunk.choices.is_empty() { continue; }
+
+                if let Some(ref reasoning_content) = chunk.choices[0].delta.reasoning_content {
+                    if let Some(event) = reasoning_event(display, reasoning_content) {
+                        events.emit(event);
+                    }
+                    reasoning.push_str(reasoning_content);
+                }
+
+                let content = filters.apply(&chunk.choices[0].delta.content);
+                events.emit(UiEvent::ContentDelta(content.clone()));
+                answer.push_str(&content);
+            }
+            let cancelled = answer.ends_with(CANCELLED_NOTE);
+            response.log(started, cancelled, error.as_deref());
+            if let Some(ref recorder) = recorder {
+                recorder.response(&response, cancelled, error.as_deref());
+            }
+            (reasoning, answer, error, response.finish_reason(), tools_call)
+        }.await;
+        let mut answer = answer;
+        if error.is_some() && !answer.is_empty() && !answer.ends_with(CANCELLED_NOTE) {
+            answer.push_str(DROPPED_NOTE);
+        } else if !answer.ends_with(CANCELLED_NOTE) {
+            report_stop(&ctx.events, finish_reason);
+        }
+        ctx.finish_reason = finish_reason;
+        if error.is_some() {
+            ctx.turn_error = error;
+        }
+        ctx.transcript.push(Role::Reasoning, &reasoning);
+        ctx.transcript.push(Role::Assistant, &answer);
+        // The calls of an answer that was cancelled or broke off are incomplete, like those of the first one.
+        let cut = answer.ends_with(CANCELLED_NOTE) || ctx.turn_error.is_some();
+        // What was shown is what the model is told it said, complete or not.
+        ctx.manager.add(ChatCompletionRequestAssistantMessageArgs::default()
+            .content(answer)
+            .build()?
+            .into());
+        Ok(if cut { vec![] } else { sorted_calls(tools_call) })
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::config::OpenAIConfig;
+    use async_openai::types::{ChatCompletionRequestAssistantMessageContent, ChatCompletionRequestMessage};
+    use crate::client::mock::MockClient;
+    use crate::tools::{Tool, ToolMetaData};
+    use crate::events::{ChannelRenderer, Discard};
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    /// A processor that answers with `script` and runs tool calls like the default hooks do. What it sent is in the
+    /// client's `requests`, what it showed comes out of the receiver.
+    fn test_processor(config: Config, script: Vec<Vec<Value>>) -> (Processor, Context, Arc<MockClient>, UnboundedReceiver<UiEvent>) {
+        let mock = Arc::new(MockClient::new(script));
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let tools = ToolRegistry::new(&config).unwrap();
+        let (processor, context) = Processor::builder()
+            .with_config(config)
+            .with_backend(Client::with_config(OpenAIConfig::new().into()))
+            .with_tools(tools)
+            .with_renderer(Box::new(ChannelRenderer::new(sender)))
+            .with_chat_client(mock.clone())
+            .with_subscriber(&[EventKind::UserInput], PRIORITY_DEFAULT, Arc::new(CommandParser::new(&BTreeMap::new())))
+            .with_subscriber(&[EventKind::Chunk], PRIORITY_DEFAULT, Arc::new(ReasoningCollector))
+            .with_subscriber(&[EventKind::Chunk, EventKind::TurnEnd], PRIORITY_DEFAULT, Arc::new(ThinkingBudget::new()))
+            .with_subscriber(&[EventKind::Chunk], PRIORITY_DEFAULT, Arc::new(ContentCollector))
+            .with_subscriber(&[EventKind::Chunk, EventKind::Cancelled, EventKind::TurnEnd], PRIORITY_DEFAULT, Arc::new(ToolsExecutor::new()))
+            .build()
+            .unwrap();
+        (processor, context, mock, receiver)
+    }
 
-        let rq_body = ctx.rq_body.messages(ctx.manager.as_messages()).build()?;
-        let client = ctx.client.clone();
+    #[tokio::test]
+    async fn test_builder() {
+        let mut config = Config::default();
+        config.system_prompt = Some("Be brief.".to_string());
+        // The required parts may come in any order, `build` only exists once both are there.
+        let (processor, context) = Processor::builder()
+            .with_context_policy(ContextManager::new(4))
+            .with_renderer(Box::new(Discard))
+            .with_subscriber(&[EventKind::Chunk], PRIORITY_DEFAULT, Arc::new(ContentCollector))
+            .with_backend(Client::with_config(OpenAIConfig::new().into()))
+            .with_config(config)
+            .build()
+            .unwrap();
+
+        assert_eq!(context.manager.system().as_deref(), Some("Be brief."));
+        assert_eq!(context.manager.max_size(), 4);
+        // Without the default hooks there are no commands, only what was subscribed.
+        assert!(processor.commands.is_empty());
+        assert!(Arc::ptr_eq(&processor.bus, &context.bus));
+    }
 
-        futures::executor::block_on(async move {
-            let mut stream: Pin<Box<dyn Stream<Item = Result<Value, OpenAIError>>>> = client
-                .chat()
-                .create_stream_byot(rq_body.to_rq_body())
-                .await
-                .unwrap();
+    #[tokio::test]
+    async fn test_turn_runs_tool_calls() {
+        let tool_call = |delta: Value| MockClient::chunk(serde_json::json!({ "role": "assistant", "content": "", "tool_calls": [delta] }));
+        let script = vec![
+            vec![
+                tool_call(serde_json::json!({ "index": 0, "id": "c", "type": "function", "function": { "name": "Add", "arguments": "{\"a\": 2," } })),
+                tool_call(serde_json::json!({ "index": 0, "function": { "arguments": " \"b\": 3}" } })),
+            ],
+            MockClient::answer("It is 5."),
+        ];
+        let (mut processor, mut context, mock, _) = test_processor(Config::default(), script);
+
+        let outcome = processor.run_once(&mut context, "what is 2+3?".to_string()).await.unwrap();
+        assert_eq!(outcome, TurnOutcome::Answered);
+
+        let requests = mock.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        let result = requests[1]["messages"].as_array().unwrap().last().unwrap().clone();
+        assert_eq!((result["role"].as_str(), result["content"].as_str()), (Some("tool"), Some(r#"{"result":5}"#)));
+
+        let entries = context.transcript.exchanges(None).concat();
+        assert_eq!(entries.iter().map(|e| (e.role, e.text.as_str())).collect::<Vec<_>>(), [
+            (Role::User, "what is 2+3?"),
+            (Role::Tool, r#"Add({"a": 2, "b": 3}) -> {"result":5}"#),
+            (Role::Assistant, "It is 5."),
+        ]);
+    }
 
-            while let Some(result) = stream.next().await {
-                if let Ok(chunk) = result {
-                    let chunk = serde_json::from_value::<RsChunkBody>(chunk.clone()).expect("Failed to parse chunk");
+    #[tokio::test]
+    async fn test_tool_results_follow_their_calls() {
+        let add = |index: u32, id: &str, b: i32| serde_json::json!({
+            "index": index, "id": id, "type": "function", "function": { "name": "Add", "arguments": format!("{{\"a\": 1, \"b\": {}}}", b) },
+        });
+        let script = vec![
+            vec![MockClient::chunk(serde_json::json!({ "role": "assistant", "content": "", "tool_calls": [add(0, "call_a", 1), add(1, "call_b", 2)] }))],
+            MockClient::answer("2 and 3."),
+        ];
+        let (mut processor, mut context, mock, _) = test_processor(Config::default(), script);
+
+        processor.run_once(&mut context, "add 1 to 1 and 2".to_string()).await.unwrap();
+        let requests = mock.requests.lock().unwrap();
+        let messages = requests[1]["messages"].as_array().unwrap();
+        let roles = messages.iter().map(|e| e["role"].as_str().unwrap()).collect::<Vec<_>>();
+        assert_eq!(roles, ["user", "assistant", "tool", "tool"]);
+        let calls = messages[1]["tool_calls"].as_array().unwrap();
+        assert_eq!(calls.iter().map(|e| e["id"].as_str().unwrap()).collect::<Vec<_>>(), ["call_a", "call_b"]);
+        assert_eq!(calls[1]["function"]["arguments"], "{\"a\": 1, \"b\": 2}");
+        assert_eq!((messages[2]["tool_call_id"].as_str(), messages[2]["content"].as_str()), (Some("call_a"), Some(r#"{"result":2}"#)));
+        assert_eq!((messages[3]["tool_call_id"].as_str(), messages[3]["content"].as_str()), (Some("call_b"), Some(r#"{"result":3}"#)));
+    }
 
-                    if chunk.choices.is_empty() { continue; }
+    #[tokio::test]
+    async fn test_tool_loop() {
+        let add = |id: &str, a: i32| vec![MockClient::chunk(serde_json::json!({ "role": "assistant", "content": "", "tool_calls": [{
+            "index": 0, "id": id, "type": "function", "function": { "name": "Add", "arguments": format!("{{\"a\": {}, \"b\": 1}}", a) },
+        }] }))];
+        let (mut processor, mut context, mock, _) = test_processor(Config::default(), vec![add("c1", 1), add("c2", 2), MockClient::answer("It is 3.")]);
+
+        processor.run_once(&mut context, "add 1 twice".to_string()).await.unwrap();
+        let requests = mock.requests.lock().unwrap().clone();
+        assert_eq!(requests.len(), 3);
+        let messages = requests[2]["messages"].as_array().unwrap();
+        let sent = messages.iter().map(|e| (e["role"].as_str().unwrap(), e["tool_call_id"].as_str().or(e["tool_calls"][0]["id"].as_str())));
+        assert_eq!(sent.collect::<Vec<_>>(), [
+            ("user", None),
+            ("assistant", Some("c1")),
+            ("tool", Some("c1")),
+            ("assistant", Some("c2")),
+            ("tool", Some("c2")),
+        ]);
+        assert_eq!(messages[4]["content"], r#"{"result":3}"#);
+        assert_eq!(context.manager.last_answer().as_deref(), Some("It is 3."));
+
+        // A model that keeps calling is stopped.
+        let script = (0..=ToolsExecutor::MAX_ROUNDS).map(|e| add(&format!("c{}", e), 1)).collect();
+        let (mut processor, mut context, mock, mut receiver) = test_processor(Config::default(), script);
+        processor.run_once(&mut context, "add forever".to_string()).await.unwrap();
+        assert_eq!(mock.requests.lock().unwrap().len(), ToolsExecutor::MAX_ROUNDS + 1);
+        let events = std::iter::from_fn(|| receiver.try_recv().ok()).collect::<Vec<_>>();
+        assert!(events.contains(&UiEvent::Warning("Stopped after 10 rounds of tool calls, the last calls didn't run".to_string())));
+    }
 
-                    let mut lock = stdout().lock();
+    #[tokio::test]
+    async fn test_reasoning_after_a_tool_call_is_shown_as_set() {
+        let call = serde_json::json!({ "index": 0, "id": "c", "type": "function", "function": { "name": "Add", "arguments": "{\"a\": 2, \"b\": 3}" } });
+        let script = vec![
+            vec![MockClient::chunk(serde_json::json!({ "role": "assistant", "content": "", "tool_calls": [call] }))],
+            vec![
+                MockClient::chunk(serde_json::json!({ "role": "assistant", "content": "", "reasoning_content": "Add returned 5." })),
+                MockClient::chunk(serde_json::json!({ "role": "assistant", "content": "It is 5." })),
+            ],
+        ];
+        let mut config = Config::default();
+        config.reasoning = Some(ReasoningDisplay::Collapse);
+        let (mut processor, mut context, _, mut receiver) = test_processor(config, script);
+
+        processor.run_once(&mut context, "what is 2+3?".to_string()).await.unwrap();
+        context.events.flush();
+        let events = std::iter::from_fn(|| receiver.try_recv().ok()).collect::<Vec<_>>();
+        assert!(events.contains(&UiEvent::CollapsedReasoning("Add returned 5.".to_string())));
+        assert!(!events.iter().any(|e| matches!(e, UiEvent::Reasoning(_))));
+    }
 
-                    if let Some(ref reasoning_content) = chunk.choices[0].delta.reasoning_content {
-                        write!(lock, "{}", format!("{}", reasoning_content).truecolor(128, 138, 135)).expect("Failed to write reasoning message");
-                    }
+    struct FailingTool;
 
-                    let content = &chunk.choices[0].delta.content;
-                    write!(lock, "{}", content).expect("Failed to write content message");
-                    stdout().flush().expect("Failed to flush stdout");
-                }
+    impl Tool for FailingTool {
+        fn metadata(&self) -> ToolMetaData {
+            ToolMetaData {
+                name: "Fail".to_string(),
+                description: "always fails".to_string(),
+                parameters: serde_json::json!({ "type": "object", "properties": {} }),
             }
+        }
+
+        fn execute(&self, _parameters: Value) -> anyhow::Result<Value> {
+            Err(anyhow::anyhow!("disk full").context("Failed to write"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_tool_call_is_answered() {
+        let call = serde_json::json!({ "index": 0, "id": "c", "type": "function", "function": { "name": "Fail", "arguments": "{}" } });
+        let script = vec![
+            vec![MockClient::chunk(serde_json::json!({ "role": "assistant", "content": "", "tool_calls": [call] }))],
+            MockClient::answer("The write failed."),
+        ];
+        let (mut processor, mut context, mock, _) = test_processor(Config::default(), script);
+        context.tools.register(FailingTool);
+
+        assert_eq!(processor.run_once(&mut context, "save it".to_string()).await.unwrap(), TurnOutcome::Answered);
+        let requests = mock.requests.lock().unwrap();
+        let result = requests[1]["messages"].as_array().unwrap().last().unwrap().clone();
+        let result = serde_json::from_str::<Value>(result["content"].as_str().unwrap()).unwrap();
+        assert_eq!(result, serde_json::json!({ "executed": false, "error": "Failed to write: disk full" }));
+        assert_eq!(context.transcript.exchanges(None).concat().last().unwrap().text, "The write failed.");
+    }
+
+    #[tokio::test]
+    async fn test_url_command() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        // Answers once, the same page asked for twice is fetched once.
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let _ = socket.read(&mut [0; 1024]).await;
+            socket.write_all(b"HTTP/1.1 200 OK\r\ncontent-type: text/plain\r\ncontent-length: 5\r\nconnection: close\r\n\r\nhello").await.unwrap();
         });
+        let (_, mut context, _, _) = test_processor(Config::default(), vec![]);
 
-        self.tools_call.borrow_mut().clear();
-        Ok(())
+        let mut input = format!("compare @url(http://{0}/a) with @url(http://{0}/a)", address);
+        UrlCommand::new().execute(&mut context, &mut input).await.unwrap();
+        assert_eq!(input, format!("compare http://{0}/a:\nhello\n with http://{0}/a:\nhello\n", address));
+    }
+
+    #[tokio::test]
+    async fn test_commands_tell_through_the_events() {
+        let (_, mut context, _, mut receiver) = test_processor(Config::default(), vec![]);
+
+        SystemPromptCommand::new().execute(&mut context, &mut "@system".to_string()).await.unwrap();
+        UndoCommand::new().execute(&mut context, &mut "@undo".to_string()).await.unwrap();
+        context.events.flush();
+        assert_eq!(receiver.try_recv().unwrap(), UiEvent::Notice("No system prompt set".to_string()));
+        assert_eq!(receiver.try_recv().unwrap(), UiEvent::Warning("There is nothing to undo".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_dropped_stream_keeps_the_partial_answer() {
+        let dropped = |text: &str| {
+            let mut chunks = MockClient::answer(text);
+            chunks.push(serde_json::json!({ "error": "connection reset" }));
+            chunks
+        };
+        for continue_dropped in [false, true] {
+            let mut config = Config::default();
+            config.retry = Some(RetryConfig { initial_delay_ms: Some(1), continue_dropped: Some(continue_dropped), ..Default::default() });
+            let (mut processor, mut context, mock, _) = test_processor(config, vec![dropped("The first half "), MockClient::answer("and the rest.")]);
+
+            let outcome = processor.run_once(&mut context, "tell me".to_string()).await.unwrap();
+            let answer = context.manager.messages().last().cloned().unwrap();
+            let ChatCompletionRequestMessage::Assistant(answer) = answer else { panic!() };
+            let Some(ChatCompletionRequestAssistantMessageContent::Text(answer)) = answer.content else { panic!() };
+            if continue_dropped {
+                assert_eq!(outcome, TurnOutcome::Answered);
+                assert_eq!(answer, "The first half and the rest.");
+                let requests = mock.requests.lock().unwrap();
+                let messages = requests[1]["messages"].as_array().unwrap();
+                assert_eq!(messages[messages.len() - 2]["content"], "The first half ");
+                assert_eq!(messages[messages.len() - 1]["content"], retry::CONTINUE_PROMPT);
+            } else {
+                assert_eq!(outcome, TurnOutcome::Failed("stream failed: connection reset".to_string()));
+                assert_eq!(answer, format!("The first half {}", DROPPED_NOTE));
+                assert_eq!(mock.requests.lock().unwrap().len(), 1);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_one_shot_outcomes() {
+        let call = serde_json::json!({ "index": 0, "id": "c", "type": "function", "function": { "name": "Add", "arguments": "{\"a\": 2, \"b\": 3}" } });
+        let script = vec![
+            vec![MockClient::chunk(serde_json::json!({ "role": "assistant", "content": "", "tool_calls": [call] }))],
+            vec![serde_json::json!({ "error": "connection reset" })],
+        ];
+        let (mut processor, mut context, mock, _) = test_processor(Config::default(), script);
+
+        // Nothing left to ask, `rag -p` exits with 0 without a request.
+        assert_eq!(processor.run_once(&mut context, "  ".to_string()).await.unwrap(), TurnOutcome::Skipped);
+        assert!(mock.requests.lock().unwrap().is_empty());
+        // The answer after the tool call failing fails the turn, `rag -p` exits with 1.
+        let outcome = processor.run_once(&mut context, "what is 2+3?".to_string()).await.unwrap();
+        assert_eq!(outcome, TurnOutcome::Failed("stream failed: connection reset".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_dry_run() {
+        let (mut processor, mut context, mock, mut receiver) = test_processor(Config::default(), vec![MockClient::answer("Hi.")]);
+
+        let mut input = "@dry hello".to_string();
+        DryCommand::new().execute(&mut context, &mut input).await.unwrap();
+        assert_eq!(processor.run_once(&mut context, input).await.unwrap(), TurnOutcome::Skipped);
+        assert!(mock.requests.lock().unwrap().is_empty());
+        assert!(context.manager.messages().is_empty());
+        let Ok(UiEvent::Notice(body)) = receiver.try_recv() else { panic!() };
+        let body = serde_json::from_str::<Value>(&body).unwrap();
+        assert_eq!(body["messages"].as_array().unwrap().last().unwrap()["content"], "hello");
+
+        // Only the one question is held back.
+        assert_eq!(processor.run_once(&mut context, "hello".to_string()).await.unwrap(), TurnOutcome::Answered);
+        assert_eq!(mock.requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_thinking_budget_stops_the_stream() {
+        let reasoning = |text: &str| MockClient::chunk(serde_json::json!({ "role": "assistant", "content": "", "reasoning_content": text }));
+        let mut chunks = ["Let", " me", " think", " more"].map(reasoning).to_vec();
+        chunks.push(MockClient::chunk(serde_json::json!({ "role": "assistant", "content": "Too late." })));
+        let mut config = Config::default();
+        config.thinking_budget = Some(2);
+        let (mut processor, mut context, _, mut receiver) = test_processor(config, vec![chunks]);
+
+        processor.run_once(&mut context, "think hard".to_string()).await.unwrap();
+        let events = std::iter::from_fn(|| receiver.try_recv().ok()).collect::<Vec<_>>();
+        assert!(events.contains(&UiEvent::ThinkingBudget { used: 3, budget: 2, exceeded: true }));
+        assert!(!events.contains(&UiEvent::ContentDelta("Too late.".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_thinking_progress() {
+        async fn progress(display: ReasoningDisplay) -> Vec<(u64, u64)> {
+            let reasoning = |text: &str| MockClient::chunk(serde_json::json!({ "role": "assistant", "content": "", "reasoning_content": text }));
+            let mut chunks = ["Let", " me", " think"].map(reasoning).to_vec();
+            chunks.push(MockClient::chunk(serde_json::json!({ "role": "assistant", "content": "Done." })));
+            let mut config = Config::default();
+            config.thinking_budget = Some(10);
+            config.reasoning = Some(display);
+            let (mut processor, mut context, _, mut receiver) = test_processor(config, vec![chunks]);
+
+            processor.run_once(&mut context, "think".to_string()).await.unwrap();
+            std::iter::from_fn(|| receiver.try_recv().ok())
+                .filter_map(|e| match e {
+                    UiEvent::ThinkingProgress { used, budget } => Some((used, budget)),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        assert_eq!(progress(ReasoningDisplay::Off).await, [(1, 10), (2, 10), (3, 10)]);
+        assert_eq!(progress(ReasoningDisplay::Collapse).await, [(1, 10), (2, 10), (3, 10)]);
+        // The reasoning shown as it streams isn't drawn over.
+        assert_eq!(progress(ReasoningDisplay::On).await, []);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_open_command() {
+        let (_, mut context, _, _) = test_processor(Config::default(), vec![]);
+        context.manager.add(ChatCompletionRequestAssistantMessageArgs::default()
+            .content("Like this:\n```rust\nfn a() {}\n```\n")
+            .build()
+            .unwrap()
+            .into());
+
+        // The editor gets the code block in a file of its language.
+        context.config.editor = Some(r#"sh -c 'case "$0" in *.rs) echo "fn b() {}" >> "$0";; esac'"#.to_string());
+        let mut input = "why does @open(1) fail?".to_string();
+        OpenCommand::new().execute(&mut context, &mut input).await.unwrap();
+        assert_eq!(input, "why does fn a() {}\nfn b() {}\n fail?");
+
+        // What comes back unchanged isn't sent.
+        context.config.editor = Some("true".to_string());
+        let mut input = "@open".to_string();
+        OpenCommand::new().execute(&mut context, &mut input).await.unwrap();
+        assert_eq!(input, "");
+    }
+
+    #[tokio::test]
+    async fn test_model_command() {
+        let (mut processor, mut context, mock, mut receiver) = test_processor(Config::default(), vec![MockClient::answer("Hi.")]);
+
+        ModelCommand::new().execute(&mut context, &mut "@model o3-mini".to_string()).await.unwrap();
+        let mut input = "@model".to_string();
+        ModelCommand::new().execute(&mut context, &mut input).await.unwrap();
+        assert_eq!(input, "");
+        processor.run_once(&mut context, "hello".to_string()).await.unwrap();
+
+        assert_eq!(receiver.try_recv().unwrap(), UiEvent::Notice("model: o3-mini".to_string()));
+        assert_eq!(receiver.try_recv().unwrap(), UiEvent::Notice("model: o3-mini".to_string()));
+        assert_eq!(mock.requests.lock().unwrap()[0]["model"], "o3-mini");
+    }
+
+    #[tokio::test]
+    async fn test_clip_command() {
+        let (_, mut context, _, _) = test_processor(Config::default(), vec![]);
+        let command = ClipCommand::new();
+
+        let mut input = "explain @clip please".to_string();
+        command.insert(&mut context, &mut input, Clip::Text("```\nfn a() {}\n```\n".to_string()));
+        assert_eq!(input, "explain \n````\n```\nfn a() {}\n```\n````\n please");
+        assert!(context.images.is_empty());
+
+        let mut input = "what is this @clip".to_string();
+        let image = Image::from_rgba("clipboard", 1, 1, &[255, 0, 0, 255]).unwrap();
+        command.insert(&mut context, &mut input, Clip::Image(image));
+        assert_eq!(input, "what is this ");
+        assert_eq!(context.images.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_finish_reason() {
+        let mut cut_off = MockClient::answer("Step one, ");
+        cut_off.push(MockClient::chunk(serde_json::json!({ "tool_calls": [
+            { "index": 0, "id": "c", "type": "function", "function": { "name": "Add", "arguments": "{\"a\": 2," } },
+        ] })));
+        cut_off.push(serde_json::json!({ "id": "mock", "choices": [{ "index": 0, "delta": {}, "finish_reason": "length" }] }));
+        let (mut processor, mut context, mock, _) = test_processor(Config::default(), vec![cut_off, MockClient::answer("step two.")]);
+
+        // The call's arguments are cut off, it doesn't run.
+        assert_eq!(processor.run_once(&mut context, "list the steps".to_string()).await.unwrap(), TurnOutcome::Answered);
+        assert_eq!(context.finish_reason, Some(FinishReason::Length));
+        assert_eq!(mock.requests.lock().unwrap().len(), 1);
+
+        processor.run_once(&mut context, "@continue".to_string()).await.unwrap();
+        let requests = mock.requests.lock().unwrap();
+        let question = requests[1]["messages"].as_array().unwrap().last().unwrap().clone();
+        assert_eq!(question["content"], CONTINUE_PROMPT);
+        assert_eq!(context.finish_reason, None);
+    }
+
+    #[tokio::test]
+    async fn test_continues_answers_cut_off_at_the_token_limit() {
+        let cut_off = |text: &str| {
+            let mut chunks = MockClient::answer(text);
+            chunks.push(serde_json::json!({ "id": "mock", "choices": [{ "index": 0, "delta": {}, "finish_reason": "length" }] }));
+            chunks
+        };
+        let mut config = Config::default();
+        config.max_continuations = Some(2);
+        let (mut processor, mut context, mock, _) = test_processor(config, vec![cut_off("One, "), cut_off("two, "), cut_off("three, ")]);
+
+        processor.run_once(&mut context, "count".to_string()).await.unwrap();
+        let requests = mock.requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        let messages = requests[2]["messages"].as_array().unwrap();
+        assert_eq!(messages[messages.len() - 2]["content"], "One, two, ");
+        assert_eq!(context.finish_reason, Some(FinishReason::Length));
+        // The stitched answer is one message in the context and one entry in the transcript.
+        assert_eq!(context.manager.messages().len(), 2);
+        let entries = context.transcript.exchanges(None).concat();
+        assert_eq!(entries.iter().map(|e| (e.role, e.text.as_str())).collect::<Vec<_>>(), [
+            (Role::User, "count"),
+            (Role::Assistant, "One, two, three, "),
+        ]);
+    }
+
+    /// A reasoning model calling a tool, as a provider streamed it.
+    #[cfg(feature = "vcr")]
+    #[tokio::test]
+    async fn test_replayed_tool_call_with_reasoning() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tool_call_with_reasoning.json");
+        let config = Config::default();
+        let tools = ToolRegistry::new(&config).unwrap();
+        let (mut processor, mut context) = Processor::builder()
+            .with_config(config)
+            .with_backend(Client::with_config(OpenAIConfig::new().into()))
+            .with_tools(tools)
+            .with_renderer(Box::new(Discard))
+            .with_chat_client(Arc::new(crate::vcr::ReplayClient::new(crate::vcr::load(&path).unwrap())))
+            .with_subscriber(&[EventKind::Chunk], PRIORITY_DEFAULT, Arc::new(ReasoningCollector))
+            .with_subscriber(&[EventKind::Chunk], PRIORITY_DEFAULT, Arc::new(ContentCollector))
+            .with_subscriber(&[EventKind::Chunk, EventKind::Cancelled, EventKind::TurnEnd], PRIORITY_DEFAULT, Arc::new(ToolsExecutor::new()))
+            .build()
+            .unwrap();
+
+        let outcome = processor.run_once(&mut context, "what is 2+3?".to_string()).await.unwrap();
+        assert_eq!(outcome, TurnOutcome::Answered);
+        let entries = context.transcript.exchanges(None).concat();
+        assert_eq!(entries.iter().map(|e| (e.role, e.text.as_str())).collect::<Vec<_>>(), [
+            (Role::User, "what is 2+3?"),
+            (Role::Reasoning, "The user wants a sum, I'll call Add."),
+            (Role::Tool, r#"Add({"a": 2, "b": 3}) -> {"result":5}"#),
+            (Role::Reasoning, "Add returned 5."),
+            (Role::Assistant, "2 + 3 = 5."),
+        ]);
+    }
+
+    #[test]
+    fn test_inline_overrides() {
+        let hook = InlineOverrides::new();
+        assert_eq!(hook.parse("what is 1+1?"), None);
+        assert_eq!(
+            hook.parse("?? temp=1.2 max=400 write a poem about x=1"),
+            Some((TurnOverrides { temperature: Some(1.2), max_tokens: Some(400) }, "write a poem about x=1".to_string())),
+        );
+        assert_eq!(
+            hook.parse("??max=abc hi"),
+            Some((TurnOverrides::default(), "hi".to_string())),
+        );
+    }
+
+    #[test]
+    fn test_json_response_format() {
+        assert_eq!(JsonCommand::response_format(None).unwrap(), ResponseFormat::JsonObject);
+
+        let path = std::env::temp_dir().join(format!("rag-json-{}.person schema.json", std::process::id()));
+        fs::write(&path, r#"{"type": "object", "properties": {"name": {"type": "string"}}}"#).unwrap();
+        let ResponseFormat::JsonSchema { json_schema } = JsonCommand::response_format(Some(&path)).unwrap() else { panic!() };
+        assert_eq!(json_schema.name, format!("rag-json-{}_person_schema", std::process::id()));
+        assert_eq!(json_schema.schema.unwrap()["type"], "object");
+    }
+
+    #[test]
+    fn test_stats_report() {
+        let turn = |model: &str, first_token: u64, streaming: u64, tokens: u64| TurnPerf {
+            model: model.to_string(),
+            first_token: Duration::from_millis(first_token),
+            streaming: Duration::from_millis(streaming),
+            tokens,
+        };
+        let perf = [turn("gpt-4o", 400, 2000, 100), turn("llama3", 100, 1000, 20), turn("gpt-4o", 600, 2000, 60)];
+        assert_eq!(perf[0].summary(), "first token 0.40s, 2.0s at 50.0 tokens/s");
+        assert_eq!(StatsCommand::report(&perf), [
+            "model   answers  first token  tokens/s",
+            "gpt-4o        2        0.50s      40.0",
+            "llama3        1        0.10s      20.0",
+        ]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_help_lists_every_command() {
+        let parser = CommandParser::new(&BTreeMap::new());
+        assert_eq!(parser.commands.last().unwrap().help().0, "@help");
+        let lines = HelpCommand::new(&parser.commands).lines();
+        for (syntax, _) in parser.commands.iter().map(|e| e.help()) {
+            assert!(lines.iter().any(|e| e.starts_with(syntax)), "{} is missing", syntax);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_commands() {
+        let config = |pattern: Option<&str>, shell: Option<&str>, template: Option<&str>| CustomCommandConfig {
+            pattern: pattern.map(str::to_string),
+            shell: shell.map(str::to_string),
+            template: template.map(str::to_string),
+            description: None,
+        };
+        let render = |command: &CustomCommand, input: &str| command.render(&command.pattern.captures(input).unwrap());
+
+        let echo = CustomCommand::new("say", &config(Some(r"@say\((?<text>[^)]*)\)"), Some("echo {{text}}"), None)).unwrap();
+        let text = render(&echo, "@say(a; rm -rf x)").unwrap();
+        assert_eq!(echo.expand(text).await.unwrap(), "a; rm -rf x");
+        assert_eq!(echo.help(), ("@say", "insert the output of `echo {{text}}`"));
+
+        let review = CustomCommand::new("review", &config(Some(r"@review (\S+)"), None, Some("Review {{1}} strictly."))).unwrap();
+        assert_eq!(review.expand(render(&review, "@review main.rs").unwrap()).await.unwrap(), "Review main.rs strictly.");
+        let plain = CustomCommand::new("x.y", &config(None, None, Some("z"))).unwrap();
+        assert!(plain.is("@x.y") && !plain.is("@xzy"));
+
+        assert!(CustomCommand::new("both", &config(None, Some("a"), Some("b"))).is_err());
+        assert!(CustomCommand::new("bad", &config(Some("("), Some("a"), None)).is_err());
+    }
+
+    #[test]
+    fn test_copy_selection() {
+        let command = CopyCommand::new();
+        assert!(command.is("@copy") && command.is(" @copy code 2 ") && !command.is("@copy that"));
+
+        let answer = "Two ways:\n```rust\nfn a() {}\n```\nor\n```\nfn b() {}\n```\n";
+        assert_eq!(command.select(answer, None).unwrap(), (answer.to_string(), "the last answer".to_string()));
+        assert_eq!(command.select(answer, Some(2)).unwrap(), ("fn b() {}\n".to_string(), "code block 2".to_string()));
+        assert!(command.select(answer, Some(3)).is_err());
+    }
+
+    #[test]
+    fn test_tail_lines_follows_rotation() {
+        let dir = std::env::temp_dir().join(format!("rag-logs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("app.log.1"), "one\ntwo\nthree\n").unwrap();
+        fs::write(dir.join("app.log"), "four\nfive\n").unwrap();
+
+        assert_eq!(tail_lines(&dir.join("app.log"), 2).unwrap(), ["four", "five"]);
+        assert_eq!(tail_lines(&dir.join("app.log"), 4).unwrap(), ["two", "three", "four", "five"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+use async_openai::types::FinishReason;
+use chrono::Local;
+use colored::Colorize;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::client::ChatClient;
+use crate::config::RetryConfig;
+use crate::events::EventSender;
+use crate::rq::RsChunkBody;
+
+/// Where the recordings are kept, relative to the config directory.
+pub const TRANSCRIPTS_DIR: &str = "transcripts";
+
+/// A tool call put together from its streamed pieces.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RecordedToolCall {
+    pub name: String,
+    pub arguments: String,
+}
+
+/// What a response stream brought, put back together from its chunks before any filter touched them.
+#[derive(Debug, Default)]
+pub struct ResponseLog {
+    id: String,
+    model: String,
+    chunks: u64,
+    content: String,
+    reasoning: String,
+    tool_calls: BTreeMap<u32, RecordedToolCall>,
+    finish_reason: Option<FinishReason>,
+    prompt_tokens: Option<u64>,
+    completion_tokens: Option<u64>,
+}
+
+impl ResponseLog {
+    pub fn add(&mut self, chunk: &RsChunkBody) {
+        if self.chunks == 0 {
+            self.id = chunk.id.clone();
+            self.model = chunk.model.clone();
+        }
+        self.chunks += 1;
+        if let Some(ref usage) = chunk.usage {
+            self.prompt_tokens = Some(usage.prompt_tokens);
+            self.completion_tokens = Some(usage.completion_tokens);
+        }
+        let Some(choice) = chunk.choices.first() else { return };
+        if let Some(reason) = choice.finish_reason {
+            self.finish_reason = Some(reason);
+        }
+        self.content.push_str(&choice.delta.content);
+        if let Some(ref reasoning) = choice.delta.reasoning_content {
+            self.reasoning.push_str(reasoning);
+        }
+        for call in choice.delta.tool_calls.iter().flatten() {
+            let recorded = self.tool_calls.entry(call.index).or_default();
+            if let Some(ref function) = call.function {
+                recorded.name.push_str(function.name.as_deref().unwrap_or_default());
+                recorded.arguments.push_str(function.arguments.as_deref().unwrap_or_default());
+            }
+        }
+    }
+
+    pub fn finish_reason(&self) -> Option<FinishReason> {
+        self.finish_reason
+    }
+
+    pub fn has_tool_calls(&self) -> bool {
+        !self.tool_calls.is_empty()
+    }
+
+    /// Logs the metadata, never the content.
+    pub fn log(&self, started: Instant, cancelled: bool, error: Option<&str>) {
+        let elapsed_ms = started.elapsed().as_millis() as u64;
+        let (id, model, chunks, finish_reason) = (&self.id, &self.model, self.chunks, &self.finish_reason);
+        let (prompt_tokens, completion_tokens) = (self.prompt_tokens, self.completion_tokens);
+        match error {
+            Some(error) => tracing::warn!(id, model, chunks, elapsed_ms, error, "response failed"),
+            None => tracing::info!(id, model, chunks, elapsed_ms, ?finish_reason, prompt_tokens, completion_tokens, cancelled, "response"),
+        }
+    }
+}
+
+/// A line of a recording.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Entry {
+    Request {
+        time: String,
+        body: Value,
+    },
+    Response {
+        time: String,
+        #[serde(default)]
+        model: String,
+        #[serde(default)]
+        content: String,
+        #[serde(default)]
+        reasoning: String,
+        #[serde(default)]
+        tool_calls: Vec<RecordedToolCall>,
+        #[serde(default)]
+        finish_reason: Option<FinishReason>,
+        #[serde(default)]
+        cancelled: bool,
+        #[serde(default)]
+        error: Option<String>,
+    },
+}
+
+impl Entry {
+    fn response(response: &ResponseLog, cancelled: bool, error: Option<&str>) -> Self {
+        Entry::Response {
+            time: Local::now().to_rfc3339(),
+            model: response.model.clone(),
+            content: response.content.clone(),
+            reasoning: response.reasoning.clone(),
+            tool_calls: response.tool_calls.values().cloned().collect(),
+            finish_reason: response.finish_reason,
+            cancelled,
+            error: error.map(str::to_string),
+        }
+    }
+}
+
+/// Appends every request body and the response it got to a JSONL file of the session, for `rag replay`.
+#[derive(Debug)]
+pub struct Recorder {
+    path: PathBuf,
+    warned: AtomicBool,
+}
+
+impl Recorder {
+    /// Records to a new file in `TRANSCRIPTS_DIR`, named after the time the session started.
+    pub fn start(config_dir: &Path) -> anyhow::Result<Self> {
+        let dir = config_dir.join(TRANSCRIPTS_DIR);
+        std::fs::create_dir_all(&dir)?;
+        let name = format!("{}-{}.jsonl", Local::now().format("%Y%m%d-%H%M%S"), std::process::id());
+        Ok(Self { path: dir.join(name), warned: AtomicBool::new(false) })
+    }
+
+    pub fn request(&self, body: &Value) {
+        self.append(&Entry::Request { time: Local::now().to_rfc3339(), body: body.clone() });
+    }
+
+    pub fn response(&self, response: &ResponseLog, cancelled: bool, error: Option<&str>) {
+        self.append(&Entry::response(response, cancelled, error));
+    }
+
+    /// Warns about the first failure only, the session goes on without the recording.
+    fn append(&self, entry: &Entry) {
+        let written = serde_json::to_string(entry).map_err(anyhow::Error::from).and_then(|line| {
+            let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+            Ok(writeln!(file, "{}", line)?)
+        });
+        if let Err(e) = written
+            && !self.warned.swap(true, Ordering::Relaxed)
+        {
+            eprintln!("{}", format!("Warning: Failed to record to {}: {}", self.path.display(), e).yellow());
+        }
+    }
+}
+
+/// Reads a recording, naming the line that isn't an entry.
+pub fn load(path: &Path) -> anyhow::Result<Vec<Entry>> {
+    let content = std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| serde_json::from_str(line).map_err(|e| anyhow::anyhow!("Line {} of {}: {}", index + 1, path.display(), e)))
+        .collect()
+}
+
+/// The text of the last message of a request body, what was asked or what a tool returned.
+fn last_message(body: &Value) -> (String, String) {
+    let message = body["messages"].as_array().and_then(|e| e.last()).cloned().unwrap_or_default();
+    let text = match &message["content"] {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts.iter().filter_map(|e| e["text"].as_str()).collect::<Vec<_>>().join("\n"),
+        _ => String::new(),
+    };
+    (message["role"].as_str().unwrap_or("user").to_string(), text)
+}
+
+/// Prints the requests and responses of a recording as a conversation.
+pub fn print(entries: &[Entry]) {
+    for entry in entries {
+        match entry {
+            Entry::Request { body, .. } => {
+                let (role, text) = last_message(body);
+                println!("{}", format!("{} → {}:", role, body["model"].as_str().unwrap_or_default()).bold());
+                println!("{}\n", text);
+            }
+            Entry::Response { model, content, reasoning, tool_calls, error, cancelled, .. } => {
+                println!("{}", format!("{}:", model).bold());
+                print_response(reasoning, content, tool_calls);
+                if let Some(e) = error {
+                    println!("{}", format!("Error: {}", e).red());
+                }
+                if *cancelled {
+                    println!("{}", "Cancelled".yellow());
+                }
+                println!();
+            }
+        }
+    }
+}
+
+fn print_response(reasoning: &str, content: &str, tool_calls: &[RecordedToolCall]) {
+    if !reasoning.is_empty() {
+        println!("{}", reasoning.truecolor(128, 138, 135));
+    }
+    if !content.is_empty() {
+        println!("{}", content);
+    }
+    for call in tool_calls {
+        println!("{}", format!("tool call {}({})", call.name, call.arguments).truecolor(128, 138, 135));
+    }
+}
+
+/// Sends every recorded request again, exactly as it was, and prints what comes back this time.
+pub async fn rerun(client: &dyn ChatClient, entries: &[Entry], events: &EventSender) -> anyhow::Result<()> {
+    let retry = RetryConfig::default();
+    for body in entries.iter().filter_map(|e| match e {
+        Entry::Request { body, .. } => Some(body),
+        _ => None,
+    }) {
+        let (role, text) = last_message(body);
+        println!("{}", format!("{} → {}:", role, body["model"].as_str().unwrap_or_default()).bold());
+        println!("{}\n", text);
+
+        let mut response = ResponseLog::default();
+        let mut error = None;
+        match client.stream(body.clone(), &retry, events).await {
+            Ok(mut stream) => {
+                while let Some(chunk) = stream.next().await {
+                    match chunk {
+                        Ok(chunk) => response.add(&chunk),
+                        Err(e) => error = Some(e.to_string()),
+                    }
+                }
+            }
+            Err(e) => error = Some(e.to_string()),
+        }
+        println!("{}", format!("{}:", response.model).bold());
+        print_response(&response.reasoning, &response.content, &response.tool_calls.values().cloned().collect::<Vec<_>>());
+        if let Some(e) = error {
+            println!("{}", format!("Error: {}", e).red());
+        }
+        println!();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(value: Value) -> RsChunkBody {
+        let mut chunk = serde_json::json!({ "id": "r1", "created": 0, "model": "m", "object": "chat.completion.chunk", "choices": [] });
+        chunk.as_object_mut().unwrap().extend(value.as_object().unwrap().clone());
+        serde_json::from_value(chunk).unwrap()
+    }
+
+    #[test]
+    fn test_recording() {
+        let mut response = ResponseLog::default();
+        let delta = |delta: Value| chunk(serde_json::json!({ "choices": [{ "index": 0, "delta": delta }] }));
+        response.add(&delta(serde_json::json!({ "role": "assistant", "content": "", "reasoning_content": "hmm" })));
+        response.add(&delta(serde_json::json!({ "role": "assistant", "content": "Let me look", "tool_calls": [
+            { "index": 0, "id": "c", "type": "function", "function": { "name": "read_file", "arguments": "{\"pa" } },
+        ] })));
+        response.add(&delta(serde_json::json!({ "role": "assistant", "content": ".", "tool_calls": [
+            { "index": 0, "function": { "arguments": "th\": \"a\"}" } },
+        ] })));
+
+        let dir = std::env::temp_dir().join(format!("rag-recorder-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let recorder = Recorder::start(&dir).unwrap();
+        let body = serde_json::json!({ "model": "m", "messages": [{ "role": "user", "content": "read a" }] });
+        recorder.request(&body);
+        recorder.response(&response, false, None);
+
+        let entries = load(&recorder.path).unwrap();
+        assert!(matches!(&entries[0], Entry::Request { body: recorded, .. } if *recorded == body));
+        let Entry::Response { ref content, ref reasoning, ref tool_calls, .. } = entries[1] else { panic!() };
+        assert_eq!((content.as_str(), reasoning.as_str()), ("Let me look.", "hmm"));
+        assert_eq!(tool_calls, &[RecordedToolCall { name: "read_file".to_string(), arguments: "{\"path\": \"a\"}".to_string() }]);
+        assert_eq!(last_message(&body), ("user".to_string(), "read a".to_string()));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::pin::Pin;
+use std::sync::LazyLock;
+use std::time::Duration;
+use async_openai::Client;
+use async_openai::config::Config as ClientConfig;
+use async_openai::error::OpenAIError;
+use futures::StreamExt;
+use futures_core::Stream;
+use regex::Regex;
+use serde_json::Value;
+use crate::config::RetryConfig;
+use crate::events::{EventSender, UiEvent};
+
+/// The chunks as the provider sent them, before they are parsed into the chunks of `client::ChunkStream`.
+pub type RawChunkStream = Pin<Box<dyn Stream<Item = Result<Value, OpenAIError>> + Send>>;
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+
+/// Appended to an answer whose stream broke off, so neither the model nor the transcript take it as complete.
+pub const DROPPED_NOTE: &str = "\n\n[The connection dropped during this answer, it is incomplete.]";
+/// Asks the model to go on with an answer that broke off, sent after the partial answer.
+pub const CONTINUE_PROMPT: &str = "Your answer was cut off. Continue exactly where it stopped, without repeating anything or commenting on the interruption.";
+const DEFAULT_INITIAL_DELAY_MS: u64 = 500;
+const DEFAULT_MAX_DELAY_SECS: u64 = 30;
+
+/// The status of a failed request, in the text of an event source error.
+static STATUS_CODE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"Invalid status code: (\d{3})").unwrap());
+
+/// Whether the request may succeed when sent again: rate limits, server errors and dropped connections.
+fn is_transient(error: &OpenAIError) -> bool {
+    match error {
+        OpenAIError::Reqwest(e) => {
+            e.is_connect() || e.is_timeout() || e.status().is_some_and(|e| e.as_u16() == 429 || e.is_server_error())
+        }
+        // The event source only reports its errors as text.
+        OpenAIError::StreamError(e) => {
+            match STATUS_CODE.captures(e) {
+                Some(caps) => caps[1] == *"429" || caps[1].starts_with('5'),
+                None => ["error sending request", "connection", "timed out", "reset"].iter().any(|needle| e.contains(needle)),
+            }
+        }
+        _ => false,
+    }
+}
+
+/// Whether another model may answer a request that failed for good: rate limits, overloads and a context that is too long.
+pub fn falls_back(error: &OpenAIError) -> bool {
+    let too_long = |message: &str| {
+        let message = message.to_lowercase();
+        ["context length", "context_length", "context window", "too long", "too many tokens"].iter().any(|e| message.contains(e))
+    };
+    match error {
+        OpenAIError::ApiError(e) => too_long(&e.message) || e.code.as_deref().is_some_and(too_long),
+        e => is_transient(e),
+    }
+}
+
+/// Attempts in total `config` allows, the first one included.
+pub fn max_attempts(config: &RetryConfig) -> u32 {
+    config.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS).max(1)
+}
+
+/// Exponential backoff with up to 50% jitter, so clients that failed together don't retry together.
+pub fn backoff(config: &RetryConfig, attempt: u32) -> Duration {
+    let initial = config.initial_delay_ms.unwrap_or(DEFAULT_INITIAL_DELAY_MS);
+    let max = config.max_delay_secs.unwrap_or(DEFAULT_MAX_DELAY_SECS) * 1000;
+    let delay = initial.saturating_mul(1 << (attempt - 1).min(20)).min(max);
+    let jitter = RandomState::new().build_hasher().finish() % (delay / 2 + 1);
+    Duration::from_millis(delay + jitter)
+}
+
+/// Opens the response stream, sending the request again while it fails transiently before the first chunk.
+/// Once chunks arrived the answer can't be resumed, later errors are the caller's.
+pub async fn open_stream<C: ClientConfig>(client: &Client<C>, body: Value, config: &RetryConfig, events: &EventSender) -> Result<RawChunkStream, OpenAIError> {
+    retrying(config, events, || async {
+        let mut stream = client.chat().create_stream_byot(body.clone()).await?;
+        match stream.next().await {
+            Some(Err(e)) => Err(e),
+            Some(first) => Ok(Box::pin(futures::stream::once(async { first }).chain(stream)) as RawChunkStream),
+            None => Ok(stream),
+        }
+    })
+    .await
+}
+
+/// Sends a request that isn't streamed and returns the whole response, retried like `open_stream`.
+pub async fn complete<C: ClientConfig>(client: &Client<C>, body: Value, config: &RetryConfig, events: &EventSender) -> Result<Value, OpenAIError> {
+    retrying(config, events, || async { client.chat().create_byot::<Value, Value>(body.clone()).await }).await
+}
+
+/// Calls `send` again while it fails transiently, with a growing delay, as often as `config` allows.
+pub async fn retrying<T, F, Fut>(config: &RetryConfig, events: &EventSender, mut send: F) -> Result<T, OpenAIError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, OpenAIError>>,
+{
+    let max_attempts = max_attempts(config);
+    let mut attempt = 1;
+    loop {
+        let e = match send().await {
+            Ok(result) => return Ok(result),
+            Err(e) => e,
+        };
+
+        if attempt >= max_attempts || !is_transient(&e) {
+            return Err(e);
+        }
+        let delay = backoff(config, attempt);
+        tracing::warn!(attempt, max_attempts, delay_ms = delay.as_millis() as u64, error = %e, "request failed, retrying");
+        events.emit(UiEvent::Retrying { attempt, max_attempts, delay, error: e.to_string() });
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_openai::error::ApiError;
+    use super::*;
+
+    #[test]
+    fn test_retry_policy() {
+        assert!(is_transient(&OpenAIError::StreamError("Invalid status code: 429 Too Many Requests".to_string())));
+        assert!(is_transient(&OpenAIError::StreamError("Invalid status code: 503 Service Unavailable".to_string())));
+        assert!(!is_transient(&OpenAIError::StreamError("Invalid status code: 401 Unauthorized".to_string())));
+        assert!(is_transient(&OpenAIError::StreamError("Transport error: error sending request for url".to_string())));
+        assert!(!is_transient(&OpenAIError::InvalidArgument("model".to_string())));
+
+        let config = RetryConfig { max_attempts: None, initial_delay_ms: Some(100), max_delay_secs: Some(1), continue_dropped: None };
+        for (attempt, base) in [(1, 100), (2, 200), (3, 400), (5, 1000), (30, 1000)] {
+            let delay = backoff(&config, attempt).as_millis() as u64;
+            assert!((base..=base + base / 2).contains(&delay), "attempt {}: {}ms", attempt, delay);
+        }
+
+        assert!(falls_back(&OpenAIError::StreamError("Invalid status code: 529 Overloaded".to_string())));
+        let too_long = ApiError { message: "This model's maximum context length is 8192 tokens".to_string(), r#type: None, param: None, code: None };
+        assert!(falls_back(&OpenAIError::ApiError(too_long)));
+        assert!(!falls_back(&OpenAIError::StreamError("Invalid status code: 401 Unauthorized".to_string())));
+    }
+}
@@ -1,26 +1,184 @@
 use std::borrow::Cow;
 use std::borrow::Cow::{Borrowed, Owned};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use colored::Colorize;
-use rustyline::{Cmd, Completer, CompletionType, Config, EditMode, Editor, Helper, Hinter, KeyEvent, Validator};
-use rustyline::completion::FilenameCompleter;
+use regex::Regex;
+use rustyline::{Cmd, CompletionType, Config, Context, EditMode, Editor, Helper, Hinter, KeyEvent};
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::error::ReadlineError;
 use rustyline::highlight::{CmdKind, Highlighter, MatchingBracketHighlighter};
 use rustyline::hint::HistoryHinter;
 use rustyline::history::DefaultHistory;
-use rustyline::validate::MatchingBracketValidator;
+use rustyline::validate::{MatchingBracketValidator, ValidationContext, ValidationResult, Validator};
+use crate::config::HistoryConfig;
+use crate::templates::Templates;
 
-#[derive(Helper, Completer, Hinter, Validator)]
+/// Where the input history is kept, relative to the config directory.
+pub const HISTORY_FILE: &str = "history";
+/// Where it was kept before, relative to the working directory, read once if there is no history yet.
+const LEGACY_HISTORY_FILE: &str = "_history.txt";
+const DEFAULT_MAX_ENTRIES: usize = 1000;
+
+/// What the completer offers besides file paths.
+#[derive(Debug, Default, Clone)]
+pub struct Completions {
+    /// What each command starts with, `@file(` or `@model ` for the ones that take arguments.
+    pub commands: Vec<String>,
+    /// Filled in once the provider answered, the list is fetched in the background.
+    pub models: Arc<Mutex<Vec<String>>>,
+    pub personas: Vec<String>,
+    /// Where the prompt templates are looked up.
+    pub config_dir: PathBuf,
+}
+
+/// The text a command's completion inserts, from the syntax `@help` lists: `@file(path)` gives `@file(`.
+pub fn command_prefix(syntax: &str) -> String {
+    let name = syntax.chars().skip(1).take_while(|e| e.is_alphanumeric()).collect::<String>();
+    match syntax[1 + name.len()..].chars().next() {
+        Some('(') => format!("@{}(", name),
+        Some(' ') => format!("@{} ", name),
+        Some('`') if name.is_empty() => "@`".to_string(),
+        _ => format!("@{}", name),
+    }
+}
+
+/// Completes commands after `@`, their arguments where they are known and file paths everywhere else.
+pub struct RagCompleter {
+    files: FilenameCompleter,
+    completions: Completions,
+    command: Regex,
+    argument: Regex,
+}
+
+impl RagCompleter {
+    pub fn new(completions: Completions) -> Self {
+        Self {
+            files: FilenameCompleter::new(),
+            completions,
+            command: Regex::new(r"(?:^|\s)(@\w*)$").unwrap(),
+            argument: Regex::new(r"@(?<command>model\s+|persona\s+|reasoning\s+|tpl\(\s*)(?<word>[\w.:/-]*)$").unwrap(),
+        }
+    }
+
+    /// The candidates for the word ending at `pos`, `None` if only a file path fits there.
+    fn complete_word(&self, line: &str, pos: usize) -> Option<(usize, Vec<Pair>)> {
+        let before = &line[..pos];
+        let (word, candidates) = if let Some(caps) = self.argument.captures(before) {
+            let candidates = match caps["command"].chars().next() {
+                Some('m') => self.completions.models.lock().unwrap().clone(),
+                Some('p') => self.completions.personas.clone(),
+                Some('r') => ["on", "off", "collapse"].map(str::to_string).to_vec(),
+                _ => Templates::new(&self.completions.config_dir).list().unwrap_or_default(),
+            };
+            (caps.name("word").unwrap(), candidates)
+        } else {
+            let caps = self.command.captures(before)?;
+            (caps.get(1).unwrap(), self.completions.commands.clone())
+        };
+
+        let pairs = candidates
+            .into_iter()
+            .filter(|e| e.starts_with(word.as_str()))
+            .map(|e| Pair { display: e.clone(), replacement: e })
+            .collect();
+        Some((word.start(), pairs))
+    }
+}
+
+impl Completer for RagCompleter {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        match self.complete_word(line, pos) {
+            Some(completion) => Ok(completion),
+            // Inside `@file(` too, the completer breaks paths at the parenthesis.
+            None => self.files.complete(line, pos, ctx),
+        }
+    }
+}
+
+/// Starts a multi-line input, which ends with an empty line or [`MULTILINE_END`].
+pub const MULTILINE_START: &str = "@ml";
+pub const MULTILINE_END: &str = ";;";
+
+/// Keeps reading after `@ml` until an empty line or `;;`, and after a line ending with a backslash.
+/// Anything else is complete once its brackets match.
+pub struct MultilineValidator {
+    brackets: MatchingBracketValidator,
+}
+
+impl Validator for MultilineValidator {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        let input = ctx.input();
+        if input.trim_start().starts_with(MULTILINE_START) {
+            return Ok(match input.ends_with('\n') || input.trim_end().ends_with(MULTILINE_END) {
+                true => ValidationResult::Valid(None),
+                false => ValidationResult::Incomplete,
+            });
+        }
+        if input.ends_with('\\') {
+            return Ok(ValidationResult::Incomplete);
+        }
+        self.brackets.validate(ctx)
+    }
+}
+
+/// The question a multi-line input asks, without `@ml`, `;;` and the backslashes that continued lines.
+pub fn join_lines(input: &str) -> String {
+    let input = input.trim();
+    match input.strip_prefix(MULTILINE_START) {
+        Some(rest) => rest.trim_end().strip_suffix(MULTILINE_END).unwrap_or(rest).trim().to_string(),
+        None => input.replace("\\\n", "\n"),
+    }
+}
+
+/// What a line read at the prompt asks for.
+#[derive(Debug, PartialEq)]
+pub enum Prompted {
+    Input(String),
+    /// Ctrl+C at the prompt only drops what was typed.
+    Again,
+    /// Ctrl+D ends the session.
+    Quit,
+}
+
+impl Prompted {
+    pub fn from_readline(line: Result<String, ReadlineError>) -> Result<Self, ReadlineError> {
+        match line {
+            Ok(line) => Ok(Self::Input(join_lines(&line))),
+            Err(ReadlineError::Interrupted) => Ok(Self::Again),
+            Err(ReadlineError::Eof) => Ok(Self::Quit),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[derive(Helper, Hinter)]
 pub struct RlHelper {
-    #[rustyline(Completer)]
-    completer: FilenameCompleter,
+    completer: RagCompleter,
     #[rustyline(Highlighter)]
     highlighter: MatchingBracketHighlighter,
-    #[rustyline(Validator)]
-    validator: MatchingBracketValidator,
+    validator: MultilineValidator,
     #[rustyline(Hinter)]
     hinter: HistoryHinter,
     colored_prompt: String,
 }
 
+impl Completer for RlHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        self.completer.complete(line, pos, ctx)
+    }
+}
+
+impl Validator for RlHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        self.validator.validate(ctx)
+    }
+}
+
 impl Highlighter for RlHelper {
     fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
         self.highlighter.highlight(line, pos)
@@ -31,7 +189,7 @@ impl Highlighter for RlHelper {
     }
 
     fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
-        Owned("\x1b[1m".to_owned() + hint + "\x1b[m")
+        Owned(hint.bold().to_string())
     }
 
     fn highlight_char(&self, line: &str, pos: usize, kind: CmdKind) -> bool {
@@ -39,29 +197,113 @@ impl Highlighter for RlHelper {
     }
 }
 
+/// The file the history is read from, the one in the working directory until there is one in the config directory.
+fn history_to_load(history_file: &Path) -> &Path {
+    match history_file.exists() {
+        true => history_file,
+        false => Path::new(LEGACY_HISTORY_FILE),
+    }
+}
+
 impl RlHelper {
-    pub fn new_rl() -> anyhow::Result<Editor<RlHelper, DefaultHistory>> {
+    /// `prompt` has to be passed to `readline` as well, it is only colored here so its width is measured right.
+    /// The history is read from `history_file`, the caller saves it.
+    pub fn new_rl(prompt: &str, completions: Completions, history: &HistoryConfig, history_file: &Path) -> anyhow::Result<Editor<RlHelper, DefaultHistory>> {
         let config = Config::builder()
             .history_ignore_space(true)
+            .max_history_size(history.max_entries.unwrap_or(DEFAULT_MAX_ENTRIES))?
+            .history_ignore_dups(history.ignore_duplicates.unwrap_or(true))?
             .completion_type(CompletionType::List)
             .edit_mode(EditMode::Emacs)
             .build();
 
         let helper = Self {
-            completer: FilenameCompleter::new(),
+            completer: RagCompleter::new(completions),
             highlighter: MatchingBracketHighlighter::new(),
             hinter: HistoryHinter::new(),
             colored_prompt: "".to_owned(),
-            validator: MatchingBracketValidator::new(),
+            validator: MultilineValidator { brackets: MatchingBracketValidator::new() },
         };
 
         let mut rl = Editor::with_config(config)?;
         rl.set_helper(Some(helper));
         rl.bind_sequence(KeyEvent::alt('n'), Cmd::HistorySearchForward);
         rl.bind_sequence(KeyEvent::alt('p'), Cmd::HistorySearchBackward);
-        let _ = rl.load_history("_history.txt");
-        
-        rl.helper_mut().expect("No helper found").colored_prompt = "🌟 ^D:".blue().to_string();
+        let _ = rl.load_history(history_to_load(history_file));
+
+        rl.helper_mut().expect("No helper found").colored_prompt = prompt.blue().to_string();
         Ok(rl)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustyline::history::DefaultHistory;
+
+    fn complete(completer: &RagCompleter, line: &str) -> (usize, Vec<String>) {
+        let history = DefaultHistory::new();
+        let (start, pairs) = completer.complete(line, line.len(), &Context::new(&history)).unwrap();
+        (start, pairs.into_iter().map(|e| e.replacement).collect())
+    }
+
+    #[test]
+    fn test_prompted() {
+        assert_eq!(Prompted::from_readline(Ok("  hi \\\nthere ".to_string())).unwrap(), Prompted::Input("hi \nthere".to_string()));
+        assert_eq!(Prompted::from_readline(Err(ReadlineError::Interrupted)).unwrap(), Prompted::Again);
+        assert_eq!(Prompted::from_readline(Err(ReadlineError::Eof)).unwrap(), Prompted::Quit);
+        assert!(Prompted::from_readline(Err(ReadlineError::WindowResized)).is_err());
+    }
+
+    #[test]
+    fn test_history() {
+        let dir = std::env::temp_dir().join(format!("rag-history-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let history_file = dir.join(HISTORY_FILE);
+        assert_eq!(history_to_load(&history_file), Path::new(LEGACY_HISTORY_FILE));
+
+        std::fs::write(&history_file, "#V2\nfirst\nsecond\nthird\n").unwrap();
+        assert_eq!(history_to_load(&history_file), history_file);
+        let history = HistoryConfig { max_entries: Some(2), ignore_duplicates: None };
+        let rl = RlHelper::new_rl(">", Completions::default(), &history, &history_file).unwrap();
+        assert_eq!(rl.history().iter().collect::<Vec<_>>(), ["second", "third"]);
+    }
+
+    #[test]
+    fn test_completer() {
+        let dir = std::env::temp_dir().join(format!("rag-completer-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        Templates::new(&dir).add("translate", "{{text}}").unwrap();
+        std::fs::write(dir.join("notes.txt"), "").unwrap();
+
+        let commands = ["@file(path[:10-80] | dir/ | glob)", "@model [name]", "@tokens", "@open[(n)]", "@`command`"];
+        let completions = Completions {
+            commands: commands.iter().map(|e| command_prefix(e)).collect(),
+            models: Arc::new(Mutex::new(vec!["gpt-4o".to_string(), "gpt-4o-mini".to_string(), "o3".to_string()])),
+            personas: vec!["reviewer".to_string()],
+            config_dir: dir.clone(),
+        };
+        assert_eq!(completions.commands, ["@file(", "@model ", "@tokens", "@open", "@`"]);
+        let completer = RagCompleter::new(completions);
+
+        assert_eq!(complete(&completer, "explain @fi"), (8, vec!["@file(".to_string()]));
+        assert_eq!(complete(&completer, "@").1.len(), 5);
+        assert_eq!(complete(&completer, "@model gpt").1, ["gpt-4o", "gpt-4o-mini"]);
+        assert_eq!(complete(&completer, "@persona r"), (9, vec!["reviewer".to_string()]));
+        assert_eq!(complete(&completer, "@tpl(tr").1, ["translate"]);
+        assert_eq!(complete(&completer, "@reasoning c").1, ["collapse"]);
+
+        let line = format!("@file({}/no", dir.display());
+        let (start, files) = complete(&completer, &line);
+        assert_eq!((start, files), (6, vec![format!("{}/notes.txt", dir.display())]));
+    }
+
+    #[test]
+    fn test_join_lines() {
+        assert_eq!(join_lines("@ml\nfn main() {\n    println!(\"{\");\n}\n"), "fn main() {\n    println!(\"{\");\n}");
+        assert_eq!(join_lines("@ml explain\n```\nx = 1\n```\n;;"), "explain\n```\nx = 1\n```");
+        assert_eq!(join_lines("first \\\nsecond"), "first \nsecond");
+        assert_eq!(join_lines(" one line "), "one line");
+    }
+}
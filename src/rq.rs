@@ -1,7 +1,10 @@
-use async_openai::types::{ChatCompletionMessageToolCallChunk, ChatCompletionRequestMessage, FinishReason, FunctionCall};
+use async_openai::types::{ChatCompletionMessageToolCallChunk, ChatCompletionRequestMessage, FinishReason, ResponseFormat};
 use derive_builder::Builder;
-use serde::{Deserialize, Serialize};
+use async_openai::error::OpenAIError;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::Value;
+use crate::config::SamplingConfig;
 
 #[derive(Debug, Clone, Builder, Serialize)]
 pub struct RqBody {
@@ -9,16 +12,71 @@ pub struct RqBody {
     pub messages: Vec<ChatCompletionRequestMessage>,
     #[builder(default = "true")]
     pub stream: bool,
-    #[builder(default)]
-    pub stream_options: StreamOptions,
+    #[builder(default = "Some(StreamOptions::default())")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<StreamOptions>,
     #[builder(default = None)]
     pub tools: Option<Value>,
     #[builder(default = "auto".to_string())]
     pub tool_choice: String,
+    #[builder(default = None)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[builder(default = None)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[builder(default = None)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[builder(default = None)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[builder(default = None)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[builder(default = None)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[builder(default = None)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+    #[builder(default = None)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_effort: Option<String>,
+    #[builder(default = None)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thinking: Option<Thinking>,
+}
+
+/// Extended thinking with a token budget, in the form Anthropic's compatible endpoint takes.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Thinking {
+    pub r#type: String,
+    pub budget_tokens: u32,
+}
+
+impl Thinking {
+    pub fn enabled(budget_tokens: u32) -> Self {
+        Self { r#type: "enabled".to_string(), budget_tokens }
+    }
+}
+
+impl RqBodyBuilder {
+    /// Sends the given parameters from now on, replacing all previously set ones.
+    pub fn sampling(&mut self, sampling: &SamplingConfig) -> &mut Self {
+        self.temperature(sampling.temperature)
+            .top_p(sampling.top_p)
+            .max_tokens(sampling.max_tokens)
+            .presence_penalty(sampling.presence_penalty)
+            .frequency_penalty(sampling.frequency_penalty)
+            .stop((!sampling.stop.is_empty()).then(|| sampling.stop.clone()))
+            .reasoning_effort(sampling.reasoning_effort.clone())
+            .thinking(sampling.thinking.map(Thinking::enabled))
+    }
 }
 
 #[derive(Debug, Clone, Builder, Serialize)]
-struct StreamOptions {
+pub struct StreamOptions {
     #[builder(default = "true")]
     pub include_usage: bool,
 }
@@ -32,48 +90,158 @@ impl Default for StreamOptions {
 }
 
 impl RqBody {
-    pub fn to_rq_body(self) -> Value {
+    pub fn into_rq_body(self) -> Value {
         serde_json::to_value(self).unwrap()
     }
 }
 
-#[derive(Debug, Deserialize)]
+/// A streamed chunk. Providers differ in what they leave out or send as null, so every field falls back to its default.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct RsChunkBody {
+    #[serde(deserialize_with = "null_as_default")]
     pub id: String,
+    #[serde(deserialize_with = "null_as_default")]
     pub choices: Vec<Choice>,
+    #[serde(deserialize_with = "null_as_default")]
     pub created: u64,
+    #[serde(deserialize_with = "null_as_default")]
     pub model: String,
     pub system_fingerprint: Option<String>,
+    #[serde(deserialize_with = "null_as_default")]
     pub object: String,
     pub usage: Option<Usage>,
 }
 
-#[derive(Debug, Deserialize)]
+impl RsChunkBody {
+    /// Reads a streamed chunk. An error sent in the stream fails it, a chunk that can't be read is skipped with `None`.
+    pub fn parse(mut value: Value) -> Result<Option<Self>, OpenAIError> {
+        if let Some(error) = value.get("error").filter(|e| !e.is_null()) {
+            let message = error["message"].as_str().or(error.as_str()).map(str::to_string).unwrap_or_else(|| error.to_string());
+            return Err(OpenAIError::StreamError(message));
+        }
+        // Groq reports the usage of the last chunk in its own field.
+        if value["usage"].is_null()
+            && let Some(usage) = value.pointer_mut("/x_groq/usage").map(Value::take)
+        {
+            value["usage"] = usage;
+        }
+        match serde_json::from_value(value.clone()) {
+            Ok(chunk) => Ok(Some(chunk)),
+            Err(e) => {
+                tracing::debug!(error = %e, chunk = %value, "skipped a chunk that can't be read");
+                Ok(None)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Choice {
+    #[serde(deserialize_with = "null_as_default")]
     pub delta: Delta,
+    /// `None` for a reason this client doesn't know as well.
+    #[serde(deserialize_with = "known_or_none")]
     pub finish_reason: Option<FinishReason>,
+    #[serde(deserialize_with = "null_as_default")]
     pub index: u64,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Delta {
+    #[serde(deserialize_with = "null_as_default")]
     pub content: String,
+    /// DeepSeek's name for it, Ollama calls it `reasoning`.
+    #[serde(alias = "reasoning")]
     pub reasoning_content: Option<String>,
+    #[serde(deserialize_with = "null_as_default")]
     pub role: String,
     pub tool_calls: Option<Vec<ChatCompletionMessageToolCallChunk>>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Usage {
+    #[serde(deserialize_with = "null_as_default")]
     pub completion_tokens: u64,
+    #[serde(deserialize_with = "null_as_default")]
     pub prompt_tokens: u64,
     pub prompt_cache_hit_tokens: Option<u64>,
     pub prompt_cache_miss_tokens: Option<u64>,
+    #[serde(deserialize_with = "null_as_default")]
     pub total_tokens: u64,
     pub completion_tokens_details: Option<CompletionTokensDetails>
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct CompletionTokensDetails {
+    #[serde(deserialize_with = "null_as_default")]
     pub reasoning_tokens: u64,
-}
\ No newline at end of file
+}
+
+fn null_as_default<'de, D: Deserializer<'de>, T: Default + Deserialize<'de>>(deserializer: D) -> Result<T, D::Error> {
+    Ok(Option::deserialize(deserializer)?.unwrap_or_default())
+}
+
+fn known_or_none<'de, D: Deserializer<'de>, T: DeserializeOwned>(deserializer: D) -> Result<Option<T>, D::Error> {
+    Ok(serde_json::from_value(Value::deserialize(deserializer)?).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::*;
+
+    /// The content, reasoning, finish reason and total tokens a stream of raw chunks adds up to.
+    fn read(fixture: &str) -> (String, String, Option<FinishReason>, Option<u64>) {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/chunks").join(fixture);
+        let chunks: Vec<Value> = serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        let (mut content, mut reasoning, mut finish_reason, mut tokens) = (String::new(), String::new(), None, None);
+        for chunk in chunks.into_iter().filter_map(|e| RsChunkBody::parse(e).unwrap()) {
+            for choice in &chunk.choices {
+                content.push_str(&choice.delta.content);
+                reasoning.push_str(choice.delta.reasoning_content.as_deref().unwrap_or_default());
+                finish_reason = choice.finish_reason.or(finish_reason);
+            }
+            tokens = chunk.usage.map(|e| e.total_tokens).or(tokens);
+        }
+        (content, reasoning, finish_reason, tokens)
+    }
+
+    #[test]
+    fn test_sampling_is_sent() {
+        let body = |sampling: &SamplingConfig| {
+            let body = RqBodyBuilder::default().model("m".to_string()).messages(vec![]).sampling(sampling).build().unwrap();
+            serde_json::to_value(body).unwrap()
+        };
+        let mut sampling = SamplingConfig::default();
+        let unset = body(&sampling);
+        assert!(unset.get("reasoning_effort").is_none() && unset.get("thinking").is_none());
+
+        sampling.set("effort", "high").unwrap();
+        sampling.set("thinking", "2048").unwrap();
+        let set = body(&sampling);
+        assert_eq!(set["reasoning_effort"], "high");
+        assert_eq!(set["thinking"], serde_json::json!({ "type": "enabled", "budget_tokens": 2048 }));
+    }
+
+    #[test]
+    fn test_provider_chunks() {
+        assert_eq!(read("openai.json"), ("Hello!".to_string(), String::new(), Some(FinishReason::Stop), Some(19)));
+        assert_eq!(read("deepseek.json"), ("Hi.".to_string(), "Greet back.".to_string(), Some(FinishReason::Stop), Some(30)));
+        assert_eq!(read("groq.json"), ("Hey there".to_string(), String::new(), Some(FinishReason::Stop), Some(48)));
+        assert_eq!(read("ollama.json"), ("Hello".to_string(), "Say hi.".to_string(), Some(FinishReason::Length), None));
+    }
+
+    #[test]
+    fn test_unreadable_chunks() {
+        assert!(RsChunkBody::parse(serde_json::json!({ "choices": "none" })).unwrap().is_none());
+        let chunk = RsChunkBody::parse(serde_json::json!({ "choices": [{ "delta": { "content": "a" }, "finish_reason": "eos" }] })).unwrap().unwrap();
+        assert_eq!((chunk.choices[0].delta.content.as_str(), chunk.choices[0].finish_reason), ("a", None));
+        let error = RsChunkBody::parse(serde_json::json!({ "error": { "message": "model overloaded" } })).unwrap_err();
+        assert_eq!(error.to_string(), "stream failed: model overloaded");
+    }
+}
@@ -0,0 +1,176 @@
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use colored::Colorize;
+use regex::Regex;
+use crate::app::Context;
+use crate::processor::{Processor, TurnOutcome};
+
+/// How much of the answer a failed assertion shows.
+const ANSWER_PREVIEW: usize = 200;
+
+/// What an assertion line expects of the last answer.
+#[derive(Debug)]
+enum Assertion {
+    Contains(String),
+    NotContains(String),
+    Matches(Regex),
+}
+
+impl Assertion {
+    /// `contains <text>`, `not contains <text>` or `matches <regex>`, the text may be quoted to keep spaces at its ends.
+    fn parse(line: &str) -> anyhow::Result<Self> {
+        let unquote = |e: &str| {
+            let e = e.trim();
+            e.strip_prefix('"').and_then(|e| e.strip_suffix('"')).unwrap_or(e).to_string()
+        };
+        if let Some(text) = line.strip_prefix("not contains ") {
+            Ok(Self::NotContains(unquote(text)))
+        } else if let Some(text) = line.strip_prefix("contains ") {
+            Ok(Self::Contains(unquote(text)))
+        } else if let Some(pattern) = line.strip_prefix("matches ") {
+            Ok(Self::Matches(Regex::new(&unquote(pattern))?))
+        } else {
+            anyhow::bail!("Expected `assert contains`, `assert not contains` or `assert matches`")
+        }
+    }
+
+    fn holds(&self, answer: &str) -> bool {
+        match self {
+            Self::Contains(text) => answer.contains(text.as_str()),
+            Self::NotContains(text) => !answer.contains(text.as_str()),
+            Self::Matches(pattern) => pattern.is_match(answer),
+        }
+    }
+}
+
+impl Display for Assertion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Contains(text) => write!(f, "contains {:?}", text),
+            Self::NotContains(text) => write!(f, "doesn't contain {:?}", text),
+            Self::Matches(pattern) => write!(f, "matches /{}/", pattern),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Step {
+    /// A prompt or an `@` command, entered as if typed at the prompt.
+    Input(String),
+    Assert(Assertion),
+}
+
+/// The steps of a script with their line numbers. Lines starting with `#` are comments, `assert` lines check the last
+/// answer and a line starting with `@` is a command. The other lines are prompts, consecutive ones make a single prompt.
+fn parse(script: &str) -> anyhow::Result<Vec<(usize, Step)>> {
+    let mut steps = vec![];
+    let mut prompt: Option<(usize, String)> = None;
+    for (number, line) in script.lines().enumerate().map(|(i, e)| (i + 1, e)) {
+        let trimmed = line.trim();
+        let is_prompt = !trimmed.is_empty() && !trimmed.starts_with('#') && !trimmed.starts_with('@') && !trimmed.starts_with("assert ");
+        if is_prompt {
+            match prompt {
+                Some((_, ref mut text)) => {
+                    text.push('\n');
+                    text.push_str(line);
+                }
+                None => prompt = Some((number, line.to_string())),
+            }
+            continue;
+        }
+        if let Some((start, text)) = prompt.take() {
+            steps.push((start, Step::Input(text)));
+        }
+        if trimmed.starts_with('@') {
+            steps.push((number, Step::Input(trimmed.to_string())));
+        } else if let Some(assertion) = trimmed.strip_prefix("assert ") {
+            steps.push((number, Step::Assert(Assertion::parse(assertion.trim()).map_err(|e| anyhow::anyhow!("Line {}: {}", number, e))?)));
+        }
+    }
+    if let Some((start, text)) = prompt {
+        steps.push((start, Step::Input(text)));
+    }
+    Ok(steps)
+}
+
+/// Runs the script at `path` through the session without asking for input, then tells how the assertions did. Fails if
+/// any of them did, so a script can guard prompts in CI.
+pub async fn run(context: &mut Context, processor: &mut Processor, path: &Path) -> anyhow::Result<()> {
+    let steps = parse(&std::fs::read_to_string(path)?)?;
+    // The answer the assertions check, or why there is none.
+    let mut answer: Result<String, String> = Err("nothing was asked yet".to_string());
+    let (mut passed, mut failed) = (0, 0);
+    for (number, step) in steps {
+        match step {
+            Step::Input(input) => match processor.run_once(context, input).await.map_err(|e| anyhow::anyhow!("Line {}: {:#}", number, e))? {
+                TurnOutcome::Answered | TurnOutcome::Cancelled => answer = Ok(context.manager.last_answer().unwrap_or_default()),
+                TurnOutcome::Failed(e) => answer = Err(e),
+                // Commands leave the last answer as it was.
+                TurnOutcome::Skipped => {}
+            },
+            Step::Assert(assertion) => {
+                let result = match answer {
+                    Ok(ref answer) if assertion.holds(answer) => Ok(()),
+                    Ok(ref answer) => {
+                        let mut preview = answer.chars().take(ANSWER_PREVIEW).collect::<String>();
+                        if preview.len() < answer.len() {
+                            preview.push('…');
+                        }
+                        Err(format!("the answer was {:?}", preview))
+                    }
+                    Err(ref e) => Err(format!("there is no answer, {}", e)),
+                };
+                match result {
+                    Ok(()) => {
+                        passed += 1;
+                        println!("{}", format!("✓ line {}: the answer {}", number, assertion).green());
+                    }
+                    Err(e) => {
+                        failed += 1;
+                        println!("{}", format!("✗ line {}: the answer {}, but {}", number, assertion, e).red());
+                    }
+                }
+            }
+        }
+    }
+
+    match failed {
+        0 if passed > 0 => println!("{}", format!("All {} assertions passed", passed).truecolor(128, 138, 135)),
+        0 => {}
+        failed => anyhow::bail!("{} of {} assertions failed", failed, passed + failed),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let script = "# greets\n@model gpt-4o\nSay hi\nin two words\n\nassert contains hi\nassert not contains \" bye \"\nassert matches ^\\w+\nWhat now?\n";
+        let steps = parse(script).unwrap();
+        let described = steps
+            .iter()
+            .map(|(number, step)| match step {
+                Step::Input(input) => format!("{} input {}", number, input),
+                Step::Assert(assertion) => format!("{} assert {}", number, assertion),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(described, [
+            "2 input @model gpt-4o",
+            "3 input Say hi\nin two words",
+            "6 assert contains \"hi\"",
+            "7 assert doesn't contain \" bye \"",
+            "8 assert matches /^\\w+/",
+            "9 input What now?",
+        ]);
+
+        assert!(parse("hi\nassert equals hi").unwrap_err().to_string().starts_with("Line 2"));
+        assert!(parse("assert matches (").is_err());
+
+        assert!(Assertion::parse("contains 4").unwrap().holds("2 + 2 = 4"));
+        assert!(!Assertion::parse("not contains 4").unwrap().holds("2 + 2 = 4"));
+        assert!(Assertion::parse("matches (?i)^hello").unwrap().holds("Hello there"));
+    }
+}
@@ -0,0 +1,236 @@
+use std::fmt::{Debug, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use async_trait::async_trait;
+use colored::Colorize;
+use rhai::{CallFnOptions, Dynamic, Engine, Map, Scope, AST};
+use crate::app::Context;
+use crate::filters::ChunkFilter;
+use crate::bus::{Event, Subscriber};
+use crate::rq::RsChunkBody;
+
+/// Operations a single call into a script may take, so a runaway loop can't hang the session.
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// A user script from the hooks directory. Every function it defines is optional:
+///
+/// - `pre_call(input)` returns the rewritten prompt, or nothing to keep it.
+/// - `filter(delta)` returns the rewritten content delta, or nothing to keep it.
+/// - `post_call(chunk)` sees every chunk as `#{ content, reasoning, tool_call, total_tokens }`.
+/// - `tool_call(call)` sees every tool call as `#{ name, arguments }` before it runs.
+/// - `finished(answer)` sees the whole answer once it is streamed.
+///
+/// The functions share `this`, a map that lives as long as the session.
+struct Script {
+    name: String,
+    ast: AST,
+    state: Mutex<Dynamic>,
+}
+
+impl Script {
+    fn defines(&self, function: &str, arity: usize) -> bool {
+        self.ast.iter_functions().any(|e| e.name == function && e.params.len() == arity)
+    }
+}
+
+/// The loaded scripts, shared by the hooks and the content filter that run them.
+pub(crate) struct Scripts {
+    engine: Engine,
+    scripts: Vec<Script>,
+    /// The answer streamed so far, passed to `finished`.
+    answer: Mutex<String>,
+}
+
+impl Debug for Scripts {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Scripts")
+            .field("scripts", &self.scripts.iter().map(|e| &e.name).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// Lists the `.rhai` files in `dir`, a missing directory has none.
+fn script_files(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut files = std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|e| e.is_file() && e.extension().is_some_and(|e| e == "rhai"))
+        .collect::<Vec<_>>();
+    files.sort();
+    Ok(files)
+}
+
+impl Scripts {
+    /// Compiles every script in `dir` and runs its top level once, a script that fails is reported and skipped.
+    pub fn load(dir: &Path) -> anyhow::Result<Self> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+
+        let scripts = script_files(dir)?
+            .iter()
+            .filter_map(|path| match Self::compile(&engine, path) {
+                Ok(script) => Some(script),
+                Err(e) => {
+                    eprintln!("{}", format!("Warning: Failed to load hook script {}: {:#}", path.display(), e).yellow());
+                    None
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            engine,
+            scripts,
+            answer: Mutex::new(String::new()),
+        })
+    }
+
+    fn compile(engine: &Engine, path: &Path) -> anyhow::Result<Script> {
+        let ast = engine.compile_file(path.to_path_buf()).map_err(|e| anyhow::anyhow!("{}", e))?;
+        engine.run_ast(&ast).map_err(|e| anyhow::anyhow!("{}", e))?;
+        Ok(Script {
+            name: path.file_stem().unwrap_or_default().to_string_lossy().to_string(),
+            ast,
+            state: Mutex::new(Dynamic::from_map(Map::new())),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scripts.is_empty()
+    }
+
+    /// Calls `function` of `script`, a failure is reported and gives `None`.
+    fn call_one(&self, script: &Script, function: &str, arg: Dynamic) -> Option<Dynamic> {
+        let mut state = script.state.lock().unwrap();
+        let options = CallFnOptions::new().eval_ast(false).bind_this_ptr(&mut state);
+        match self.engine.call_fn_with_options::<Dynamic>(options, &mut Scope::new(), &script.ast, function, (arg,)) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                eprintln!("{}", format!("Warning: Hook script {} failed in `{}`: {}", script.name, function, e).yellow());
+                None
+            }
+        }
+    }
+
+    /// Calls `function` in every script that defines it, in file name order.
+    fn call(&self, function: &str, arg: Dynamic) -> Vec<Dynamic> {
+        self.scripts
+            .iter()
+            .filter(|e| e.defines(function, 1))
+            .filter_map(|e| self.call_one(e, function, arg.clone()))
+            .collect()
+    }
+
+    /// Passes `text` through `function` of every script in turn, a script returning anything but a string keeps it as is.
+    fn rewrite(&self, function: &str, text: &mut String) {
+        for script in self.scripts.iter().filter(|e| e.defines(function, 1)) {
+            if let Some(Ok(rewritten)) = self.call_one(script, function, text.clone().into()).map(Dynamic::into_string) {
+                *text = rewritten;
+            }
+        }
+    }
+}
+
+fn chunk_to_map(chunk: &RsChunkBody) -> Map {
+    let mut map = Map::new();
+    if let Some(choice) = chunk.choices.first() {
+        map.insert("content".into(), choice.delta.content.clone().into());
+        map.insert("reasoning".into(), choice.delta.reasoning_content.clone().map(Dynamic::from).unwrap_or_default());
+        map.insert("tool_call".into(), choice.delta.tool_calls.is_some().into());
+    }
+    if let Some(ref usage) = chunk.usage {
+        map.insert("total_tokens".into(), (usage.total_tokens as i64).into());
+    }
+    map
+}
+
+#[derive(Debug)]
+pub(crate) struct ScriptHook {
+    scripts: Arc<Scripts>,
+}
+
+impl ScriptHook {
+    pub fn new(scripts: Arc<Scripts>) -> Self {
+        Self { scripts }
+    }
+}
+
+#[async_trait]
+impl Subscriber for ScriptHook {
+    async fn handle(&self, _ctx: &mut Context, event: &mut Event<'_>) -> anyhow::Result<()> {
+        match event {
+            Event::UserInput(input) if !input.trim().is_empty() => self.scripts.rewrite("pre_call", input),
+            Event::Chunk(chunk) => {
+                if let Some(choice) = chunk.choices.first() {
+                    self.scripts.answer.lock().unwrap().push_str(&choice.delta.content);
+                }
+                self.scripts.call("post_call", chunk_to_map(chunk).into());
+            }
+            Event::ToolCall { name, arguments } => {
+                let call = Map::from_iter([("name".into(), (*name).into()), ("arguments".into(), (*arguments).into())]);
+                self.scripts.call("tool_call", call.into());
+            }
+            Event::TurnEnd => {
+                let answer = std::mem::take(&mut *self.scripts.answer.lock().unwrap());
+                self.scripts.call("finished", answer.into());
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Runs the `filter` function of the scripts as the last content filter.
+#[derive(Debug)]
+pub(crate) struct ScriptFilter {
+    scripts: Arc<Scripts>,
+}
+
+impl ScriptFilter {
+    pub fn new(scripts: Arc<Scripts>) -> Self {
+        Self { scripts }
+    }
+}
+
+impl ChunkFilter for ScriptFilter {
+    fn apply(&mut self, delta: &str) -> String {
+        let mut delta = delta.to_string();
+        self.scripts.rewrite("filter", &mut delta);
+        delta
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scripts() {
+        let dir = std::env::temp_dir().join(format!("rag-scripts-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.rhai"), r#"
+            fn pre_call(input) { "Answer briefly. " + input }
+            fn filter(delta) { delta.replace("colour", "color"); delta }
+            fn finished(answer) { this.answers = (this.answers ?? 0) + 1; this.answers }
+        "#).unwrap();
+        std::fs::write(dir.join("b.rhai"), "fn pre_call(input) { input.to_upper() }").unwrap();
+        std::fs::write(dir.join("broken.rhai"), "fn pre_call(input) {").unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a script").unwrap();
+
+        let scripts = Scripts::load(&dir).unwrap();
+        assert_eq!(scripts.scripts.len(), 2);
+
+        let mut input = "hi".to_string();
+        scripts.rewrite("pre_call", &mut input);
+        assert_eq!(input, "ANSWER BRIEFLY. HI");
+
+        let mut filter = ScriptFilter::new(Arc::new(scripts));
+        assert_eq!(filter.apply("a colour"), "a color");
+
+        let scripts = filter.scripts;
+        let answers = (0..2).flat_map(|_| scripts.call("finished", "".into())).map(|e| e.as_int().unwrap()).collect::<Vec<_>>();
+        assert_eq!(answers, [1, 2]);
+    }
+}
@@ -0,0 +1,379 @@
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use async_openai::types::FinishReason;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use colored::Colorize;
+use futures::Stream;
+use serde_json::{json, Value};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use crate::app::Context;
+use crate::events::{ChannelRenderer, EventSender, TokenUsage, UiEvent};
+use crate::processor::{Processor, TurnOutcome};
+
+/// The model name that stands for whatever `model` is configured.
+const SERVED_MODEL: &str = "rag";
+
+/// What the session sends back for a request while it answers it.
+#[derive(Debug)]
+enum Reply {
+    Event(UiEvent),
+    /// The turn is over, with why the answer ended or what went wrong.
+    Done(Result<Option<FinishReason>, Failure>),
+}
+
+#[derive(Debug)]
+struct Failure {
+    status: StatusCode,
+    message: String,
+}
+
+impl Failure {
+    fn new(status: StatusCode, message: impl ToString) -> Self {
+        Self { status, message: message.to_string() }
+    }
+
+    fn body(&self) -> Value {
+        let kind = match self.status.is_client_error() {
+            true => "invalid_request_error",
+            false => "server_error",
+        };
+        json!({ "error": { "message": self.message, "type": kind } })
+    }
+}
+
+impl IntoResponse for Failure {
+    fn into_response(self) -> Response {
+        (self.status, Json(self.body())).into_response()
+    }
+}
+
+/// A request waiting for the session.
+struct Job {
+    request: Value,
+    replies: UnboundedSender<Reply>,
+}
+
+#[derive(Clone)]
+struct Server {
+    jobs: UnboundedSender<Job>,
+    model: String,
+}
+
+/// The text of a message's `content`, a string or the text parts of an array.
+fn content_text(content: &Value) -> String {
+    match content {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts.iter().filter_map(|e| e["text"].as_str()).collect::<Vec<_>>().join("\n"),
+        _ => String::new(),
+    }
+}
+
+/// What the session was started with, every request begins from it.
+struct Defaults {
+    model: String,
+    system: Option<String>,
+}
+
+/// Puts the conversation of `request` into the context and returns its last message, which is asked as the input.
+fn prepare(context: &mut Context, defaults: &Defaults, request: &Value) -> anyhow::Result<String> {
+    let messages = request["messages"].as_array().map(Vec::as_slice).unwrap_or_default();
+    let Some((last, history)) = messages.split_last() else { anyhow::bail!("`messages` is missing or empty") };
+    if last["role"] != "user" {
+        anyhow::bail!("The last message has to be the user's");
+    }
+
+    let is_system = |e: &&Value| matches!(e["role"].as_str(), Some("system" | "developer"));
+    let system = history.iter().filter(is_system).map(|e| content_text(&e["content"])).collect::<Vec<_>>();
+    context.manager.clear();
+    match system.is_empty() {
+        true => match defaults.system {
+            Some(ref prompt) => context.manager.set_system(prompt),
+            None => context.manager.remove_system(),
+        },
+        false => context.manager.set_system(&system.join("\n\n")),
+    }
+    for message in history.iter().filter(|e| !is_system(e)) {
+        context.manager.add(serde_json::from_value(message.clone())?);
+    }
+
+    let model = request["model"].as_str().filter(|e| !e.is_empty() && *e != SERVED_MODEL).unwrap_or(&defaults.model);
+    context.rq_body.model(model.to_string());
+    context.config.model = model.to_string();
+    Ok(content_text(&last["content"]))
+}
+
+/// Answers `job` through the hooks and tools, sending the events as they come. The input is taken as plain text, an `@`
+/// command in it would read files or run programs on this machine for whoever can reach the port.
+async fn answer(context: &mut Context, processor: &mut Processor, receiver: &mut UnboundedReceiver<UiEvent>, defaults: &Defaults, job: Job) {
+    let input = match prepare(context, defaults, &job.request) {
+        Ok(input) => input,
+        Err(e) => {
+            let _ = job.replies.send(Reply::Done(Err(Failure::new(StatusCode::BAD_REQUEST, format!("{:#}", e)))));
+            return;
+        }
+    };
+
+    context.plain_input = true;
+    let outcome = {
+        let turn = processor.run_once(context, input);
+        tokio::pin!(turn);
+        loop {
+            tokio::select! {
+                outcome = &mut turn => break outcome,
+                Some(event) = receiver.recv() => {
+                    let _ = job.replies.send(Reply::Event(event));
+                }
+            }
+        }
+    };
+    while let Ok(event) = receiver.try_recv() {
+        let _ = job.replies.send(Reply::Event(event));
+    }
+    let done = match outcome {
+        Ok(TurnOutcome::Answered | TurnOutcome::Skipped | TurnOutcome::Cancelled) => Ok(context.finish_reason.take()),
+        Ok(TurnOutcome::Failed(e)) => Err(Failure::new(StatusCode::BAD_GATEWAY, e)),
+        Err(e) => Err(Failure::new(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))),
+    };
+    let _ = job.replies.send(Reply::Done(done));
+}
+
+/// An answer put together from the events, in the shape of the OpenAI API.
+#[derive(Debug)]
+struct Completion {
+    id: String,
+    created: i64,
+    model: String,
+    content: String,
+    reasoning: String,
+    /// Added up over the requests of the turn, a tool call takes more than one.
+    usage: Option<TokenUsage>,
+}
+
+impl Completion {
+    fn new(model: &str) -> Self {
+        let now = chrono::Utc::now();
+        Self {
+            id: format!("chatcmpl-rag-{}", now.timestamp_nanos_opt().unwrap_or_default()),
+            created: now.timestamp(),
+            model: model.to_string(),
+            content: String::new(),
+            reasoning: String::new(),
+            usage: None,
+        }
+    }
+
+    /// Takes in `event`, returns the delta it adds to the answer, if any.
+    fn apply(&mut self, event: UiEvent) -> Option<Value> {
+        match event {
+            UiEvent::AnswerStarted { model } | UiEvent::FellBack { to: model, .. } => self.model = model,
+            UiEvent::ContentDelta(content) if !content.is_empty() => {
+                self.content.push_str(&content);
+                return Some(json!({ "content": content }));
+            }
+            UiEvent::Reasoning(content) | UiEvent::CollapsedReasoning(content) if !content.is_empty() => {
+                self.reasoning.push_str(&content);
+                return Some(json!({ "reasoning_content": content }));
+            }
+            UiEvent::Usage { turn, .. } => {
+                let usage = self.usage.get_or_insert_default();
+                usage.prompt_tokens += turn.prompt_tokens;
+                usage.completion_tokens += turn.completion_tokens;
+                usage.total_tokens += turn.total_tokens;
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn chunk(&self, delta: Value, finish_reason: Option<&Value>) -> Value {
+        json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+        })
+    }
+
+    fn response(&self, finish_reason: &Value) -> Value {
+        let mut message = json!({ "role": "assistant", "content": self.content });
+        if !self.reasoning.is_empty() {
+            message["reasoning_content"] = json!(self.reasoning);
+        }
+        json!({
+            "id": self.id,
+            "object": "chat.completion",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{ "index": 0, "message": message, "finish_reason": finish_reason }],
+            "usage": self.usage,
+        })
+    }
+}
+
+fn finish_reason(reason: Option<FinishReason>) -> Value {
+    serde_json::to_value(reason.unwrap_or(FinishReason::Stop)).unwrap_or_default()
+}
+
+/// The events of a streamed answer as server-sent events, ending with `[DONE]` like the OpenAI API.
+fn stream(completion: Completion, replies: UnboundedReceiver<Reply>, include_usage: bool) -> impl Stream<Item = Result<SseEvent, Infallible>> {
+    let first = completion.chunk(json!({ "role": "assistant", "content": "" }), None).to_string();
+    let state = (completion, replies, VecDeque::from([first]), false);
+    futures::stream::unfold(state, move |(mut completion, mut replies, mut pending, mut done)| async move {
+        while pending.is_empty() && !done {
+            match replies.recv().await {
+                Some(Reply::Event(event)) => {
+                    if let Some(delta) = completion.apply(event) {
+                        pending.push_back(completion.chunk(delta, None).to_string());
+                    }
+                    continue;
+                }
+                Some(Reply::Done(Ok(reason))) => {
+                    pending.push_back(completion.chunk(json!({}), Some(&finish_reason(reason))).to_string());
+                    if include_usage {
+                        let mut chunk = completion.chunk(json!({}), None);
+                        chunk["choices"] = json!([]);
+                        chunk["usage"] = json!(completion.usage);
+                        pending.push_back(chunk.to_string());
+                    }
+                }
+                Some(Reply::Done(Err(failure))) => pending.push_back(failure.body().to_string()),
+                None => {}
+            }
+            pending.push_back("[DONE]".to_string());
+            done = true;
+        }
+        let data = pending.pop_front()?;
+        Some((Ok(SseEvent::default().data(data)), (completion, replies, pending, done)))
+    })
+}
+
+async fn chat_completions(State(server): State<Server>, Json(request): Json<Value>) -> Response {
+    let (replies, mut receiver) = unbounded_channel();
+    let streamed = request["stream"].as_bool().unwrap_or_default();
+    let include_usage = request["stream_options"]["include_usage"].as_bool().unwrap_or_default();
+    let model = request["model"].as_str().filter(|e| !e.is_empty() && *e != SERVED_MODEL).unwrap_or(&server.model).to_string();
+    if server.jobs.send(Job { request, replies }).is_err() {
+        return Failure::new(StatusCode::SERVICE_UNAVAILABLE, "The session is gone").into_response();
+    }
+
+    let mut completion = Completion::new(&model);
+    if streamed {
+        return Sse::new(stream(completion, receiver, include_usage)).keep_alive(KeepAlive::default()).into_response();
+    }
+    while let Some(reply) = receiver.recv().await {
+        match reply {
+            Reply::Event(event) => {
+                completion.apply(event);
+            }
+            Reply::Done(Ok(reason)) => return Json(completion.response(&finish_reason(reason))).into_response(),
+            Reply::Done(Err(failure)) => return failure.into_response(),
+        }
+    }
+    Failure::new(StatusCode::INTERNAL_SERVER_ERROR, "The session stopped before answering").into_response()
+}
+
+async fn models(State(server): State<Server>) -> Json<Value> {
+    let model = |id: &str| json!({ "id": id, "object": "model", "created": 0, "owned_by": SERVED_MODEL });
+    Json(json!({ "object": "list", "data": [model(SERVED_MODEL), model(&server.model)] }))
+}
+
+/// Serves `/v1/chat/completions` on `address`, every request goes through the session like a question asked at the
+/// prompt. Requests are answered one at a time, and the tools that need confirming ask on this terminal.
+pub async fn run(context: &mut Context, processor: &mut Processor, address: &str) -> anyhow::Result<()> {
+    let listener = tokio::net::TcpListener::bind(address).await?;
+    let (jobs, mut queue) = unbounded_channel();
+    let router = Router::new()
+        .route("/v1/chat/completions", post(chat_completions))
+        .route("/v1/models", get(models))
+        .with_state(Server { jobs, model: context.config.model.clone() });
+    let server = tokio::spawn(async move { axum::serve(listener, router).await });
+    println!("{}", format!("Serving the OpenAI API on http://{}/v1, Ctrl+C to stop", address).truecolor(128, 138, 135));
+
+    let (sender, mut receiver) = unbounded_channel();
+    context.events = EventSender::spawn(Box::new(ChannelRenderer::new(sender)));
+    let defaults = Defaults { model: context.config.model.clone(), system: context.manager.system() };
+    while let Some(job) = queue.recv().await {
+        answer(context, processor, &mut receiver, &defaults, job).await;
+    }
+    server.await??;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use async_openai::Client;
+    use async_openai::config::OpenAIConfig;
+    use super::*;
+    use crate::bus::{EventKind, PRIORITY_DEFAULT};
+    use crate::client::mock::{Discard, MockClient};
+    use crate::config::Config;
+    use crate::processor::CommandParser;
+
+    #[test]
+    fn test_completion() {
+        assert_eq!(content_text(&json!("hi")), "hi");
+        assert_eq!(content_text(&json!([{ "type": "text", "text": "a" }, { "type": "image_url" }, { "type": "text", "text": "b" }])), "a\nb");
+
+        let mut completion = Completion::new("rag");
+        let usage = TokenUsage { prompt_tokens: 10, completion_tokens: 5, total_tokens: 15 };
+        assert_eq!(completion.apply(UiEvent::AnswerStarted { model: "gpt-4o".to_string() }), None);
+        assert_eq!(completion.apply(UiEvent::Reasoning("hm".to_string())), Some(json!({ "reasoning_content": "hm" })));
+        assert_eq!(completion.apply(UiEvent::ContentDelta("Hel".to_string())), Some(json!({ "content": "Hel" })));
+        completion.apply(UiEvent::ContentDelta("lo".to_string()));
+        completion.apply(UiEvent::Usage { turn: usage, total_tokens: 15 });
+        completion.apply(UiEvent::Usage { turn: usage, total_tokens: 30 });
+
+        let response = completion.response(&finish_reason(Some(FinishReason::Length)));
+        assert_eq!(response["model"], "gpt-4o");
+        assert_eq!(response["choices"][0]["message"], json!({ "role": "assistant", "content": "Hello", "reasoning_content": "hm" }));
+        assert_eq!(response["choices"][0]["finish_reason"], "length");
+        assert_eq!(response["usage"], json!({ "prompt_tokens": 20, "completion_tokens": 10, "total_tokens": 30 }));
+
+        let chunk = completion.chunk(json!({ "content": "x" }), None);
+        assert_eq!(chunk["object"], "chat.completion.chunk");
+        assert_eq!(chunk["choices"][0], json!({ "index": 0, "delta": { "content": "x" }, "finish_reason": null }));
+        assert_eq!(Failure::new(StatusCode::BAD_REQUEST, "no").body()["error"]["type"], "invalid_request_error");
+    }
+
+    #[tokio::test]
+    async fn test_commands_are_plain_text() {
+        let mock = Arc::new(MockClient::new(vec![MockClient::answer("Sure."), MockClient::answer("Bye.")]));
+        let (mut processor, mut context) = Processor::builder()
+            .with_config(Config::default())
+            .with_backend(Client::with_config(OpenAIConfig::new().into()))
+            .with_renderer(Box::new(Discard))
+            .with_chat_client(mock.clone())
+            .with_subscriber(&[EventKind::UserInput], PRIORITY_DEFAULT, Arc::new(CommandParser::new(&BTreeMap::new())))
+            .build()
+            .unwrap();
+        let (sender, mut receiver) = unbounded_channel();
+        context.events = EventSender::spawn(Box::new(ChannelRenderer::new(sender)));
+        let defaults = Defaults { model: "gpt-4o".to_string(), system: None };
+
+        // `@exit` would end the test run if it were run.
+        for input in ["@`echo hi`", "@exit"] {
+            let (replies, mut received) = unbounded_channel();
+            let request = json!({ "messages": [{ "role": "user", "content": input }] });
+            answer(&mut context, &mut processor, &mut receiver, &defaults, Job { request, replies }).await;
+
+            let mut done = None;
+            while let Ok(reply) = received.try_recv() {
+                if let Reply::Done(result) = reply {
+                    done = Some(result);
+                }
+            }
+            assert!(matches!(done, Some(Ok(_))));
+            let requests = mock.requests.lock().unwrap();
+            assert_eq!(requests.last().unwrap()["messages"].as_array().unwrap().last().unwrap()["content"], input);
+        }
+        assert_eq!(mock.requests.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,119 @@
+use std::io::{stdin, stdout, IsTerminal, Write};
+use async_openai::Client;
+use async_openai::config::{Config as ClientConfig, OpenAIConfig};
+use async_openai::error::OpenAIError;
+use colored::Colorize;
+use serde_json::Value;
+use crate::config::{Config, DEFAULT_BASE_URL, DEFAULT_MODEL};
+
+/// Asks for `label`, an empty answer keeps `default`.
+fn ask(label: &str, default: &str) -> anyhow::Result<String> {
+    print!("{} [{}]: ", label, default);
+    stdout().flush()?;
+    let mut answer = String::new();
+    stdin().read_line(&mut answer)?;
+    Ok(match answer.trim() {
+        "" => default.to_string(),
+        answer => answer.to_string(),
+    })
+}
+
+fn confirm(question: &str) -> anyhow::Result<bool> {
+    Ok(matches!(ask(question, "y/N")?.to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Sends a one token request, which fails unless the url, key and model all work.
+pub async fn probe<C: ClientConfig>(client: &Client<C>, model: &str) -> Result<(), OpenAIError> {
+    client
+        .chat()
+        .create_byot::<_, Value>(serde_json::json!({
+            "model": model,
+            "messages": [{ "role": "user", "content": "ping" }],
+            "max_tokens": 1,
+        }))
+        .await?;
+    Ok(())
+}
+
+/// Asks for the provider settings until they work or the user keeps them anyway, then saves the config.
+pub async fn first_run(config: &mut Config) -> anyhow::Result<()> {
+    if !stdin().is_terminal() {
+        anyhow::bail!("No config at {}, run rag in a terminal once to set it up", config.config_file_path().display());
+    }
+    println!("{}", format!("No config found, setting up {}", config.config_file_path().display()).yellow());
+
+    let (mut base_url, mut model) = (DEFAULT_BASE_URL.to_string(), DEFAULT_MODEL.to_string());
+    loop {
+        base_url = ask("Base url", &base_url)?;
+        model = ask("Model", &model)?;
+        let api_key = rpassword::prompt_password("API key: ")?.trim().to_string();
+        if api_key.is_empty() {
+            eprintln!("{}", "Warning: The API key can't be empty".yellow());
+            continue;
+        }
+
+        print!("{}", "Checking the settings... ".truecolor(128, 138, 135));
+        stdout().flush()?;
+        let client = Client::with_config(OpenAIConfig::new().with_api_base(&base_url).with_api_key(&api_key));
+        let result = probe(&client, &model).await;
+        match result {
+            Ok(()) => println!("{}", "ok".green()),
+            Err(ref e) => println!("{}", format!("failed: {}", e).red()),
+        }
+
+        if result.is_ok() || confirm("Save these settings anyway?")? {
+            config.base_url = base_url;
+            config.model = model;
+            config.api_key = api_key;
+            config.save_config()?;
+            println!("{}", format!("Saved {}", config.config_file_path().display()).truecolor(128, 138, 135));
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Answers a single request with `status` and `body`, handing back what was sent.
+    async fn serve_once(status: &'static str, body: &'static str) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            // Until the end of the JSON body, which may come apart from the headers.
+            let mut request = String::new();
+            while !request.ends_with('}') {
+                let mut buffer = [0; 4096];
+                let n = socket.read(&mut buffer).await.unwrap();
+                request.push_str(&String::from_utf8_lossy(&buffer[..n]));
+            }
+            let response = format!(
+                "HTTP/1.1 {}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            request
+        });
+        (format!("http://{}/v1", address), handle)
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        let (base_url, request) = serve_once("200 OK", r#"{"choices": []}"#).await;
+        let client = Client::with_config(OpenAIConfig::new().with_api_base(&base_url).with_api_key("sk-test"));
+        probe(&client, "gpt-4o").await.unwrap();
+        let request = request.await.unwrap();
+        assert!(request.starts_with("POST /v1/chat/completions"));
+        assert!(request.contains(r#""max_tokens":1"#) && request.contains(r#""model":"gpt-4o""#));
+
+        let (base_url, _) = serve_once("401 Unauthorized", r#"{"error": {"message": "Incorrect API key", "type": "invalid_request_error"}}"#).await;
+        let client = Client::with_config(OpenAIConfig::new().with_api_base(&base_url).with_api_key("sk-wrong"));
+        let e = probe(&client, "gpt-4o").await.unwrap_err();
+        assert!(e.to_string().contains("Incorrect API key"), "{}", e);
+    }
+}
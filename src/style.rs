@@ -0,0 +1,25 @@
+use std::io::{stdout, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static EMOJI: AtomicBool = AtomicBool::new(true);
+
+/// Decides once at startup whether output gets colors and emoji. Both are left out for `plain` output and when
+/// stdout isn't a terminal, where they'd end up as escape codes in files and logs. `NO_COLOR` turns the colors off
+/// everywhere, `CLICOLOR_FORCE` keeps them on in pipes.
+pub fn init(plain: bool) {
+    let plain = plain || !stdout().is_terminal();
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|e| !e.is_empty());
+    let force_color = std::env::var("CLICOLOR_FORCE").is_ok_and(|e| e != "0");
+    if no_color || (plain && !force_color) {
+        colored::control::set_override(false);
+    }
+    EMOJI.store(!plain, Ordering::Relaxed);
+}
+
+/// `emoji` followed by a space, or nothing once emoji are turned off.
+pub fn emoji(emoji: &str) -> String {
+    match EMOJI.load(Ordering::Relaxed) {
+        true => format!("{} ", emoji),
+        false => String::new(),
+    }
+}
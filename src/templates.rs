@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::io::{stdin, IsTerminal, Read};
+use std::path::{Path, PathBuf};
+use regex::Regex;
+
+/// Where the templates are kept, relative to the config directory.
+pub const TEMPLATES_DIR: &str = "prompts";
+
+/// Prompt templates, one `<name>.md` file each. `{{variable}}` in a template is filled in when it is used,
+/// `{{stdin}}` and `{{clipboard}}` with what was piped to rag and what is on the clipboard unless they are given.
+#[derive(Debug)]
+pub struct Templates {
+    dir: PathBuf,
+}
+
+impl Templates {
+    pub fn new(config_dir: &Path) -> Self {
+        Self { dir: config_dir.join(TEMPLATES_DIR) }
+    }
+
+    fn path(&self, name: &str) -> anyhow::Result<PathBuf> {
+        if name.is_empty() || !name.chars().all(|e| e.is_alphanumeric() || e == '-' || e == '_') {
+            anyhow::bail!("{:?} isn't a valid template name, use letters, digits, `-` and `_`", name);
+        }
+        Ok(self.dir.join(format!("{}.md", name)))
+    }
+
+    pub fn add(&self, name: &str, template: &str) -> anyhow::Result<PathBuf> {
+        let path = self.path(name)?;
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(&path, template)?;
+        Ok(path)
+    }
+
+    pub fn load(&self, name: &str) -> anyhow::Result<String> {
+        let path = self.path(name)?;
+        if !path.is_file() {
+            anyhow::bail!("There is no template named {}, `rag prompt list` shows the saved ones", name);
+        }
+        Ok(std::fs::read_to_string(path)?)
+    }
+
+    /// The names of the saved templates, sorted.
+    pub fn list(&self) -> anyhow::Result<Vec<String>> {
+        if !self.dir.is_dir() {
+            return Ok(vec![]);
+        }
+        let mut names = std::fs::read_dir(&self.dir)?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|e| e.is_file() && e.extension().is_some_and(|e| e == "md"))
+            .filter_map(|e| e.file_stem().map(|e| e.to_string_lossy().to_string()))
+            .collect::<Vec<_>>();
+        names.sort();
+        Ok(names)
+    }
+
+    /// Loads the template `name` and fills it in with `vars`.
+    pub fn expand(&self, name: &str, vars: &HashMap<String, String>) -> anyhow::Result<String> {
+        render(&self.load(name)?, |variable| match vars.get(variable) {
+            Some(value) => Ok(Some(value.clone())),
+            None => builtin(variable),
+        })
+    }
+}
+
+/// The variables `template` uses, in order of their first use.
+pub fn variables(template: &str) -> Vec<String> {
+    let mut variables = Vec::<String>::new();
+    for caps in variable_pattern().captures_iter(template) {
+        if !variables.iter().any(|e| *e == caps["name"]) {
+            variables.push(caps["name"].to_string());
+        }
+    }
+    variables
+}
+
+fn variable_pattern() -> Regex {
+    Regex::new(r"\{\{\s*(?<name>[\w-]+)\s*\}\}").unwrap()
+}
+
+/// Replaces every `{{variable}}` with what `lookup` gives for it, failing with all the variables it has no value for.
+pub fn render(template: &str, mut lookup: impl FnMut(&str) -> anyhow::Result<Option<String>>) -> anyhow::Result<String> {
+    let mut values = HashMap::new();
+    let mut missing = vec![];
+    for variable in variables(template) {
+        match lookup(&variable)? {
+            Some(value) => {
+                values.insert(variable, value);
+            }
+            None => missing.push(variable),
+        }
+    }
+    if !missing.is_empty() {
+        anyhow::bail!("No value for {}, pass them as key=value", missing.join(", "));
+    }
+    Ok(variable_pattern().replace_all(template, |caps: &regex::Captures| values[&caps["name"]].clone()).to_string())
+}
+
+/// `stdin` and `clipboard`, `None` for any other variable.
+fn builtin(variable: &str) -> anyhow::Result<Option<String>> {
+    match variable {
+        "stdin" => {
+            if stdin().is_terminal() {
+                anyhow::bail!("The template uses {{{{stdin}}}}, but nothing was piped to rag");
+            }
+            let mut piped = String::new();
+            stdin().read_to_string(&mut piped)?;
+            Ok(Some(piped.trim_end().to_string()))
+        }
+        "clipboard" => Ok(Some(arboard::Clipboard::new()?.get_text()?)),
+        _ => Ok(None),
+    }
+}
+
+/// `key=value` pairs, an argument without `=` is an error.
+pub fn parse_vars<'a>(args: impl IntoIterator<Item = &'a str>) -> anyhow::Result<HashMap<String, String>> {
+    args.into_iter()
+        .map(|e| match e.split_once('=') {
+            Some((key, value)) => Ok((key.trim().to_string(), value.trim().to_string())),
+            None => Err(anyhow::anyhow!("Expected key=value, got {}", e)),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_templates() {
+        let dir = std::env::temp_dir().join(format!("rag-templates-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let templates = Templates::new(&dir);
+        assert!(templates.list().unwrap().is_empty());
+        assert!(templates.add("../escape", "x").is_err());
+
+        templates.add("translate", "Translate {{ text }} into {{lang}}, keep {{lang}} idioms.").unwrap();
+        assert_eq!(templates.list().unwrap(), ["translate"]);
+        assert_eq!(variables(&templates.load("translate").unwrap()), ["text", "lang"]);
+
+        let vars = parse_vars(["text=hello", "lang = French"]).unwrap();
+        assert_eq!(templates.expand("translate", &vars).unwrap(), "Translate hello into French, keep French idioms.");
+        let error = templates.expand("translate", &parse_vars(["text=hi"]).unwrap()).unwrap_err();
+        assert_eq!(error.to_string(), "No value for lang, pass them as key=value");
+        assert!(parse_vars(["text"]).is_err());
+    }
+}
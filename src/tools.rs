@@ -1,14 +1,63 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
 use std::fmt::Debug;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use tokio::sync::oneshot;
 use macros::function_tool;
+use crate::config::{Config, ToolLimits, ToolPermission};
+use crate::tools::archive::{ArchiveExtractMemberTool, ArchiveListTool};
+use crate::tools::data::{DataDescribeTool, DataPreviewTool};
+use crate::tools::env::SessionEnv;
+use crate::tools::exec::{ExecuteCommandTool, RunPythonTool};
+use crate::tools::external::ExternalTool;
+use crate::tools::fs::{GlobTool, ListDirectoryTool, ReadFileTool, Sandbox, WriteFileTool};
+use crate::tools::git::{GitCommitTool, GitDiffTool, GitStatusTool};
+use crate::tools::guard::WriteGuard;
+use crate::tools::http::{HttpPolicy, HttpRequestTool};
+use crate::tools::issues::{github_tools, jira_tools};
+use crate::tools::mail::{CalendarEventsTool, MailSearchTool};
+use crate::tools::patch::{ApplyPatchTool, DiffTextTool, EditFileTool};
+use crate::tools::plugin::load_plugins;
+use crate::tools::query::{JsonPathQueryTool, RegexExtractTool};
+use crate::tools::search::GrepCodebaseTool;
+use crate::tools::web::WebSearchTool;
 
-pub trait Tool {
+mod archive;
+mod data;
+pub mod env;
+mod exec;
+mod external;
+mod fs;
+pub mod git;
+pub mod guard;
+mod http;
+mod issues;
+mod mail;
+mod patch;
+mod plugin;
+mod query;
+mod search;
+pub mod web;
+
+/// Tools of one turn run on separate threads, so they must be shareable.
+pub trait Tool: Send + Sync {
 
     fn metadata(&self) -> ToolMetaData;
 
     fn execute(&self, parameters: Value) -> anyhow::Result<Value>;
+
+    /// Limits the tool needs instead of the defaults, `tool_limits` in the config still takes precedence.
+    fn limits(&self) -> ToolLimits {
+        ToolLimits::default()
+    }
+
+    /// Whether `execute` may stop to ask the user on the terminal for a call with `parameters`, such calls never run
+    /// alongside others.
+    fn confirms(&self, _parameters: &Value) -> bool {
+        false
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -28,7 +77,8 @@ impl ToolMetaData {
                 "parameters": {
                     "type": "object",
                     "properties": self.parameters["properties"],
-                    "required": self.parameters["required"],
+                    "required": self.parameters.get("required").cloned().unwrap_or(json!([])),
+                    "$defs": self.parameters.get("$defs").cloned().unwrap_or(json!({})),
                 }
             }
         })
@@ -51,81 +101,298 @@ macro_rules! impl_tool_params {
     }
 }
 
+/// The model asked for a tool that isn't registered, usually a hallucinated name.
+#[derive(Debug, thiserror::Error)]
+#[error("There is no tool named {name}, the available tools are: {}", available.join(", "))]
+pub struct UnknownTool {
+    pub name: String,
+    pub available: Vec<String>,
+}
+
+const DEFAULT_TIMEOUT_SECS: u64 = 120;
+const DEFAULT_MAX_OUTPUT_BYTES: usize = 128 * 1024;
+
 pub struct ToolRegistry {
-    tools: HashMap<String, Box<dyn Tool>>,
+    tools: HashMap<String, Arc<dyn Tool>>,
+    permissions: HashMap<String, ToolPermission>,
+    /// The configured limits until registration, afterward the resolved ones of each tool.
+    limits: HashMap<String, ToolLimits>,
+    validators: HashMap<String, jsonschema::Validator>,
+    /// The tools offered to the model when a persona picks some, all registered ones otherwise.
+    enabled: Option<HashSet<String>>,
+    /// Variables set with `@env` for every subprocess the tools spawn.
+    pub env: SessionEnv,
 }
 
 impl ToolRegistry {
-    pub fn new() -> Self {
+    pub fn new(config: &Config) -> anyhow::Result<Self> {
         let mut tools = Self {
             tools: HashMap::new(),
+            permissions: config.tool_permissions.clone(),
+            limits: config.tool_limits.clone(),
+            validators: HashMap::new(),
+            enabled: None,
+            env: SessionEnv::new(),
+        };
+        let env = tools.env.clone();
+
+        let sandbox = match config.sandbox_root {
+            Some(ref root) => Sandbox::new(root)?,
+            None => Sandbox::new(std::env::current_dir()?)?,
         };
 
+        let guard = WriteGuard::new(
+            config.confirm_writes,
+            Some(config.backup_dir.clone().unwrap_or_else(|| config.config_dir().join("backups"))),
+        );
+
         tools.register(AddTool {});
-        // tools.register(ExecuteCommandTool {});
+        tools.register(ReadFileTool::new(sandbox.clone()));
+        tools.register(WriteFileTool::new(sandbox.clone(), guard.clone()));
+        tools.register(ListDirectoryTool::new(sandbox.clone()));
+        tools.register(GlobTool::new(sandbox.clone()));
+        tools.register(GrepCodebaseTool::new(sandbox.clone()));
+        tools.register(RegexExtractTool::new(sandbox.clone()));
+        tools.register(JsonPathQueryTool::new(sandbox.clone()));
+        tools.register(ArchiveListTool::new(sandbox.clone()));
+        tools.register(ArchiveExtractMemberTool::new(sandbox.clone(), guard.clone()));
+        tools.register(DataPreviewTool::new(sandbox.clone()));
+        tools.register(DataDescribeTool::new(sandbox.clone()));
+        tools.register(DiffTextTool);
+        tools.register(ApplyPatchTool::new(sandbox.clone(), guard.clone()));
+        tools.register(EditFileTool::new(sandbox.clone(), guard));
+        tools.register(GitStatusTool::new(sandbox.clone(), env.clone()));
+        tools.register(GitDiffTool::new(sandbox.clone(), env.clone()));
+        tools.register_with_permission(GitCommitTool::new(sandbox.clone(), env.clone()), ToolPermission::Confirm);
+        if let Some(ref command_sandbox) = config.command_sandbox {
+            tools.register(ExecuteCommandTool::new(sandbox.clone(), command_sandbox.clone(), env.clone()));
+        }
+        for external in &config.external_tools {
+            tools.register(ExternalTool::new(external.clone(), sandbox.clone(), env.clone())?);
+        }
+        // Without a sandbox the script runs with the user's privileges, so every call is confirmed.
+        let python = RunPythonTool::new(
+            config.python_interpreter.clone(),
+            env.clone(),
+            config.command_sandbox.clone().map(|e| ExecuteCommandTool::new(sandbox, e, env)),
+        );
+        let permission = if python.is_sandboxed() { ToolPermission::Allow } else { ToolPermission::Confirm };
+        tools.register_with_permission(python, permission);
+
+        let http_policy = HttpPolicy::new(&config.http_allowed_hosts, config.http_max_response_bytes)?;
+        if http_policy.is_enabled() {
+            tools.register(HttpRequestTool::new(http_policy)?);
+        }
+        if let Some(ref web_search) = config.web_search {
+            tools.register(WebSearchTool::new(web_search.clone())?);
+        }
+        // Personal data is only read after the user agreed to each call, unless configured otherwise.
+        if let Some(ref mail) = config.mail {
+            tools.register_with_permission(MailSearchTool::new(mail.clone()), ToolPermission::Confirm);
+        }
+        if let Some(ref calendar) = config.calendar {
+            tools.register_with_permission(CalendarEventsTool::new(calendar.clone())?, ToolPermission::Confirm);
+        }
+        // Reading is harmless, anything posted in the user's name is confirmed first.
+        if let Some(ref github) = config.github {
+            let (search, read, create, comment) = github_tools(github.clone())?;
+            tools.register(search);
+            tools.register(read);
+            tools.register_with_permission(create, ToolPermission::Confirm);
+            tools.register_with_permission(comment, ToolPermission::Confirm);
+        }
+        if let Some(ref jira) = config.jira {
+            let (search, read, create, comment) = jira_tools(jira.clone())?;
+            tools.register(search);
+            tools.register(read);
+            tools.register_with_permission(create, ToolPermission::Confirm);
+            tools.register_with_permission(comment, ToolPermission::Confirm);
+        }
+
+        for plugin in load_plugins(&config.config_dir().join("plugins"))? {
+            tools.register(plugin);
+        }
 
-        tools
+        Ok(tools)
     }
 
     pub fn register<T: Tool + 'static>(&mut self, tool: T) {
+        self.register_with_permission(tool, ToolPermission::Allow);
+    }
+
+    /// Registers `tool` with a default permission, `tool_permissions` in the config takes precedence.
+    /// Denied tools are never offered to the model.
+    pub fn register_with_permission<T: Tool + 'static>(&mut self, tool: T, default: ToolPermission) {
         let metadata = tool.metadata();
-        self.tools.insert(metadata.name, Box::new(tool));
+        let permission = *self.permissions.entry(metadata.name.clone()).or_insert(default);
+
+        if permission != ToolPermission::Deny {
+            // A schema the validator can't compile only means the arguments go unchecked.
+            if let Ok(validator) = jsonschema::validator_for(&metadata.parameters) {
+                self.validators.insert(metadata.name.clone(), validator);
+            }
+            let configured = self.limits.get(&metadata.name).copied().unwrap_or_default();
+            let default = self.limits.get("default").copied().unwrap_or_default();
+            self.limits.insert(metadata.name.clone(), configured.or(tool.limits()).or(default));
+            self.tools.insert(metadata.name, Arc::new(tool));
+        }
     }
 
-    pub fn execute(
+    #[cfg(test)]
+    pub async fn execute(
         &self,
         tool_name: impl AsRef<str>,
         parameters: Value,
     ) -> anyhow::Result<Value> {
-        let res = self.tools
-            .get(tool_name.as_ref())
-            .expect("Unknown Tool")
-            .execute(parameters)?;
+        let tool_name = tool_name.as_ref();
+        if let Some(rejection) = self.prepare(tool_name, &parameters)? {
+            return Ok(rejection);
+        }
+        self.run(tool_name, parameters).await
+    }
+
+    /// Executes the calls of one turn, the results are in the order of `calls`.
+    ///
+    /// Each call is validated, and confirmed if its permission says so, one at a time. The calls to tools that ask the
+    /// user themselves run right then too, so no two prompts are ever up at once, the rest run concurrently afterwards.
+    pub async fn execute_all(&self, calls: Vec<(String, Value)>) -> Vec<anyhow::Result<Value>> {
+        let mut results = Vec::with_capacity(calls.len());
+        let mut concurrent = vec![];
+        for (index, (tool_name, parameters)) in calls.into_iter().enumerate() {
+            let result = match self.prepare(&tool_name, &parameters) {
+                Ok(None) if self.confirms(&tool_name, &parameters) => self.run(&tool_name, parameters).await,
+                Ok(None) => {
+                    concurrent.push(async move { (index, self.run(&tool_name, parameters).await) });
+                    // Filled in below once the call is done.
+                    Ok(Value::Null)
+                }
+                Ok(Some(rejection)) => Ok(rejection),
+                Err(e) => Err(e),
+            };
+            results.push(result);
+        }
+
+        for (index, result) in futures::future::join_all(concurrent).await {
+            results[index] = result;
+        }
+        results
+    }
+
+    /// Offers only the tools in `names` to the model, or all of them for `None`. Returns the names that aren't registered.
+    pub fn restrict(&mut self, names: Option<&[String]>) -> Vec<String> {
+        let Some(names) = names else {
+            self.enabled = None;
+            return vec![];
+        };
+        let unknown = names.iter().filter(|e| !self.tools.contains_key(*e)).cloned().collect();
+        self.enabled = Some(names.iter().cloned().collect());
+        unknown
+    }
+
+    fn is_enabled(&self, tool_name: &str) -> bool {
+        self.tools.contains_key(tool_name) && self.enabled.as_ref().is_none_or(|e| e.contains(tool_name))
+    }
+
+    /// Checks a call before it runs, `Some` is the result to report instead when it must not run.
+    fn prepare(&self, tool_name: &str, parameters: &Value) -> anyhow::Result<Option<Value>> {
+        if !self.is_enabled(tool_name) {
+            return Err(self.unknown(tool_name).into());
+        }
+
+        // Mistakes are reported back so the model can correct the call instead of failing deep inside the tool.
+        if let Some(validator) = self.validators.get(tool_name) {
+            let details = validator
+                .iter_errors(parameters)
+                .map(|e| format!("{}: {}", e.instance_path(), e))
+                .collect::<Vec<_>>();
+            if !details.is_empty() {
+                return Ok(Some(json!({
+                    "executed": false,
+                    "error": format!("The arguments don't match the parameters of {}", tool_name),
+                    "details": details,
+                })));
+            }
+        }
+
+        if self.permissions.get(tool_name) == Some(&ToolPermission::Confirm)
+            && !guard::ask(&format!("\nAllow {} with {}", tool_name, parameters))? {
+            return Ok(Some(json!({
+                "executed": false,
+                "reason": "The user declined this call",
+            })));
+        }
 
-        Ok(res)
+        Ok(None)
     }
 
-    pub fn list_metadata(&self) -> Vec<ToolMetaData> {
-        self.tools
-            .values()
-            .map(|t| t.metadata())
-            .collect()
+    /// Runs the tool on its own thread, a call that outlives its timeout is abandoned rather than waited for. Abandoning
+    /// doesn't stop it, the tool keeps running in the background, only the exec tools kill what they started once their
+    /// own timeout is up. Calls that ask the user aren't timed, they can't be abandoned while the user is still deciding.
+    /// The thread enters the runtime, so the tools that block on reqwest find its reactor.
+    async fn run(&self, tool_name: &str, parameters: Value) -> anyhow::Result<Value> {
+        let tool = self.tools.get(tool_name).cloned().ok_or_else(|| self.unknown(tool_name))?;
+        let limits = self.limits.get(tool_name).copied().unwrap_or_default();
+        let timeout_secs = limits.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS);
+        let timed = !tool.confirms(&parameters);
+
+        let (sender, receiver) = oneshot::channel();
+        let runtime = tokio::runtime::Handle::current();
+        std::thread::spawn(move || {
+            let _runtime = runtime.enter();
+            let _ = sender.send(tool.execute(parameters));
+        });
+
+        let finished = match timed {
+            true => tokio::time::timeout(Duration::from_secs(timeout_secs), receiver).await,
+            false => Ok(receiver.await),
+        };
+        let result = match finished {
+            Ok(Ok(result)) => result?,
+            Ok(Err(_)) => anyhow::bail!("{} panicked", tool_name),
+            Err(_) => return Ok(json!({
+                "executed": false,
+                "error": format!("{} didn't finish within {} seconds and was abandoned", tool_name, timeout_secs),
+            })),
+        };
+        truncate_output(result, limits.max_output_bytes.unwrap_or(DEFAULT_MAX_OUTPUT_BYTES))
+    }
+
+    fn confirms(&self, tool_name: &str, parameters: &Value) -> bool {
+        self.tools.get(tool_name).is_some_and(|e| e.confirms(parameters))
+    }
+
+    fn unknown(&self, tool_name: &str) -> UnknownTool {
+        let mut available = self.tools.keys().filter(|e| self.is_enabled(e)).cloned().collect::<Vec<_>>();
+        available.sort();
+        UnknownTool { name: tool_name.to_string(), available }
     }
 
     pub fn to_tools_call_body(&self) -> Value {
         serde_json::to_value(
             self.tools
                 .iter()
+                .filter(|(name, _)| self.is_enabled(name))
                 .map(|(_, item)| item.metadata().to_tools_call_body())
                 .collect::<Vec<_>>()
         ).unwrap()
     }
 }
 
-struct StubTool;
-
-#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
-pub struct StubToolParameters {
-    pub message: String,
-}
-
-impl_tool_params!(StubToolParameters);
-
-impl Tool for StubTool {
-    fn metadata(&self) -> ToolMetaData {
-        ToolMetaData {
-            name: "stub_tool".to_string(),
-            description: "This is an example".to_string(),
-            parameters: StubToolParameters::schema(),
-        }
+/// Replaces a result longer than `max_bytes` with its cut JSON text and a note for the model.
+fn truncate_output(result: Value, max_bytes: usize) -> anyhow::Result<Value> {
+    let text = serde_json::to_string(&result)?;
+    if text.len() <= max_bytes {
+        return Ok(result);
     }
-
-    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
-        let params = serde_json::from_value::<StubToolParameters>(parameters)?;
-        println!("Execute StubTool {}", params.message);
-
-        Ok(Value::Null)
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
     }
+    Ok(json!({
+        "output": &text[..end],
+        "truncated": true,
+        "note": format!("The result was {} bytes and only the first {} are shown, narrow the call to see the rest", text.len(), end),
+    }))
 }
 
 #[function_tool(name = "Add", description = "add a with b")]
@@ -133,15 +400,231 @@ fn add(a: i32, b: i32) -> i32 {
     a + b
 }
 
-#[function_tool(name = "ExecuteCommand", description = "Execute any command you pass by (no check). Return `Ok` if executing successfully, otherwise, return reason.")]
-fn execute_command(command: String) -> String {
-    todo!() 
-}
-
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use super::*;
 
+    fn registry(limits: HashMap<String, ToolLimits>) -> ToolRegistry {
+        ToolRegistry {
+            tools: HashMap::new(),
+            permissions: HashMap::new(),
+            limits,
+            validators: HashMap::new(),
+            enabled: None,
+            env: SessionEnv::new(),
+        }
+    }
+
+    #[function_tool(name = "Repeat", description = "repeat a text")]
+    fn repeat(
+        #[param(description = "what to repeat")] text: String,
+        #[param(default = 2)] times: usize,
+        separator: Option<String>,
+    ) -> String {
+        vec![text; times].join(&separator.unwrap_or_default())
+    }
+
+    #[test]
+    fn test_param_attributes() {
+        let metadata = RepeatTool {}.metadata().to_tools_call_body();
+        let parameters = &metadata["function"]["parameters"];
+
+        assert_eq!(parameters["required"], json!(["text"]));
+        assert_eq!(parameters["properties"]["text"]["description"], "what to repeat");
+        assert_eq!(parameters["properties"]["times"]["default"], 2);
+
+        let result = RepeatTool {}.execute(json!({ "text": "ab" })).unwrap();
+        assert_eq!(result["result"], "abab");
+    }
+
+    /// Parse an integer.
+    /// Fails on anything else.
+    #[function_tool(name = "ParseInt")]
+    fn parse_int(text: String) -> anyhow::Result<i64> {
+        Ok(text.trim().parse()?)
+    }
+
+    #[test]
+    fn test_doc_description_and_result() {
+        assert_eq!(ParseIntTool {}.metadata().description, "Parse an integer. Fails on anything else.");
+        assert_eq!(AddTool {}.metadata().description, "add a with b");
+
+        assert_eq!(ParseIntTool {}.execute(json!({ "text": " 42" })).unwrap(), json!({ "result": 42 }));
+        assert_eq!(
+            ParseIntTool {}.execute(json!({ "text": "x" })).unwrap(),
+            json!({ "error": "invalid digit found in string" }),
+        );
+    }
+
+    struct Counter {
+        start: i64,
+    }
+
+    #[function_tool]
+    impl Counter {
+        /// Add to the counter's start value.
+        #[function_tool(name = "CountFrom")]
+        fn count_from(&self, by: i64) -> i64 {
+            self.start + by
+        }
+
+        #[allow(dead_code)]
+        fn helper(&self) {}
+    }
+
+    #[test]
+    fn test_method_tool() {
+        let tool = CountFromTool::new(Counter { start: 40 });
+
+        assert_eq!(tool.metadata().name, "CountFrom");
+        assert_eq!(tool.metadata().description, "Add to the counter's start value.");
+        assert_eq!(tool.execute(json!({ "by": 2 })).unwrap(), json!({ "result": 42 }));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_arguments_are_reported() {
+        let mut registry = registry(HashMap::new());
+        registry.register(AddTool {});
+
+        let result = registry.execute("Add", json!({ "a": "3" })).await.unwrap();
+        assert_eq!(result["executed"], false);
+        assert_eq!(result["details"].as_array().unwrap().len(), 2);
+        assert_eq!(registry.execute("Add", json!({ "a": 3, "b": 5 })).await.unwrap(), json!({ "result": 8 }));
+    }
+
+    #[tokio::test]
+    async fn test_execute_all_keeps_order() {
+        let mut registry = registry(HashMap::new());
+        registry.register(AddTool {});
+
+        let results = registry.execute_all(vec![
+            ("Add".to_string(), json!({ "a": 1, "b": 2 })),
+            ("Subtract".to_string(), json!({})),
+            ("Add".to_string(), json!({ "a": "1" })),
+            ("Add".to_string(), json!({ "a": 3, "b": 4 })),
+        ]).await;
+        assert_eq!(results[0].as_ref().unwrap(), &json!({ "result": 3 }));
+        assert!(results[1].as_ref().unwrap_err().is::<UnknownTool>());
+        assert_eq!(results[2].as_ref().unwrap()["executed"], false);
+        assert_eq!(results[3].as_ref().unwrap(), &json!({ "result": 7 }));
+    }
+
+    /// Counts how many of its calls run at once, like a tool that asks before writing and waits `millis` for the answer.
+    struct Asking {
+        running: Arc<AtomicUsize>,
+        most: Arc<AtomicUsize>,
+        millis: u64,
+    }
+
+    impl Tool for Asking {
+        fn metadata(&self) -> ToolMetaData {
+            ToolMetaData { name: "Ask".to_string(), description: String::new(), parameters: json!({ "type": "object", "properties": {} }) }
+        }
+
+        fn execute(&self, _parameters: Value) -> anyhow::Result<Value> {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most.fetch_max(running, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(self.millis));
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            Ok(json!(running))
+        }
+
+        fn confirms(&self, _parameters: &Value) -> bool {
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn test_confirming_calls_run_alone() {
+        let mut registry = registry(HashMap::new());
+        let most = Arc::new(AtomicUsize::new(0));
+        registry.register(Asking { running: Arc::default(), most: most.clone(), millis: 50 });
+        registry.register(AddTool {});
+
+        let results = registry.execute_all(vec![
+            ("Ask".to_string(), json!({})),
+            ("Add".to_string(), json!({ "a": 1, "b": 2 })),
+            ("Ask".to_string(), json!({})),
+        ]).await;
+        assert_eq!(most.load(Ordering::SeqCst), 1);
+        assert_eq!(results.into_iter().map(Result::unwrap).collect::<Vec<_>>(), [json!(1), json!({ "result": 3 }), json!(1)]);
+    }
+
+    #[function_tool(name = "Sleep", description = "sleep for a while")]
+    fn sleep(millis: u64) -> String {
+        std::thread::sleep(Duration::from_millis(millis));
+        "x".repeat(100)
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let mut registry = registry(HashMap::from([
+            ("default".to_string(), ToolLimits { timeout_secs: None, max_output_bytes: Some(40) }),
+            ("Sleep".to_string(), ToolLimits { timeout_secs: Some(1), max_output_bytes: None }),
+            ("Ask".to_string(), ToolLimits { timeout_secs: Some(1), max_output_bytes: None }),
+        ]));
+        registry.register(SleepTool {});
+        registry.register(AddTool {});
+        assert_eq!(registry.limits["Sleep"], ToolLimits { timeout_secs: Some(1), max_output_bytes: Some(40) });
+
+        let result = registry.execute("Sleep", json!({ "millis": 0 })).await.unwrap();
+        assert_eq!(result["truncated"], true);
+        assert_eq!(result["output"].as_str().unwrap().len(), 40);
+        assert_eq!(registry.execute("Add", json!({ "a": 1, "b": 2 })).await.unwrap(), json!({ "result": 3 }));
+
+        let result = registry.execute("Sleep", json!({ "millis": 3000 })).await.unwrap();
+        assert_eq!(result["executed"], false);
+
+        // The time spent waiting for the user doesn't count.
+        registry.register(Asking { running: Arc::default(), most: Arc::default(), millis: 1500 });
+        assert_eq!(registry.execute("Ask", json!({})).await.unwrap(), json!(1));
+    }
+
+    #[tokio::test]
+    async fn test_http_request_reaches_the_runtime() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buffer = [0; 4096];
+            let _ = socket.read(&mut buffer).await.unwrap();
+            socket.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok").await.unwrap();
+        });
+        let mut registry = registry(HashMap::new());
+        registry.register(HttpRequestTool::new(HttpPolicy::new(&["127.0.0.1".to_string()], None).unwrap()).unwrap());
+
+        let result = registry.run("http_request", json!({ "url": format!("http://{}/", address) })).await.unwrap();
+        assert_eq!((result["status"].clone(), result["body"].clone()), (json!(200), json!("ok")));
+    }
+
+    #[tokio::test]
+    async fn test_restrict() {
+        let mut registry = registry(HashMap::new());
+        registry.register(AddTool {});
+        registry.register(SleepTool {});
+
+        assert_eq!(registry.restrict(Some(&["Add".to_string(), "Subtract".to_string()])), ["Subtract"]);
+        assert_eq!(registry.to_tools_call_body().as_array().unwrap().len(), 1);
+        assert!(registry.execute("Sleep", json!({ "millis": 0 })).await.unwrap_err().is::<UnknownTool>());
+
+        assert!(registry.restrict(None).is_empty());
+        assert_eq!(registry.to_tools_call_body().as_array().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_tool() {
+        let mut registry = registry(HashMap::new());
+        registry.register(AddTool {});
+
+        let error = registry.execute("Subtract", json!({})).await.unwrap_err();
+        let error = error.downcast_ref::<UnknownTool>().unwrap();
+        assert_eq!(error.available, ["Add"]);
+        assert_eq!(error.to_string(), "There is no tool named Subtract, the available tools are: Add");
+    }
+
     #[test]
     fn test_schema() {
         let tool = AddTool {};
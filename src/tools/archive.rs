@@ -0,0 +1,258 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use anyhow::{anyhow, bail};
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::impl_tool_params;
+use crate::tools::{Tool, ToolMetaData, ToolParameters};
+use crate::tools::fs::Sandbox;
+use crate::tools::guard::WriteGuard;
+
+/// Members bigger than this are only listed, never read into memory.
+const MAX_MEMBER_BYTES: u64 = 4 * 1024 * 1024;
+/// How much of a member is returned inline to the model.
+const MAX_INLINE_BYTES: usize = 256 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl ArchiveKind {
+    fn detect(path: &Path) -> anyhow::Result<Self> {
+        let name = path.file_name().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+
+        if name.ends_with(".zip") || name.ends_with(".jar") {
+            Ok(ArchiveKind::Zip)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Ok(ArchiveKind::TarGz)
+        } else if name.ends_with(".tar") {
+            Ok(ArchiveKind::Tar)
+        } else {
+            bail!("Unsupported archive {:?}, expected .zip, .tar or .tar.gz", path)
+        }
+    }
+}
+
+fn open_tar(path: &Path, kind: ArchiveKind) -> anyhow::Result<tar::Archive<Box<dyn Read>>> {
+    let file = BufReader::new(File::open(path)?);
+    let reader: Box<dyn Read> = match kind {
+        ArchiveKind::TarGz => Box::new(GzDecoder::new(file)),
+        _ => Box::new(file),
+    };
+    Ok(tar::Archive::new(reader))
+}
+
+fn list_entries(path: &Path) -> anyhow::Result<Vec<Value>> {
+    let mut entries = vec![];
+
+    match ArchiveKind::detect(path)? {
+        ArchiveKind::Zip => {
+            let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+            for index in 0..archive.len() {
+                let entry = archive.by_index(index)?;
+                entries.push(json!({
+                    "name": entry.name(),
+                    "size": entry.size(),
+                    "is_dir": entry.is_dir(),
+                }));
+            }
+        }
+        kind => {
+            let mut archive = open_tar(path, kind)?;
+            for entry in archive.entries()? {
+                let entry = entry?;
+                entries.push(json!({
+                    "name": entry.path()?.to_string_lossy(),
+                    "size": entry.size(),
+                    "is_dir": entry.header().entry_type().is_dir(),
+                }));
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+fn read_member(path: &Path, member: &str) -> anyhow::Result<Vec<u8>> {
+    let mut content = vec![];
+
+    match ArchiveKind::detect(path)? {
+        ArchiveKind::Zip => {
+            let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+            let entry = archive
+                .by_name(member)
+                .map_err(|_| anyhow!("No member {} in archive", member))?;
+            if entry.size() > MAX_MEMBER_BYTES {
+                bail!("Member {} is too large ({} bytes)", member, entry.size());
+            }
+            entry.take(MAX_MEMBER_BYTES).read_to_end(&mut content)?;
+        }
+        kind => {
+            let mut archive = open_tar(path, kind)?;
+            let mut found = false;
+            for entry in archive.entries()? {
+                let entry = entry?;
+                if entry.path()?.to_string_lossy() != member { continue; }
+                if entry.size() > MAX_MEMBER_BYTES {
+                    bail!("Member {} is too large ({} bytes)", member, entry.size());
+                }
+                entry.take(MAX_MEMBER_BYTES).read_to_end(&mut content)?;
+                found = true;
+                break;
+            }
+            if !found {
+                bail!("No member {} in archive", member);
+            }
+        }
+    }
+
+    Ok(content)
+}
+
+pub struct ArchiveListTool {
+    sandbox: Sandbox,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct ArchiveListParameters {
+    /// Path of a .zip, .tar or .tar.gz archive, relative to the workspace root
+    pub archive: String,
+}
+
+impl_tool_params!(ArchiveListParameters);
+
+impl ArchiveListTool {
+    pub fn new(sandbox: Sandbox) -> Self {
+        Self { sandbox }
+    }
+}
+
+impl Tool for ArchiveListTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "archive_list".to_string(),
+            description: "List the members of a zip, tar or tar.gz archive in the workspace.".to_string(),
+            parameters: ArchiveListParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<ArchiveListParameters>(parameters)?;
+        let path = self.sandbox.resolve(&params.archive)?;
+        let entries = list_entries(&path)
+            .map_err(|e| anyhow!("Failed to read archive {}: {}", params.archive, e))?;
+
+        Ok(json!({
+            "archive": params.archive,
+            "entries": entries,
+        }))
+    }
+}
+
+pub struct ArchiveExtractMemberTool {
+    sandbox: Sandbox,
+    guard: WriteGuard,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct ArchiveExtractMemberParameters {
+    /// Path of a .zip, .tar or .tar.gz archive, relative to the workspace root
+    pub archive: String,
+    /// Name of the member exactly as reported by archive_list
+    pub member: String,
+    /// Where to write the member, relative to the workspace root. When omitted the content is returned as text
+    pub destination: Option<String>,
+}
+
+impl_tool_params!(ArchiveExtractMemberParameters);
+
+impl ArchiveExtractMemberTool {
+    pub fn new(sandbox: Sandbox, guard: WriteGuard) -> Self {
+        Self { sandbox, guard }
+    }
+}
+
+impl Tool for ArchiveExtractMemberTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "archive_extract_member".to_string(),
+            description: "Read a single member of a zip, tar or tar.gz archive, or extract it to a file in the workspace.".to_string(),
+            parameters: ArchiveExtractMemberParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<ArchiveExtractMemberParameters>(parameters)?;
+        let path = self.sandbox.resolve(&params.archive)?;
+        let content = read_member(&path, &params.member)
+            .map_err(|e| anyhow!("Failed to read archive {}: {}", params.archive, e))?;
+
+        if let Some(ref destination) = params.destination {
+            let target = self.sandbox.resolve(destination)?;
+            let text = String::from_utf8(content)
+                .map_err(|_| anyhow!("Member {} is binary and can't be extracted as text", params.member))?;
+            let preview = format!("Extract {} from {}", params.member, params.archive);
+            return self.guard.write(&self.sandbox, &target, &text, &preview);
+        }
+
+        let mut inline = String::from_utf8_lossy(&content).to_string();
+        let truncated = inline.len() > MAX_INLINE_BYTES;
+        if truncated {
+            let mut end = MAX_INLINE_BYTES;
+            while !inline.is_char_boundary(end) { end -= 1; }
+            inline.truncate(end);
+        }
+
+        Ok(json!({
+            "member": params.member,
+            "size": content.len(),
+            "truncated": truncated,
+            "content": inline,
+        }))
+    }
+
+    /// Only writing to a destination asks, reading a member inline is as harmless as listing.
+    fn confirms(&self, parameters: &Value) -> bool {
+        self.guard.confirms() && parameters.get("destination").is_some_and(|e| !e.is_null())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use crate::tools::fs::tests::temp_sandbox;
+
+    #[test]
+    fn test_list_and_extract_zip() {
+        let sandbox = temp_sandbox("archive");
+        let mut writer = zip::ZipWriter::new(File::create(sandbox.root().join("logs.zip")).unwrap());
+        writer.start_file("logs/build.log", zip::write::SimpleFileOptions::default()).unwrap();
+        writer.write_all(b"error: linker failed").unwrap();
+        writer.finish().unwrap();
+
+        let listed = ArchiveListTool::new(sandbox.clone())
+            .execute(json!({ "archive": "logs.zip" }))
+            .unwrap();
+        assert_eq!(listed["entries"][0]["name"], "logs/build.log");
+
+        let tool = ArchiveExtractMemberTool::new(sandbox.clone(), WriteGuard::new(false, None));
+        let read = tool.execute(json!({ "archive": "logs.zip", "member": "logs/build.log" })).unwrap();
+        assert_eq!(read["content"], "error: linker failed");
+
+        assert!(tool.execute(json!({ "archive": "logs.zip", "member": "logs/build.log", "destination": "../x.log" })).is_err());
+    }
+
+    #[test]
+    fn test_only_extracting_confirms() {
+        let tool = ArchiveExtractMemberTool::new(temp_sandbox("archive_confirms"), WriteGuard::new(true, None));
+        assert!(!tool.confirms(&json!({ "archive": "logs.zip", "member": "a.log" })));
+        assert!(!tool.confirms(&json!({ "archive": "logs.zip", "member": "a.log", "destination": null })));
+        assert!(tool.confirms(&json!({ "archive": "logs.zip", "member": "a.log", "destination": "a.log" })));
+    }
+}
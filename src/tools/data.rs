@@ -0,0 +1,272 @@
+use std::collections::HashSet;
+use std::path::Path;
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use crate::impl_tool_params;
+use crate::tools::{Tool, ToolMetaData, ToolParameters};
+use crate::tools::fs::Sandbox;
+
+const DEFAULT_PREVIEW_ROWS: usize = 10;
+const MAX_PREVIEW_ROWS: usize = 200;
+/// Distinct values are only counted exactly up to this many per column.
+const MAX_DISTINCT: usize = 10_000;
+
+type Rows = Box<dyn Iterator<Item = anyhow::Result<Vec<String>>>>;
+
+/// A dataset read row by row with every cell as text, empty cells meaning null.
+struct Table {
+    columns: Vec<String>,
+    rows: Rows,
+}
+
+impl Table {
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+
+        match extension.as_str() {
+            "csv" | "tsv" => {
+                let mut reader = csv::ReaderBuilder::new()
+                    .delimiter(if extension == "tsv" { b'\t' } else { b',' })
+                    .flexible(true)
+                    .from_path(path)?;
+                let columns = reader.headers()?.iter().map(|e| e.to_string()).collect();
+                let rows = reader
+                    .into_records()
+                    .map(|record| Ok(record?.iter().map(|e| e.to_string()).collect()));
+
+                Ok(Self { columns, rows: Box::new(rows) })
+            }
+            #[cfg(feature = "parquet")]
+            "parquet" => Self::open_parquet(path),
+            #[cfg(not(feature = "parquet"))]
+            "parquet" => bail!("Parquet support isn't compiled in, rebuild with `--features parquet`"),
+            _ => bail!("Unsupported data file {:?}, expected .csv, .tsv or .parquet", path),
+        }
+    }
+
+    #[cfg(feature = "parquet")]
+    fn open_parquet(path: &Path) -> anyhow::Result<Self> {
+        use parquet::file::reader::{FileReader, SerializedFileReader};
+        use parquet::record::Field;
+
+        let reader = SerializedFileReader::new(std::fs::File::open(path)?)?;
+        let columns = reader
+            .metadata()
+            .file_metadata()
+            .schema_descr()
+            .columns()
+            .iter()
+            .map(|e| e.name().to_string())
+            .collect();
+        let rows = reader.into_iter().map(|row| {
+            Ok(row?
+                .get_column_iter()
+                .map(|(_, field)| match field {
+                    Field::Null => String::new(),
+                    Field::Str(e) => e.clone(),
+                    other => other.to_string(),
+                })
+                .collect())
+        });
+
+        Ok(Self { columns, rows: Box::new(rows) })
+    }
+}
+
+#[derive(Debug, Default)]
+struct ColumnStats {
+    non_null: usize,
+    nulls: usize,
+    integers: usize,
+    numbers: usize,
+    booleans: usize,
+    sum: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+    distinct: HashSet<String>,
+}
+
+impl ColumnStats {
+    fn push(&mut self, cell: &str) {
+        let cell = cell.trim();
+        if cell.is_empty() || cell.eq_ignore_ascii_case("null") || cell.eq_ignore_ascii_case("na") {
+            self.nulls += 1;
+            return;
+        }
+
+        self.non_null += 1;
+        if self.distinct.len() < MAX_DISTINCT {
+            self.distinct.insert(cell.to_string());
+        }
+        if cell.parse::<i64>().is_ok() {
+            self.integers += 1;
+        }
+        if let Ok(number) = cell.parse::<f64>() {
+            self.numbers += 1;
+            self.sum += number;
+            self.min = Some(self.min.map_or(number, |e| e.min(number)));
+            self.max = Some(self.max.map_or(number, |e| e.max(number)));
+        }
+        if matches!(cell.to_lowercase().as_str(), "true" | "false") {
+            self.booleans += 1;
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self.non_null {
+            0 => "empty",
+            n if self.integers == n => "integer",
+            n if self.numbers == n => "float",
+            n if self.booleans == n => "boolean",
+            _ => "string",
+        }
+    }
+
+    fn describe(&self, name: &str) -> Value {
+        let kind = self.kind();
+        let mut column = json!({
+            "name": name,
+            "type": kind,
+            "non_null": self.non_null,
+            "nulls": self.nulls,
+            "distinct": self.distinct.len(),
+            "distinct_exact": self.distinct.len() < MAX_DISTINCT,
+        });
+
+        if matches!(kind, "integer" | "float") {
+            column["min"] = json!(self.min);
+            column["max"] = json!(self.max);
+            column["mean"] = json!(self.sum / self.numbers as f64);
+        }
+        column
+    }
+}
+
+pub struct DataPreviewTool {
+    sandbox: Sandbox,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct DataPreviewParameters {
+    /// Path of a .csv, .tsv or .parquet file, relative to the workspace root
+    pub path: String,
+    /// Number of rows to return, defaults to 10
+    pub n: Option<usize>,
+}
+
+impl_tool_params!(DataPreviewParameters);
+
+impl DataPreviewTool {
+    pub fn new(sandbox: Sandbox) -> Self {
+        Self { sandbox }
+    }
+}
+
+impl Tool for DataPreviewTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "data_preview".to_string(),
+            description: "Return the column names and the first rows of a CSV/TSV or Parquet dataset.".to_string(),
+            parameters: DataPreviewParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<DataPreviewParameters>(parameters)?;
+        let table = Table::open(&self.sandbox.resolve(&params.path)?)?;
+        let n = params.n.unwrap_or(DEFAULT_PREVIEW_ROWS).min(MAX_PREVIEW_ROWS);
+
+        let mut rows = vec![];
+        for row in table.rows.take(n) {
+            let row = table.columns
+                .iter()
+                .zip(row?)
+                .map(|(column, cell)| (column.clone(), json!(cell)))
+                .collect::<Map<_, _>>();
+            rows.push(Value::Object(row));
+        }
+
+        Ok(json!({
+            "columns": table.columns,
+            "rows": rows,
+        }))
+    }
+}
+
+pub struct DataDescribeTool {
+    sandbox: Sandbox,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct DataDescribeParameters {
+    /// Path of a .csv, .tsv or .parquet file, relative to the workspace root
+    pub path: String,
+}
+
+impl_tool_params!(DataDescribeParameters);
+
+impl DataDescribeTool {
+    pub fn new(sandbox: Sandbox) -> Self {
+        Self { sandbox }
+    }
+}
+
+impl Tool for DataDescribeTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "data_describe".to_string(),
+            description: "Summarize a CSV/TSV or Parquet dataset: row count, inferred column types, nulls, distinct values and min/max/mean of numeric columns.".to_string(),
+            parameters: DataDescribeParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<DataDescribeParameters>(parameters)?;
+        let table = Table::open(&self.sandbox.resolve(&params.path)?)?;
+
+        let mut stats = table.columns.iter().map(|_| ColumnStats::default()).collect::<Vec<_>>();
+        let mut row_count = 0;
+        for row in table.rows {
+            row_count += 1;
+            for (column, cell) in stats.iter_mut().zip(row?.iter()) {
+                column.push(cell);
+            }
+        }
+
+        Ok(json!({
+            "rows": row_count,
+            "columns": table.columns
+                .iter()
+                .zip(stats.iter())
+                .map(|(name, column)| column.describe(name))
+                .collect::<Vec<_>>(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::fs::tests::temp_sandbox;
+
+    #[test]
+    fn test_preview_and_describe_csv() {
+        let sandbox = temp_sandbox("data");
+        std::fs::write(sandbox.root().join("people.csv"), "name,age,score\nada,36,1.5\nbob,,2.5\ncy,40,3\n").unwrap();
+
+        let preview = DataPreviewTool::new(sandbox.clone())
+            .execute(json!({ "path": "people.csv", "n": 1 }))
+            .unwrap();
+        assert_eq!(preview["rows"], json!([{ "name": "ada", "age": "36", "score": "1.5" }]));
+
+        let described = DataDescribeTool::new(sandbox)
+            .execute(json!({ "path": "people.csv" }))
+            .unwrap();
+        assert_eq!(described["rows"], 3);
+        assert_eq!(described["columns"][1]["type"], "integer");
+        assert_eq!(described["columns"][1]["nulls"], 1);
+        assert_eq!(described["columns"][2]["type"], "float");
+        assert_eq!(described["columns"][2]["mean"], 7.0 / 3.0);
+    }
+}
@@ -0,0 +1,85 @@
+use std::collections::BTreeMap;
+use std::process::Command;
+use std::sync::{Arc, RwLock};
+use anyhow::bail;
+
+/// Variables that change how programs load code are never passed on, whatever the session sets.
+const SCRUBBED: &[&str] = &["LD_*", "DYLD_*", "BASH_ENV", "ENV", "PROMPT_COMMAND", "PYTHONSTARTUP", "GIT_SSH_COMMAND", "GIT_EXEC_PATH"];
+
+fn is_scrubbed(name: &str) -> bool {
+    SCRUBBED.iter().any(|pattern| match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == *pattern,
+    })
+}
+
+/// Whether listing the value would leak a credential.
+fn is_secret(name: &str) -> bool {
+    ["KEY", "TOKEN", "SECRET", "PASSWORD", "PASS", "CREDENTIAL"]
+        .iter()
+        .any(|e| name.to_uppercase().contains(e))
+}
+
+/// Environment variables set with `@env`, shared by every tool that spawns a subprocess.
+#[derive(Debug, Clone, Default)]
+pub struct SessionEnv {
+    vars: Arc<RwLock<BTreeMap<String, String>>>,
+}
+
+impl SessionEnv {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&self, name: &str, value: &str) -> anyhow::Result<()> {
+        if name.is_empty() || !name.chars().all(|e| e.is_ascii_alphanumeric() || e == '_') {
+            bail!("{:?} isn't a valid variable name", name);
+        }
+        if is_scrubbed(name) {
+            bail!("{} is scrubbed from tool environments and can't be set", name);
+        }
+        self.vars.write().unwrap().insert(name.to_string(), value.to_string());
+        Ok(())
+    }
+
+    pub fn remove(&self, name: &str) -> bool {
+        self.vars.write().unwrap().remove(name).is_some()
+    }
+
+    pub fn vars(&self) -> Vec<(String, String)> {
+        self.vars.read().unwrap().iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+
+    /// `KEY=value` lines with the values of secret looking variables masked.
+    pub fn describe(&self) -> Vec<String> {
+        self.vars()
+            .into_iter()
+            .map(|(name, value)| {
+                let value = if is_secret(&name) { "********".to_string() } else { value };
+                format!("{}={}", name, value)
+            })
+            .collect()
+    }
+
+    pub fn apply(&self, command: &mut Command) {
+        command.envs(self.vars());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_env() {
+        let env = SessionEnv::new();
+        env.set("DATA_DIR", "/data").unwrap();
+        env.set("API_TOKEN", "abc").unwrap();
+        assert!(env.set("LD_PRELOAD", "/tmp/x.so").is_err());
+        assert!(env.set("A B", "x").is_err());
+
+        assert_eq!(env.describe(), ["API_TOKEN=********", "DATA_DIR=/data"]);
+        assert!(env.clone().remove("API_TOKEN"));
+        assert_eq!(env.vars(), [("DATA_DIR".to_string(), "/data".to_string())]);
+    }
+}
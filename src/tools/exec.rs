@@ -0,0 +1,344 @@
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::config::{CommandSandboxConfig, SandboxRuntime, ToolLimits};
+use crate::impl_tool_params;
+use crate::tools::{Tool, ToolMetaData, ToolParameters};
+use crate::tools::env::SessionEnv;
+use crate::tools::fs::Sandbox;
+
+const DEFAULT_IMAGE: &str = "debian:stable-slim";
+/// The image `run_python` uses when the sandbox names none, the default image has no Python.
+const PYTHON_IMAGE: &str = "python:3-slim";
+const DEFAULT_CPUS: f32 = 1.0;
+const DEFAULT_MEMORY_MB: u64 = 512;
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+/// stdout and stderr are each cut to this many bytes.
+const MAX_OUTPUT_BYTES: usize = 64 * 1024;
+/// Where the project is mounted inside the sandbox.
+const WORKSPACE: &str = "/workspace";
+
+static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug)]
+pub struct Output {
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
+}
+
+impl Output {
+    pub fn to_value(&self) -> Value {
+        json!({
+            "stdout": self.stdout,
+            "stderr": self.stderr,
+            "exit_code": self.exit_code,
+            "timed_out": self.timed_out,
+        })
+    }
+}
+
+fn read_capped(mut pipe: impl Read) -> String {
+    let mut buffer = vec![];
+    let _ = pipe.by_ref().take(MAX_OUTPUT_BYTES as u64).read_to_end(&mut buffer);
+    // Keep draining so the child never blocks on a full pipe.
+    let truncated = std::io::copy(&mut pipe, &mut std::io::sink()).unwrap_or_default() > 0;
+
+    let mut text = String::from_utf8_lossy(&buffer).to_string();
+    if truncated {
+        text.push_str("\n[truncated]");
+    }
+    text
+}
+
+/// Runs `command` to completion, killing it once `timeout` elapsed. `on_timeout` runs before the kill,
+/// e.g. to stop a container the client process doesn't own.
+pub fn run_with_timeout(mut command: Command, stdin: Option<&str>, timeout: Duration, on_timeout: impl FnOnce()) -> anyhow::Result<Output> {
+    let mut child = command
+        .stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+        let input = input.to_string();
+        thread::spawn(move || std::io::Write::write_all(&mut pipe, input.as_bytes()));
+    }
+    let stdout = child.stdout.take().map(|e| thread::spawn(move || read_capped(e)));
+    let stderr = child.stderr.take().map(|e| thread::spawn(move || read_capped(e)));
+
+    let deadline = Instant::now() + timeout;
+    let mut timed_out = false;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() > deadline {
+            timed_out = true;
+            on_timeout();
+            let _ = child.kill();
+            break child.wait()?;
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+
+    let join = |handle: Option<thread::JoinHandle<String>>| handle.and_then(|e| e.join().ok()).unwrap_or_default();
+    Ok(Output {
+        stdout: join(stdout),
+        stderr: join(stderr),
+        exit_code: status.code(),
+        timed_out,
+    })
+}
+
+pub struct ExecuteCommandTool {
+    sandbox: Sandbox,
+    config: CommandSandboxConfig,
+    env: SessionEnv,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct ExecuteCommandParameters {
+    /// Shell command, run with `sh -c` in the project directory
+    pub command: String,
+}
+
+impl_tool_params!(ExecuteCommandParameters);
+
+impl ExecuteCommandTool {
+    pub fn new(sandbox: Sandbox, config: CommandSandboxConfig, env: SessionEnv) -> Self {
+        Self { sandbox, config, env }
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.config.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS))
+    }
+
+    fn run(&self, shell_command: &str) -> anyhow::Result<Output> {
+        let name = format!("rag-exec-{}-{}", std::process::id(), RUNS.fetch_add(1, Ordering::Relaxed));
+        let runtime = self.config.runtime;
+
+        run_with_timeout(self.build(&name, shell_command), None, self.timeout(), || {
+            // Killing the docker client leaves the container running.
+            if runtime != SandboxRuntime::Bubblewrap {
+                let program = if runtime == SandboxRuntime::Docker { "docker" } else { "podman" };
+                let _ = Command::new(program).args(["kill", &name]).output();
+            }
+        })
+    }
+
+    /// Builds the runtime invocation, the project is mounted read-only at `/workspace` with a writable `/tmp`.
+    fn build(&self, name: &str, shell_command: &str) -> Command {
+        let root = self.sandbox.root();
+        let memory_mb = self.config.memory_mb.unwrap_or(DEFAULT_MEMORY_MB);
+
+        match self.config.runtime {
+            SandboxRuntime::Docker | SandboxRuntime::Podman => {
+                let program = if self.config.runtime == SandboxRuntime::Docker { "docker" } else { "podman" };
+                let mut command = Command::new(program);
+                command
+                    .args(["run", "--rm", "--name", name, "--read-only", "--tmpfs", "/tmp"])
+                    .args(["--security-opt", "no-new-privileges", "--cap-drop", "ALL", "--pids-limit", "256"])
+                    .arg(format!("--cpus={}", self.config.cpus.unwrap_or(DEFAULT_CPUS)))
+                    .arg(format!("--memory={}m", memory_mb))
+                    .arg(format!("--network={}", if self.config.network { "bridge" } else { "none" }))
+                    .arg("-v")
+                    .arg(format!("{}:{}:ro", root.display(), WORKSPACE))
+                    .args(["-w", WORKSPACE]);
+                for (name, value) in self.env.vars() {
+                    command.arg("-e").arg(format!("{}={}", name, value));
+                }
+                command
+                    .arg(self.config.image.as_deref().unwrap_or(DEFAULT_IMAGE))
+                    .args(["sh", "-c", shell_command]);
+                command
+            }
+            SandboxRuntime::Bubblewrap => {
+                let mut command = Command::new("bwrap");
+                command
+                    .args(["--ro-bind", "/", "/", "--dev", "/dev", "--proc", "/proc", "--tmpfs", "/tmp"])
+                    .args(["--tmpfs", "/home", "--tmpfs", "/root", "--tmpfs", "/run"])
+                    .arg("--ro-bind")
+                    .arg(root)
+                    .arg(WORKSPACE)
+                    .args(["--chdir", WORKSPACE, "--unshare-all", "--die-with-parent", "--new-session"]);
+                if self.config.network {
+                    command.arg("--share-net");
+                }
+                for (name, value) in self.env.vars() {
+                    command.args(["--setenv", &name, &value]);
+                }
+                // bwrap has no resource controls of its own.
+                command
+                    .args(["prlimit", &format!("--as={}", memory_mb * 1024 * 1024), &format!("--cpu={}", self.timeout().as_secs())])
+                    .args(["--", "sh", "-c", shell_command]);
+                command
+            }
+        }
+    }
+}
+
+impl Tool for ExecuteCommandTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "execute_command".to_string(),
+            description: format!(
+                "Run a shell command in an isolated sandbox and return its stdout, stderr and exit code. The project is mounted read-only at {}, only /tmp is writable{}, commands are killed after {}s.",
+                WORKSPACE,
+                if self.config.network { "" } else { " and there is no network" },
+                self.timeout().as_secs(),
+            ),
+            parameters: ExecuteCommandParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<ExecuteCommandParameters>(parameters)?;
+        Ok(self.run(&params.command)?.to_value())
+    }
+
+    /// Leaves the sandbox time to kill and remove the container after its own timeout.
+    fn limits(&self) -> ToolLimits {
+        ToolLimits {
+            timeout_secs: Some(self.timeout().as_secs() + 10),
+            max_output_bytes: None,
+        }
+    }
+}
+
+pub struct RunPythonTool {
+    interpreter: String,
+    env: SessionEnv,
+    /// Runs through the command sandbox when one is configured, otherwise in a bare subprocess.
+    sandboxed: Option<ExecuteCommandTool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct RunPythonParameters {
+    /// Python 3 source, print whatever you want to see
+    pub code: String,
+}
+
+impl_tool_params!(RunPythonParameters);
+
+impl RunPythonTool {
+    pub fn new(interpreter: Option<String>, env: SessionEnv, sandboxed: Option<ExecuteCommandTool>) -> Self {
+        Self {
+            interpreter: interpreter.unwrap_or("python3".to_string()),
+            env,
+            sandboxed: sandboxed.map(|mut e| {
+                e.config.image.get_or_insert_with(|| PYTHON_IMAGE.to_string());
+                e
+            }),
+        }
+    }
+
+    pub fn is_sandboxed(&self) -> bool {
+        self.sandboxed.is_some()
+    }
+
+    /// Runs `code` in isolated mode inside an empty scratch directory with a cleared environment.
+    fn run_local(&self, code: &str) -> anyhow::Result<Output> {
+        let scratch = std::env::temp_dir().join(format!("rag-python-{}-{}", std::process::id(), RUNS.fetch_add(1, Ordering::Relaxed)));
+        std::fs::create_dir_all(&scratch)?;
+
+        let mut command = Command::new(&self.interpreter);
+        command
+            .args(["-I", "-"])
+            .current_dir(&scratch)
+            .env_clear()
+            .env("PATH", std::env::var_os("PATH").unwrap_or_default())
+            .env("PYTHONIOENCODING", "utf-8");
+        self.env.apply(&mut command);
+        let output = run_with_timeout(command, Some(code), Duration::from_secs(DEFAULT_TIMEOUT_SECS), || {});
+
+        let _ = std::fs::remove_dir_all(&scratch);
+        output
+    }
+}
+
+impl Tool for RunPythonTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "run_python".to_string(),
+            description: "Execute a Python 3 script in an isolated process and return its stdout, stderr, exit code and the exception if one was raised. Each call starts from scratch; use it for calculations and data analysis.".to_string(),
+            parameters: RunPythonParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<RunPythonParameters>(parameters)?;
+        let output = match self.sandboxed {
+            Some(ref sandbox) => sandbox.run(&format!("{} -I -c {}", self.interpreter, shell_words::quote(&params.code)))?,
+            None => self.run_local(&params.code)?,
+        };
+
+        let mut result = output.to_value();
+        // The last line of a traceback names the exception.
+        if output.exit_code != Some(0) {
+            result["exception"] = json!(output.stderr.lines().rev().find(|e| !e.trim().is_empty()));
+        }
+        Ok(result)
+    }
+
+    fn limits(&self) -> ToolLimits {
+        self.sandboxed.as_ref().map(|e| e.limits()).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::fs::tests::temp_sandbox;
+
+    #[test]
+    fn test_build_docker_command() {
+        let sandbox = temp_sandbox("exec");
+        let env = SessionEnv::new();
+        env.set("DATA_DIR", "/data").unwrap();
+        let tool = ExecuteCommandTool::new(sandbox.clone(), CommandSandboxConfig::default(), env);
+        let command = tool.build("rag-exec-test", "ls -la");
+        let args = command.get_args().map(|e| e.to_string_lossy().to_string()).collect::<Vec<_>>();
+
+        assert_eq!(command.get_program(), "docker");
+        assert!(args.contains(&"--network=none".to_string()));
+        assert!(args.contains(&"DATA_DIR=/data".to_string()));
+        assert!(args.contains(&format!("{}:/workspace:ro", sandbox.root().display())));
+        assert_eq!(args[args.len() - 3..], ["sh", "-c", "ls -la"]);
+
+        // Scripts run in an image that has Python, unless one is configured.
+        let python = RunPythonTool::new(None, SessionEnv::new(), Some(tool));
+        let command = python.sandboxed.unwrap().build("rag-exec-test", "python3");
+        assert!(command.get_args().any(|e| e == "python:3-slim"));
+    }
+
+    #[test]
+    fn test_run_with_timeout() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "cat; echo oops >&2; exit 3"]);
+        let output = run_with_timeout(command, Some("hi"), Duration::from_secs(5), || {}).unwrap();
+        assert_eq!((output.stdout.as_str(), output.stderr.as_str(), output.exit_code), ("hi", "oops\n", Some(3)));
+
+        let mut command = Command::new("sleep");
+        command.arg("5");
+        let output = run_with_timeout(command, None, Duration::from_millis(100), || {}).unwrap();
+        assert!(output.timed_out);
+    }
+
+    #[test]
+    fn test_run_python() {
+        if Command::new("python3").arg("--version").output().is_err() {
+            return;
+        }
+
+        let tool = RunPythonTool::new(None, SessionEnv::new(), None);
+        let result = tool.execute(json!({ "code": "print(sum(range(10)))\n1 / 0" })).unwrap();
+        assert_eq!(result["stdout"], "45\n");
+        assert_eq!(result["exception"], "ZeroDivisionError: division by zero");
+    }
+}
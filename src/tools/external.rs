@@ -0,0 +1,107 @@
+use std::process::Command;
+use std::time::Duration;
+use anyhow::bail;
+use serde_json::{json, Value};
+use crate::config::{ExternalToolConfig, ToolLimits};
+use crate::tools::{Tool, ToolMetaData};
+use crate::tools::env::SessionEnv;
+use crate::tools::exec::run_with_timeout;
+use crate::tools::fs::Sandbox;
+
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+/// A tool declared in the config, implemented by any program that reads the JSON arguments from stdin
+/// and prints a JSON result to stdout.
+pub struct ExternalTool {
+    config: ExternalToolConfig,
+    sandbox: Sandbox,
+    env: SessionEnv,
+}
+
+impl ExternalTool {
+    pub fn new(config: ExternalToolConfig, sandbox: Sandbox, env: SessionEnv) -> anyhow::Result<Self> {
+        if config.command.is_empty() {
+            bail!("The command of the external tool {} is empty", config.name);
+        }
+        Ok(Self { config, sandbox, env })
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.config.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS))
+    }
+}
+
+impl Tool for ExternalTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: self.config.name.clone(),
+            description: self.config.description.clone(),
+            parameters: self.config.parameters
+                .clone()
+                .unwrap_or(json!({ "type": "object", "properties": {} })),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let (program, args) = self.config.command.split_first().unwrap();
+        let mut command = Command::new(program);
+        command.args(args).current_dir(self.sandbox.root());
+        self.env.apply(&mut command);
+
+        let output = run_with_timeout(command, Some(&parameters.to_string()), self.timeout(), || {})?;
+        if output.timed_out {
+            return Ok(json!({ "error": format!("{} timed out after {}s", self.config.name, self.timeout().as_secs()) }));
+        }
+        if output.exit_code != Some(0) {
+            return Ok(json!({
+                "error": output.stderr.trim(),
+                "exit_code": output.exit_code,
+            }));
+        }
+
+        match serde_json::from_str(&output.stdout) {
+            Ok(result) => Ok(result),
+            Err(_) => Ok(json!({
+                "error": format!("{} didn't print a JSON result", self.config.name),
+                "stdout": output.stdout,
+            })),
+        }
+    }
+
+    fn limits(&self) -> ToolLimits {
+        ToolLimits {
+            timeout_secs: Some(self.timeout().as_secs() + 5),
+            max_output_bytes: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::fs::tests::temp_sandbox;
+
+    fn tool(script: &str) -> ExternalTool {
+        let config = ExternalToolConfig {
+            name: "script".to_string(),
+            description: "runs a script".to_string(),
+            command: vec!["sh".to_string(), "-c".to_string(), script.to_string()],
+            parameters: None,
+            timeout_secs: Some(5),
+        };
+        ExternalTool::new(config, temp_sandbox("external"), SessionEnv::new()).unwrap()
+    }
+
+    #[test]
+    fn test_external_tool() {
+        let arguments = json!({ "city": "Berlin" });
+        assert_eq!(tool("cat").execute(arguments.clone()).unwrap(), arguments);
+        assert_eq!(tool("cat").metadata().parameters["properties"], json!({}));
+
+        let result = tool("echo oops >&2; exit 3").execute(json!({})).unwrap();
+        assert_eq!(result, json!({ "error": "oops", "exit_code": 3 }));
+
+        let result = tool("echo plain text").execute(json!({})).unwrap();
+        assert_eq!(result["stdout"], "plain text\n");
+    }
+}
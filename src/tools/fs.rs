@@ -0,0 +1,309 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use anyhow::{anyhow, bail};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::impl_tool_params;
+use crate::tools::{Tool, ToolMetaData, ToolParameters};
+use crate::tools::guard::{render_diff, WriteGuard};
+
+/// Confines every path handed to the filesystem tools to a single root directory.
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    root: PathBuf,
+}
+
+impl Sandbox {
+    pub fn new(root: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let root = root.as_ref()
+            .canonicalize()
+            .map_err(|e| anyhow!("Failed to open sandbox root {:?}: {}", root.as_ref(), e))?;
+
+        Ok(Self { root })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Resolves `path` against the sandbox root and rejects anything that ends up outside of it,
+    /// including escapes through `..` or symlinks. The path itself doesn't have to exist.
+    pub fn resolve(&self, path: impl AsRef<Path>) -> anyhow::Result<PathBuf> {
+        let path = path.as_ref();
+        let joined = if path.is_absolute() { path.to_path_buf() } else { self.root.join(path) };
+
+        let mut normalized = PathBuf::new();
+        for component in joined.components() {
+            match component {
+                Component::CurDir => {}
+                Component::ParentDir => { normalized.pop(); }
+                other => normalized.push(other),
+            }
+        }
+
+        // Canonicalize the deepest existing ancestor so symlinks can't point out of the root.
+        let mut existing = normalized.as_path();
+        let mut rest = vec![];
+        while !existing.exists() {
+            match (existing.parent(), existing.file_name()) {
+                (Some(parent), Some(name)) => {
+                    rest.push(name.to_owned());
+                    existing = parent;
+                }
+                _ => break,
+            }
+        }
+
+        let mut resolved = existing.canonicalize().unwrap_or_else(|_| existing.to_path_buf());
+        resolved.extend(rest.iter().rev());
+
+        if !resolved.starts_with(&self.root) {
+            bail!("Path {:?} is outside of the sandbox root {:?}", path, self.root);
+        }
+        Ok(resolved)
+    }
+
+    pub fn relative<'a>(&self, path: &'a Path) -> &'a Path {
+        path.strip_prefix(&self.root).unwrap_or(path)
+    }
+}
+
+pub struct ReadFileTool {
+    sandbox: Sandbox,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct ReadFileParameters {
+    /// Path of the file, relative to the workspace root
+    pub path: String,
+}
+
+impl_tool_params!(ReadFileParameters);
+
+impl ReadFileTool {
+    pub fn new(sandbox: Sandbox) -> Self {
+        Self { sandbox }
+    }
+}
+
+impl Tool for ReadFileTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "read_file".to_string(),
+            description: "Read the whole content of a text file in the workspace.".to_string(),
+            parameters: ReadFileParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<ReadFileParameters>(parameters)?;
+        let path = self.sandbox.resolve(&params.path)?;
+        let content = fs::read_to_string(&path)
+            .map_err(|e| anyhow!("Failed to read file {}: {}", params.path, e))?;
+
+        Ok(json!({
+            "path": self.sandbox.relative(&path),
+            "content": content,
+        }))
+    }
+}
+
+pub struct WriteFileTool {
+    sandbox: Sandbox,
+    guard: WriteGuard,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct WriteFileParameters {
+    /// Path of the file, relative to the workspace root. Missing parent directories are created
+    pub path: String,
+    /// The full new content of the file
+    pub content: String,
+}
+
+impl_tool_params!(WriteFileParameters);
+
+impl WriteFileTool {
+    pub fn new(sandbox: Sandbox, guard: WriteGuard) -> Self {
+        Self { sandbox, guard }
+    }
+}
+
+impl Tool for WriteFileTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "write_file".to_string(),
+            description: "Create or overwrite a text file in the workspace.".to_string(),
+            parameters: WriteFileParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<WriteFileParameters>(parameters)?;
+        let path = self.sandbox.resolve(&params.path)?;
+
+        let original = fs::read_to_string(&path).unwrap_or_default();
+        let preview = render_diff(&diffy::create_patch(&original, &params.content).to_string());
+
+        self.guard
+            .write(&self.sandbox, &path, &params.content, &preview)
+            .map_err(|e| anyhow!("Failed to write file {}: {}", params.path, e))
+    }
+
+    fn confirms(&self, _parameters: &Value) -> bool {
+        self.guard.confirms()
+    }
+}
+
+pub struct ListDirectoryTool {
+    sandbox: Sandbox,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct ListDirectoryParameters {
+    /// Directory to list, relative to the workspace root. Defaults to the root itself
+    pub path: Option<String>,
+}
+
+impl_tool_params!(ListDirectoryParameters);
+
+impl ListDirectoryTool {
+    pub fn new(sandbox: Sandbox) -> Self {
+        Self { sandbox }
+    }
+}
+
+impl Tool for ListDirectoryTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "list_directory".to_string(),
+            description: "List the entries of a directory in the workspace with their type and size.".to_string(),
+            parameters: ListDirectoryParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<ListDirectoryParameters>(parameters)?;
+        let path = self.sandbox.resolve(params.path.as_deref().unwrap_or("."))?;
+
+        let mut entries = vec![];
+        for entry in fs::read_dir(&path).map_err(|e| anyhow!("Failed to list directory {:?}: {}", path, e))? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            let kind = if file_type.is_dir() {
+                "directory"
+            } else if file_type.is_symlink() {
+                "symlink"
+            } else {
+                "file"
+            };
+
+            entries.push(json!({
+                "name": entry.file_name().to_string_lossy(),
+                "type": kind,
+                "size": entry.metadata().map(|m| m.len()).unwrap_or(0),
+            }));
+        }
+        entries.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+
+        Ok(json!({
+            "path": self.sandbox.relative(&path),
+            "entries": entries,
+        }))
+    }
+}
+
+pub struct GlobTool {
+    sandbox: Sandbox,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct GlobParameters {
+    /// Glob pattern relative to the workspace root, e.g. `src/**/*.rs`
+    pub pattern: String,
+}
+
+impl_tool_params!(GlobParameters);
+
+impl GlobTool {
+    pub fn new(sandbox: Sandbox) -> Self {
+        Self { sandbox }
+    }
+}
+
+impl Tool for GlobTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "glob".to_string(),
+            description: "Find files in the workspace whose path matches a glob pattern.".to_string(),
+            parameters: GlobParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<GlobParameters>(parameters)?;
+        if Path::new(&params.pattern).components().any(|c| matches!(c, Component::ParentDir | Component::RootDir | Component::Prefix(_))) {
+            bail!("Pattern {} must stay relative to the sandbox root", params.pattern);
+        }
+
+        let pattern = self.sandbox.root().join(&params.pattern);
+        let mut matches = vec![];
+        for entry in glob::glob(&pattern.to_string_lossy())? {
+            // Skip unreadable entries and anything a symlink leads out of the sandbox.
+            if let Ok(path) = entry.map_err(anyhow::Error::from).and_then(|p| self.sandbox.resolve(p)) {
+                matches.push(self.sandbox.relative(&path).to_string_lossy().into_owned());
+            }
+        }
+
+        Ok(json!({
+            "pattern": params.pattern,
+            "matches": matches,
+        }))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn temp_sandbox(name: &str) -> Sandbox {
+        let root = std::env::temp_dir().join(format!("rag-fs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        Sandbox::new(&root).unwrap()
+    }
+
+    #[test]
+    fn test_resolve_rejects_escapes() {
+        let sandbox = temp_sandbox("resolve");
+
+        assert!(sandbox.resolve("a/b/../c.txt").unwrap().ends_with("a/c.txt"));
+        assert!(sandbox.resolve("../outside.txt").is_err());
+        assert!(sandbox.resolve("/etc/passwd").is_err());
+    }
+
+    #[test]
+    fn test_write_read_list_glob() {
+        let sandbox = temp_sandbox("tools");
+
+        WriteFileTool::new(sandbox.clone(), WriteGuard::new(false, None))
+            .execute(json!({ "path": "src/main.rs", "content": "fn main() {}" }))
+            .unwrap();
+
+        let read = ReadFileTool::new(sandbox.clone())
+            .execute(json!({ "path": "src/main.rs" }))
+            .unwrap();
+        assert_eq!(read["content"], "fn main() {}");
+
+        let listed = ListDirectoryTool::new(sandbox.clone())
+            .execute(json!({ "path": "src" }))
+            .unwrap();
+        assert_eq!(listed["entries"][0]["name"], "main.rs");
+
+        let globbed = GlobTool::new(sandbox.clone())
+            .execute(json!({ "pattern": "**/*.rs" }))
+            .unwrap();
+        assert_eq!(globbed["matches"], json!(["src/main.rs"]));
+        assert!(GlobTool::new(sandbox).execute(json!({ "pattern": "../*" })).is_err());
+    }
+}
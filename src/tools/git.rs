@@ -0,0 +1,227 @@
+use std::path::Path;
+use std::process::Command;
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::impl_tool_params;
+use crate::tools::{Tool, ToolMetaData, ToolParameters};
+use crate::tools::env::SessionEnv;
+use crate::tools::fs::Sandbox;
+
+/// Diffs longer than this are cut before they are handed to the model.
+pub const MAX_DIFF_BYTES: usize = 64 * 1024;
+
+/// Runs `git` in `dir` and returns its stdout, failing with stderr when git does.
+pub fn git(dir: &Path, env: &SessionEnv, args: &[&str]) -> anyhow::Result<String> {
+    let mut command = Command::new("git");
+    env.apply(&mut command);
+    let output = command.current_dir(dir).args(args).output()?;
+    if !output.status.success() {
+        bail!("git {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+pub fn truncate_diff(diff: String) -> (String, bool) {
+    if diff.len() <= MAX_DIFF_BYTES {
+        return (diff, false);
+    }
+    let mut end = MAX_DIFF_BYTES;
+    while !diff.is_char_boundary(end) {
+        end -= 1;
+    }
+    (diff[..end].to_string(), true)
+}
+
+/// Parses `git status --porcelain=v1 --branch`.
+fn parse_status(output: &str) -> Value {
+    let mut branch = Value::Null;
+    let mut files = vec![];
+
+    for line in output.lines() {
+        if let Some(head) = line.strip_prefix("## ") {
+            branch = json!(head.split("...").next().unwrap_or(head));
+        } else if line.len() > 3 {
+            let (index, worktree) = (&line[..1], &line[1..2]);
+            files.push(json!({
+                "path": &line[3..],
+                "staged": index != " " && index != "?",
+                "status": match (index, worktree) {
+                    ("?", _) => "untracked",
+                    ("A", _) => "added",
+                    ("D", _) | (_, "D") => "deleted",
+                    ("R", _) => "renamed",
+                    ("U", _) | (_, "U") => "conflicted",
+                    _ => "modified",
+                },
+            }));
+        }
+    }
+
+    json!({ "branch": branch, "files": files })
+}
+
+pub struct GitStatusTool {
+    sandbox: Sandbox,
+    env: SessionEnv,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct GitStatusParameters {}
+
+impl_tool_params!(GitStatusParameters);
+
+impl GitStatusTool {
+    pub fn new(sandbox: Sandbox, env: SessionEnv) -> Self {
+        Self { sandbox, env }
+    }
+}
+
+impl Tool for GitStatusTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "git_status".to_string(),
+            description: "Show the current branch and the changed, staged and untracked files of the workspace repository.".to_string(),
+            parameters: GitStatusParameters::schema(),
+        }
+    }
+
+    fn execute(&self, _parameters: Value) -> anyhow::Result<Value> {
+        let output = git(self.sandbox.root(), &self.env, &["status", "--porcelain=v1", "--branch"])?;
+        Ok(parse_status(&output))
+    }
+}
+
+pub struct GitDiffTool {
+    sandbox: Sandbox,
+    env: SessionEnv,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct GitDiffParameters {
+    /// Diff the staged changes instead of the unstaged ones
+    pub staged: Option<bool>,
+    /// Restrict the diff to this path, relative to the workspace root
+    pub path: Option<String>,
+}
+
+impl_tool_params!(GitDiffParameters);
+
+impl GitDiffTool {
+    pub fn new(sandbox: Sandbox, env: SessionEnv) -> Self {
+        Self { sandbox, env }
+    }
+}
+
+impl Tool for GitDiffTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "git_diff".to_string(),
+            description: "Show the unstaged or staged changes of the workspace repository as a unified diff.".to_string(),
+            parameters: GitDiffParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<GitDiffParameters>(parameters)?;
+
+        let mut args = vec!["diff", "--no-color"];
+        if params.staged.unwrap_or(false) {
+            args.push("--staged");
+        }
+        let path = params.path.map(|e| self.sandbox.resolve(e)).transpose()?;
+        let path = path.as_ref().map(|e| e.to_string_lossy().to_string());
+        if let Some(ref path) = path {
+            args.extend(["--", path.as_str()]);
+        }
+
+        let (diff, truncated) = truncate_diff(git(self.sandbox.root(), &self.env, &args)?);
+        Ok(json!({
+            "diff": diff,
+            "truncated": truncated,
+        }))
+    }
+}
+
+pub struct GitCommitTool {
+    sandbox: Sandbox,
+    env: SessionEnv,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct GitCommitParameters {
+    /// The full commit message
+    pub message: String,
+    /// Stage every modified tracked file first, like `git commit -a`
+    pub all: Option<bool>,
+}
+
+impl_tool_params!(GitCommitParameters);
+
+impl GitCommitTool {
+    pub fn new(sandbox: Sandbox, env: SessionEnv) -> Self {
+        Self { sandbox, env }
+    }
+}
+
+impl Tool for GitCommitTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "git_commit".to_string(),
+            description: "Commit the staged changes of the workspace repository with the given message.".to_string(),
+            parameters: GitCommitParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<GitCommitParameters>(parameters)?;
+
+        let mut args = vec!["commit", "-m", params.message.as_str()];
+        if params.all.unwrap_or(false) {
+            args.push("--all");
+        }
+        git(self.sandbox.root(), &self.env, &args)?;
+
+        let commit = git(self.sandbox.root(), &self.env, &["log", "-1", "--format=%H %s"])?;
+        let (hash, subject) = commit.trim().split_once(' ').unwrap_or((commit.trim(), ""));
+        Ok(json!({
+            "commit": hash,
+            "subject": subject,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::fs::tests::temp_sandbox;
+
+    #[test]
+    fn test_status_diff_commit() {
+        let sandbox = temp_sandbox("git");
+        let root = sandbox.root();
+        let env = SessionEnv::new();
+        if git(root, &env, &["init", "-q", "-b", "main"]).is_err() {
+            return;
+        }
+        git(root, &env, &["config", "user.email", "rag@example.org"]).unwrap();
+        git(root, &env, &["config", "user.name", "rag"]).unwrap();
+
+        std::fs::write(root.join("a.txt"), "one\n").unwrap();
+        git(root, &env, &["add", "a.txt"]).unwrap();
+        std::fs::write(root.join("b.txt"), "two\n").unwrap();
+
+        let status = GitStatusTool::new(sandbox.clone(), env.clone()).execute(json!({})).unwrap();
+        assert_eq!(status["branch"], "No commits yet on main");
+        assert_eq!(status["files"], json!([
+            { "path": "a.txt", "staged": true, "status": "added" },
+            { "path": "b.txt", "staged": false, "status": "untracked" },
+        ]));
+
+        let diff = GitDiffTool::new(sandbox.clone(), env.clone()).execute(json!({ "staged": true })).unwrap();
+        assert!(diff["diff"].as_str().unwrap().contains("+one"));
+
+        let commit = GitCommitTool::new(sandbox, env).execute(json!({ "message": "feat: add a" })).unwrap();
+        assert_eq!(commit["subject"], "feat: add a");
+    }
+}
@@ -0,0 +1,227 @@
+use std::fs;
+use std::io::{stdin, stdout, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+use colored::Colorize;
+use serde_json::{json, Value};
+use crate::tools::fs::Sandbox;
+
+/// Asks before a tool modifies the workspace and keeps a shadow copy of every file it overwrites.
+#[derive(Debug, Clone)]
+pub struct WriteGuard {
+    confirm: bool,
+    backup_dir: Option<PathBuf>,
+    /// Gets the user's answer, from the terminal unless a test answers instead.
+    answer: fn(&str) -> anyhow::Result<bool>,
+}
+
+impl WriteGuard {
+    pub fn new(confirm: bool, backup_dir: Option<PathBuf>) -> Self {
+        Self { confirm, backup_dir, answer: read_answer }
+    }
+
+    /// Whether `confirm` asks the user at all.
+    pub fn confirms(&self) -> bool {
+        self.confirm
+    }
+
+    /// Prints `preview` and waits for the user to accept the change, always accepts when confirmation is off.
+    pub fn confirm(&self, action: &str, preview: &str) -> anyhow::Result<bool> {
+        if !self.confirm {
+            return Ok(true);
+        }
+
+        on_terminal(|| {
+            println!();
+            if !preview.is_empty() {
+                println!("{}", preview);
+            }
+            (self.answer)(action)
+        })
+    }
+
+    /// Copies `path` into a fresh timestamped directory below the backup dir, mirroring its sandbox-relative path.
+    pub fn backup(&self, sandbox: &Sandbox, path: &Path) -> anyhow::Result<Option<PathBuf>> {
+        let Some(ref backup_dir) = self.backup_dir else { return Ok(None) };
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let stamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        let target = backup_dir
+            .join(stamp.to_string())
+            .join(path.strip_prefix(sandbox.root()).unwrap_or(path));
+
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(path, &target)?;
+        Ok(Some(target))
+    }
+
+    /// Confirms, backs up and finally writes `content` to `path`, returning the tool result either way.
+    pub fn write(&self, sandbox: &Sandbox, path: &Path, content: &str, preview: &str) -> anyhow::Result<Value> {
+        let relative = path.strip_prefix(sandbox.root()).unwrap_or(path);
+
+        if !self.confirm(&format!("Write {}", relative.display()), preview)? {
+            return Ok(json!({
+                "path": relative,
+                "applied": false,
+                "reason": "The user rejected the change",
+            }));
+        }
+
+        let backup = self.backup(sandbox, path)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, content.as_bytes())?;
+
+        Ok(json!({
+            "path": relative,
+            "applied": true,
+            "bytes_written": content.len(),
+            "backup": backup,
+        }))
+    }
+}
+
+/// A full-screen interface that steps aside while the user is asked something on the terminal.
+pub trait Screen: Send {
+    fn leave(&mut self) -> std::io::Result<()>;
+
+    fn enter(&mut self) -> std::io::Result<()>;
+}
+
+static SCREEN: Mutex<Option<Box<dyn Screen>>> = Mutex::new(None);
+
+/// Has `screen` step aside for every prompt from now on, `None` once it is gone.
+pub fn set_screen(screen: Option<Box<dyn Screen>>) {
+    *SCREEN.lock().unwrap() = screen;
+}
+
+/// Runs `f` with the terminal to itself. Prompts from tools running at once take turns, and a full-screen interface is
+/// left for them and entered again after.
+pub fn on_terminal<T>(f: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+    let mut screen = SCREEN.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(ref mut screen) = *screen {
+        screen.leave()?;
+    }
+    let result = f();
+    if let Some(ref mut screen) = *screen {
+        screen.enter()?;
+    }
+    result
+}
+
+/// Asks a yes/no question on the terminal, anything but `y`/`yes` counts as no.
+pub fn ask(question: &str) -> anyhow::Result<bool> {
+    on_terminal(|| read_answer(question))
+}
+
+fn read_answer(question: &str) -> anyhow::Result<bool> {
+    print!("{}", format!("{}? [y/N] ", question).yellow());
+    stdout().flush()?;
+
+    let mut answer = String::new();
+    stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Colors a unified diff the way `git diff` does.
+pub fn render_diff(diff: &str) -> String {
+    diff.lines()
+        .map(|line| {
+            if line.starts_with("+++") || line.starts_with("---") {
+                line.bold().to_string()
+            } else if line.starts_with('+') {
+                line.green().to_string()
+            } else if line.starts_with('-') {
+                line.red().to_string()
+            } else if line.starts_with("@@") {
+                line.cyan().to_string()
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::tools::fs::tests::temp_sandbox;
+
+    /// Taken by the tests that prompt, the screen is shared by all of them.
+    static TERMINAL: Mutex<()> = Mutex::new(());
+
+    struct Recorder(Arc<Mutex<Vec<&'static str>>>);
+
+    impl Screen for Recorder {
+        fn leave(&mut self) -> std::io::Result<()> {
+            self.0.lock().unwrap().push("leave");
+            Ok(())
+        }
+
+        fn enter(&mut self) -> std::io::Result<()> {
+            self.0.lock().unwrap().push("enter");
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_screen_steps_aside_for_prompts() {
+        let _terminal = TERMINAL.lock().unwrap_or_else(PoisonError::into_inner);
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        set_screen(Some(Box::new(Recorder(calls.clone()))));
+        let asked = on_terminal(|| {
+            calls.lock().unwrap().push("ask");
+            Ok(true)
+        });
+        set_screen(None);
+
+        assert!(asked.unwrap());
+        // Other tests may have prompted meanwhile, but never in between.
+        let calls = calls.lock().unwrap();
+        let ask = calls.iter().position(|e| *e == "ask").unwrap();
+        assert_eq!(calls[ask - 1..=ask + 1], ["leave", "ask", "enter"]);
+    }
+
+    #[test]
+    fn test_declined_write_leaves_the_file() {
+        let _terminal = TERMINAL.lock().unwrap_or_else(PoisonError::into_inner);
+        let sandbox = temp_sandbox("guard-declined");
+        let backups = sandbox.root().join(".backups");
+        let path = sandbox.root().join("a.txt");
+        fs::write(&path, "old").unwrap();
+
+        let guard = WriteGuard { answer: |_| Ok(false), ..WriteGuard::new(true, Some(backups.clone())) };
+        let result = guard.write(&sandbox, &path, "new", "").unwrap();
+
+        assert_eq!(result, json!({ "path": "a.txt", "applied": false, "reason": "The user rejected the change" }));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+        assert!(!backups.exists());
+    }
+
+    #[test]
+    fn test_backup_mirrors_the_path() {
+        let sandbox = temp_sandbox("guard-backup");
+        let path = sandbox.root().join("src").join("a.txt");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "old").unwrap();
+        let guard = WriteGuard::new(false, Some(sandbox.root().join(".backups")));
+
+        let result = guard.write(&sandbox, &path, "new", "").unwrap();
+        let backup = PathBuf::from(result["backup"].as_str().unwrap());
+        assert!(backup.ends_with("src/a.txt"));
+        assert_eq!(fs::read_to_string(backup).unwrap(), "old");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+
+        // A new file has nothing to back up.
+        let result = guard.write(&sandbox, &sandbox.root().join("b.txt"), "new", "").unwrap();
+        assert_eq!((result["applied"].clone(), result["backup"].clone()), (json!(true), Value::Null));
+    }
+}
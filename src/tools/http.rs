@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use anyhow::{anyhow, bail};
+use reqwest::{Method, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::impl_tool_params;
+use crate::tools::{Tool, ToolMetaData, ToolParameters};
+
+pub const DEFAULT_MAX_RESPONSE_BYTES: usize = 1024 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_REDIRECTS: usize = 10;
+
+/// Decides which hosts the network tools may talk to and how much of a response they keep.
+#[derive(Debug, Clone)]
+pub struct HttpPolicy {
+    allowed_hosts: Vec<glob::Pattern>,
+    max_response_bytes: usize,
+}
+
+impl HttpPolicy {
+    pub fn new(allowed_hosts: &[String], max_response_bytes: Option<usize>) -> anyhow::Result<Self> {
+        let allowed_hosts = allowed_hosts
+            .iter()
+            .map(|e| glob::Pattern::new(&e.to_lowercase()).map_err(|err| anyhow!("Invalid host pattern {}: {}", e, err)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            allowed_hosts,
+            max_response_bytes: max_response_bytes.unwrap_or(DEFAULT_MAX_RESPONSE_BYTES),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.allowed_hosts.is_empty()
+    }
+
+    pub fn max_response_bytes(&self) -> usize {
+        self.max_response_bytes
+    }
+
+    /// Parses `url` and fails unless it is http(s) and its host matches one of the allowed patterns.
+    pub fn check(&self, url: &str) -> anyhow::Result<Url> {
+        let url = Url::parse(url)?;
+        if !matches!(url.scheme(), "http" | "https") {
+            bail!("Unsupported scheme {}, only http and https are allowed", url.scheme());
+        }
+
+        let host = url.host_str().unwrap_or_default().to_lowercase();
+        if !self.allowed_hosts.iter().any(|e| e.matches(&host)) {
+            bail!("Host {} is not in http_allowed_hosts", host);
+        }
+        Ok(url)
+    }
+}
+
+/// Reads at most `limit` bytes of the body, reporting whether anything was cut off.
+pub async fn read_limited(mut response: reqwest::Response, limit: usize) -> anyhow::Result<(Vec<u8>, bool)> {
+    let mut body = vec![];
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > limit {
+            body.extend_from_slice(&chunk[..limit - body.len()]);
+            return Ok((body, true));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok((body, false))
+}
+
+pub struct HttpRequestTool {
+    policy: HttpPolicy,
+    client: reqwest::Client,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct HttpRequestParameters {
+    /// Absolute http(s) URL to call
+    pub url: String,
+    /// HTTP method, defaults to GET
+    pub method: Option<String>,
+    /// Extra request headers
+    pub headers: Option<HashMap<String, String>>,
+    /// Raw request body, e.g. a JSON document
+    pub body: Option<String>,
+}
+
+impl_tool_params!(HttpRequestParameters);
+
+impl HttpRequestTool {
+    pub fn new(policy: HttpPolicy) -> anyhow::Result<Self> {
+        // Every hop is checked, an allowed host must not lead anywhere else.
+        let hops = policy.clone();
+        let redirect = reqwest::redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                return attempt.error(format!("More than {} redirects", MAX_REDIRECTS));
+            }
+            match hops.check(attempt.url().as_str()) {
+                Ok(_) => attempt.follow(),
+                Err(e) => attempt.error(e.to_string()),
+            }
+        });
+        let client = reqwest::Client::builder().timeout(REQUEST_TIMEOUT).redirect(redirect).build()?;
+        Ok(Self { policy, client })
+    }
+}
+
+impl Tool for HttpRequestTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "http_request".to_string(),
+            description: "Call a REST endpoint and return its status, headers and body. Only hosts allowed by the user can be reached.".to_string(),
+            parameters: HttpRequestParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<HttpRequestParameters>(parameters)?;
+        let url = self.policy.check(&params.url)?;
+        let method = Method::from_bytes(params.method.as_deref().unwrap_or("GET").to_uppercase().as_bytes())?;
+
+        let mut request = self.client.request(method, url);
+        for (name, value) in params.headers.unwrap_or_default() {
+            request = request.header(name, value);
+        }
+        if let Some(body) = params.body {
+            request = request.body(body);
+        }
+
+        let limit = self.policy.max_response_bytes();
+        futures::executor::block_on(async move {
+            let response = request.send().await?;
+            let status = response.status().as_u16();
+            let headers = response
+                .headers()
+                .iter()
+                .map(|(name, value)| (name.to_string(), json!(value.to_str().unwrap_or_default())))
+                .collect::<serde_json::Map<_, _>>();
+            let (body, truncated) = read_limited(response, limit).await?;
+
+            Ok(json!({
+                "status": status,
+                "headers": headers,
+                "body": String::from_utf8_lossy(&body),
+                "truncated": truncated,
+            }))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy_host_patterns() {
+        let policy = HttpPolicy::new(&["api.github.com".to_string(), "*.example.org".to_string()], None).unwrap();
+
+        assert!(policy.check("https://api.github.com/repos").is_ok());
+        assert!(policy.check("https://docs.example.org/a").is_ok());
+        assert!(policy.check("https://example.com/").is_err());
+        assert!(policy.check("file:///etc/passwd").is_err());
+        assert!(!HttpPolicy::new(&[], None).unwrap().is_enabled());
+    }
+
+    #[tokio::test]
+    async fn test_redirects_are_checked() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // The allowed host sends the client on to itself, and then to a host that isn't allowed.
+        tokio::spawn(async move {
+            for location in [format!("http://127.0.0.1:{}/b", port), format!("http://localhost:{}/c", port)] {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let _ = socket.read(&mut [0; 4096]).await.unwrap();
+                let response = format!("HTTP/1.1 302 Found\r\nlocation: {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", location);
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        let tool = HttpRequestTool::new(HttpPolicy::new(&["127.0.0.1".to_string()], None).unwrap()).unwrap();
+
+        let url = format!("http://127.0.0.1:{}/a", port);
+        let error = tokio::task::spawn_blocking(move || tool.execute(json!({ "url": url }))).await.unwrap().unwrap_err();
+        assert!(format!("{:#}", error).contains("Host localhost is not in http_allowed_hosts"), "{:#}", error);
+    }
+}
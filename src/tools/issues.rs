@@ -0,0 +1,466 @@
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{anyhow, bail};
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::config::{GitHubConfig, JiraConfig};
+use crate::impl_tool_params;
+use crate::keychain;
+use crate::tools::{Tool, ToolMetaData, ToolParameters};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_GITHUB_API: &str = "https://api.github.com";
+const DEFAULT_MAX_RESULTS: usize = 20;
+/// Long issue bodies and comments are cut to this many characters.
+const MAX_TEXT_CHARS: usize = 4_000;
+
+fn truncate(text: &str) -> String {
+    match text.char_indices().nth(MAX_TEXT_CHARS) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+async fn send(request: reqwest::RequestBuilder) -> anyhow::Result<Value> {
+    let response = request.send().await?;
+    let status = response.status();
+    let text = response.text().await?;
+    if !status.is_success() {
+        bail!("{}: {}", status, truncate(&text));
+    }
+    Ok(if text.is_empty() { Value::Null } else { serde_json::from_str(&text)? })
+}
+
+struct GitHub {
+    config: GitHubConfig,
+    client: reqwest::Client,
+}
+
+impl GitHub {
+    fn new(config: GitHubConfig) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .user_agent("rag")
+            .build()?;
+        Ok(Self { config, client })
+    }
+
+    fn repo(&self, repo: Option<String>) -> anyhow::Result<String> {
+        repo.or(self.config.repo.clone())
+            .ok_or(anyhow!("No repository given and github.repo isn't configured"))
+    }
+
+    fn request(&self, method: Method, path: &str, query: &[(&str, String)], body: Option<Value>) -> anyhow::Result<Value> {
+        let token = keychain::get_secret(self.config.token_secret.as_deref().unwrap_or("github_token"))?;
+        let url = format!("{}{}", self.config.api_url.as_deref().unwrap_or(DEFAULT_GITHUB_API).trim_end_matches('/'), path);
+
+        let mut request = self.client
+            .request(method, url)
+            .bearer_auth(token)
+            .query(query)
+            .header("Accept", "application/vnd.github+json");
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        futures::executor::block_on(send(request)).map_err(|e| anyhow!("GitHub request failed: {}", e))
+    }
+}
+
+fn github_issue(issue: &Value) -> Value {
+    json!({
+        "number": issue["number"],
+        "title": issue["title"],
+        "state": issue["state"],
+        "pull_request": issue.get("pull_request").is_some(),
+        "author": issue["user"]["login"],
+        "labels": issue["labels"].as_array().into_iter().flatten().map(|e| e["name"].clone()).collect::<Vec<_>>(),
+        "updated_at": issue["updated_at"],
+        "url": issue["html_url"],
+    })
+}
+
+fn github_comment(comment: &Value) -> Value {
+    json!({
+        "author": comment["user"]["login"],
+        "created_at": comment["created_at"],
+        "body": truncate(comment["body"].as_str().unwrap_or_default()),
+    })
+}
+
+pub struct GitHubSearchIssuesTool(Arc<GitHub>);
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct GitHubSearchIssuesParameters {
+    /// GitHub search terms and qualifiers, e.g. `streaming is:open label:bug` or `is:pr review:required`
+    pub query: String,
+    /// `owner/name` of the repository, defaults to the configured one
+    pub repo: Option<String>,
+    /// Defaults to 20
+    pub max_results: Option<usize>,
+}
+
+impl_tool_params!(GitHubSearchIssuesParameters);
+
+impl Tool for GitHubSearchIssuesTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "github_search_issues".to_string(),
+            description: "Search the issues and pull requests of a GitHub repository.".to_string(),
+            parameters: GitHubSearchIssuesParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<GitHubSearchIssuesParameters>(parameters)?;
+        let query = format!("repo:{} {}", self.0.repo(params.repo)?, params.query);
+        let per_page = params.max_results.unwrap_or(DEFAULT_MAX_RESULTS).min(100);
+
+        let found = self.0.request(Method::GET, "/search/issues", &[("q", query), ("per_page", per_page.to_string())], None)?;
+
+        Ok(json!({
+            "total": found["total_count"],
+            "issues": found["items"].as_array().into_iter().flatten().map(github_issue).collect::<Vec<_>>(),
+        }))
+    }
+}
+
+pub struct GitHubReadIssueTool(Arc<GitHub>);
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct GitHubReadIssueParameters {
+    /// Issue or pull request number
+    pub number: u64,
+    /// `owner/name` of the repository, defaults to the configured one
+    pub repo: Option<String>,
+}
+
+impl_tool_params!(GitHubReadIssueParameters);
+
+impl Tool for GitHubReadIssueTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "github_read_issue".to_string(),
+            description: "Read a GitHub issue or pull request together with its comments.".to_string(),
+            parameters: GitHubReadIssueParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<GitHubReadIssueParameters>(parameters)?;
+        let path = format!("/repos/{}/issues/{}", self.0.repo(params.repo)?, params.number);
+
+        let issue = self.0.request(Method::GET, &path, &[], None)?;
+        let comments = self.0.request(Method::GET, &format!("{}/comments", path), &[("per_page", "100".to_string())], None)?;
+
+        let mut result = github_issue(&issue);
+        result["body"] = json!(truncate(issue["body"].as_str().unwrap_or_default()));
+        result["comments"] = json!(comments.as_array().into_iter().flatten().map(github_comment).collect::<Vec<_>>());
+        Ok(result)
+    }
+}
+
+pub struct GitHubCreateIssueTool(Arc<GitHub>);
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct GitHubCreateIssueParameters {
+    pub title: String,
+    /// Markdown description
+    pub body: String,
+    pub labels: Option<Vec<String>>,
+    /// `owner/name` of the repository, defaults to the configured one
+    pub repo: Option<String>,
+}
+
+impl_tool_params!(GitHubCreateIssueParameters);
+
+impl Tool for GitHubCreateIssueTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "github_create_issue".to_string(),
+            description: "File a new GitHub issue.".to_string(),
+            parameters: GitHubCreateIssueParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<GitHubCreateIssueParameters>(parameters)?;
+        let path = format!("/repos/{}/issues", self.0.repo(params.repo)?);
+        let body = json!({
+            "title": params.title,
+            "body": params.body,
+            "labels": params.labels.unwrap_or_default(),
+        });
+
+        Ok(github_issue(&self.0.request(Method::POST, &path, &[], Some(body))?))
+    }
+}
+
+pub struct GitHubCommentTool(Arc<GitHub>);
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct GitHubCommentParameters {
+    /// Issue or pull request number
+    pub number: u64,
+    /// Markdown comment
+    pub body: String,
+    /// `owner/name` of the repository, defaults to the configured one
+    pub repo: Option<String>,
+}
+
+impl_tool_params!(GitHubCommentParameters);
+
+impl Tool for GitHubCommentTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "github_comment".to_string(),
+            description: "Add a comment to a GitHub issue or pull request.".to_string(),
+            parameters: GitHubCommentParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<GitHubCommentParameters>(parameters)?;
+        let path = format!("/repos/{}/issues/{}/comments", self.0.repo(params.repo)?, params.number);
+        let comment = self.0.request(Method::POST, &path, &[], Some(json!({ "body": params.body })))?;
+
+        Ok(json!({ "url": comment["html_url"] }))
+    }
+}
+
+/// Builds the GitHub tools sharing one client.
+pub fn github_tools(config: GitHubConfig) -> anyhow::Result<(GitHubSearchIssuesTool, GitHubReadIssueTool, GitHubCreateIssueTool, GitHubCommentTool)> {
+    let github = Arc::new(GitHub::new(config)?);
+    Ok((
+        GitHubSearchIssuesTool(github.clone()),
+        GitHubReadIssueTool(github.clone()),
+        GitHubCreateIssueTool(github.clone()),
+        GitHubCommentTool(github),
+    ))
+}
+
+struct Jira {
+    config: JiraConfig,
+    client: reqwest::Client,
+}
+
+impl Jira {
+    fn new(config: JiraConfig) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+        Ok(Self { config, client })
+    }
+
+    fn request(&self, method: Method, path: &str, body: Option<Value>) -> anyhow::Result<Value> {
+        let token = keychain::get_secret(self.config.token_secret.as_deref().unwrap_or("jira_token"))?;
+        let url = format!("{}/rest/api/2{}", self.config.url.trim_end_matches('/'), path);
+
+        let mut request = match self.config.username {
+            Some(ref username) => self.client.request(method, url).basic_auth(username, Some(token)),
+            None => self.client.request(method, url).bearer_auth(token),
+        };
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        futures::executor::block_on(send(request)).map_err(|e| anyhow!("Jira request failed: {}", e))
+    }
+
+    fn browse_url(&self, key: &Value) -> String {
+        format!("{}/browse/{}", self.config.url.trim_end_matches('/'), key.as_str().unwrap_or_default())
+    }
+}
+
+fn jira_issue(issue: &Value) -> Value {
+    let fields = &issue["fields"];
+    json!({
+        "key": issue["key"],
+        "summary": fields["summary"],
+        "status": fields["status"]["name"],
+        "type": fields["issuetype"]["name"],
+        "priority": fields["priority"]["name"],
+        "assignee": fields["assignee"]["displayName"],
+        "reporter": fields["reporter"]["displayName"],
+        "updated": fields["updated"],
+    })
+}
+
+pub struct JiraSearchIssuesTool(Arc<Jira>);
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct JiraSearchIssuesParameters {
+    /// JQL query, e.g. `project = CORE AND status != Done AND text ~ "streaming"`
+    pub jql: String,
+    /// Defaults to 20
+    pub max_results: Option<usize>,
+}
+
+impl_tool_params!(JiraSearchIssuesParameters);
+
+impl Tool for JiraSearchIssuesTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "jira_search_issues".to_string(),
+            description: "Search Jira issues with a JQL query.".to_string(),
+            parameters: JiraSearchIssuesParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<JiraSearchIssuesParameters>(parameters)?;
+        let body = json!({
+            "jql": params.jql,
+            "maxResults": params.max_results.unwrap_or(DEFAULT_MAX_RESULTS),
+            "fields": ["summary", "status", "issuetype", "priority", "assignee", "reporter", "updated"],
+        });
+        let found = self.0.request(Method::POST, "/search", Some(body))?;
+
+        Ok(json!({
+            "total": found["total"],
+            "issues": found["issues"].as_array().into_iter().flatten().map(jira_issue).collect::<Vec<_>>(),
+        }))
+    }
+}
+
+pub struct JiraReadIssueTool(Arc<Jira>);
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct JiraReadIssueParameters {
+    /// Issue key like `CORE-123`
+    pub key: String,
+}
+
+impl_tool_params!(JiraReadIssueParameters);
+
+impl Tool for JiraReadIssueTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "jira_read_issue".to_string(),
+            description: "Read a Jira issue together with its description and comments.".to_string(),
+            parameters: JiraReadIssueParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<JiraReadIssueParameters>(parameters)?;
+        let issue = self.0.request(Method::GET, &format!("/issue/{}", params.key), None)?;
+
+        let mut result = jira_issue(&issue);
+        result["url"] = json!(self.0.browse_url(&issue["key"]));
+        result["description"] = json!(truncate(issue["fields"]["description"].as_str().unwrap_or_default()));
+        result["comments"] = issue["fields"]["comment"]["comments"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|e| json!({
+                "author": e["author"]["displayName"],
+                "created": e["created"],
+                "body": truncate(e["body"].as_str().unwrap_or_default()),
+            }))
+            .collect();
+        Ok(result)
+    }
+}
+
+pub struct JiraCreateIssueTool(Arc<Jira>);
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct JiraCreateIssueParameters {
+    pub summary: String,
+    pub description: String,
+    /// Issue type name, defaults to `Task`
+    pub issue_type: Option<String>,
+    /// Project key, defaults to the configured one
+    pub project: Option<String>,
+}
+
+impl_tool_params!(JiraCreateIssueParameters);
+
+impl Tool for JiraCreateIssueTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "jira_create_issue".to_string(),
+            description: "File a new Jira issue.".to_string(),
+            parameters: JiraCreateIssueParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<JiraCreateIssueParameters>(parameters)?;
+        let project = params.project
+            .or(self.0.config.project.clone())
+            .ok_or(anyhow!("No project given and jira.project isn't configured"))?;
+        let body = json!({
+            "fields": {
+                "project": { "key": project },
+                "summary": params.summary,
+                "description": params.description,
+                "issuetype": { "name": params.issue_type.unwrap_or("Task".to_string()) },
+            }
+        });
+        let created = self.0.request(Method::POST, "/issue", Some(body))?;
+
+        Ok(json!({
+            "key": created["key"],
+            "url": self.0.browse_url(&created["key"]),
+        }))
+    }
+}
+
+pub struct JiraCommentTool(Arc<Jira>);
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct JiraCommentParameters {
+    /// Issue key like `CORE-123`
+    pub key: String,
+    pub body: String,
+}
+
+impl_tool_params!(JiraCommentParameters);
+
+impl Tool for JiraCommentTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "jira_comment".to_string(),
+            description: "Add a comment to a Jira issue.".to_string(),
+            parameters: JiraCommentParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<JiraCommentParameters>(parameters)?;
+        let path = format!("/issue/{}/comment", params.key);
+        let comment = self.0.request(Method::POST, &path, Some(json!({ "body": params.body })))?;
+
+        Ok(json!({ "id": comment["id"], "url": self.0.browse_url(&json!(params.key)) }))
+    }
+}
+
+/// Builds the Jira tools sharing one client.
+pub fn jira_tools(config: JiraConfig) -> anyhow::Result<(JiraSearchIssuesTool, JiraReadIssueTool, JiraCreateIssueTool, JiraCommentTool)> {
+    let jira = Arc::new(Jira::new(config)?);
+    Ok((
+        JiraSearchIssuesTool(jira.clone()),
+        JiraReadIssueTool(jira.clone()),
+        JiraCreateIssueTool(jira.clone()),
+        JiraCommentTool(jira),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summarize_issues() {
+        let github = json!({
+            "number": 7, "title": "Stream stalls", "state": "open", "html_url": "https://github.com/a/b/issues/7",
+            "user": { "login": "ada" }, "labels": [{ "name": "bug" }], "updated_at": "2026-10-01T00:00:00Z",
+            "pull_request": { "url": "x" },
+        });
+        assert_eq!(github_issue(&github)["labels"], json!(["bug"]));
+        assert_eq!(github_issue(&github)["pull_request"], true);
+
+        let jira = json!({ "key": "CORE-1", "fields": { "summary": "Stream stalls", "status": { "name": "Open" }, "assignee": null } });
+        assert_eq!(jira_issue(&jira)["status"], "Open");
+        assert_eq!(jira_issue(&jira)["assignee"], Value::Null);
+    }
+}
@@ -0,0 +1,383 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{anyhow, bail};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use chrono::{Duration as Days, Utc};
+use regex::Regex;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::config::{CalendarConfig, MailConfig};
+use crate::impl_tool_params;
+use crate::keychain;
+use crate::tools::{Tool, ToolMetaData, ToolParameters};
+use crate::tools::web::decode_entities;
+
+const NETWORK_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAIL_DAYS: u32 = 7;
+const DEFAULT_MAIL_RESULTS: usize = 10;
+const DEFAULT_CALENDAR_DAYS: u32 = 14;
+const MAX_DESCRIPTION_CHARS: usize = 300;
+
+/// Decodes RFC 2047 encoded words like `=?UTF-8?B?...?=` found in mail headers.
+fn decode_rfc2047(header: &str) -> String {
+    let word = Regex::new(r"=\?([^?]+)\?([BbQq])\?([^?]*)\?=\s*").unwrap();
+
+    word.replace_all(header, |caps: &regex::Captures| {
+        let bytes = if caps[2].eq_ignore_ascii_case("b") {
+            STANDARD.decode(&caps[3]).unwrap_or_default()
+        } else {
+            let text = caps[3].replace('_', " ");
+            let mut bytes = vec![];
+            let mut rest = text.as_bytes();
+            while let Some((&first, tail)) = rest.split_first() {
+                match (first, tail.get(..2).and_then(|e| u8::from_str_radix(std::str::from_utf8(e).ok()?, 16).ok())) {
+                    (b'=', Some(byte)) => {
+                        bytes.push(byte);
+                        rest = &tail[2..];
+                    }
+                    _ => {
+                        bytes.push(first);
+                        rest = tail;
+                    }
+                }
+            }
+            bytes
+        };
+
+        let encoding = encoding_rs::Encoding::for_label(caps[1].as_bytes()).unwrap_or(encoding_rs::UTF_8);
+        encoding.decode(&bytes).0.to_string()
+    }).trim().to_string()
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// A minimal read-only IMAP4 client speaking just enough of the protocol to search and fetch headers.
+struct ImapSession {
+    stream: BufReader<StreamOwned<ClientConnection, TcpStream>>,
+    tag: u32,
+}
+
+impl ImapSession {
+    fn connect(host: &str, port: u16) -> anyhow::Result<Self> {
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_native_certs::load_native_certs().certs {
+            let _ = roots.add(cert);
+        }
+        let config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connection = ClientConnection::new(Arc::new(config), ServerName::try_from(host.to_string())?)?;
+
+        let tcp = TcpStream::connect((host, port))?;
+        tcp.set_read_timeout(Some(NETWORK_TIMEOUT))?;
+        let mut session = Self {
+            stream: BufReader::new(StreamOwned::new(connection, tcp)),
+            tag: 0,
+        };
+
+        let mut greeting = String::new();
+        session.stream.read_line(&mut greeting)?;
+        if !greeting.starts_with("* OK") {
+            bail!("Unexpected IMAP greeting: {}", greeting.trim());
+        }
+        Ok(session)
+    }
+
+    /// Sends `command` and returns its untagged responses with any literals inlined.
+    fn command(&mut self, command: &str) -> anyhow::Result<Vec<String>> {
+        self.tag += 1;
+        let tag = format!("a{}", self.tag);
+        write!(self.stream.get_mut(), "{} {}\r\n", tag, command)?;
+        self.stream.get_mut().flush()?;
+
+        let literal = Regex::new(r"\{(\d+)\}\r\n$").unwrap();
+        let mut responses = vec![];
+        let mut current = String::new();
+
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line)? == 0 {
+                bail!("IMAP server closed the connection");
+            }
+
+            if let Some(caps) = literal.captures(&line) {
+                let mut bytes = vec![0; caps[1].parse()?];
+                self.stream.read_exact(&mut bytes)?;
+                current.push_str(&line);
+                current.push_str(&String::from_utf8_lossy(&bytes));
+                continue;
+            }
+
+            current.push_str(&line);
+            let response = std::mem::take(&mut current);
+            if let Some(status) = response.strip_prefix(&format!("{} ", tag)) {
+                if !status.starts_with("OK") {
+                    bail!("IMAP command failed: {}", status.trim());
+                }
+                return Ok(responses);
+            }
+            responses.push(response);
+        }
+    }
+}
+
+/// Extracts sequence number and From/Subject/Date out of a `* n FETCH (BODY[HEADER.FIELDS ...] ...)` response.
+fn parse_fetch(response: &str) -> Option<Value> {
+    let sequence = Regex::new(r"^\* (\d+) FETCH").unwrap();
+    let id = sequence.captures(response)?[1].parse::<u32>().ok()?;
+
+    // Headers may be folded over several lines.
+    let unfolded = Regex::new(r"\r?\n[ \t]+").unwrap().replace_all(response, " ");
+    let header = |name: &str| {
+        unfolded
+            .lines()
+            .find_map(|line| line.split_once(':').filter(|(key, _)| key.trim().eq_ignore_ascii_case(name)))
+            .map(|(_, value)| decode_rfc2047(value.trim()))
+    };
+
+    Some(json!({
+        "id": id,
+        "from": header("From"),
+        "subject": header("Subject"),
+        "date": header("Date"),
+    }))
+}
+
+pub struct MailSearchTool {
+    config: MailConfig,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct MailSearchParameters {
+    /// Text to look for anywhere in the message
+    pub query: Option<String>,
+    /// Only messages whose sender contains this text
+    pub from: Option<String>,
+    /// How many days back to search, defaults to 7
+    pub days: Option<u32>,
+    /// Maximum number of messages to return, newest first. Defaults to 10
+    pub max_results: Option<usize>,
+}
+
+impl_tool_params!(MailSearchParameters);
+
+impl MailSearchTool {
+    pub fn new(config: MailConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl Tool for MailSearchTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "mail_search".to_string(),
+            description: "Search the user's recent emails (read-only) and return sender, subject and date of the matches.".to_string(),
+            parameters: MailSearchParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<MailSearchParameters>(parameters)?;
+        let password = keychain::get_secret(self.config.password_secret.as_deref().unwrap_or("imap"))?;
+
+        let mut session = ImapSession::connect(&self.config.imap_host, self.config.imap_port)
+            .map_err(|e| anyhow!("Failed to connect to {}: {}", self.config.imap_host, e))?;
+        session.command(&format!("LOGIN {} {}", quote(&self.config.username), quote(&password)))?;
+        // EXAMINE opens the mailbox read-only, so fetching never flips the \Seen flag.
+        session.command("EXAMINE INBOX")?;
+
+        let since = Utc::now() - Days::days(params.days.unwrap_or(DEFAULT_MAIL_DAYS) as i64);
+        let mut criteria = format!("SINCE {}", since.format("%d-%b-%Y"));
+        if let Some(ref query) = params.query {
+            criteria.push_str(&format!(" TEXT {}", quote(query)));
+        }
+        if let Some(ref from) = params.from {
+            criteria.push_str(&format!(" FROM {}", quote(from)));
+        }
+
+        let ids = session
+            .command(&format!("SEARCH {}", criteria))?
+            .iter()
+            .filter_map(|e| e.strip_prefix("* SEARCH"))
+            .flat_map(|e| e.split_whitespace().map(|id| id.to_string()).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let newest = ids
+            .iter()
+            .rev()
+            .take(params.max_results.unwrap_or(DEFAULT_MAIL_RESULTS))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut messages = vec![];
+        if !newest.is_empty() {
+            let fetched = session.command(&format!("FETCH {} (BODY.PEEK[HEADER.FIELDS (FROM SUBJECT DATE)])", newest.join(",")))?;
+            messages = fetched.iter().filter_map(|e| parse_fetch(e)).collect();
+            messages.sort_by_key(|e| std::cmp::Reverse(e["id"].as_u64()));
+        }
+        let _ = session.command("LOGOUT");
+
+        Ok(json!({
+            "total": ids.len(),
+            "messages": messages,
+        }))
+    }
+}
+
+/// Reads the VEVENTs out of an iCalendar document.
+fn parse_ics(ics: &str) -> Vec<Value> {
+    let unfolded = Regex::new(r"\r?\n[ \t]").unwrap().replace_all(ics, "");
+    let mut events = vec![];
+    let mut event: Option<Value> = None;
+
+    for line in unfolded.lines() {
+        match line.trim_end() {
+            "BEGIN:VEVENT" => event = Some(json!({ "attendees": [] })),
+            "END:VEVENT" => events.extend(event.take()),
+            line => {
+                let Some(ref mut current) = event else { continue };
+                let Some((key, value)) = line.split_once(':') else { continue };
+                let (name, params) = key.split_once(';').unwrap_or((key, ""));
+                let value = value.replace("\\n", "\n").replace("\\,", ",").replace("\\;", ";");
+
+                match name {
+                    "SUMMARY" => current["summary"] = json!(value),
+                    "LOCATION" => current["location"] = json!(value),
+                    "DESCRIPTION" => current["description"] = json!(value.chars().take(MAX_DESCRIPTION_CHARS).collect::<String>()),
+                    "DTSTART" | "DTEND" => {
+                        let field = if name == "DTSTART" { "start" } else { "end" };
+                        current[field] = json!(value);
+                        if let Some(tz) = params.split(';').find_map(|e| e.strip_prefix("TZID=")) {
+                            current["timezone"] = json!(tz);
+                        }
+                    }
+                    "ORGANIZER" | "ATTENDEE" => {
+                        let who = params
+                            .split(';')
+                            .find_map(|e| e.strip_prefix("CN="))
+                            .map(|e| e.trim_matches('"').to_string())
+                            .unwrap_or(value.trim_start_matches("mailto:").to_string());
+                        if name == "ORGANIZER" {
+                            current["organizer"] = json!(who);
+                        } else if let Some(attendees) = current["attendees"].as_array_mut() {
+                            attendees.push(json!(who));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    events
+}
+
+pub struct CalendarEventsTool {
+    config: CalendarConfig,
+    client: reqwest::Client,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct CalendarEventsParameters {
+    /// How many days ahead to look, defaults to 14
+    pub days: Option<u32>,
+    /// Only events whose title, description, location or attendees contain this text
+    pub query: Option<String>,
+}
+
+impl_tool_params!(CalendarEventsParameters);
+
+impl CalendarEventsTool {
+    pub fn new(config: CalendarConfig) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder().timeout(NETWORK_TIMEOUT).build()?;
+        Ok(Self { config, client })
+    }
+}
+
+impl Tool for CalendarEventsTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "calendar_events".to_string(),
+            description: "List the user's upcoming calendar events (read-only), optionally filtered by text, ordered by start time.".to_string(),
+            parameters: CalendarEventsParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<CalendarEventsParameters>(parameters)?;
+        let password = keychain::get_secret(self.config.password_secret.as_deref().unwrap_or("caldav"))?;
+
+        let start = Utc::now();
+        let end = start + Days::days(params.days.unwrap_or(DEFAULT_CALENDAR_DAYS) as i64);
+        let range = format!(r#"start="{}" end="{}""#, start.format("%Y%m%dT%H%M%SZ"), end.format("%Y%m%dT%H%M%SZ"));
+        let body = format!(r#"<?xml version="1.0" encoding="utf-8"?>
+<c:calendar-query xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
+  <d:prop><c:calendar-data><c:expand {range}/></c:calendar-data></d:prop>
+  <c:filter><c:comp-filter name="VCALENDAR"><c:comp-filter name="VEVENT"><c:time-range {range}/></c:comp-filter></c:comp-filter></c:filter>
+</c:calendar-query>"#);
+
+        let request = self.client
+            .request(reqwest::Method::from_bytes(b"REPORT")?, &self.config.url)
+            .basic_auth(&self.config.username, Some(password))
+            .header("Depth", "1")
+            .header("Content-Type", "application/xml; charset=utf-8")
+            .body(body);
+        let xml = futures::executor::block_on(async move {
+            request.send().await?.error_for_status()?.text().await
+        }).map_err(|e| anyhow!("CalDAV request failed: {}", e))?;
+
+        let calendar_data = Regex::new(r"(?s)<[^>/]*calendar-data[^>]*>(.*?)</[^>]*calendar-data>").unwrap();
+        let query = params.query.map(|e| e.to_lowercase());
+        let mut events = calendar_data
+            .captures_iter(&xml)
+            .flat_map(|caps| parse_ics(&decode_entities(&caps[1])))
+            .filter(|e| query.as_ref().is_none_or(|q| e.to_string().to_lowercase().contains(q)))
+            .collect::<Vec<_>>();
+        events.sort_by(|a, b| a["start"].as_str().cmp(&b["start"].as_str()));
+
+        Ok(json!({ "events": events }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_rfc2047() {
+        assert_eq!(decode_rfc2047("=?UTF-8?B?5L2g5aW9?= world"), "你好world");
+        assert_eq!(decode_rfc2047("=?ISO-8859-1?Q?caf=E9_bar?="), "café bar");
+        assert_eq!(decode_rfc2047("plain"), "plain");
+    }
+
+    #[test]
+    fn test_parse_fetch() {
+        let response = "* 12 FETCH (BODY[HEADER.FIELDS (FROM SUBJECT DATE)] {80}\r\nFrom: Ada <ada@example.org>\r\nSubject: Sync\r\n about streaming\r\nDate: Mon, 12 Oct 2026\r\n\r\n)\r\n";
+
+        assert_eq!(parse_fetch(response).unwrap(), json!({
+            "id": 12,
+            "from": "Ada <ada@example.org>",
+            "subject": "Sync about streaming",
+            "date": "Mon, 12 Oct 2026",
+        }));
+    }
+
+    #[test]
+    fn test_parse_ics() {
+        let ics = "BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\nSUMMARY:1:1 with\r\n  Bob\r\nDTSTART;TZID=Europe/Berlin:20261020T100000\r\nATTENDEE;CN=\"Bob\":mailto:bob@example.org\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n";
+
+        assert_eq!(parse_ics(ics), vec![json!({
+            "summary": "1:1 with Bob",
+            "start": "20261020T100000",
+            "timezone": "Europe/Berlin",
+            "attendees": ["Bob"],
+        })]);
+    }
+}
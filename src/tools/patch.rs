@@ -0,0 +1,222 @@
+use std::fs;
+use anyhow::{anyhow, bail};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::impl_tool_params;
+use crate::tools::{Tool, ToolMetaData, ToolParameters};
+use crate::tools::fs::Sandbox;
+use crate::tools::guard::{render_diff, WriteGuard};
+
+pub struct DiffTextTool;
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct DiffTextParameters {
+    /// The original text
+    pub old: String,
+    /// The modified text
+    pub new: String,
+}
+
+impl_tool_params!(DiffTextParameters);
+
+impl Tool for DiffTextTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "diff_text".to_string(),
+            description: "Compute a unified diff between two texts.".to_string(),
+            parameters: DiffTextParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<DiffTextParameters>(parameters)?;
+        let patch = diffy::create_patch(&params.old, &params.new);
+
+        Ok(json!({
+            "identical": patch.hunks().is_empty(),
+            "diff": patch.to_string(),
+        }))
+    }
+}
+
+pub struct ApplyPatchTool {
+    sandbox: Sandbox,
+    guard: WriteGuard,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct ApplyPatchParameters {
+    /// Path of the file to patch, relative to the workspace root. A missing file is treated as empty
+    pub file: String,
+    /// Unified diff to apply, with `---`/`+++` headers and `@@` hunks
+    pub unified_diff: String,
+}
+
+impl_tool_params!(ApplyPatchParameters);
+
+impl ApplyPatchTool {
+    pub fn new(sandbox: Sandbox, guard: WriteGuard) -> Self {
+        Self { sandbox, guard }
+    }
+}
+
+impl Tool for ApplyPatchTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "apply_patch".to_string(),
+            description: "Apply a unified diff to a file in the workspace instead of rewriting the whole file.".to_string(),
+            parameters: ApplyPatchParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<ApplyPatchParameters>(parameters)?;
+        let path = self.sandbox.resolve(&params.file)?;
+
+        let original = if path.exists() { fs::read_to_string(&path)? } else { String::new() };
+        let patch = diffy::Patch::from_str(&params.unified_diff)
+            .map_err(|e| anyhow!("Invalid unified diff: {}", e))?;
+        let patched = diffy::apply(&original, &patch)
+            .map_err(|e| anyhow!("Patch does not apply to {}: {}", params.file, e))?;
+
+        self.guard.write(&self.sandbox, &path, &patched, &render_diff(&params.unified_diff))
+    }
+
+    fn confirms(&self, _parameters: &Value) -> bool {
+        self.guard.confirms()
+    }
+}
+
+pub struct EditFileTool {
+    sandbox: Sandbox,
+    guard: WriteGuard,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct SearchReplace {
+    /// Exact text to find, it has to occur exactly once in the file. Include enough surrounding lines to be unique
+    pub search: String,
+    /// Text to put in place of `search`
+    pub replace: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct EditFileParameters {
+    /// Path of the file to edit, relative to the workspace root
+    pub path: String,
+    /// Search/replace blocks, applied in order
+    pub edits: Vec<SearchReplace>,
+}
+
+impl_tool_params!(EditFileParameters);
+
+impl EditFileTool {
+    pub fn new(sandbox: Sandbox, guard: WriteGuard) -> Self {
+        Self { sandbox, guard }
+    }
+}
+
+/// Applies every block to `content`, failing if a search text is missing or ambiguous.
+fn apply_edits(content: &str, edits: &[SearchReplace]) -> anyhow::Result<String> {
+    let mut content = content.to_string();
+
+    for (index, edit) in edits.iter().enumerate() {
+        if edit.search.is_empty() {
+            bail!("Edit #{} has an empty search text", index + 1);
+        }
+        match content.matches(edit.search.as_str()).count() {
+            0 => bail!("Edit #{}: search text not found", index + 1),
+            1 => content = content.replacen(edit.search.as_str(), edit.replace.as_str(), 1),
+            n => bail!("Edit #{}: search text found {} times, add more context to make it unique", index + 1, n),
+        }
+    }
+
+    Ok(content)
+}
+
+impl Tool for EditFileTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "edit_file".to_string(),
+            description: "Modify a file in the workspace by replacing exact snippets of it. Prefer this over write_file for changes to existing files.".to_string(),
+            parameters: EditFileParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<EditFileParameters>(parameters)?;
+        let path = self.sandbox.resolve(&params.path)?;
+
+        let original = fs::read_to_string(&path)
+            .map_err(|e| anyhow!("Failed to read file {}: {}", params.path, e))?;
+        let edited = apply_edits(&original, &params.edits)?;
+
+        let diff = diffy::create_patch(&original, &edited).to_string();
+        let mut result = self.guard.write(&self.sandbox, &path, &edited, &render_diff(&diff))?;
+        result["diff"] = json!(diff);
+        Ok(result)
+    }
+
+    fn confirms(&self, _parameters: &Value) -> bool {
+        self.guard.confirms()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::fs::tests::temp_sandbox;
+
+    #[test]
+    fn test_diff_then_apply() {
+        let sandbox = temp_sandbox("patch");
+        let backups = sandbox.root().join(".backups");
+        fs::write(sandbox.root().join("a.txt"), "one\ntwo\nthree\n").unwrap();
+
+        let diff = DiffTextTool
+            .execute(json!({ "old": "one\ntwo\nthree\n", "new": "one\n2\nthree\n" }))
+            .unwrap();
+
+        let result = ApplyPatchTool::new(sandbox.clone(), WriteGuard::new(false, Some(backups)))
+            .execute(json!({ "file": "a.txt", "unified_diff": diff["diff"] }))
+            .unwrap();
+
+        assert_eq!(result["applied"], true);
+        assert_eq!(fs::read_to_string(sandbox.root().join("a.txt")).unwrap(), "one\n2\nthree\n");
+        let backup = result["backup"].as_str().unwrap();
+        assert_eq!(fs::read_to_string(backup).unwrap(), "one\ntwo\nthree\n");
+    }
+
+    #[test]
+    fn test_apply_edits() {
+        let edit = |search: &str, replace: &str| SearchReplace { search: search.to_string(), replace: replace.to_string() };
+        let content = "fn a() {}\nfn b() {}\nfn b() {}\n";
+
+        assert_eq!(apply_edits(content, &[edit("fn a", "fn c")]).unwrap(), "fn c() {}\nfn b() {}\nfn b() {}\n");
+        assert!(apply_edits(content, &[edit("fn b", "fn d")]).is_err());
+        assert!(apply_edits(content, &[edit("fn x", "fn d")]).is_err());
+    }
+
+    #[test]
+    fn test_failed_edits_leave_the_file() {
+        let sandbox = temp_sandbox("patch-failed");
+        let path = sandbox.root().join("a.rs");
+        fs::write(&path, "fn a() {}\nfn b() {}\nfn b() {}\n").unwrap();
+        let guard = WriteGuard::new(false, None);
+
+        let diff = DiffTextTool.execute(json!({ "old": "fn x() {}\n", "new": "fn y() {}\n" })).unwrap();
+        let e = ApplyPatchTool::new(sandbox.clone(), guard.clone())
+            .execute(json!({ "file": "a.rs", "unified_diff": diff["diff"] }))
+            .unwrap_err();
+        assert!(e.to_string().starts_with("Patch does not apply to a.rs"), "{}", e);
+
+        let edit = |search: &str| EditFileTool::new(sandbox.clone(), guard.clone())
+            .execute(json!({ "path": "a.rs", "edits": [{ "search": search, "replace": "fn c" }] }))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(edit("fn x"), "Edit #1: search text not found");
+        assert_eq!(edit("fn b"), "Edit #1: search text found 2 times, add more context to make it unique");
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fn a() {}\nfn b() {}\nfn b() {}\n");
+    }
+}
@@ -0,0 +1,207 @@
+use std::path::{Path, PathBuf};
+use colored::Colorize;
+use serde_json::Value;
+use crate::tools::{Tool, ToolMetaData};
+
+/// Lists the `.wasm` files in `dir`, a missing directory has none.
+fn plugin_files(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut files = std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|e| e.is_file() && e.extension().is_some_and(|e| e == "wasm"))
+        .collect::<Vec<_>>();
+    files.sort();
+    Ok(files)
+}
+
+/// Loads every plugin in `dir`, a plugin that fails to load is reported and skipped.
+pub fn load_plugins(dir: &Path) -> anyhow::Result<Vec<PluginTool>> {
+    let files = plugin_files(dir)?;
+    if files.is_empty() {
+        return Ok(vec![]);
+    }
+
+    #[cfg(feature = "plugins")]
+    {
+        let engine = runtime::engine()?;
+        Ok(files
+            .iter()
+            .filter_map(|path| match PluginTool::load(&engine, path) {
+                Ok(tool) => Some(tool),
+                Err(e) => {
+                    eprintln!("{}", format!("Warning: Failed to load plugin {}: {:#}", path.display(), e).yellow());
+                    None
+                }
+            })
+            .collect())
+    }
+    #[cfg(not(feature = "plugins"))]
+    {
+        eprintln!("{}", format!("Warning: Found {} plugins in {}, but plugin support isn't compiled in, rebuild with `--features plugins`", files.len(), dir.display()).yellow());
+        Ok(vec![])
+    }
+}
+
+pub struct PluginTool {
+    metadata: ToolMetaData,
+    #[cfg(feature = "plugins")]
+    plugin: runtime::Plugin,
+}
+
+#[cfg(feature = "plugins")]
+impl PluginTool {
+    fn load(engine: &wasmtime::Engine, path: &Path) -> anyhow::Result<Self> {
+        let plugin = runtime::Plugin::new(engine, path)?;
+        let metadata = plugin.metadata()?;
+        Ok(Self {
+            metadata: ToolMetaData {
+                name: metadata.name,
+                description: metadata.description,
+                parameters: serde_json::from_str(&metadata.parameters)?,
+            },
+            plugin,
+        })
+    }
+}
+
+impl Tool for PluginTool {
+    fn metadata(&self) -> ToolMetaData {
+        self.metadata.clone()
+    }
+
+    #[cfg(feature = "plugins")]
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        match self.plugin.execute(&parameters.to_string())? {
+            Ok(result) => Ok(serde_json::from_str(&result)?),
+            Err(e) => Ok(serde_json::json!({ "error": e })),
+        }
+    }
+
+    #[cfg(not(feature = "plugins"))]
+    fn execute(&self, _parameters: Value) -> anyhow::Result<Value> {
+        unreachable!("plugins are never loaded without the `plugins` feature")
+    }
+}
+
+#[cfg(feature = "plugins")]
+mod runtime {
+    use std::path::Path;
+    use wasmtime::component::{Component, Linker};
+    use wasmtime::{Config, Engine, Store};
+
+    wasmtime::component::bindgen!({
+        path: "wit",
+        world: "tool-plugin",
+    });
+
+    /// Instructions a single call may run, so a looping plugin doesn't keep a thread busy forever.
+    const FUEL: u64 = 10_000_000_000;
+
+    pub fn engine() -> anyhow::Result<Engine> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        Engine::new(&config)
+    }
+
+    /// A compiled plugin, every call runs in a fresh instance so calls can't affect each other.
+    pub struct Plugin {
+        engine: Engine,
+        component: Component,
+        linker: Linker<()>,
+    }
+
+    impl Plugin {
+        pub fn new(engine: &Engine, path: &Path) -> anyhow::Result<Self> {
+            Ok(Self {
+                engine: engine.clone(),
+                component: Component::from_file(engine, path)?,
+                linker: Linker::new(engine),
+            })
+        }
+
+        fn instantiate(&self) -> anyhow::Result<(Store<()>, ToolPlugin)> {
+            let mut store = Store::new(&self.engine, ());
+            store.set_fuel(FUEL)?;
+            let plugin = ToolPlugin::instantiate(&mut store, &self.component, &self.linker)?;
+            Ok((store, plugin))
+        }
+
+        pub fn metadata(&self) -> anyhow::Result<Metadata> {
+            let (mut store, plugin) = self.instantiate()?;
+            plugin.call_metadata(&mut store)
+        }
+
+        pub fn execute(&self, arguments: &str) -> anyhow::Result<Result<String, String>> {
+            let (mut store, plugin) = self.instantiate()?;
+            plugin.call_execute(&mut store, arguments)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plugin_files() {
+        let dir = std::env::temp_dir().join(format!("rag-plugins-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("b.wasm"), b"not a component").unwrap();
+        std::fs::write(dir.join("a.wasm"), b"").unwrap();
+        std::fs::write(dir.join("notes.txt"), b"").unwrap();
+
+        assert_eq!(plugin_files(&dir).unwrap(), [dir.join("a.wasm"), dir.join("b.wasm")]);
+        assert!(plugin_files(&dir.join("missing")).unwrap().is_empty());
+        // Broken plugins are skipped instead of failing the session.
+        assert!(load_plugins(&dir).unwrap().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// An `echo` plugin that hands the arguments back as its result.
+    #[cfg(feature = "plugins")]
+    const ECHO_PLUGIN: &str = r#"
+        (component
+            (type $metadata (record (field "name" string) (field "description" string) (field "parameters" string)))
+            (import "metadata" (type $metadata' (eq $metadata)))
+            (core module $m
+                (memory (export "memory") 1)
+                (global $heap (mut i32) (i32.const 1024))
+                (func (export "realloc") (param i32 i32 i32 i32) (result i32)
+                    (local $ptr i32)
+                    (local.set $ptr (global.get $heap))
+                    (global.set $heap (i32.add (global.get $heap) (local.get 3)))
+                    (local.get $ptr))
+                (data (i32.const 16) "echo")
+                (data (i32.const 32) "Echo the arguments")
+                (data (i32.const 64) "{\"type\":\"object\"}")
+                (data (i32.const 128) "\10\00\00\00\04\00\00\00\20\00\00\00\12\00\00\00\40\00\00\00\11\00\00\00")
+                (func (export "metadata") (result i32) (i32.const 128))
+                (func (export "execute") (param i32 i32) (result i32)
+                    (i32.store8 (i32.const 256) (i32.const 0))
+                    (i32.store (i32.const 260) (local.get 0))
+                    (i32.store (i32.const 264) (local.get 1))
+                    (i32.const 256)))
+            (core instance $i (instantiate $m))
+            (func (export "metadata") (result $metadata')
+                (canon lift (core func $i "metadata") (memory (core memory $i "memory")) (realloc (core func $i "realloc"))))
+            (func (export "execute") (param "arguments" string) (result (result string (error string)))
+                (canon lift (core func $i "execute") (memory (core memory $i "memory")) (realloc (core func $i "realloc")))))
+    "#;
+
+    #[cfg(feature = "plugins")]
+    #[test]
+    fn test_load_and_execute_plugin() {
+        let dir = std::env::temp_dir().join(format!("rag-plugins-echo-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("echo.wasm"), wat::parse_str(ECHO_PLUGIN).unwrap()).unwrap();
+
+        let plugins = load_plugins(&dir).unwrap();
+        assert_eq!(plugins.len(), 1);
+        assert_eq!(plugins[0].metadata().name, "echo");
+        assert_eq!(plugins[0].metadata().parameters, serde_json::json!({ "type": "object" }));
+        assert_eq!(plugins[0].execute(serde_json::json!({ "a": [1, 2] })).unwrap(), serde_json::json!({ "a": [1, 2] }));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
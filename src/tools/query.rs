@@ -0,0 +1,159 @@
+use std::fs;
+use anyhow::anyhow;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use serde_json_path::JsonPath;
+use crate::impl_tool_params;
+use crate::tools::{Tool, ToolMetaData, ToolParameters};
+use crate::tools::fs::Sandbox;
+
+const DEFAULT_MAX_MATCHES: usize = 100;
+
+pub struct RegexExtractTool {
+    sandbox: Sandbox,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct RegexExtractParameters {
+    /// Path of a file in the workspace to search, or the literal text itself when no such file exists
+    pub text_or_file: String,
+    /// Regular expression (Rust `regex` syntax), named groups like `(?<name>...)` are reported by name
+    pub pattern: String,
+    /// Maximum number of matches to return, defaults to 100
+    pub max_matches: Option<usize>,
+}
+
+impl_tool_params!(RegexExtractParameters);
+
+impl RegexExtractTool {
+    pub fn new(sandbox: Sandbox) -> Self {
+        Self { sandbox }
+    }
+}
+
+impl Tool for RegexExtractTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "regex_extract".to_string(),
+            description: "Extract the matches of a regular expression, with their line numbers and capture groups, from a file or a text.".to_string(),
+            parameters: RegexExtractParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<RegexExtractParameters>(parameters)?;
+        let pattern = Regex::new(&params.pattern)?;
+
+        let text = match self.sandbox.resolve(&params.text_or_file) {
+            Ok(path) if path.is_file() => fs::read_to_string(&path)
+                .map_err(|e| anyhow!("Failed to read file {}: {}", params.text_or_file, e))?,
+            _ => params.text_or_file.clone(),
+        };
+
+        let max_matches = params.max_matches.unwrap_or(DEFAULT_MAX_MATCHES);
+        let mut matches = vec![];
+        let mut total = 0;
+
+        for caps in pattern.captures_iter(&text) {
+            total += 1;
+            if matches.len() >= max_matches { continue; }
+
+            let whole = caps.get(0).unwrap();
+            let mut groups = Map::new();
+            for (index, name) in pattern.capture_names().enumerate().skip(1) {
+                let key = name.map(|e| e.to_string()).unwrap_or(index.to_string());
+                groups.insert(key, json!(caps.get(index).map(|e| e.as_str())));
+            }
+
+            matches.push(json!({
+                "match": whole.as_str(),
+                "line": text[..whole.start()].matches('\n').count() + 1,
+                "groups": groups,
+            }));
+        }
+
+        Ok(json!({
+            "total": total,
+            "truncated": total > matches.len(),
+            "matches": matches,
+        }))
+    }
+}
+
+pub struct JsonPathQueryTool {
+    sandbox: Sandbox,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct JsonPathQueryParameters {
+    /// Path of a JSON file, relative to the workspace root
+    pub file: String,
+    /// JSONPath expression (RFC 9535), e.g. `$.dependencies[*].name`
+    pub path: String,
+}
+
+impl_tool_params!(JsonPathQueryParameters);
+
+impl JsonPathQueryTool {
+    pub fn new(sandbox: Sandbox) -> Self {
+        Self { sandbox }
+    }
+}
+
+impl Tool for JsonPathQueryTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "jsonpath_query".to_string(),
+            description: "Select values from a JSON file with a JSONPath expression instead of reading the whole file.".to_string(),
+            parameters: JsonPathQueryParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<JsonPathQueryParameters>(parameters)?;
+        let path = self.sandbox.resolve(&params.file)?;
+
+        let content = fs::read_to_string(&path)
+            .map_err(|e| anyhow!("Failed to read file {}: {}", params.file, e))?;
+        let document = serde_json::from_str::<Value>(&content)
+            .map_err(|e| anyhow!("{} is not valid JSON: {}", params.file, e))?;
+        let query = JsonPath::parse(&params.path)
+            .map_err(|e| anyhow!("Invalid JSONPath {}: {}", params.path, e))?;
+
+        let results = query
+            .query_located(&document)
+            .into_iter()
+            .map(|node| json!({
+                "location": node.location().to_string(),
+                "value": node.node(),
+            }))
+            .collect::<Vec<_>>();
+
+        Ok(json!({ "results": results }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::fs::tests::temp_sandbox;
+
+    #[test]
+    fn test_regex_extract_and_jsonpath() {
+        let sandbox = temp_sandbox("query");
+        fs::write(sandbox.root().join("deps.json"), r#"{"deps": [{"name": "serde"}, {"name": "regex"}]}"#).unwrap();
+
+        let extracted = RegexExtractTool::new(sandbox.clone())
+            .execute(json!({ "text_or_file": "a = 1\nb = 2", "pattern": r"(?<key>\w) = (\d)" }))
+            .unwrap();
+        assert_eq!(extracted["total"], 2);
+        assert_eq!(extracted["matches"][1]["line"], 2);
+        assert_eq!(extracted["matches"][1]["groups"], json!({ "key": "b", "2": "2" }));
+
+        let queried = JsonPathQueryTool::new(sandbox)
+            .execute(json!({ "file": "deps.json", "path": "$.deps[*].name" }))
+            .unwrap();
+        assert_eq!(queried["results"][1], json!({ "location": "$['deps'][1]['name']", "value": "regex" }));
+    }
+}
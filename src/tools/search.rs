@@ -0,0 +1,103 @@
+use std::fs;
+use regex::RegexBuilder;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::impl_tool_params;
+use crate::tools::{Tool, ToolMetaData, ToolParameters};
+use crate::tools::fs::Sandbox;
+
+const DEFAULT_MAX_RESULTS: usize = 50;
+const MAX_SNIPPET_CHARS: usize = 200;
+
+pub struct GrepCodebaseTool {
+    sandbox: Sandbox,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct GrepCodebaseParameters {
+    /// Regular expression to search for, matched line by line
+    pub pattern: String,
+    /// File or directory to search in, relative to the workspace root. Defaults to the whole workspace
+    pub path: Option<String>,
+    /// Maximum number of matches to return, defaults to 50
+    pub max_results: Option<usize>,
+    /// Match case-insensitively
+    pub ignore_case: Option<bool>,
+}
+
+impl_tool_params!(GrepCodebaseParameters);
+
+impl GrepCodebaseTool {
+    pub fn new(sandbox: Sandbox) -> Self {
+        Self { sandbox }
+    }
+}
+
+impl Tool for GrepCodebaseTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "grep_codebase".to_string(),
+            description: "Search the workspace for a regular expression like ripgrep does, skipping git-ignored and binary files. Use it to locate code before reading or editing it.".to_string(),
+            parameters: GrepCodebaseParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<GrepCodebaseParameters>(parameters)?;
+        let root = self.sandbox.resolve(params.path.as_deref().unwrap_or("."))?;
+        let pattern = RegexBuilder::new(&params.pattern)
+            .case_insensitive(params.ignore_case.unwrap_or(false))
+            .build()?;
+        let max_results = params.max_results.unwrap_or(DEFAULT_MAX_RESULTS);
+
+        let mut matches = vec![];
+        let mut truncated = false;
+
+        'walk: for entry in ignore::WalkBuilder::new(&root).build() {
+            let Ok(entry) = entry else { continue };
+            if !entry.file_type().is_some_and(|e| e.is_file()) { continue; }
+            // Binary or non UTF-8 files are skipped, just like ripgrep does by default.
+            let Ok(content) = fs::read_to_string(entry.path()) else { continue };
+
+            for (number, line) in content.lines().enumerate() {
+                if !pattern.is_match(line) { continue; }
+                if matches.len() >= max_results {
+                    truncated = true;
+                    break 'walk;
+                }
+
+                matches.push(json!({
+                    "file": self.sandbox.relative(entry.path()),
+                    "line": number + 1,
+                    "snippet": line.trim().chars().take(MAX_SNIPPET_CHARS).collect::<String>(),
+                }));
+            }
+        }
+
+        Ok(json!({
+            "matches": matches,
+            "truncated": truncated,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::fs::tests::temp_sandbox;
+
+    #[test]
+    fn test_grep_codebase() {
+        let sandbox = temp_sandbox("grep");
+        fs::create_dir_all(sandbox.root().join("src")).unwrap();
+        fs::write(sandbox.root().join("src/lib.rs"), "fn alpha() {}\nfn beta() {}\n").unwrap();
+        fs::write(sandbox.root().join("src/bin.dat"), [0xff, 0xfe, b'f', b'n']).unwrap();
+
+        let result = GrepCodebaseTool::new(sandbox)
+            .execute(json!({ "pattern": r"fn \w+", "max_results": 1 }))
+            .unwrap();
+
+        assert_eq!(result["matches"], json!([{ "file": "src/lib.rs", "line": 1, "snippet": "fn alpha() {}" }]));
+        assert_eq!(result["truncated"], true);
+    }
+}
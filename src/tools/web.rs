@@ -0,0 +1,295 @@
+use std::time::Duration;
+use anyhow::{anyhow, bail};
+use regex::Regex;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::config::{WebSearchBackend, WebSearchConfig};
+use crate::impl_tool_params;
+use crate::tools::{Tool, ToolMetaData, ToolParameters};
+
+const DEFAULT_MAX_RESULTS: usize = 8;
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(20);
+const BRAVE_ENDPOINT: &str = "https://api.search.brave.com/res/v1/web/search";
+const DUCKDUCKGO_ENDPOINT: &str = "https://html.duckduckgo.com/html/";
+/// DuckDuckGo serves an empty page to clients without a browser-like user agent.
+pub const BROWSER_USER_AGENT: &str = "Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0";
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SearchResult {
+    pub title: String,
+    pub url: String,
+    pub snippet: String,
+}
+
+/// Removes every tag from an html fragment and decodes the common entities.
+pub fn html_to_text(html: &str) -> String {
+    let tags = Regex::new(r"(?s)<[^>]*>").unwrap();
+    decode_entities(tags.replace_all(html, "").trim())
+}
+
+pub fn decode_entities(text: &str) -> String {
+    let numeric = Regex::new(r"&#(x?)([0-9a-fA-F]+);").unwrap();
+    let decoded = numeric.replace_all(text, |caps: &regex::Captures| {
+        let radix = if caps[1].is_empty() { 10 } else { 16 };
+        u32::from_str_radix(&caps[2], radix)
+            .ok()
+            .and_then(char::from_u32)
+            .map(|e| e.to_string())
+            .unwrap_or_default()
+    });
+
+    decoded
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Turns a web page into markdown, keeping the main article and dropping scripts, navigation and other boilerplate.
+pub fn html_to_markdown(html: &str) -> String {
+    let mut html = Regex::new(r"(?s)<!--.*?-->").unwrap().replace_all(html, "").to_string();
+    for tag in ["script", "style", "noscript", "svg", "nav", "header", "footer", "aside", "form", "iframe", "button"] {
+        let boilerplate = Regex::new(&format!(r"(?is)<{tag}\b[^>]*>.*?</{tag}>")).unwrap();
+        html = boilerplate.replace_all(&html, "").to_string();
+    }
+
+    // Prefer the main content when the page marks it up.
+    for tag in ["article", "main", "body"] {
+        let content = Regex::new(&format!(r"(?is)<{tag}\b[^>]*>(.*)</{tag}>")).unwrap();
+        if let Some(caps) = content.captures(&html) {
+            html = caps[1].to_string();
+            break;
+        }
+    }
+
+    // Code blocks keep their whitespace, so set them aside before everything gets collapsed.
+    let mut blocks = vec![];
+    let pre = Regex::new(r"(?is)<pre\b[^>]*>(.*?)</pre>").unwrap();
+    let html = pre.replace_all(&html, |caps: &regex::Captures| {
+        let tags = Regex::new(r"(?s)<[^>]*>").unwrap();
+        blocks.push(decode_entities(tags.replace_all(&caps[1], "").trim_matches('\n')));
+        format!("\n\n\u{0}{}\u{0}\n\n", blocks.len() - 1)
+    });
+
+    let rules = [
+        (r"\s+", " "),
+        (r"(?is)<h([1-6])\b[^>]*>(.*?)</h[1-6]>", "\n\n\u{1}$1 $2\n\n"),
+        (r##"(?is)<a\b[^>]*href="([^"#][^"]*)"[^>]*>(.*?)</a>"##, "[$2]($1)"),
+        (r"(?is)<code\b[^>]*>(.*?)</code>", "`$1`"),
+        (r"(?is)<(strong|b)\b[^>]*>(.*?)</(strong|b)>", "**$2**"),
+        (r"(?i)<li\b[^>]*>", "\n- "),
+        (r"(?i)<br\s*/?>", "\n"),
+        (r"(?i)</?(p|div|section|ul|ol|table|tr|blockquote|figure)\b[^>]*>", "\n\n"),
+        (r"(?s)<[^>]*>", ""),
+    ];
+    let mut text = html.to_string();
+    for (pattern, replacement) in rules {
+        text = Regex::new(pattern).unwrap().replace_all(&text, replacement).to_string();
+    }
+
+    // Headings were marked with their level, expand them into `#`s.
+    let heading = Regex::new(r"\u{1}([1-6])").unwrap();
+    let text = heading.replace_all(&text, |caps: &regex::Captures| "#".repeat(caps[1].parse().unwrap_or(1)));
+    let text = decode_entities(&text)
+        .lines()
+        .map(|e| e.trim())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let text = Regex::new(r"\n{3,}").unwrap().replace_all(text.trim(), "\n\n");
+
+    let placeholder = Regex::new(r"\u{0}(\d+)\u{0}").unwrap();
+    placeholder
+        .replace_all(&text, |caps: &regex::Captures| {
+            let index = caps[1].parse::<usize>().unwrap_or_default();
+            format!("```\n{}\n```", blocks.get(index).map(|e| e.as_str()).unwrap_or_default())
+        })
+        .to_string()
+}
+
+fn parse_searxng(body: &Value) -> Vec<SearchResult> {
+    body["results"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|e| SearchResult {
+            title: e["title"].as_str().unwrap_or_default().to_string(),
+            url: e["url"].as_str().unwrap_or_default().to_string(),
+            snippet: e["content"].as_str().unwrap_or_default().to_string(),
+        })
+        .collect()
+}
+
+fn parse_brave(body: &Value) -> Vec<SearchResult> {
+    body["web"]["results"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|e| SearchResult {
+            title: e["title"].as_str().unwrap_or_default().to_string(),
+            url: e["url"].as_str().unwrap_or_default().to_string(),
+            snippet: html_to_text(e["description"].as_str().unwrap_or_default()),
+        })
+        .collect()
+}
+
+fn parse_duckduckgo(html: &str) -> Vec<SearchResult> {
+    let link = Regex::new(r#"(?s)<a[^>]*class="result__a"[^>]*href="([^"]*)"[^>]*>(.*?)</a>"#).unwrap();
+    let snippet = Regex::new(r#"(?s)<a[^>]*class="result__snippet"[^>]*>(.*?)</a>"#).unwrap();
+
+    let snippets = snippet.captures_iter(html).map(|caps| html_to_text(&caps[1])).collect::<Vec<_>>();
+    link.captures_iter(html)
+        .enumerate()
+        .map(|(index, caps)| {
+            // Results link to a redirect like `//duckduckgo.com/l/?uddg=<target>`, unwrap it.
+            let href = decode_entities(&caps[1]);
+            let url = Url::parse(&format!("https:{}", href.trim_start_matches("https:")))
+                .ok()
+                .and_then(|e| e.query_pairs().find(|(k, _)| k == "uddg").map(|(_, v)| v.to_string()))
+                .unwrap_or(href);
+
+            SearchResult {
+                title: html_to_text(&caps[2]),
+                url,
+                snippet: snippets.get(index).cloned().unwrap_or_default(),
+            }
+        })
+        .collect()
+}
+
+pub struct WebSearchTool {
+    config: WebSearchConfig,
+    client: reqwest::Client,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct WebSearchParameters {
+    /// The search query
+    pub query: String,
+}
+
+impl_tool_params!(WebSearchParameters);
+
+impl WebSearchTool {
+    pub fn new(config: WebSearchConfig) -> anyhow::Result<Self> {
+        match config.backend {
+            WebSearchBackend::Searxng if config.url.is_none() => bail!("web_search.url is required for the searxng backend"),
+            WebSearchBackend::Brave if config.api_key.is_none() => bail!("web_search.api_key is required for the brave backend"),
+            _ => {}
+        }
+
+        let client = reqwest::Client::builder()
+            .timeout(SEARCH_TIMEOUT)
+            .user_agent(BROWSER_USER_AGENT)
+            .build()?;
+        Ok(Self { config, client })
+    }
+
+    async fn search(&self, query: &str) -> anyhow::Result<Vec<SearchResult>> {
+        let results = match self.config.backend {
+            WebSearchBackend::Searxng => {
+                let url = format!("{}/search", self.config.url.as_deref().unwrap_or_default().trim_end_matches('/'));
+                let body = self.client
+                    .get(url)
+                    .query(&[("q", query), ("format", "json")])
+                    .send().await?
+                    .error_for_status()?
+                    .json::<Value>().await?;
+                parse_searxng(&body)
+            }
+            WebSearchBackend::Brave => {
+                let body = self.client
+                    .get(BRAVE_ENDPOINT)
+                    .query(&[("q", query)])
+                    .header("Accept", "application/json")
+                    .header("X-Subscription-Token", self.config.api_key.as_deref().unwrap_or_default())
+                    .send().await?
+                    .error_for_status()?
+                    .json::<Value>().await?;
+                parse_brave(&body)
+            }
+            WebSearchBackend::DuckDuckGo => {
+                let html = self.client
+                    .post(DUCKDUCKGO_ENDPOINT)
+                    .form(&[("q", query)])
+                    .send().await?
+                    .error_for_status()?
+                    .text().await?;
+                parse_duckduckgo(&html)
+            }
+        };
+
+        Ok(results)
+    }
+}
+
+impl Tool for WebSearchTool {
+    fn metadata(&self) -> ToolMetaData {
+        ToolMetaData {
+            name: "web_search".to_string(),
+            description: "Search the web and return the titles, urls and snippets of the top results. Use it for recent events or anything you may not know.".to_string(),
+            parameters: WebSearchParameters::schema(),
+        }
+    }
+
+    fn execute(&self, parameters: Value) -> anyhow::Result<Value> {
+        let params = serde_json::from_value::<WebSearchParameters>(parameters)?;
+        let mut results = futures::executor::block_on(self.search(&params.query))
+            .map_err(|e| anyhow!("Web search failed: {}", e))?;
+        results.truncate(self.config.max_results.unwrap_or(DEFAULT_MAX_RESULTS));
+
+        Ok(json!({
+            "query": params.query,
+            "results": results,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duckduckgo() {
+        let html = r#"
+            <div class="result"><h2><a rel="nofollow" class="result__a" href="//duckduckgo.com/l/?uddg=https%3A%2F%2Fwww.rust-lang.org%2F&amp;rut=abc">Rust <b>Programming</b> Language</a></h2>
+            <a class="result__snippet" href="x">A language empowering everyone &amp; more.</a></div>
+        "#;
+
+        assert_eq!(parse_duckduckgo(html), vec![SearchResult {
+            title: "Rust Programming Language".to_string(),
+            url: "https://www.rust-lang.org/".to_string(),
+            snippet: "A language empowering everyone & more.".to_string(),
+        }]);
+    }
+
+    #[test]
+    fn test_html_to_markdown() {
+        let html = r#"
+            <html><head><title>x</title><style>p { color: red }</style></head>
+            <body><nav><a href="/">Home</a></nav>
+            <article><h1>Ownership</h1>
+            <p>Every value has an <a href="https://doc.rust-lang.org/book/">owner</a>
+               &amp; <strong>one</strong> only.</p>
+            <ul><li>move</li><li>borrow</li></ul>
+            <pre><code>let a = 1;
+let b = &amp;a;</code></pre></article>
+            <footer>Copyright</footer><script>track()</script></body></html>
+        "#;
+
+        assert_eq!(
+            html_to_markdown(html),
+            "# Ownership\n\nEvery value has an [owner](https://doc.rust-lang.org/book/) & **one** only.\n\n- move\n- borrow\n\n```\nlet a = 1;\nlet b = &a;\n```",
+        );
+    }
+
+    #[test]
+    fn test_parse_json_backends() {
+        let searxng = json!({ "results": [{ "title": "a", "url": "https://a", "content": "b" }] });
+        let brave = json!({ "web": { "results": [{ "title": "a", "url": "https://a", "description": "<strong>b</strong>" }] } });
+
+        assert_eq!(parse_searxng(&searxng), parse_brave(&brave));
+    }
+}
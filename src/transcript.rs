@@ -0,0 +1,216 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use crate::events::TokenUsage;
+use crate::style;
+
+/// Where every session keeps its transcript for `rag export`, in the config dir.
+pub const LAST_SESSION_FILE: &str = "last_session.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Reasoning,
+    Assistant,
+    Tool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub role: Role,
+    pub text: String,
+}
+
+/// Everything shown during the session, unlike the `ContextManager` nothing is ever dropped.
+#[derive(Debug, Default)]
+pub(crate) struct Transcript {
+    entries: Vec<Entry>,
+    /// Tokens of each answer, by the index of the user entry it answers.
+    usage: BTreeMap<usize, TokenUsage>,
+}
+
+impl Transcript {
+    pub fn new() -> Self {
+        Self { entries: vec![], usage: BTreeMap::new() }
+    }
+
+    /// Records the tokens of the answer to the last user message.
+    pub fn push_usage(&mut self, usage: TokenUsage) {
+        if let Some(index) = self.entries.iter().rposition(|e| e.role == Role::User) {
+            self.usage.insert(index, usage);
+        }
+    }
+
+    pub fn to_session(&self, model: &str) -> Session {
+        let mut start = 0;
+        let exchanges = self
+            .exchanges(None)
+            .into_iter()
+            .map(|entries| {
+                let usage = self.usage.get(&start).copied();
+                start += entries.len();
+                Exchange { entries: entries.to_vec(), usage }
+            })
+            .collect();
+        Session { model: model.to_string(), exchanges }
+    }
+
+    /// Appends `text`, streamed deltas of the same role are merged into one entry.
+    pub fn push(&mut self, role: Role, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        match self.entries.last_mut() {
+            Some(last) if last.role == role && role != Role::User && role != Role::Tool => last.text.push_str(text),
+            _ => self.entries.push(Entry { role, text: text.to_string() }),
+        }
+    }
+
+    /// The last `n` exchanges, each starting with a user message, or all of them.
+    pub fn exchanges(&self, n: Option<usize>) -> Vec<&[Entry]> {
+        let mut exchanges = vec![];
+        let mut start = 0;
+        for (index, entry) in self.entries.iter().enumerate() {
+            if entry.role == Role::User && index > start {
+                exchanges.push(&self.entries[start..index]);
+                start = index;
+            }
+        }
+        if start < self.entries.len() {
+            exchanges.push(&self.entries[start..]);
+        }
+
+        let skip = n.map_or(0, |n| exchanges.len().saturating_sub(n));
+        exchanges.split_off(skip)
+    }
+
+    pub fn render(&self, n: Option<usize>, model: &str) -> String {
+        let separator = "─".repeat(60).truecolor(128, 138, 135).to_string();
+
+        self.exchanges(n)
+            .iter()
+            .map(|exchange| {
+                let body = exchange
+                    .iter()
+                    .map(|entry| match entry.role {
+                        Role::User => format!("{}\n{}", format!("{}you:", style::emoji("🌟")).blue().bold(), entry.text),
+                        Role::Reasoning => entry.text.trim().truecolor(128, 138, 135).to_string(),
+                        Role::Assistant => format!("{}\n{}", format!("{}{}:", style::emoji("🤖"), model).green().bold(), entry.text.trim()),
+                        Role::Tool => entry.text.cyan().to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                format!("{}\n{}", separator, body)
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Exchange {
+    pub entries: Vec<Entry>,
+    pub usage: Option<TokenUsage>,
+}
+
+/// A conversation as it is exported, `.json` files get this as is and anything else gets markdown.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    pub model: String,
+    pub exchanges: Vec<Exchange>,
+}
+
+impl Session {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let json = std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    pub fn export(&self, path: &Path) -> anyhow::Result<()> {
+        let content = match path.extension().is_some_and(|e| e == "json") {
+            true => serde_json::to_string_pretty(self)?,
+            false => self.to_markdown(),
+        };
+        std::fs::write(path, content).map_err(|e| anyhow::anyhow!("Failed to write {}: {}", path.display(), e))
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut markdown = format!("# Conversation with {}\n", self.model);
+        for exchange in &self.exchanges {
+            markdown.push_str("\n---\n");
+            for entry in &exchange.entries {
+                let text = entry.text.trim();
+                match entry.role {
+                    Role::User => markdown.push_str(&format!("\n## You\n\n{}\n", text)),
+                    Role::Reasoning => {
+                        let quoted = text.lines().map(|e| format!("> {}", e).trim_end().to_string()).collect::<Vec<_>>();
+                        markdown.push_str(&format!("\n{}\n", quoted.join("\n")));
+                    }
+                    Role::Assistant => markdown.push_str(&format!("\n## {}\n\n{}\n", self.model, text)),
+                    Role::Tool => markdown.push_str(&format!("\n**Tool call**\n\n```\n{}\n```\n", text)),
+                }
+            }
+            if let Some(usage) = exchange.usage {
+                markdown.push_str(&format!(
+                    "\n*{} prompt + {} completion = {} tokens*\n",
+                    usage.prompt_tokens, usage.completion_tokens, usage.total_tokens
+                ));
+            }
+        }
+        markdown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exchanges() {
+        let mut transcript = Transcript::new();
+        transcript.push(Role::User, "hi");
+        transcript.push(Role::Assistant, "hel");
+        transcript.push(Role::Assistant, "lo");
+        transcript.push(Role::User, "add 1 and 2");
+        transcript.push(Role::Tool, "Add({\"a\":1,\"b\":2}) -> 3");
+        transcript.push(Role::Assistant, "3");
+
+        assert_eq!(transcript.exchanges(None).len(), 2);
+        assert_eq!(transcript.exchanges(Some(5)).len(), 2);
+
+        let last = transcript.exchanges(Some(1));
+        assert_eq!(last[0].len(), 3);
+        assert_eq!(transcript.exchanges(None)[0][1].text, "hello");
+    }
+
+    #[test]
+    fn test_export() {
+        let mut transcript = Transcript::new();
+        transcript.push(Role::User, "hi");
+        transcript.push(Role::Assistant, "hello");
+        transcript.push(Role::User, "add 1 and 2");
+        transcript.push(Role::Reasoning, "use the tool\n\nthen answer");
+        transcript.push(Role::Tool, "Add({\"a\":1,\"b\":2}) -> 3");
+        transcript.push(Role::Assistant, "3");
+        transcript.push_usage(TokenUsage { prompt_tokens: 10, completion_tokens: 2, total_tokens: 12 });
+
+        let session = transcript.to_session("m");
+        assert_eq!(session.exchanges[0].usage, None);
+        assert_eq!(session.exchanges[1].usage.unwrap().total_tokens, 12);
+        assert_eq!(session.exchanges[1].entries.len(), 4);
+
+        let dir = std::env::temp_dir().join(format!("rag-export-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        session.export(&dir.join("session.json")).unwrap();
+        assert_eq!(Session::load(&dir.join("session.json")).unwrap(), session);
+
+        session.export(&dir.join("session.md")).unwrap();
+        let markdown = std::fs::read_to_string(dir.join("session.md")).unwrap();
+        assert!(markdown.contains("## You\n\nadd 1 and 2\n\n> use the tool\n>\n> then answer\n"));
+        assert!(markdown.contains("```\nAdd({\"a\":1,\"b\":2}) -> 3\n```"));
+        assert!(markdown.ends_with("## m\n\n3\n\n*10 prompt + 2 completion = 12 tokens*\n"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,522 @@
+use std::io::stdout;
+use std::sync::{Arc, Mutex};
+use ratatui::crossterm::event::{self, Event as TermEvent, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use regex::Regex;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+use crate::app::Context;
+use crate::budget;
+use crate::bus::Event;
+use crate::config::Config;
+use crate::events::{format_tokens, stopped, ChannelRenderer, EventSender, TokenUsage, UiEvent};
+use crate::processor::Processor;
+use crate::tools::guard::{self, Screen};
+
+const GRAY: Color = Color::Rgb(128, 138, 135);
+/// The tool panel is left out on terminals narrower than this.
+const MIN_WIDTH_FOR_TOOLS: u16 = 80;
+const TOOLS_WIDTH: u16 = 36;
+
+#[derive(Debug, Default, PartialEq)]
+struct Answer {
+    model: String,
+    reasoning: String,
+    content: String,
+    cached: bool,
+}
+
+#[derive(Debug, PartialEq)]
+enum Entry {
+    Question(String),
+    Answer(Answer),
+    Note(String, Color),
+}
+
+/// The session as the TUI shows it, built up from the events.
+#[derive(Debug, Default)]
+struct Conversation {
+    entries: Vec<Entry>,
+    /// Every tool call of the session as `name arguments`, for the side panel.
+    tools: Vec<String>,
+    /// The tool call whose arguments are still streaming.
+    calling: Option<String>,
+    total_tokens: u64,
+    /// What the answers of the current turn used, priced once the turn is over and the config is at hand again.
+    unpriced: Vec<(String, TokenUsage)>,
+    /// What the session cost so far, `None` while none of its models has a price.
+    cost: Option<f64>,
+}
+
+impl Conversation {
+    /// The answer to the last question, one is started if there is none.
+    fn answer(&mut self) -> &mut Answer {
+        let index = match self.entries.iter().rposition(|e| matches!(e, Entry::Answer(_))) {
+            Some(i) if !self.entries[i..].iter().any(|e| matches!(e, Entry::Question(_))) => i,
+            _ => {
+                self.entries.push(Entry::Answer(Answer::default()));
+                self.entries.len() - 1
+            }
+        };
+        match &mut self.entries[index] {
+            Entry::Answer(answer) => answer,
+            _ => unreachable!(),
+        }
+    }
+
+    fn note(&mut self, text: String, color: Color) {
+        self.entries.push(Entry::Note(text, color));
+    }
+
+    fn apply(&mut self, event: UiEvent) {
+        match event {
+            UiEvent::AnswerStarted { model } => self.entries.push(Entry::Answer(Answer { model, ..Default::default() })),
+            UiEvent::Reasoning(content) | UiEvent::CollapsedReasoning(content) => self.answer().reasoning.push_str(&content),
+            UiEvent::ContentDelta(content) => self.answer().content.push_str(&content),
+            UiEvent::ToolCallDelta { name, .. } => self.calling = Some(name),
+            UiEvent::ToolStarted { name, arguments } => {
+                self.calling = None;
+                self.note(format!("Info: call tools {}", name), GRAY);
+                self.tools.push(format!("{} {}", name, arguments));
+            }
+            UiEvent::ThinkingBudget { used, budget, exceeded: true } => self.note(
+                format!("thinking {}/{} tokens, budget exceeded, stopping", format_tokens(used), format_tokens(budget)),
+                Color::Yellow,
+            ),
+            UiEvent::ThinkingBudget { .. } | UiEvent::ThinkingProgress { .. } | UiEvent::Perf(_) | UiEvent::AnswerFinished => {}
+            UiEvent::Usage { turn, total_tokens } => {
+                self.total_tokens = total_tokens;
+                let model = self.answer().model.clone();
+                self.unpriced.push((model, turn));
+            }
+            UiEvent::Cached => self.answer().cached = true,
+            // The answer is started over for the model that answers it.
+            UiEvent::FellBack { from, to, error } => {
+                if self.entries.last() == Some(&Entry::Answer(Answer { model: from.clone(), ..Default::default() })) {
+                    self.entries.pop();
+                }
+                self.note(format!("Warning: {} failed, {}, asking {}", from, error, to), Color::Yellow);
+                self.entries.push(Entry::Answer(Answer { model: format!("{} (for {})", to, from), ..Default::default() }));
+            }
+            UiEvent::Retrying { attempt, max_attempts, delay, error } => self.note(
+                format!("Warning: {}, retrying in {:.1}s ({}/{})", error, delay.as_secs_f32(), attempt + 1, max_attempts),
+                Color::Yellow,
+            ),
+            UiEvent::Stopped(reason) => self.note(stopped(reason), Color::Yellow),
+            UiEvent::Cancelled => self.note("Cancelled, the answer is incomplete".to_string(), Color::Yellow),
+            UiEvent::Error(e) => self.note(format!("Error: {}", e), Color::Red),
+            UiEvent::Notice(text) => self.note(plain(&text), GRAY),
+            UiEvent::Warning(text) => self.note(format!("Warning: {}", plain(&text)), Color::Yellow),
+        }
+    }
+
+    /// Adds what the answers of the last turn cost.
+    fn price(&mut self, config: &Config) {
+        for (model, usage) in self.unpriced.drain(..) {
+            let model = model.split(" (for ").next().unwrap_or_default();
+            if let Some(usd) = budget::cost(config, model, &usage) {
+                self.cost = Some(self.cost.unwrap_or_default() + usd);
+            }
+        }
+    }
+
+    /// The conversation as lines of at most `width` columns, the reasoning folded to a single line unless `reasoning` is set.
+    fn lines(&self, width: usize, reasoning: bool) -> Vec<Line<'static>> {
+        let gray = Style::default().fg(GRAY);
+        let mut lines = vec![];
+        for entry in &self.entries {
+            match entry {
+                Entry::Question(question) => {
+                    lines.push(Line::styled("you:", Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD)));
+                    lines.extend(wrap(question, width).into_iter().map(Line::raw));
+                }
+                Entry::Answer(answer) => {
+                    let mut header = vec![Span::styled(format!("{}:", answer.model), Style::default().fg(Color::Green).add_modifier(Modifier::BOLD))];
+                    if answer.cached {
+                        header.push(Span::styled(" (cached)", gray));
+                    }
+                    lines.push(Line::from(header));
+                    let thought = answer.reasoning.trim();
+                    if !thought.is_empty() {
+                        let wrapped = wrap(thought, width.saturating_sub(2));
+                        if reasoning {
+                            lines.push(Line::styled("▾ reasoning", gray));
+                            lines.extend(wrapped.into_iter().map(|e| Line::styled(format!("  {}", e), gray)));
+                        } else {
+                            lines.push(Line::styled(format!("▸ reasoning, {} lines", wrapped.len()), gray));
+                        }
+                    }
+                    lines.extend(wrap(&answer.content, width).into_iter().map(Line::raw));
+                }
+                Entry::Note(text, color) => lines.extend(wrap(text, width).into_iter().map(|e| Line::styled(e, Style::default().fg(*color)))),
+            }
+            lines.push(Line::default());
+        }
+        lines
+    }
+}
+
+/// `text` without its terminal colors, like those of `@scrollback`, which would garble the screen.
+fn plain(text: &str) -> String {
+    Regex::new(r"\x1b\[[0-9;]*m").unwrap().replace_all(text, "").into_owned()
+}
+
+/// Breaks `text` into lines of at most `width` columns, at the last space where there is one.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    let mut lines = vec![];
+    for line in text.replace('\t', "    ").split('\n') {
+        let mut current = String::new();
+        for c in line.chars() {
+            if current.width() + c.width().unwrap_or_default() > width {
+                match current.rfind(' ') {
+                    Some(i) if i > 0 => {
+                        let rest = current.split_off(i + 1);
+                        lines.push(current.trim_end().to_string());
+                        current = rest;
+                    }
+                    _ => lines.push(std::mem::take(&mut current)),
+                }
+            }
+            current.push(c);
+        }
+        lines.push(current);
+    }
+    lines
+}
+
+/// What is typed and how the conversation is looked at, the state that isn't the session's.
+#[derive(Debug, Default)]
+struct View {
+    input: String,
+    /// In chars, not bytes.
+    cursor: usize,
+    /// Lines scrolled up from the bottom, 0 follows the answer as it streams.
+    scroll: usize,
+    reasoning: bool,
+    hide_tools: bool,
+    model: String,
+    busy: bool,
+    /// Shared with the [`Backdrop`], which lends the terminal to the confirmations.
+    lent: Arc<Mutex<Lent>>,
+}
+
+impl View {
+    fn byte_index(&self) -> usize {
+        self.input.char_indices().nth(self.cursor).map_or(self.input.len(), |(i, _)| i)
+    }
+
+    /// Edits the input or the view for `key`, returns the input once it is entered.
+    fn key(&mut self, key: KeyEvent, page: usize) -> Option<String> {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('r') if control => self.reasoning = !self.reasoning,
+            KeyCode::Char('t') if control => self.hide_tools = !self.hide_tools,
+            KeyCode::Char('u') if control => {
+                self.input.clear();
+                self.cursor = 0;
+            }
+            KeyCode::Char(c) if !control => {
+                let i = self.byte_index();
+                self.input.insert(i, c);
+                self.cursor += 1;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                let i = self.byte_index();
+                self.input.remove(i);
+            }
+            KeyCode::Delete if self.cursor < self.input.chars().count() => {
+                let i = self.byte_index();
+                self.input.remove(i);
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.input.chars().count()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.input.chars().count(),
+            KeyCode::Up => self.scroll += 1,
+            KeyCode::Down => self.scroll = self.scroll.saturating_sub(1),
+            KeyCode::PageUp => self.scroll += page,
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(page),
+            KeyCode::Enter if !self.input.trim().is_empty() => {
+                self.cursor = 0;
+                self.scroll = 0;
+                return Some(std::mem::take(&mut self.input));
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+/// The conversation pane's height, what a page is.
+fn page_height(terminal: &DefaultTerminal) -> usize {
+    terminal.size().map(|e| e.height.saturating_sub(6) as usize).unwrap_or(10).max(1)
+}
+
+fn draw(frame: &mut Frame, conversation: &Conversation, view: &mut View) {
+    let [main, status, input] = Layout::vertical([Constraint::Min(3), Constraint::Length(1), Constraint::Length(3)]).areas(frame.area());
+    let (pane, tools) = match !view.hide_tools && main.width >= MIN_WIDTH_FOR_TOOLS {
+        true => {
+            let [pane, tools] = Layout::horizontal([Constraint::Min(20), Constraint::Length(TOOLS_WIDTH)]).areas(main);
+            (pane, Some(tools))
+        }
+        false => (main, None),
+    };
+
+    let lines = conversation.lines(pane.width.saturating_sub(2) as usize, view.reasoning);
+    let height = pane.height.saturating_sub(2) as usize;
+    view.scroll = view.scroll.min(lines.len().saturating_sub(height));
+    let end = lines.len() - view.scroll;
+    let title = match view.scroll {
+        0 => " rag ".to_string(),
+        scroll => format!(" rag, {} lines below ", scroll),
+    };
+    let visible = lines[end.saturating_sub(height)..end].to_vec();
+    frame.render_widget(Paragraph::new(visible).block(Block::default().borders(Borders::ALL).title(title)), pane);
+
+    if let Some(area) = tools {
+        draw_tools(frame, conversation, area);
+    }
+
+    let cost = conversation.cost.map_or("-".to_string(), |e| format!("${:.4}", e));
+    let state = if view.busy { "answering…  " } else { "" };
+    let status_line = format!(
+        " {} │ {} tokens │ {} │ {}^R reasoning  ^T tools  PgUp/PgDn scroll  ^D quit",
+        view.model,
+        format_tokens(conversation.total_tokens),
+        cost,
+        state
+    );
+    frame.render_widget(Paragraph::new(status_line).style(Style::default().fg(Color::Black).bg(GRAY)), status);
+
+    // The end of a long input is shown, where the cursor usually is.
+    let width = input.width.saturating_sub(2) as usize;
+    let before = view.input.chars().take(view.cursor).collect::<String>();
+    let skip = before.width().saturating_sub(width.saturating_sub(1));
+    let mut shown = String::new();
+    let mut skipped = 0;
+    for c in view.input.chars() {
+        if skipped < skip {
+            skipped += c.width().unwrap_or_default();
+            continue;
+        }
+        shown.push(c);
+    }
+    frame.render_widget(Paragraph::new(shown).block(Block::default().borders(Borders::ALL)), input);
+    let x = input.x + 1 + before.width().saturating_sub(skipped) as u16;
+    frame.set_cursor_position((x.min(input.right().saturating_sub(2)), input.y + 1));
+}
+
+/// The latest tool calls, as many as fit.
+fn draw_tools(frame: &mut Frame, conversation: &Conversation, area: Rect) {
+    let width = area.width.saturating_sub(2) as usize;
+    let mut lines = vec![];
+    for call in &conversation.tools {
+        lines.extend(wrap(call, width).into_iter().take(3).map(Line::raw));
+    }
+    if let Some(ref name) = conversation.calling {
+        lines.push(Line::styled(format!("{}…", name), Style::default().fg(GRAY)));
+    }
+    let height = area.height.saturating_sub(2) as usize;
+    let lines = lines.split_off(lines.len().saturating_sub(height));
+    frame.render_widget(Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(" tools ")), area);
+}
+
+/// Who has the terminal during a turn.
+#[derive(Debug, Default)]
+struct Lent {
+    /// The shell's screen is up for a confirmation, nothing is drawn.
+    away: bool,
+    /// Something was written to the terminal meanwhile, it is drawn anew.
+    stale: bool,
+}
+
+/// Steps the TUI aside while a tool asks for confirmation, so its diff and `[y/N]` aren't drawn over.
+struct Backdrop(Arc<Mutex<Lent>>);
+
+impl Screen for Backdrop {
+    fn leave(&mut self) -> std::io::Result<()> {
+        let mut lent = self.0.lock().unwrap();
+        execute!(stdout(), LeaveAlternateScreen)?;
+        lent.away = true;
+        Ok(())
+    }
+
+    fn enter(&mut self) -> std::io::Result<()> {
+        let mut lent = self.0.lock().unwrap();
+        execute!(stdout(), EnterAlternateScreen)?;
+        *lent = Lent { away: false, stale: true };
+        Ok(())
+    }
+}
+
+/// Draws the turn so far, unless the terminal is lent out.
+fn redraw(terminal: &mut DefaultTerminal, conversation: &Conversation, view: &mut View) -> std::io::Result<()> {
+    let lent = view.lent.clone();
+    let mut lent = lent.lock().unwrap();
+    if lent.away {
+        return Ok(());
+    }
+    if std::mem::take(&mut lent.stale) {
+        terminal.clear()?;
+    }
+    terminal.draw(|frame| draw(frame, conversation, view))?;
+    Ok(())
+}
+
+/// Runs `input` as a turn, drawing the events as they come. Raw mode is off meanwhile, so Ctrl+C cancels the answer
+/// and the confirmations of the tools read their line like they do in the REPL, on the shell's screen.
+async fn turn(
+    terminal: &mut DefaultTerminal,
+    context: &mut Context,
+    processor: &mut Processor,
+    receiver: &mut UnboundedReceiver<UiEvent>,
+    conversation: &mut Conversation,
+    view: &mut View,
+    input: String,
+) -> anyhow::Result<()> {
+    conversation.entries.push(Entry::Question(input.clone()));
+    view.busy = true;
+    disable_raw_mode()?;
+    redraw(terminal, conversation, view)?;
+
+    let outcome = {
+        let turn = processor.run_once(context, input);
+        tokio::pin!(turn);
+        loop {
+            tokio::select! {
+                outcome = &mut turn => break outcome,
+                Some(event) = receiver.recv() => {
+                    conversation.apply(event);
+                    redraw(terminal, conversation, view)?;
+                }
+            }
+        }
+    };
+    while let Ok(event) = receiver.try_recv() {
+        conversation.apply(event);
+    }
+    if let Err(e) = outcome {
+        conversation.note(format!("Error: {:#}", e), Color::Red);
+    }
+    conversation.price(&context.config);
+    view.busy = false;
+    view.model = context.config.model.clone();
+
+    enable_raw_mode()?;
+    // Whatever was written to the terminal meanwhile is drawn over.
+    terminal.clear()?;
+    Ok(())
+}
+
+/// The full-screen interface of `--tui`, the same session as the REPL with the conversation kept on screen.
+pub async fn run(context: &mut Context, processor: &mut Processor) -> anyhow::Result<()> {
+    let (sender, mut receiver) = unbounded_channel();
+    context.events = EventSender::spawn(Box::new(ChannelRenderer::new(sender)));
+    let mut conversation = Conversation { total_tokens: context.usage.total_tokens, ..Default::default() };
+    let mut view = View { model: context.config.model.clone(), ..Default::default() };
+
+    let mut terminal = ratatui::init();
+    guard::set_screen(Some(Box::new(Backdrop(view.lent.clone()))));
+    let result = async {
+        loop {
+            let bus = context.bus.clone();
+            bus.dispatch(context, &mut Event::BeforeInput).await?;
+            terminal.draw(|frame| draw(frame, &conversation, &mut view))?;
+
+            let TermEvent::Key(key) = tokio::task::block_in_place(event::read)? else { continue };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            let control = key.modifiers.contains(KeyModifiers::CONTROL);
+            match key.code {
+                KeyCode::Char('d') if control && view.input.is_empty() => return Ok(()),
+                // Like at the REPL's prompt, Ctrl+C drops what was typed and quits when there is nothing.
+                KeyCode::Char('c') if control && view.input.is_empty() => return Ok(()),
+                KeyCode::Char('c') if control => {
+                    view.input.clear();
+                    view.cursor = 0;
+                }
+                _ => {
+                    // `@exit` would end the process with the terminal still in the TUI's modes.
+                    let input = view.key(key, page_height(&terminal));
+                    if input.as_ref().is_some_and(|e| e.starts_with("@exit")) {
+                        return Ok(());
+                    }
+                    if let Some(input) = input {
+                        turn(&mut terminal, context, processor, &mut receiver, &mut conversation, &mut view, input).await?;
+                    }
+                }
+            }
+        }
+    }
+    .await;
+    guard::set_screen(None);
+    ratatui::restore();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ModelPrice;
+
+    #[test]
+    fn test_notices() {
+        let mut conversation = Conversation::default();
+        conversation.apply(UiEvent::Notice("\x1b[1myou:\x1b[0m hi\nnext".to_string()));
+        conversation.apply(UiEvent::Warning("There is nothing to undo".to_string()));
+        assert_eq!(conversation.entries, [
+            Entry::Note("you: hi\nnext".to_string(), GRAY),
+            Entry::Note("Warning: There is nothing to undo".to_string(), Color::Yellow),
+        ]);
+    }
+
+    #[test]
+    fn test_conversation() {
+        assert_eq!(wrap("the quick brown fox", 10), ["the quick", "brown fox"]);
+        assert_eq!(wrap("abcdefghij", 4), ["abcd", "efgh", "ij"]);
+        assert_eq!(wrap("你好世界", 5), ["你好", "世界"]);
+        assert_eq!(wrap("a\n\nb", 10), ["a", "", "b"]);
+
+        let mut conversation = Conversation::default();
+        conversation.entries.push(Entry::Question("hi".to_string()));
+        for event in [
+            UiEvent::AnswerStarted { model: "gpt-4o".to_string() },
+            UiEvent::FellBack { from: "gpt-4o".to_string(), to: "llama3".to_string(), error: "down".to_string() },
+            UiEvent::Reasoning("first\nsecond".to_string()),
+            UiEvent::ToolCallDelta { index: 0, name: "shell".to_string(), arguments: "{".to_string() },
+            UiEvent::ToolStarted { name: "shell".to_string(), arguments: r#"{"cmd":"ls"}"#.to_string() },
+            UiEvent::ContentDelta("hello".to_string()),
+            UiEvent::Usage { turn: TokenUsage { prompt_tokens: 1_000_000, completion_tokens: 0, total_tokens: 1_000_000 }, total_tokens: 1_000_000 },
+            UiEvent::AnswerFinished,
+        ] {
+            conversation.apply(event);
+        }
+        assert_eq!(conversation.tools, [r#"shell {"cmd":"ls"}"#]);
+        assert_eq!(conversation.calling, None);
+        assert_eq!(conversation.entries.len(), 4);
+        assert!(matches!(&conversation.entries[1], Entry::Note(e, Color::Yellow) if e.contains("asking llama3")));
+        let answer = Answer { model: "llama3 (for gpt-4o)".to_string(), reasoning: "first\nsecond".to_string(), content: "hello".to_string(), cached: false };
+        assert_eq!(conversation.entries[2], Entry::Answer(answer));
+
+        let text = |lines: Vec<Line>| lines.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        let folded = text(conversation.lines(40, false));
+        assert!(folded.contains(&"▸ reasoning, 2 lines".to_string()));
+        assert!(!folded.contains(&"  first".to_string()));
+        assert!(text(conversation.lines(40, true)).contains(&"  first".to_string()));
+
+        let mut config = Config::default();
+        config.prices.insert("llama3".to_string(), ModelPrice { input: 0.5, output: 1.0 });
+        conversation.price(&config);
+        assert_eq!(conversation.cost, Some(0.5));
+        assert!(conversation.unpriced.is_empty());
+    }
+}
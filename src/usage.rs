@@ -0,0 +1,277 @@
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+use async_trait::async_trait;
+use chrono::{Local, NaiveDate, TimeZone};
+use rusqlite::{params, Connection};
+use crate::app::Context;
+use crate::bus::{Event, Subscriber};
+use crate::budget::cost;
+use crate::events::{format_tokens, TokenUsage, UiEvent};
+
+/// Where the usage of every request is kept, relative to the config directory.
+pub const USAGE_DB: &str = "usage.db";
+
+/// One request, as it is kept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageRecord {
+    /// Unix seconds.
+    pub timestamp: i64,
+    pub model: String,
+    pub usage: TokenUsage,
+    /// USD, `None` without a price for the model.
+    pub cost: Option<f64>,
+    pub duration_ms: u64,
+}
+
+/// How `rag usage` groups the requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum UsageGrouping {
+    Model,
+    Day,
+}
+
+/// The requests of a group added up.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageRow {
+    pub group: String,
+    pub requests: u64,
+    pub usage: TokenUsage,
+    /// `None` if none of the requests had a price.
+    pub cost: Option<f64>,
+    pub duration_ms: u64,
+}
+
+pub struct UsageDb {
+    connection: Connection,
+}
+
+impl UsageDb {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS requests (
+                id INTEGER PRIMARY KEY,
+                timestamp INTEGER NOT NULL,
+                model TEXT NOT NULL,
+                prompt_tokens INTEGER NOT NULL,
+                completion_tokens INTEGER NOT NULL,
+                total_tokens INTEGER NOT NULL,
+                cost REAL,
+                duration_ms INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS requests_timestamp ON requests (timestamp);",
+        )?;
+        Ok(Self { connection })
+    }
+
+    pub fn record(&self, record: &UsageRecord) -> anyhow::Result<()> {
+        self.connection.execute(
+            "INSERT INTO requests (timestamp, model, prompt_tokens, completion_tokens, total_tokens, cost, duration_ms)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                record.timestamp,
+                record.model,
+                record.usage.prompt_tokens,
+                record.usage.completion_tokens,
+                record.usage.total_tokens,
+                record.cost,
+                record.duration_ms
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The requests since `since` in unix seconds added up by `grouping`, sorted by the group.
+    pub fn report(&self, since: i64, grouping: UsageGrouping) -> anyhow::Result<Vec<UsageRow>> {
+        let group = match grouping {
+            UsageGrouping::Model => "model",
+            UsageGrouping::Day => "date(timestamp, 'unixepoch', 'localtime')",
+        };
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT {group}, COUNT(*), SUM(prompt_tokens), SUM(completion_tokens), SUM(total_tokens), SUM(cost), SUM(duration_ms)
+             FROM requests WHERE timestamp >= ?1 GROUP BY 1 ORDER BY 1",
+        ))?;
+        let rows = statement.query_map(params![since], |row| {
+            Ok(UsageRow {
+                group: row.get(0)?,
+                requests: row.get(1)?,
+                usage: TokenUsage { prompt_tokens: row.get(2)?, completion_tokens: row.get(3)?, total_tokens: row.get(4)? },
+                cost: row.get(5)?,
+                duration_ms: row.get(6)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+}
+
+/// The unix seconds `since` stands for: a number of hours, days or weeks back like `12h`, `7d` or `2w`, or a date like `2025-01-31`.
+/// What the session used since `recorded`, which then holds `usage`, for the subscribers that act on each turn's share.
+pub fn unrecorded(recorded: &Mutex<TokenUsage>, usage: TokenUsage) -> TokenUsage {
+    let mut recorded = recorded.lock().unwrap();
+    let added = TokenUsage {
+        prompt_tokens: usage.prompt_tokens - recorded.prompt_tokens,
+        completion_tokens: usage.completion_tokens - recorded.completion_tokens,
+        total_tokens: usage.total_tokens - recorded.total_tokens,
+    };
+    *recorded = usage;
+    added
+}
+
+pub fn parse_since(since: &str, now: i64) -> anyhow::Result<i64> {
+    if let Ok(date) = NaiveDate::parse_from_str(since, "%Y-%m-%d") {
+        let midnight = date.and_hms_opt(0, 0, 0).unwrap();
+        return Local
+            .from_local_datetime(&midnight)
+            .earliest()
+            .map(|e| e.timestamp())
+            .ok_or(anyhow::anyhow!("{} has no midnight in the local time zone", since));
+    }
+
+    let (count, unit) = since.split_at(since.len().saturating_sub(1));
+    let seconds = match unit {
+        "h" => 3600,
+        "d" => 86_400,
+        "w" => 7 * 86_400,
+        _ => 0,
+    };
+    match count.parse::<i64>() {
+        Ok(count) if seconds > 0 => Ok(now - count * seconds),
+        _ => anyhow::bail!("Expected something like 12h, 7d, 2w or 2025-01-31 for --since, got {}", since),
+    }
+}
+
+/// The table `rag usage` prints, with a total at the bottom.
+pub fn render_report(rows: &[UsageRow], grouping: UsageGrouping) -> Vec<String> {
+    let mut total = UsageRow { group: "total".to_string(), requests: 0, usage: TokenUsage::default(), cost: None, duration_ms: 0 };
+    for row in rows {
+        total.requests += row.requests;
+        total.usage.prompt_tokens += row.usage.prompt_tokens;
+        total.usage.completion_tokens += row.usage.completion_tokens;
+        total.usage.total_tokens += row.usage.total_tokens;
+        total.cost = match (total.cost, row.cost) {
+            (Some(a), Some(b)) => Some(a + b),
+            (a, b) => a.or(b),
+        };
+        total.duration_ms += row.duration_ms;
+    }
+
+    let heading = match grouping {
+        UsageGrouping::Model => "model",
+        UsageGrouping::Day => "day",
+    };
+    let width = rows.iter().map(|e| e.group.chars().count()).max().unwrap_or_default().max(heading.len()).max(5);
+    let line = |group: &str, requests: &str, prompt: &str, completion: &str, tokens: &str, cost: &str, time: &str| {
+        format!("{:<width$}  {:>8}  {:>8}  {:>10}  {:>8}  {:>8}  {:>8}", group, requests, prompt, completion, tokens, cost, time, width = width)
+    };
+    let mut lines = vec![line(heading, "requests", "prompt", "completion", "total", "cost", "time")];
+    for row in rows.iter().chain([&total]) {
+        lines.push(line(
+            &row.group,
+            &row.requests.to_string(),
+            &format_tokens(row.usage.prompt_tokens),
+            &format_tokens(row.usage.completion_tokens),
+            &format_tokens(row.usage.total_tokens),
+            &row.cost.map(|e| format!("${:.2}", e)).unwrap_or("-".to_string()),
+            &format!("{:.0}s", row.duration_ms as f64 / 1000.0),
+        ));
+    }
+    lines
+}
+
+/// Keeps a record of every answered question in [`USAGE_DB`].
+#[derive(Debug, Default)]
+pub struct UsageRecorder {
+    asked: Mutex<Option<Instant>>,
+    /// The session usage already recorded, what a turn added is the difference.
+    recorded: Mutex<TokenUsage>,
+    warned: AtomicBool,
+}
+
+impl UsageRecorder {
+    fn record(&self, ctx: &Context) -> anyhow::Result<()> {
+        let usage = unrecorded(&self.recorded, ctx.usage);
+        let asked = self.asked.lock().unwrap().take();
+        if usage.total_tokens == 0 {
+            return Ok(());
+        }
+
+        UsageDb::open(&ctx.config.config_dir().join(USAGE_DB))?.record(&UsageRecord {
+            timestamp: Local::now().timestamp(),
+            model: ctx.answered_model().to_string(),
+            usage,
+            cost: cost(&ctx.config, ctx.answered_model(), &usage),
+            duration_ms: asked.map(|e| e.elapsed().as_millis() as u64).unwrap_or_default(),
+        })
+    }
+}
+
+#[async_trait]
+impl Subscriber for UsageRecorder {
+    async fn handle(&self, ctx: &mut Context, event: &mut Event<'_>) -> anyhow::Result<()> {
+        match event {
+            Event::UserInput(_) => *self.asked.lock().unwrap() = Some(Instant::now()),
+            Event::TurnEnd => {
+                if let Err(e) = self.record(ctx)
+                    && !self.warned.swap(true, Ordering::Relaxed)
+                {
+                    ctx.events.emit(UiEvent::Warning(format!("Failed to record the usage: {}", e)));
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage_db() {
+        let path = std::env::temp_dir().join(format!("rag-usage-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let db = UsageDb::open(&path).unwrap();
+        let record = |timestamp: i64, model: &str, tokens: u64, cost: Option<f64>| UsageRecord {
+            timestamp,
+            model: model.to_string(),
+            usage: TokenUsage { prompt_tokens: tokens - 10, completion_tokens: 10, total_tokens: tokens },
+            cost,
+            duration_ms: 1500,
+        };
+        db.record(&record(100, "gpt-4o", 1000, Some(0.5))).unwrap();
+        db.record(&record(200, "gpt-4o", 2000, Some(0.25))).unwrap();
+        db.record(&record(300, "llama3", 500, None)).unwrap();
+        db.record(&record(10, "gpt-4o", 9000, Some(9.0))).unwrap();
+
+        let rows = db.report(100, UsageGrouping::Model).unwrap();
+        assert_eq!(rows.iter().map(|e| (e.group.as_str(), e.requests, e.usage.total_tokens, e.cost)).collect::<Vec<_>>(), [
+            ("gpt-4o", 2, 3000, Some(0.75)),
+            ("llama3", 1, 500, None),
+        ]);
+        assert_eq!(render_report(&rows, UsageGrouping::Model), [
+            "model   requests    prompt  completion     total      cost      time",
+            "gpt-4o         2        3k          20        3k     $0.75        3s",
+            "llama3         1       490          10       500         -        2s",
+            "total          3      3.5k          30      3.5k     $0.75        4s",
+        ]);
+        assert_eq!(db.report(0, UsageGrouping::Day).unwrap().len(), 1);
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(parse_since("7d", 1_000_000).unwrap(), 1_000_000 - 7 * 86_400);
+        assert_eq!(parse_since("12h", 1_000_000).unwrap(), 1_000_000 - 12 * 3600);
+        assert!(parse_since("2025-01-31", 0).unwrap() > 0);
+        assert!(parse_since("soon", 0).is_err());
+    }
+
+    #[test]
+    fn test_unrecorded() {
+        let recorded = Mutex::default();
+        let usage = |prompt_tokens: u64, completion_tokens: u64| TokenUsage { prompt_tokens, completion_tokens, total_tokens: prompt_tokens + completion_tokens };
+        assert_eq!(unrecorded(&recorded, usage(10, 5)), usage(10, 5));
+        assert_eq!(unrecorded(&recorded, usage(30, 7)), usage(20, 2));
+        assert_eq!(unrecorded(&recorded, usage(30, 7)), TokenUsage::default());
+    }
+}
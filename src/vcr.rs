@@ -0,0 +1,156 @@
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use async_openai::Client;
+use async_openai::error::OpenAIError;
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::client::{ChatClient, ChunkStream, Provider, WithQuery};
+use crate::config::RetryConfig;
+use crate::events::EventSender;
+use crate::rq::RsChunkBody;
+
+/// Records every chat request and its stream to the cassette at this path.
+pub const RECORD_ENV: &str = "RAG_VCR_RECORD";
+/// Answers the chat requests from the cassette at this path instead of the provider.
+pub const REPLAY_ENV: &str = "RAG_VCR_REPLAY";
+
+/// A streamed chunk, or the error the stream failed with at that point.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Recorded {
+    Error { error: String },
+    Chunk(Value),
+}
+
+/// A request and the stream that answered it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Interaction {
+    pub request: Value,
+    pub chunks: Vec<Recorded>,
+}
+
+/// The interactions of a session, kept as a JSON array.
+pub fn load(path: &Path) -> anyhow::Result<Vec<Interaction>> {
+    let content = std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("Failed to read the cassette {}: {}", path.display(), e))?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+/// The client the environment asks for, `None` to talk to the provider as usual.
+pub fn from_env(backend: &Client<WithQuery>) -> anyhow::Result<Option<Arc<dyn ChatClient>>> {
+    if let Ok(path) = std::env::var(REPLAY_ENV) {
+        return Ok(Some(Arc::new(ReplayClient::new(load(Path::new(&path))?))));
+    }
+    if let Ok(path) = std::env::var(RECORD_ENV) {
+        return Ok(Some(Arc::new(RecordingClient::new(Arc::new(Provider::new(backend.clone())), path.into()))));
+    }
+    Ok(None)
+}
+
+/// Passes the requests on to `inner` and writes each finished stream to the cassette, which is rewritten every time.
+pub struct RecordingClient {
+    inner: Arc<dyn ChatClient>,
+    path: PathBuf,
+    interactions: Arc<Mutex<Vec<Interaction>>>,
+}
+
+impl RecordingClient {
+    pub fn new(inner: Arc<dyn ChatClient>, path: PathBuf) -> Self {
+        Self { inner, path, interactions: Arc::default() }
+    }
+}
+
+#[async_trait]
+impl ChatClient for RecordingClient {
+    async fn stream(&self, body: Value, retry: &RetryConfig, events: &EventSender) -> Result<ChunkStream, OpenAIError> {
+        let stream = self.inner.stream(body.clone(), retry, events).await?;
+
+        let recorded = Arc::new(Mutex::new(vec![]));
+        let chunks = recorded.clone();
+        let (interactions, path) = (self.interactions.clone(), self.path.clone());
+        // Once the stream is done, a stream dropped halfway is left out.
+        let save = futures::stream::once(async move {
+            let chunks = std::mem::take(&mut *recorded.lock().unwrap());
+            let mut interactions = interactions.lock().unwrap();
+            interactions.push(Interaction { request: body, chunks });
+            let written = serde_json::to_string_pretty(&*interactions).map_err(anyhow::Error::from).and_then(|e| Ok(std::fs::write(&path, e)?));
+            if let Err(e) = written {
+                tracing::warn!(path = %path.display(), error = %e, "failed to write the cassette");
+            }
+            None
+        });
+        let stream = stream.map(move |e| {
+            chunks.lock().unwrap().push(match e {
+                Ok(ref chunk) => Recorded::Chunk(serde_json::to_value(chunk).unwrap_or_default()),
+                Err(ref e) => Recorded::Error { error: e.to_string() },
+            });
+            Some(e)
+        });
+        Ok(Box::pin(stream.chain(save).filter_map(futures::future::ready)))
+    }
+}
+
+/// Answers each request with the next stream of a cassette, whatever was asked.
+#[derive(Debug, Default)]
+pub struct ReplayClient {
+    interactions: Mutex<VecDeque<Interaction>>,
+}
+
+impl ReplayClient {
+    pub fn new(interactions: Vec<Interaction>) -> Self {
+        Self { interactions: Mutex::new(interactions.into()) }
+    }
+}
+
+#[async_trait]
+impl ChatClient for ReplayClient {
+    async fn stream(&self, _body: Value, _retry: &RetryConfig, _events: &EventSender) -> Result<ChunkStream, OpenAIError> {
+        let interaction = self.interactions.lock().unwrap().pop_front();
+        let interaction = interaction.ok_or(OpenAIError::StreamError("The cassette has no more recorded responses".to_string()))?;
+        let chunks = interaction.chunks.into_iter().filter_map(|e| match e {
+            Recorded::Chunk(chunk) => RsChunkBody::parse(chunk).transpose(),
+            Recorded::Error { error } => Some(Err(OpenAIError::StreamError(error))),
+        });
+        Ok(Box::pin(futures::stream::iter(chunks.collect::<Vec<_>>())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::mock::MockClient;
+    use crate::events::{Renderer, UiEvent};
+
+    struct Discard;
+
+    impl Renderer for Discard {
+        fn render(&mut self, _event: UiEvent) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    async fn collect(client: &dyn ChatClient, body: Value) -> Vec<String> {
+        let events = EventSender::spawn(Box::new(Discard));
+        let stream = client.stream(body, &RetryConfig::default(), &events).await.unwrap();
+        stream.map(|e| e.map(|e| e.choices[0].delta.content.clone()).unwrap_or_else(|e| e.to_string())).collect().await
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let path = std::env::temp_dir().join(format!("rag-vcr-{}.json", std::process::id()));
+        let mock = Arc::new(MockClient::new(vec![MockClient::answer("Hello there"), MockClient::answer("Bye")]));
+        let recording = RecordingClient::new(mock, path.clone());
+        assert_eq!(collect(&recording, serde_json::json!({ "n": 1 })).await, ["Hello ", "there"]);
+        assert_eq!(collect(&recording, serde_json::json!({ "n": 2 })).await, ["Bye"]);
+
+        let interactions = load(&path).unwrap();
+        assert_eq!(interactions.iter().map(|e| e.request["n"].as_u64()).collect::<Vec<_>>(), [Some(1), Some(2)]);
+        let replay = ReplayClient::new(interactions);
+        assert_eq!(collect(&replay, Value::Null).await, ["Hello ", "there"]);
+        assert_eq!(collect(&replay, Value::Null).await, ["Bye"]);
+        assert!(replay.stream(Value::Null, &RetryConfig::default(), &EventSender::spawn(Box::new(Discard))).await.is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+}